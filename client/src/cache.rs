@@ -0,0 +1,181 @@
+use indexmap::IndexMap;
+
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::state::transfer::checksum;
+use crate::utils;
+use crate::DraduError;
+
+// How much space cached images may take by default, in megabytes
+pub const DEFAULT_CACHE_SIZE: u64 = 256;
+
+// Images received from other players, stored in utils::local_dir()/cache under
+// their SHA-256. Once the cache gets too big, the least recently used ones are
+// deleted. Last use is the file's modification time, so it's kept between runs
+pub struct AssetCache {
+    dir: Option<PathBuf>,
+    // In bytes
+    limit: u64,
+    // Hash: Size. Least recently used first
+    entries: IndexMap<String, u64>,
+}
+
+impl AssetCache {
+    pub fn new() -> Self {
+        match utils::local_dir() {
+            Some(dir) => Self::with_dir(dir.join("cache"), DEFAULT_CACHE_SIZE * 1024 * 1024),
+            None => Self {
+                dir: None,
+                limit: 0,
+                entries: IndexMap::new(),
+            },
+        }
+    }
+
+    pub fn with_dir(dir: PathBuf, limit: u64) -> Self {
+        #[allow(unused)]
+        {
+            fs::create_dir_all(&dir);
+        }
+        let mut entries = Vec::new();
+        if let Ok(read_dir) = fs::read_dir(&dir) {
+            for entry in read_dir.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                match entry.metadata() {
+                    Ok(meta) if meta.is_file() && is_hash(&name) => {
+                        let used = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        entries.push((used, name, meta.len()));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        entries.sort();
+        let mut cache = Self {
+            dir: Some(dir),
+            limit,
+            entries: entries
+                .into_iter()
+                .map(|(_, hash, size)| (hash, size))
+                .collect(),
+        };
+        cache.evict();
+        cache
+    }
+
+    // In bytes. Extra files are deleted right away
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = limit;
+        self.evict();
+    }
+
+    pub fn size(&self) -> u64 {
+        self.entries.values().sum()
+    }
+
+    // Files which don't match their hash anymore are thrown away
+    pub fn get(&mut self, hash: &str) -> Option<Vec<u8>> {
+        let path = self.path(hash)?;
+        let size = self.entries.shift_remove(hash)?;
+        match fs::read(&path) {
+            Ok(bytes) if checksum(&bytes) == hash => {
+                self.entries.insert(hash.to_string(), size);
+                #[allow(unused)]
+                {
+                    File::options()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+                }
+                Some(bytes)
+            }
+            _ => {
+                #[allow(unused)]
+                {
+                    fs::remove_file(&path);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, bytes: &[u8]) -> Result<(), DraduError> {
+        let hash = checksum(bytes);
+        let path = self.path(&hash).ok_or(DraduError::ProjectDirNotFound)?;
+        fs::write(path, bytes)?;
+        self.entries.shift_remove(&hash);
+        self.entries.insert(hash, bytes.len() as u64);
+        self.evict();
+        Ok(())
+    }
+
+    fn evict(&mut self) {
+        let mut size = self.size();
+        while size > self.limit {
+            let (hash, len) = match self.entries.shift_remove_index(0) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(path) = self.path(&hash) {
+                #[allow(unused)]
+                {
+                    fs::remove_file(path);
+                }
+            }
+            size -= len;
+        }
+    }
+
+    // Hashes come from other players, so they're checked before being used
+    // as file names
+    fn path(&self, hash: &str) -> Option<PathBuf> {
+        match &self.dir {
+            Some(dir) if is_hash(hash) => Some(dir.join(hash)),
+            _ => None,
+        }
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::AssetCache;
+    use crate::state::transfer::checksum;
+    use crate::utils;
+
+    #[test]
+    fn lru_eviction() {
+        let dir = std::env::temp_dir().join(format!("dradu-cache-{}", utils::random_id()));
+        let mut cache = AssetCache::with_dir(dir.clone(), 250);
+        let (a, b, c) = (vec![1; 100], vec![2; 100], vec![3; 100]);
+
+        cache.insert(&a).unwrap();
+        cache.insert(&b).unwrap();
+        assert_eq!(cache.get(&checksum(&a)), Some(a.clone()));
+        // `b` hasn't been used for the longest time
+        cache.insert(&c).unwrap();
+        assert!(!dir.join(checksum(&b)).exists());
+        assert!(dir.join(checksum(&a)).exists());
+        assert_eq!(cache.size(), 200);
+
+        // Corrupted files are dropped
+        fs::write(dir.join(checksum(&c)), b"junk").unwrap();
+        assert_eq!(cache.get(&checksum(&c)), None);
+        assert_eq!(cache.size(), 100);
+
+        // Cache survives restarts
+        let mut cache = AssetCache::with_dir(dir.clone(), 250);
+        assert_eq!(cache.get(&checksum(&a)), Some(a));
+        assert_eq!(cache.get("../../etc/passwd"), None);
+        cache.set_limit(0);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
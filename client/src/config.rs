@@ -1,12 +1,21 @@
 use eframe::Storage;
 
-use crate::utils;
+use crate::{cache, net, utils};
 
 pub struct Config {
     pub theme: Theme,
     pub nickname: String,
     pub custom_color_enabled: bool,
     pub color: [u8; 3],
+    // Every session is written into utils::local_dir()/recordings
+    pub record_sessions: bool,
+    // Connection is considered lost after the server has been silent for
+    // this many seconds
+    pub timeout_secs: u64,
+    // Images received from other players are cached up to this size
+    pub cache_size_mb: u64,
+    // Rooms we're the master of can be found by players on the LAN
+    pub announce_rooms: bool,
 }
 
 impl Config {
@@ -21,6 +30,26 @@ impl Config {
             _ => false,
         };
 
+        let record_sessions = matches!(
+            storage.get_string("record_sessions"),
+            Some(s) if s.to_lowercase() == "true"
+        );
+
+        let announce_rooms = matches!(
+            storage.get_string("announce_rooms"),
+            Some(s) if s.to_lowercase() == "true"
+        );
+
+        let timeout_secs = storage
+            .get_string("timeout_secs")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(net::DEFAULT_TIMEOUT.as_secs());
+
+        let cache_size_mb = storage
+            .get_string("cache_size_mb")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(cache::DEFAULT_CACHE_SIZE);
+
         let nickname = storage
             .get_string("nickname")
             .unwrap_or(String::new())
@@ -39,6 +68,10 @@ impl Config {
             custom_color_enabled,
             nickname,
             color,
+            record_sessions,
+            timeout_secs,
+            cache_size_mb,
+            announce_rooms,
         }
     }
 
@@ -52,6 +85,10 @@ impl Config {
             self.custom_color_enabled.to_string(),
         );
         storage.set_string("theme", self.theme.to_string());
+        storage.set_string("record_sessions", self.record_sessions.to_string());
+        storage.set_string("timeout_secs", self.timeout_secs.to_string());
+        storage.set_string("cache_size_mb", self.cache_size_mb.to_string());
+        storage.set_string("announce_rooms", self.announce_rooms.to_string());
     }
 }
 
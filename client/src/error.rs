@@ -2,6 +2,8 @@ use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::sync::mpsc::RecvTimeoutError;
 
+use protocol::model::{ErrResponse, ErrorCode};
+
 #[derive(Debug)]
 pub enum DraduError {
     ProtocolError,
@@ -9,9 +11,44 @@ pub enum DraduError {
     Io(std::io::Error),
     ChannelDisconnected,
     TimeoutExceeded,
+    // Outgoing messages have been stuck in the queue for too long
+    SendStalled,
+    // Spectators can't change anything in the room
+    ReadOnly,
     ProjectDirNotFound,
     InvalidPath,
     ImageLoadError(String),
+    MalformedHeader,
+    BodyTooLarge(usize),
+    BadContentType(String),
+    MalformedBody(String),
+    InvalidAddress(String),
+    // Host name which couldn't be resolved
+    UnknownHost(String),
+    MissingRoomId,
+    WebSocketError(String),
+    IncompatibleVersion(String),
+    UnsupportedEncoding(String),
+    TransferError(String),
+    TlsError(String),
+    InvalidRecording(String),
+    // Name of the field and what's wrong with it
+    InvalidField(String, String),
+    // Server has refused our request. Code and the message it has sent
+    ServerError(ErrorCode, String),
+    // Host and the fingerprint of its new certificate
+    CertificateChanged(String, String),
+}
+
+impl DraduError {
+    // Errors in the body of a single message. That message is lost, but the
+    // ones after it can still be read
+    pub fn is_body_error(&self) -> bool {
+        matches!(
+            self,
+            Self::BadContentType(_) | Self::MalformedBody(_) | Self::UnsupportedEncoding(_)
+        )
+    }
 }
 
 impl Error for DraduError {
@@ -30,6 +67,8 @@ impl Display for DraduError {
             Self::ConnectionError => write!(f, "Connection error"),
             Self::ChannelDisconnected => write!(f, "Channel disconnected"),
             Self::TimeoutExceeded => write!(f, "Server response timeout exceeded"),
+            Self::SendStalled => write!(f, "Server isn't accepting our messages"),
+            Self::ReadOnly => write!(f, "Spectators can't change the room"),
             Self::Io(err) => write!(f, "{}", err),
             Self::ProjectDirNotFound => write!(
                 f,
@@ -37,6 +76,34 @@ impl Display for DraduError {
             ),
             Self::InvalidPath => write!(f, "Invalid path"),
             Self::ImageLoadError(err) => write!(f, "Could not load image: {}", err),
+            Self::MalformedHeader => write!(f, "Received a malformed message header"),
+            Self::BodyTooLarge(len) => write!(f, "Message body is too large ({} bytes)", len),
+            Self::BadContentType(t) => write!(f, "Unknown content type: {}", t),
+            Self::MalformedBody(err) => write!(f, "Malformed message body: {}", err),
+            Self::InvalidAddress(addr) => write!(f, "Invalid address: {}", addr),
+            Self::UnknownHost(host) => write!(f, "Could not find host {}", host),
+            Self::MissingRoomId => write!(f, "Room address has no room ID"),
+            Self::WebSocketError(err) => write!(f, "WebSocket error: {}", err),
+            Self::TransferError(e) => write!(f, "File transfer failed: {}", e),
+            Self::TlsError(e) => write!(f, "TLS error: {}", e),
+            Self::InvalidField(field, reason) => {
+                write!(f, "Invalid field `{}`: {}", field, reason)
+            }
+            Self::ServerError(_, message) => write!(f, "{}", message),
+            Self::InvalidRecording(e) => write!(f, "Could not load the recording: {}", e),
+            Self::CertificateChanged(host, fingerprint) => write!(
+                f,
+                "Certificate of {} has changed since the last connection, someone may be \
+                 intercepting it! New fingerprint: {}",
+                host, fingerprint
+            ),
+            Self::UnsupportedEncoding(e) => write!(f, "Unsupported content encoding: {}", e),
+            Self::IncompatibleVersion(ver) => write!(
+                f,
+                "Server uses protocol version {}, which is incompatible with ours ({})",
+                ver,
+                crate::net::PROTOCOL_VERSION
+            ),
         }
     }
 }
@@ -47,6 +114,26 @@ impl From<std::io::Error> for DraduError {
     }
 }
 
+impl From<protocol::Error> for DraduError {
+    fn from(error: protocol::Error) -> Self {
+        match error {
+            protocol::Error::MalformedHeader => Self::MalformedHeader,
+            protocol::Error::BodyTooLarge(len) => Self::BodyTooLarge(len),
+            protocol::Error::BadContentType(t) => Self::BadContentType(t),
+            protocol::Error::MalformedBody(err) => Self::MalformedBody(err),
+            protocol::Error::IncompatibleVersion(ver) => Self::IncompatibleVersion(ver),
+            protocol::Error::UnsupportedEncoding(e) => Self::UnsupportedEncoding(e),
+            protocol::Error::InvalidField(field, reason) => Self::InvalidField(field, reason),
+        }
+    }
+}
+
+impl From<ErrResponse> for DraduError {
+    fn from(error: ErrResponse) -> Self {
+        Self::ServerError(error.code, error.message)
+    }
+}
+
 impl From<RecvTimeoutError> for DraduError {
     fn from(error: RecvTimeoutError) -> Self {
         match error {
@@ -55,3 +142,15 @@ impl From<RecvTimeoutError> for DraduError {
         }
     }
 }
+
+impl From<tungstenite::Error> for DraduError {
+    fn from(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Io(err) => Self::Io(err),
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                Self::ConnectionError
+            }
+            err => Self::WebSocketError(err.to_string()),
+        }
+    }
+}
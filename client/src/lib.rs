@@ -0,0 +1,12 @@
+mod cache;
+pub mod config;
+mod error;
+mod fs;
+pub mod net;
+pub mod setup;
+pub mod state;
+pub mod textures;
+pub mod ui;
+pub mod utils;
+
+pub use crate::error::DraduError;
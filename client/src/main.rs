@@ -1,38 +1,35 @@
 #![windows_subsystem = "windows"]
 
-mod config;
-mod error;
-mod fs;
-mod net;
-mod setup;
-mod state;
-mod textures;
-mod ui;
-mod utils;
-
-pub use crate::error::DraduError;
-
 use eframe::egui;
 use eframe::{Frame, Storage};
 use egui::Context;
 
-use crate::config::Config;
-use crate::state::RoomState;
-use crate::textures::Textures;
-use crate::ui::{MainUi, MenuAction, MenuUi};
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use client::config::Config;
+use client::net::{JoinOptions, Recording, RoomAddr};
+use client::state::RoomState;
+use client::textures::Textures;
+use client::ui::{LoadingScreenUi, MainUi, MenuAction, MenuUi};
+use client::{setup, utils, DraduError};
 
 struct DraduApp {
     config: Config,
     textures: Textures,
     main_ui: MainUi,
     menu_ui: MenuUi,
+    loading_screen_ui: LoadingScreenUi,
     room_state: Option<RoomState>,
 }
 
 impl DraduApp {
     // This function will set everything up, including egui and the app itself:
-    // load settings, set up theming and fonts, check integrity of filesystem
-    fn new(cc: &eframe::CreationContext) -> Self {
+    // load settings, set up theming and fonts, check integrity of filesystem.
+    // With a room address, e.g. from a dradu:// link, the room is joined right
+    // away
+    fn new(cc: &eframe::CreationContext, room_address: Option<String>) -> Self {
         let config = Config::load(cc.storage.unwrap());
         setup::setup_ui(&config, &cc.egui_ctx);
 
@@ -40,29 +37,76 @@ impl DraduApp {
         let main_ui = MainUi::new(textures.clone());
         let menu_ui = MenuUi::new(textures.clone(), &config);
 
-        DraduApp {
+        let mut app = DraduApp {
             config,
             textures,
             main_ui,
             menu_ui,
+            loading_screen_ui: LoadingScreenUi::new(),
             room_state: None,
+        };
+        if let Some(address) = room_address {
+            app.join_at_startup(&address, &cc.egui_ctx);
+        }
+        app
+    }
+
+    // Errors end up in the menu, same as when joining from there
+    fn join_at_startup(&mut self, address: &str, ctx: &Context) {
+        let room = match address.parse::<RoomAddr>() {
+            Ok(room) => room,
+            Err(e) => return self.menu_ui.set_error(e),
+        };
+        let options = JoinOptions {
+            invite: room.invite,
+            ..JoinOptions::default()
+        };
+        let joined = RoomState::join_room(room.server, &room.room_id, &options, ctx)
+            .and_then(|mut state| Self::prepare_room(&self.config, &mut state).map(|()| state));
+        match joined {
+            Ok(state) => self.room_state = Some(state),
+            Err(e) => self.menu_ui.set_error(e),
         }
     }
 
-    // Called after player left the room (Disconnected)
-    fn reset(&mut self) {
+    // Called after player left the room (Disconnected). If it happened because
+    // of an error, it will be shown in the menu
+    fn reset(&mut self, reason: Option<DraduError>) {
         self.main_ui = MainUi::new(self.textures.clone());
         self.menu_ui = MenuUi::new(self.textures.clone(), &self.config);
         self.room_state = None;
+        match reason {
+            // This is what we get after quitting the room ourselves
+            Some(DraduError::ChannelDisconnected) | None => (),
+            Some(err) => self.menu_ui.set_error(err),
+        }
+    }
+
+    // Called right after joining or creating a room
+    fn prepare_room(config: &Config, state: &mut RoomState) -> Result<(), DraduError> {
+        state.set_timeout(Duration::from_secs(config.timeout_secs));
+        state.set_cache_limit(config.cache_size_mb * 1024 * 1024);
+        if config.announce_rooms {
+            state.announce_on_lan();
+        }
+        if config.record_sessions {
+            if let Some(path) = new_recording_path() {
+                if let Err(e) = state.start_recording(&path) {
+                    state.show_error(&format!("Could not start recording: {}", e));
+                }
+            }
+        }
+        Self::set_nickname_and_color(config, state)
     }
 
-    fn set_nickname_and_color(config: &Config, state: &mut RoomState) {
+    fn set_nickname_and_color(config: &Config, state: &mut RoomState) -> Result<(), DraduError> {
         if !config.nickname.trim().is_empty() {
-            state.send_chat_message(&format!("/nickname {}", config.nickname));
+            state.send_chat_message(&format!("/nickname {}", config.nickname))?;
         }
         if config.custom_color_enabled {
-            state.send_chat_message(&format!("/color {}", utils::rgb_to_string(config.color)));
+            state.send_chat_message(&format!("/color {}", utils::rgb_to_string(config.color)))?;
         }
+        Ok(())
     }
 }
 
@@ -71,27 +115,40 @@ impl eframe::App for DraduApp {
     fn update(&mut self, ctx: &Context, frame: &mut Frame) {
         match &mut self.room_state {
             Some(ref mut state) => {
-                if let Err(_) = state.update_self() {
-                    self.reset();
+                if let Err(e) = state.update_self() {
+                    self.reset(Some(e));
                     return;
                 }
-                if let Err(_) = self.main_ui.update(ctx, state) {
-                    self.reset();
+                if state.is_uploading() || state.map().is_in_motion() {
+                    ctx.request_repaint();
+                }
+                if state.loading().is_some() {
+                    self.loading_screen_ui.update(ctx, state);
+                } else if let Err(e) = self.main_ui.update(ctx, state) {
+                    self.reset(Some(e));
                 }
             }
             state => match self.menu_ui.update(ctx, frame, &mut self.config) {
-                MenuAction::JoinRoom(addr, room_id) => {
-                    *state = RoomState::join_room(addr, &room_id, ctx).ok();
-                    if let Some(s) = state {
-                        Self::set_nickname_and_color(&self.config, s);
+                MenuAction::JoinRoom(addr, room_id, options) => {
+                    let joined = RoomState::join_room(addr, &room_id, &options, ctx)
+                        .and_then(|mut s| Self::prepare_room(&self.config, &mut s).map(|()| s));
+                    match joined {
+                        Ok(s) => *state = Some(s),
+                        Err(e) => self.menu_ui.set_error(e),
                     }
                 }
-                MenuAction::NewRoom(addr) => {
-                    *state = RoomState::create_new_room(addr, ctx).ok();
-                    if let Some(s) = state {
-                        Self::set_nickname_and_color(&self.config, s);
+                MenuAction::NewRoom(addr, password) => {
+                    let created = RoomState::create_new_room(addr, password.as_deref(), ctx)
+                        .and_then(|mut s| Self::prepare_room(&self.config, &mut s).map(|()| s));
+                    match created {
+                        Ok(s) => *state = Some(s),
+                        Err(e) => self.menu_ui.set_error(e),
                     }
                 }
+                MenuAction::Replay(path) => match Recording::load(&path) {
+                    Ok(recording) => *state = Some(RoomState::replay(recording)),
+                    Err(e) => self.menu_ui.set_error(e),
+                },
                 MenuAction::MapCreator => {
                     *state = Some(RoomState::create_local_server(ctx));
                 }
@@ -105,10 +162,22 @@ impl eframe::App for DraduApp {
     }
 }
 
+// Recordings are named after the time they were started at
+fn new_recording_path() -> Option<PathBuf> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    let name = format!("{}.drec", time.as_secs());
+    Some(utils::local_dir()?.join("recordings").join(name))
+}
+
 fn main() {
     let options = eframe::NativeOptions {
         drag_and_drop_support: true,
         ..Default::default()
     };
-    eframe::run_native("Dradu", options, Box::new(|cc| Box::new(DraduApp::new(cc))));
+    let room_address = env::args().nth(1);
+    eframe::run_native(
+        "Dradu",
+        options,
+        Box::new(|cc| Box::new(DraduApp::new(cc, room_address))),
+    );
 }
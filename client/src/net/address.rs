@@ -0,0 +1,254 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::DraduError;
+
+pub const DEFAULT_PORT: u16 = 8889;
+// Room addresses can be written as URIs, e.g. dradu://example.com:8889/roomId.
+// The second scheme is for servers with TLS
+const SCHEME: &str = "dradu://";
+const TLS_SCHEME: &str = "dradu+tls://";
+
+// Where the server is and how to talk to it. Parsed from whatever the user
+// typed into the menu (Without the "#roomId" part)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerAddr {
+    // host:port, e.g. example.com:8889. Host names are looked up when
+    // connecting, so addresses shared with others keep them
+    Tcp(String),
+    // Full URL, e.g. ws://example.com:8890/dradu
+    WebSocket(String),
+    // host:port, e.g. tls://example.com:8889 (Without the prefix)
+    Tls(String),
+}
+
+impl FromStr for ServerAddr {
+    type Err = DraduError;
+
+    // Port is optional, DEFAULT_PORT is used without it
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("ws://") {
+            Ok(Self::WebSocket(s.to_string()))
+        } else if let Some(host) = s.strip_prefix("tls://") {
+            parse_host_port(host).map(Self::Tls)
+        } else {
+            parse_host_port(s).map(Self::Tcp)
+        }
+    }
+}
+
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::WebSocket(url) => write!(f, "{}", url),
+            Self::Tls(addr) => write!(f, "tls://{}", addr),
+        }
+    }
+}
+
+// Server and the room on it. Either "<server address>#<room ID>" or a URI:
+// dradu://host[:port]/roomId (dradu+tls:// with TLS). WebSocket URLs have
+// paths of their own, so they only come in the first form. Invite links add
+// "?invite=<token>" to either
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomAddr {
+    pub server: ServerAddr,
+    pub room_id: String,
+    pub invite: Option<String>,
+}
+
+impl FromStr for RoomAddr {
+    type Err = DraduError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || DraduError::InvalidAddress(s.to_string());
+        let (server, room_id) = if let Some(rest) = s.strip_prefix(SCHEME) {
+            let (host, room_id) = rest.split_once('/').ok_or_else(invalid)?;
+            (ServerAddr::Tcp(parse_host_port(host)?), room_id)
+        } else if let Some(rest) = s.strip_prefix(TLS_SCHEME) {
+            let (host, room_id) = rest.split_once('/').ok_or_else(invalid)?;
+            (ServerAddr::Tls(parse_host_port(host)?), room_id)
+        } else {
+            let (server, room_id) = s.rsplit_once('#').ok_or(DraduError::MissingRoomId)?;
+            (server.parse()?, room_id)
+        };
+        let (room_id, invite) = match room_id.split_once('?') {
+            Some((room_id, query)) => {
+                let invite = query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("invite="))
+                    .filter(|token| !token.is_empty())
+                    .ok_or_else(invalid)?;
+                (room_id, Some(invite.to_string()))
+            }
+            None => (room_id, None),
+        };
+        // Copied URIs sometimes get a trailing slash
+        let room_id = room_id.strip_suffix('/').unwrap_or(room_id);
+        if room_id.is_empty() {
+            return Err(DraduError::MissingRoomId);
+        }
+        if room_id.contains(['/', '#']) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            server,
+            room_id: room_id.to_string(),
+            invite,
+        })
+    }
+}
+
+impl Display for RoomAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.server {
+            ServerAddr::Tcp(addr) => write!(f, "{}{}/{}", SCHEME, addr, self.room_id),
+            ServerAddr::Tls(addr) => write!(f, "{}{}/{}", TLS_SCHEME, addr, self.room_id),
+            ServerAddr::WebSocket(url) => write!(f, "{}#{}", url, self.room_id),
+        }?;
+        match &self.invite {
+            Some(token) => write!(f, "?invite={}", token),
+            None => Ok(()),
+        }
+    }
+}
+
+// Splits "host:port", "[IPv6]:port", "[IPv6]" or a bare IPv6 address. Port
+// defaults to DEFAULT_PORT, host comes without the brackets
+pub(super) fn split_host_port(s: &str) -> Result<(String, u16), DraduError> {
+    let invalid = || DraduError::InvalidAddress(s.to_string());
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok((ip.to_string(), DEFAULT_PORT));
+    }
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+        host.parse::<IpAddr>().map_err(|_| invalid())?;
+        let port = match port {
+            "" => DEFAULT_PORT,
+            port => parse_port(port.strip_prefix(':').ok_or_else(invalid)?)?,
+        };
+        return Ok((host.to_string(), port));
+    }
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, port)) => (host, parse_port(port)?),
+        None => (s, DEFAULT_PORT),
+    };
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+    if host.is_empty() || !host.chars().all(valid_char) {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port))
+}
+
+// Adds the default port if there is none
+fn parse_host_port(s: &str) -> Result<String, DraduError> {
+    let (host, port) = split_host_port(s)?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port).to_string()),
+        Err(_) => Ok(format!("{}:{}", host, port)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoomAddr, ServerAddr};
+    use crate::DraduError;
+
+    #[test]
+    fn parse_server_addr() {
+        let tcp = |addr: &str| ServerAddr::Tcp(addr.to_string());
+        assert_eq!(
+            "127.0.0.1:9000".parse::<ServerAddr>().unwrap(),
+            tcp("127.0.0.1:9000")
+        );
+        assert_eq!(
+            "127.0.0.1".parse::<ServerAddr>().unwrap(),
+            tcp("127.0.0.1:8889")
+        );
+        assert_eq!("::1".parse::<ServerAddr>().unwrap(), tcp("[::1]:8889"));
+        assert_eq!("[::1]".parse::<ServerAddr>().unwrap(), tcp("[::1]:8889"));
+        assert_eq!(
+            "example.com:9000".parse::<ServerAddr>().unwrap(),
+            tcp("example.com:9000")
+        );
+        assert_eq!(
+            "ws://localhost:8890/dradu".parse::<ServerAddr>().unwrap(),
+            ServerAddr::WebSocket("ws://localhost:8890/dradu".to_string())
+        );
+        assert_eq!(
+            "tls://example.com".parse::<ServerAddr>().unwrap(),
+            ServerAddr::Tls("example.com:8889".to_string())
+        );
+        assert_eq!(
+            "tls://127.0.0.1:9000".parse::<ServerAddr>().unwrap(),
+            ServerAddr::Tls("127.0.0.1:9000".to_string())
+        );
+        assert_eq!(
+            "tls://::1".parse::<ServerAddr>().unwrap(),
+            ServerAddr::Tls("[::1]:8889".to_string())
+        );
+        assert!("tls://".parse::<ServerAddr>().is_err());
+        assert!("tls://example.com:port".parse::<ServerAddr>().is_err());
+        assert!("[::1]9000".parse::<ServerAddr>().is_err());
+        assert!("not an address".parse::<ServerAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_room_addr() {
+        let room = |s: &str| s.parse::<RoomAddr>().unwrap();
+        let tcp = ServerAddr::Tcp("127.0.0.1:9000".to_string());
+        assert_eq!(room("127.0.0.1:9000#abcd").server, tcp);
+        assert_eq!(room("dradu://127.0.0.1:9000/abcd/").server, tcp);
+        assert_eq!(room("127.0.0.1:9000#abcd").room_id, "abcd");
+        assert_eq!(
+            room("dradu://[::1]/abcd").to_string(),
+            "dradu://[::1]:8889/abcd"
+        );
+        assert_eq!(
+            room("dradu://example.com/abcd").to_string(),
+            "dradu://example.com:8889/abcd"
+        );
+        assert_eq!(
+            room("tls://example.com#abcd").to_string(),
+            "dradu+tls://example.com:8889/abcd"
+        );
+        assert_eq!(
+            room("dradu+tls://example.com/abcd").server,
+            ServerAddr::Tls("example.com:8889".to_string())
+        );
+        // URLs have their own paths
+        assert_eq!(
+            room("ws://localhost:8890/dradu#abcd").to_string(),
+            "ws://localhost:8890/dradu#abcd"
+        );
+        assert!(matches!(
+            "127.0.0.1".parse::<RoomAddr>(),
+            Err(DraduError::MissingRoomId)
+        ));
+        assert!(matches!(
+            "dradu://127.0.0.1/".parse::<RoomAddr>(),
+            Err(DraduError::MissingRoomId)
+        ));
+        assert!("dradu://127.0.0.1/a/b".parse::<RoomAddr>().is_err());
+
+        let invite = room("dradu://127.0.0.1:9000/abcd?invite=a.1.f");
+        assert_eq!(invite.room_id, "abcd");
+        assert_eq!(invite.invite.as_deref(), Some("a.1.f"));
+        assert_eq!(
+            invite.to_string(),
+            "dradu://127.0.0.1:9000/abcd?invite=a.1.f"
+        );
+        assert_eq!(
+            room("127.0.0.1:9000#abcd?invite=a.1.f").invite,
+            invite.invite
+        );
+        assert!("dradu://127.0.0.1/abcd?invite="
+            .parse::<RoomAddr>()
+            .is_err());
+    }
+}
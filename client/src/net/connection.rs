@@ -1,160 +1,583 @@
 use eframe::egui::{Color32, Context};
-
 use json::JsonValue;
 
-use std::io::{Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::str::FromStr;
-use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError};
 use std::thread;
-use std::time::Duration;
-
-use crate::net::{Message, MsgBody, MsgType};
-use crate::utils;
+use std::time::{Duration, Instant};
+
+use crate::net::address;
+use crate::net::heartbeat::{Heartbeat, PING_INTERVAL};
+use crate::net::model::{ErrResponse, Hello, OkResponse};
+use crate::net::{
+    Message, MessageFramer, MsgBody, MsgType, Playback, RoomAddr, ServerAddr, CAPABILITIES,
+    PROTOCOL_VERSION,
+};
 use crate::DraduError;
 
-const PE: DraduError = DraduError::ProtocolError;
+pub(super) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How many messages may wait for the writer before they start piling up in
+// SendQueue's overflow
+const QUEUE_CAPACITY: usize = 64;
+
+// Opens a new connection for Connection::reconnector(), blocking until it's
+// done or has failed
+pub type Reconnector = Box<dyn FnOnce() -> Result<Box<dyn Connection + Send>, DraduError> + Send>;
 
 pub trait Connection {
     fn new_messages(&mut self) -> Result<Vec<Message>, DraduError>;
     fn send_msg(&mut self, msg: Message) -> Result<usize, DraduError>;
     fn get_room_address(&self) -> Result<String, DraduError>;
+    // Opens a new connection to the same room and resumes our session (We keep
+    // the same user ID). Messages sent before the old connection died may be lost
+    fn reconnect(&mut self) -> Result<(), DraduError>;
+    // Same as .reconnect(), but the new connection can be opened on another
+    // thread, and then takes the place of this one. None if there's nothing
+    // to reconnect to
+    fn reconnector(&self) -> Option<Reconnector> {
+        None
+    }
     fn close(&mut self);
     fn get_user_id(&self) -> &str;
     fn get_nickname(&self) -> &str;
     fn get_user_color(&self) -> Color32;
+    // Full protocol version of the server (MAJOR.MINOR)
+    fn get_server_version(&self) -> &str;
+    // Optional features supported by both us and the server. See CAPABILITIES
+    fn get_capabilities(&self) -> &[String];
+
+    // Errors of the messages .new_messages() has skipped because they couldn't
+    // be decoded. The connection itself is fine after these
+    fn take_rejected(&mut self) -> Vec<DraduError> {
+        Vec::new()
+    }
+
+    fn supports(&self, capability: &str) -> bool {
+        self.get_capabilities().iter().any(|c| c == capability)
+    }
+
+    // Round trip time to the server, if the connection measures it
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+
+    // If the server stays silent for this long, .new_messages() returns
+    // TimeoutExceeded. Only works with servers that support "heartbeat".
+    // Messages that couldn't be sent for this long make it return SendStalled
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    // True while messages are sent slower than they are queued. Things that can
+    // wait, like file uploads, should hold back
+    fn is_congested(&self) -> bool {
+        false
+    }
+
+    // Pause, seek and speed controls, if this is a recorded session
+    fn playback(&mut self) -> Option<&mut Playback> {
+        None
+    }
 }
 
-pub struct ServerConnection {
-    user_id: String,
-    user_cookie: String,
-    nickname: String,
-    user_color: Color32,
-    room_id: String,
-    stream: TcpStream,
-    receiver: Receiver<Message>,
+// Where a networked connection goes and how it gets the bytes there. Session,
+// heartbeat and queues work the same over all of them, see NetConnection
+pub trait Transport: Clone + Send + 'static {
+    // Connects and starts the thread(s) which write everything from `outgoing`
+    // into the socket and send whatever they read (Or fail with) to `incoming`.
+    // Once `outgoing` is dropped they close the socket and exit
+    fn open(
+        &self,
+        outgoing: Receiver<Vec<u8>>,
+        incoming: Sender<Result<Message, DraduError>>,
+        ctx: &Context,
+    ) -> Result<(), DraduError>;
+    fn server_addr(&self) -> ServerAddr;
 }
 
-impl ServerConnection {
-    pub fn join_room(addr: SocketAddr, room_id: &str, ctx: &Context) -> Result<Self, DraduError> {
-        let mut stream = TcpStream::connect(addr)?;
+// Connection to a real server, over any transport
+pub struct NetConnection<T> {
+    transport: T,
+    session: Session,
+    heartbeat: Heartbeat,
+    ctx: Context,
+    queue: SendQueue,
+    receiver: Receiver<Result<Message, DraduError>>,
+    rejected: Vec<DraduError>,
+}
 
-        let receiver = spawn_receiving_thread(stream.try_clone().unwrap(), ctx);
+impl<T: Transport> NetConnection<T> {
+    fn open(
+        transport: T,
+        first_msg: Message,
+        room_id: Option<&str>,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        let (tx, receiver) = mpsc::channel();
+        let (mut queue, outgoing) = SendQueue::new(QUEUE_CAPACITY);
+        transport.open(outgoing, tx, ctx)?;
 
         // Setting up connection
-        let mut msg = Message::new(MsgType::Join);
-        msg.attach_body(MsgBody::Json(JsonValue::from(format!(
-            "{{\"roomId\":\"{}\"}}",
-            room_id
-        ))));
-        stream.write_all(&msg.into_bytes())?;
-
-        let mut msg = receiver.recv_timeout(Duration::from_secs(3))?;
-        let json = match (msg.msg_type(), msg.take_body()) {
-            (MsgType::Ok, Some(MsgBody::Json(json))) => json,
-            _ => return Err(DraduError::ConnectionError),
-        };
+        queue.push(first_msg.into_plain_bytes())?;
+        let session = await_ok(&receiver, room_id)?;
 
-        let user_id = json["userId"].as_str().ok_or(PE)?.to_string();
-        let user_cookie = json["userCookie"].as_str().ok_or(PE)?.to_string();
-        let nickname = json["nickname"].as_str().unwrap_or("").to_string();
-        let user_color = utils::color32_from_json_value(&json["color"]).unwrap_or(Color32::WHITE);
-
-        Ok(ServerConnection {
-            user_id,
-            user_cookie,
-            user_color,
-            nickname,
-            room_id: room_id.to_string(),
-            stream,
+        Ok(Self {
+            heartbeat: Heartbeat::new(session.supports("heartbeat"), Instant::now()),
+            transport,
+            session,
+            ctx: ctx.clone(),
+            queue,
             receiver,
+            rejected: Vec::new(),
         })
     }
 
-    pub fn create_new_room(addr: SocketAddr, ctx: &Context) -> Result<Self, DraduError> {
-        let mut stream = TcpStream::connect(addr)?;
-
-        let receiver = spawn_receiving_thread(stream.try_clone().unwrap(), ctx);
-
-        let msg = Message::new(MsgType::Init);
-        stream.write_all(&msg.into_bytes())?;
+    pub(super) fn join(
+        transport: T,
+        room_id: &str,
+        options: &JoinOptions,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::open(transport, join_msg(room_id, options), Some(room_id), ctx)
+    }
 
-        let mut msg = receiver.recv_timeout(Duration::from_secs(3))?;
-        let json = match (msg.msg_type(), msg.take_body()) {
-            (MsgType::Ok, Some(MsgBody::Json(json))) => json,
-            _ => return Err(DraduError::ConnectionError),
-        };
+    pub(super) fn create(
+        transport: T,
+        password: Option<&str>,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::open(transport, init_msg(password), None, ctx)
+    }
 
-        let user_id = json["userId"].as_str().ok_or(PE)?.to_string();
-        let user_cookie = json["userCookie"].as_str().ok_or(PE)?.to_string();
-        let nickname = json["nickname"].as_str().unwrap_or("").to_string();
-        let user_color = utils::color32_from_json_value(&json["color"]).unwrap_or(Color32::WHITE);
-        let room_id = json["roomId"].as_str().ok_or(PE)?.to_string();
-
-        Ok(ServerConnection {
-            user_id,
-            user_cookie,
-            nickname,
-            user_color,
-            room_id,
-            stream,
-            receiver,
-        })
+    // Connects to the same room again and resumes the session
+    fn reopener(&self) -> impl FnOnce() -> Result<Self, DraduError> + Send {
+        let transport = self.transport.clone();
+        let resume = resume_msg(&self.session);
+        let room_id = self.session.room_id.clone();
+        let timeout = self.heartbeat.timeout();
+        let ctx = self.ctx.clone();
+        move || {
+            let mut new = Self::open(transport, resume, Some(&room_id), &ctx)?;
+            new.heartbeat.set_timeout(timeout);
+            Ok(new)
+        }
     }
 }
 
-impl Connection for ServerConnection {
+impl<T: Transport> Connection for NetConnection<T> {
     fn new_messages(&mut self) -> Result<Vec<Message>, DraduError> {
-        let mut messages = Vec::new();
-        loop {
-            match self.receiver.try_recv() {
-                Ok(msg) => messages.push(msg),
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => return Err(DraduError::ChannelDisconnected),
-            }
+        let mut messages = collect_messages(&self.receiver, &mut self.rejected)?;
+        self.queue.flush(self.heartbeat.timeout())?;
+        if let Some(ping) = self.heartbeat.beat(&mut messages, Instant::now())? {
+            self.send_msg(ping)?;
+            self.ctx.request_repaint_after(PING_INTERVAL);
         }
         Ok(messages)
     }
 
     fn send_msg(&mut self, msg: Message) -> Result<usize, DraduError> {
-        let msg = msg
-            .set_prop("userId", &self.user_id)
-            .set_prop("userCookie", &self.user_cookie);
-        Ok(self.stream.write(&msg.into_bytes())?)
+        let bytes = self.session.encode(msg);
+        let len = bytes.len();
+        self.queue.push(bytes)?;
+        Ok(len)
     }
 
     fn get_room_address(&self) -> Result<String, DraduError> {
-        Ok(format!("{}#{}", self.stream.peer_addr()?, self.room_id))
+        let addr = RoomAddr {
+            server: self.transport.server_addr(),
+            room_id: self.session.room_id.clone(),
+            invite: None,
+        };
+        Ok(addr.to_string())
+    }
+
+    fn reconnect(&mut self) -> Result<(), DraduError> {
+        let new = self.reopener()()?;
+        self.close();
+        *self = new;
+        Ok(())
+    }
+
+    fn reconnector(&self) -> Option<Reconnector> {
+        let reopen = self.reopener();
+        Some(Box::new(move || Ok(Box::new(reopen()?))))
     }
 
     fn close(&mut self) {
-        #[allow(unused)]
-        {
-            self.stream.shutdown(Shutdown::Both);
-        }
-        // ?HACK?: Manually dropping the mpsc channel so .new_messages() will return Err
+        // Dropping the queue tells the transport to close the socket, and
+        // dropping the receiver makes .new_messages() return Err
+        self.queue.close();
         self.receiver = mpsc::channel().1;
     }
 
+    fn take_rejected(&mut self) -> Vec<DraduError> {
+        std::mem::take(&mut self.rejected)
+    }
+
     fn get_user_id(&self) -> &str {
-        &self.user_id
+        &self.session.user_id
     }
 
     fn get_nickname(&self) -> &str {
-        &self.nickname
+        &self.session.nickname
     }
 
     fn get_user_color(&self) -> Color32 {
-        self.user_color
+        self.session.user_color
+    }
+
+    fn get_server_version(&self) -> &str {
+        &self.session.server_version
+    }
+
+    fn get_capabilities(&self) -> &[String] {
+        &self.session.capabilities
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.heartbeat.rtt()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.heartbeat.set_timeout(timeout);
+    }
+
+    fn is_congested(&self) -> bool {
+        self.queue.is_congested()
+    }
+}
+
+// Plain TCP. Address should look like host:port
+pub type ServerConnection = NetConnection<TcpTransport>;
+
+impl ServerConnection {
+    pub fn join_room(
+        addr: &str,
+        room_id: &str,
+        options: &JoinOptions,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::join(TcpTransport::new(addr), room_id, options, ctx)
+    }
+
+    pub fn create_new_room(
+        addr: &str,
+        password: Option<&str>,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::create(TcpTransport::new(addr), password, ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct TcpTransport {
+    // host:port, as ServerAddr::Tcp has it
+    addr: String,
+}
+
+impl TcpTransport {
+    fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn open(
+        &self,
+        outgoing: Receiver<Vec<u8>>,
+        incoming: Sender<Result<Message, DraduError>>,
+        ctx: &Context,
+    ) -> Result<(), DraduError> {
+        let (host, port) = address::split_host_port(&self.addr)?;
+        let stream = connect_tcp(&host, port)?;
+        spawn_receiving_thread(stream.try_clone()?, incoming.clone(), ctx);
+        spawn_writing_thread(ClosingStream(stream), outgoing, incoming, ctx);
+        Ok(())
+    }
+
+    fn server_addr(&self) -> ServerAddr {
+        ServerAddr::Tcp(self.addr.clone())
     }
 }
 
+// Socket which is shut down once the writing thread is done with it, so the
+// receiving thread stops waiting for data as well
+struct ClosingStream(TcpStream);
+
+impl Write for ClosingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for ClosingStream {
+    fn drop(&mut self) {
+        #[allow(unused)]
+        {
+            self.0.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Outgoing messages on their way to the thread which writes them into the
+// socket, so the UI thread never waits for a slow link. The queue is bounded:
+// once it's full, messages are put aside into the overflow and the connection
+// reports itself congested
+struct SendQueue {
+    queue: SyncSender<Vec<u8>>,
+    overflow: VecDeque<Vec<u8>>,
+    // Last time the writer took something from us while the overflow wasn't empty
+    last_progress: Option<Instant>,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize) -> (Self, Receiver<Vec<u8>>) {
+        let (queue, outgoing) = mpsc::sync_channel(capacity);
+        let send_queue = Self {
+            queue,
+            overflow: VecDeque::new(),
+            last_progress: None,
+        };
+        (send_queue, outgoing)
+    }
+
+    // Only fails if the writer is gone
+    pub fn push(&mut self, bytes: Vec<u8>) -> Result<(), DraduError> {
+        self.overflow.push_back(bytes);
+        self.move_overflow()
+    }
+
+    // Gives the writer whatever it can take now. Returns SendStalled if it
+    // hasn't taken anything for `timeout`
+    pub fn flush(&mut self, timeout: Duration) -> Result<(), DraduError> {
+        self.move_overflow()?;
+        match self.last_progress {
+            Some(time) if time.elapsed() > timeout => Err(DraduError::SendStalled),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_congested(&self) -> bool {
+        !self.overflow.is_empty()
+    }
+
+    // Drops the queue, which makes the writer exit
+    pub fn close(&mut self) {
+        *self = Self::new(0).0;
+    }
+
+    fn move_overflow(&mut self) -> Result<(), DraduError> {
+        let mut moved = false;
+        while let Some(bytes) = self.overflow.pop_front() {
+            match self.queue.try_send(bytes) {
+                Ok(()) => moved = true,
+                Err(TrySendError::Full(bytes)) => {
+                    self.overflow.push_front(bytes);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(DraduError::ChannelDisconnected),
+            }
+        }
+        if self.overflow.is_empty() {
+            self.last_progress = None;
+        } else if moved || self.last_progress.is_none() {
+            self.last_progress = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+// What the server told us about ourselves in its OK reply to JOIN/INIT
+struct Session {
+    pub user_id: String,
+    pub user_cookie: String,
+    pub nickname: String,
+    pub user_color: Color32,
+    pub room_id: String,
+    pub server_version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl Session {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    // Attaches our credentials to the message
+    pub fn sign(&self, msg: Message) -> Message {
+        msg.set_prop("userId", &self.user_id)
+            .set_prop("userCookie", &self.user_cookie)
+    }
+
+    // Signs the message and turns it into bytes, compressing the body if the
+    // server can handle that
+    pub fn encode(&self, msg: Message) -> Vec<u8> {
+        let msg = self.sign(msg);
+        if self.supports("deflate") {
+            msg.into_bytes()
+        } else {
+            msg.into_plain_bytes()
+        }
+    }
+}
+
+// Takes everything the receiving thread has sent so far. Messages with a bad
+// body are skipped and their errors put into `rejected`, only errors after
+// which nothing more can be received are returned
+fn collect_messages(
+    receiver: &Receiver<Result<Message, DraduError>>,
+    rejected: &mut Vec<DraduError>,
+) -> Result<Vec<Message>, DraduError> {
+    let mut messages = Vec::new();
+    loop {
+        match receiver.try_recv() {
+            Ok(Ok(msg)) => messages.push(msg),
+            Ok(Err(e)) if e.is_body_error() => rejected.push(e),
+            Ok(Err(e)) => return Err(e),
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => return Err(DraduError::ChannelDisconnected),
+        }
+    }
+    Ok(messages)
+}
+
+// Host names are looked up every time, and each of their addresses is tried in
+// turn, e.g. IPv4 after IPv6 hasn't worked
+pub(super) fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, DraduError> {
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(|_| DraduError::UnknownHost(host.to_string()))?;
+    let mut error = DraduError::UnknownHost(host.to_string());
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e.into(),
+        }
+    }
+    Err(error)
+}
+
+// Adds our protocol version and capabilities to the body of JOIN/INIT
+fn hello_msg(msg_type: MsgType, hello: Hello) -> Message {
+    let hello = Hello {
+        version: Some(PROTOCOL_VERSION.to_string()),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        ..hello
+    };
+    let mut msg = Message::new(msg_type);
+    msg.attach_body(MsgBody::Json(hello.to_json()));
+    msg
+}
+
+// Without a password, anyone who knows the room ID can join
+fn init_msg(password: Option<&str>) -> Message {
+    let hello = Hello {
+        password: password.map(|p| p.to_string()),
+        ..Hello::default()
+    };
+    hello_msg(MsgType::Init, hello)
+}
+
+// What we ask for when joining a room. Server remembers it, so it doesn't have
+// to be repeated when resuming the session
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JoinOptions {
+    // Spectators can't change anything in the room
+    pub spectator: bool,
+    pub password: Option<String>,
+    // Token from an invite link. Works instead of the password, but only once
+    pub invite: Option<String>,
+}
+
+fn join_msg(room_id: &str, options: &JoinOptions) -> Message {
+    let hello = Hello {
+        room_id: Some(room_id.to_string()),
+        spectator: options.spectator,
+        password: options.password.clone(),
+        invite: options.invite.clone(),
+        ..Hello::default()
+    };
+    hello_msg(MsgType::Join, hello)
+}
+
+// Same as JOIN, but the server will give us our old ID, nickname, etc.
+fn resume_msg(session: &Session) -> Message {
+    let hello = Hello {
+        room_id: Some(session.room_id.clone()),
+        user_id: Some(session.user_id.clone()),
+        user_cookie: Some(session.user_cookie.clone()),
+        ..Hello::default()
+    };
+    hello_msg(MsgType::Join, hello)
+}
+
+// Waits for the server to answer our JOIN or INIT. If we are joining, we already
+// know the room ID, otherwise the server sends it to us
+fn await_ok(
+    receiver: &Receiver<Result<Message, DraduError>>,
+    room_id: Option<&str>,
+) -> Result<Session, DraduError> {
+    let mut msg = receiver.recv_timeout(Duration::from_secs(3))??;
+    let ok = match (msg.msg_type(), msg.take_body()) {
+        (MsgType::Ok, Some(MsgBody::Json(json))) => OkResponse::from_json(&json)?,
+        (MsgType::Err, body) => {
+            let json = match body {
+                Some(MsgBody::Json(json)) => json,
+                _ => JsonValue::Null,
+            };
+            return Err(ErrResponse::from_json(&json)?.into());
+        }
+        _ => return Err(DraduError::ConnectionError),
+    };
+
+    // Servers which don't send their version are the old 0.1 ones
+    let server_version = ok.version.unwrap_or_else(|| "0.1".to_string());
+    if !protocol::is_compatible_ver(&server_version).unwrap_or(false) {
+        return Err(DraduError::IncompatibleVersion(server_version));
+    }
+    let capabilities = ok
+        .capabilities
+        .into_iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .collect();
+
+    let room_id = match (room_id, ok.room_id) {
+        (Some(id), _) => id.to_string(),
+        (None, Some(id)) => id,
+        (None, None) => {
+            return Err(DraduError::InvalidField(
+                "roomId".into(),
+                "is missing".into(),
+            ))
+        }
+    };
+    Ok(Session {
+        user_id: ok.user_id,
+        user_cookie: ok.user_cookie,
+        nickname: ok.nickname.unwrap_or_default(),
+        user_color: ok
+            .color
+            .map(|[r, g, b]| Color32::from_rgb(r, g, b))
+            .unwrap_or(Color32::WHITE),
+        room_id,
+        server_version,
+        capabilities,
+    })
+}
+
 // A local server used for creating maps. It basically just echoes (Loops back)
 // all messages you send
 pub struct LoopbackConnection {
     message_queue: Option<Vec<Message>>,
     ctx: Context,
     closed: bool,
+    capabilities: Vec<String>,
 }
 
 impl LoopbackConnection {
@@ -163,6 +586,7 @@ impl LoopbackConnection {
             message_queue: Some(Vec::new()),
             ctx: ctx.clone(),
             closed: false,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
         }
     }
 }
@@ -185,6 +609,9 @@ impl Connection for LoopbackConnection {
     fn get_room_address(&self) -> Result<String, DraduError> {
         Ok("local".to_string())
     }
+    fn reconnect(&mut self) -> Result<(), DraduError> {
+        Ok(())
+    }
     fn get_user_id(&self) -> &str {
         ""
     }
@@ -194,65 +621,274 @@ impl Connection for LoopbackConnection {
     fn get_user_color(&self) -> Color32 {
         Color32::RED
     }
+    fn get_server_version(&self) -> &str {
+        PROTOCOL_VERSION
+    }
+    fn get_capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
     fn close(&mut self) {
         self.closed = true;
     }
 }
 
-// TODO: Refactor this
-fn spawn_receiving_thread(mut stream: TcpStream, ctx: &Context) -> Receiver<Message> {
-    let (tx, rx) = mpsc::channel();
+const READ_BUF_SIZE: usize = 64 * 1024;
 
+// Reads messages from the socket and sends them through the channel. If something
+// goes wrong, the error is sent through the channel as well and the thread exits
+fn spawn_receiving_thread(
+    mut stream: impl Read + Send + 'static,
+    tx: Sender<Result<Message, DraduError>>,
+    ctx: &Context,
+) {
     let ctx = ctx.clone();
 
-    thread::spawn(move || loop {
-        stream.take_error().unwrap();
-        let mut newlines = 0;
-        let mut bytes = Vec::new();
-        // Reading
+    thread::spawn(move || {
+        let mut framer = MessageFramer::new();
+        let mut buf = vec![0; READ_BUF_SIZE];
         loop {
-            match utils::read_byte(&mut stream) {
-                Ok(b) if b == b'\n' => {
-                    newlines += 1;
-                    bytes.push(b);
-                    if newlines == 2 {
-                        break;
-                    }
+            let result = match stream.read(&mut buf) {
+                Ok(0) => Err(DraduError::ConnectionError),
+                Ok(n) => {
+                    framer.push(&buf[..n]);
+                    drain_framer(&mut framer, &tx)
                 }
-                Ok(b) => {
-                    newlines = 0;
-                    bytes.push(b);
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(DraduError::from(e)),
+            };
+            ctx.request_repaint();
+            if let Err(e) = result {
+                #[allow(unused)]
+                {
+                    tx.send(Err(e));
                 }
-                Err(_) => panic!(),
+                return;
             }
         }
-        // Processing and sending through the channel
-        let mut msg = Message::from_str(&String::from_utf8(bytes).unwrap()).unwrap();
-        match msg.get_prop("contentLength") {
-            Some(s) if s != "0" => {
-                let len = s.parse::<usize>().unwrap();
-                let mut buf = vec![0; len];
-                stream.read_exact(&mut buf).unwrap();
-                match msg.get_prop("contentType") {
-                    Some("json") => {
-                        msg.attach_body(MsgBody::Json(
-                            json::parse(&std::str::from_utf8(&buf).unwrap()).unwrap(),
-                        ));
-                    }
-                    Some("text") => {
-                        msg.attach_body(MsgBody::Text(String::from_utf8_lossy(&buf).to_string()))
-                    }
-                    Some("image") => {
-                        msg.attach_body(MsgBody::retained_image(&buf).unwrap());
-                    }
-                    _ => (),
+    });
+}
+
+// Writes everything from the queue into the socket. Write errors are sent to
+// the receiving side, so .new_messages() returns them. Exits once the queue is
+// dropped
+fn spawn_writing_thread(
+    mut stream: impl Write + Send + 'static,
+    outgoing: Receiver<Vec<u8>>,
+    tx: Sender<Result<Message, DraduError>>,
+    ctx: &Context,
+) {
+    let ctx = ctx.clone();
+
+    thread::spawn(move || {
+        while let Ok(bytes) = outgoing.recv() {
+            if let Err(e) = stream.write_all(&bytes).and_then(|_| stream.flush()) {
+                #[allow(unused)]
+                {
+                    tx.send(Err(e.into()));
                 }
+                ctx.request_repaint();
+                return;
             }
-            _ => (),
         }
-        tx.send(msg).unwrap();
-        ctx.request_repaint();
     });
+}
+
+// Sends all complete messages through the channel. Only returns Err if the
+// stream can't be read anymore. Body errors don't break the framing, so these
+// are just passed on to the receiver
+pub(super) fn drain_framer(
+    framer: &mut MessageFramer,
+    tx: &Sender<Result<Message, DraduError>>,
+) -> Result<(), DraduError> {
+    loop {
+        let result = match framer.next_message() {
+            Ok(Some(msg)) => Ok(msg),
+            Ok(None) => return Ok(()),
+            Err(e) if e.breaks_framing() => return Err(e.into()),
+            Err(e) => Err(e.into()),
+        };
+        tx.send(result)
+            .map_err(|_| DraduError::ChannelDisconnected)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::Context;
+
+    use std::io::{self, ErrorKind, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{collect_messages, connect_tcp, drain_framer, spawn_writing_thread, SendQueue};
+    use crate::net::{Message, MessageFramer, MsgType};
+    use crate::DraduError;
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    // Takes a few bytes at a time and takes its time doing so. Blocks while
+    // `open` is false, like a socket with a full send buffer
+    #[derive(Clone)]
+    struct SlowSocket {
+        written: Arc<Mutex<Vec<u8>>>,
+        open: Arc<Mutex<bool>>,
+    }
+
+    impl SlowSocket {
+        fn new(open: bool) -> Self {
+            Self {
+                written: Arc::default(),
+                open: Arc::new(Mutex::new(open)),
+            }
+        }
 
-    rx
+        fn messages(&self) -> Vec<Message> {
+            let mut framer = MessageFramer::new();
+            framer.push(&self.written.lock().unwrap());
+            let mut messages = Vec::new();
+            while let Some(msg) = framer.next_message().unwrap() {
+                messages.push(msg);
+            }
+            messages
+        }
+    }
+
+    impl Write for SlowSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            while !*self.open.lock().unwrap() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let len = buf.len().min(7);
+            self.written.lock().unwrap().extend(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenSocket;
+
+    impl Write for BrokenSocket {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn start_writer(
+        socket: impl Write + Send + 'static,
+        capacity: usize,
+    ) -> (SendQueue, Receiver<Result<Message, DraduError>>) {
+        let (tx, rx) = mpsc::channel();
+        let (queue, outgoing) = SendQueue::new(capacity);
+        spawn_writing_thread(socket, outgoing, tx, &Context::default());
+        (queue, rx)
+    }
+
+    fn chat_msg(i: usize) -> Vec<u8> {
+        Message::new(MsgType::Msg)
+            .set_prop("n", &i.to_string())
+            .into_bytes()
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let socket = SlowSocket::new(true);
+        let (mut queue, _rx) = start_writer(socket.clone(), 4);
+        for i in 0..50 {
+            queue.push(chat_msg(i)).unwrap();
+            queue.flush(TIMEOUT).unwrap();
+        }
+        wait_until(|| {
+            queue.flush(TIMEOUT).unwrap();
+            socket.messages().len() == 50
+        });
+        for (i, msg) in socket.messages().iter().enumerate() {
+            assert_eq!(msg.get_prop("n"), Some(i.to_string().as_str()));
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let socket = SlowSocket::new(false);
+        let (mut queue, _rx) = start_writer(socket.clone(), 2);
+        for i in 0..10 {
+            queue.push(chat_msg(i)).unwrap();
+        }
+        // Writer gets stuck with the first message, and then nothing moves
+        assert!(queue.is_congested());
+        thread::sleep(Duration::from_millis(20));
+        queue.flush(TIMEOUT).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(matches!(
+            queue.flush(Duration::from_millis(10)),
+            Err(DraduError::SendStalled)
+        ));
+
+        *socket.open.lock().unwrap() = true;
+        wait_until(|| {
+            queue.flush(TIMEOUT).unwrap();
+            !queue.is_congested()
+        });
+        wait_until(|| socket.messages().len() == 10);
+    }
+
+    #[test]
+    fn write_failure() {
+        let (mut queue, rx) = start_writer(BrokenSocket, 4);
+        queue.push(chat_msg(0)).unwrap();
+        wait_until(|| match collect_messages(&rx, &mut Vec::new()) {
+            Ok(_) => false,
+            Err(DraduError::Io(e)) => e.kind() == ErrorKind::BrokenPipe,
+            Err(e) => panic!("Unexpected error: {}", e),
+        });
+        // Writer has exited, so the queue is disconnected
+        wait_until(|| queue.push(chat_msg(1)).is_err());
+    }
+
+    // Names are looked up when connecting, not when the address is parsed
+    #[test]
+    fn connect_by_host_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect_tcp("localhost", port).unwrap();
+        assert!(stream.peer_addr().unwrap().ip().is_loopback());
+        assert!(matches!(
+            connect_tcp("nonexistent.invalid", port),
+            Err(DraduError::UnknownHost(host)) if host == "nonexistent.invalid"
+        ));
+    }
+
+    #[test]
+    fn bad_body_is_skipped() {
+        let mut framer = MessageFramer::new();
+        framer.push(&chat_msg(0));
+        framer.push(b"dradu/0.1 Map\ncontentType:json\ncontentLength:3\n\n{{{");
+        framer.push(&chat_msg(1));
+        let (tx, rx) = mpsc::channel();
+        drain_framer(&mut framer, &tx).unwrap();
+
+        let mut rejected = Vec::new();
+        let messages = collect_messages(&rx, &mut rejected).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].get_prop("n"), Some("0"));
+        assert_eq!(messages[1].get_prop("n"), Some("1"));
+        assert!(matches!(rejected[..], [DraduError::MalformedBody(_)]));
+    }
 }
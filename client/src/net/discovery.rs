@@ -0,0 +1,238 @@
+use indexmap::IndexMap;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::net::model::{Announcement, RoomSummary};
+use crate::net::RoomAddr;
+use crate::DraduError;
+
+pub use protocol::DISCOVERY_PORT;
+
+// How often LanBrowser asks around
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+// Rooms which haven't been announced for this long are gone
+const ROOM_TIMEOUT: Duration = Duration::from_secs(7);
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+// Room announced by a server or a hosting client on the LAN
+#[derive(Debug, Clone)]
+pub struct LanRoom {
+    // Server address, as ServerAddr parses it
+    pub address: String,
+    pub summary: RoomSummary,
+    last_seen: Instant,
+}
+
+impl LanRoom {
+    pub fn room_address(&self) -> Result<RoomAddr, DraduError> {
+        Ok(RoomAddr {
+            server: self.address.parse()?,
+            room_id: self.summary.id.clone(),
+            invite: None,
+        })
+    }
+}
+
+// Broadcasts discovery probes and collects the answers. See "LAN discovery" in
+// docs/dev/protocol.md
+pub struct LanBrowser {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    next_probe: Instant,
+    // Room ID: Room. A room can be announced both by its server and its master,
+    // or reach us through several interfaces, so the first answer is kept
+    rooms: IndexMap<String, LanRoom>,
+    // Reused between polls, which happen every frame
+    buf: Vec<u8>,
+}
+
+impl LanBrowser {
+    // Probes the whole LAN, and localhost in case broadcasts don't come back
+    // to this machine
+    pub fn new() -> Result<Self, DraduError> {
+        Self::with_targets(vec![
+            SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, DISCOVERY_PORT)),
+        ])
+    }
+
+    pub fn with_targets(targets: Vec<SocketAddr>) -> Result<Self, DraduError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            targets,
+            next_probe: Instant::now(),
+            rooms: IndexMap::new(),
+            buf: vec![0; MAX_DATAGRAM],
+        })
+    }
+
+    // Call this every frame or so
+    pub fn poll(&mut self, now: Instant) {
+        if now >= self.next_probe {
+            self.next_probe = now + PROBE_INTERVAL;
+            let probe = Announcement::probe().dump();
+            for target in &self.targets {
+                // Broadcasts fail without a network, localhost still works
+                #[allow(unused)]
+                {
+                    self.socket.send_to(probe.as_bytes(), target);
+                }
+            }
+        }
+
+        while let Ok((len, from)) = self.socket.recv_from(&mut self.buf) {
+            let announcement = match std::str::from_utf8(&self.buf[..len])
+                .ok()
+                .and_then(|text| json::parse(text).ok())
+                .and_then(|json| Announcement::from_json(&json).ok())
+            {
+                Some(announcement) => announcement,
+                None => continue,
+            };
+            let address = match server_address(&announcement, from.ip()) {
+                Some(address) => address,
+                None => continue,
+            };
+            for summary in announcement.rooms {
+                let room = self
+                    .rooms
+                    .entry(summary.id.clone())
+                    .or_insert_with(|| LanRoom {
+                        address: address.clone(),
+                        summary: summary.clone(),
+                        last_seen: now,
+                    });
+                room.summary = summary;
+                room.last_seen = now;
+            }
+        }
+        self.rooms
+            .retain(|_, room| now.duration_since(room.last_seen) < ROOM_TIMEOUT);
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = &LanRoom> {
+        self.rooms.values()
+    }
+}
+
+// Lets players on the LAN find a room we're the master of, when its server is
+// somewhere else
+pub struct LanHost {
+    socket: UdpSocket,
+    room: RoomAddr,
+    password: bool,
+}
+
+impl LanHost {
+    // Fails if something else, e.g. a server on this machine, listens on the
+    // port already. `password` is whether joining the room takes one
+    pub fn bind(port: u16, room: RoomAddr, password: bool) -> Result<Self, DraduError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            room,
+            password,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DraduError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Answers the probes from the LAN which have come since the last call
+    pub fn poll(&self, gm: &str, players: u32) {
+        let mut buf = [0; 1024];
+        while let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+            if !protocol::is_local(from.ip()) {
+                continue;
+            }
+            let is_probe = std::str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(|text| json::parse(text).ok())
+                .is_some_and(|json| Announcement::is_probe(&json));
+            if !is_probe {
+                continue;
+            }
+            let announcement = Announcement {
+                address: Some(self.room.server.to_string()),
+                port: None,
+                tls: false,
+                rooms: vec![RoomSummary {
+                    id: self.room.room_id.clone(),
+                    name: format!("{}'s room", gm),
+                    gm: gm.to_string(),
+                    players,
+                    password: self.password,
+                }],
+            };
+            #[allow(unused)]
+            {
+                self.socket
+                    .send_to(announcement.to_json().dump().as_bytes(), from);
+            }
+        }
+    }
+}
+
+// Where players should connect to. Servers announced as localhost are only
+// that for their own machine, so the sender's IP is used instead
+fn server_address(announcement: &Announcement, sender: IpAddr) -> Option<String> {
+    let address = match (&announcement.address, announcement.port) {
+        (Some(address), _) => match address.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() || addr.ip().is_unspecified() => {
+                SocketAddr::new(sender, addr.port()).to_string()
+            }
+            _ => return Some(address.clone()),
+        },
+        (None, Some(port)) => SocketAddr::new(sender, port).to_string(),
+        (None, None) => return None,
+    };
+    if announcement.tls {
+        Some(format!("tls://{}", address))
+    } else {
+        Some(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{LanBrowser, LanHost, ROOM_TIMEOUT};
+
+    #[test]
+    fn host_and_browser() {
+        let room = "127.0.0.1:8889#abcd".parse().unwrap();
+        let host = LanHost::bind(0, room, true).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut browser =
+            LanBrowser::with_targets(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))]).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while browser.rooms().next().is_none() {
+            assert!(Instant::now() < deadline, "Room hasn't been discovered");
+            browser.poll(Instant::now());
+            host.poll("Master", 2);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let room = browser.rooms().next().unwrap();
+        assert_eq!(
+            room.room_address().unwrap().to_string(),
+            "dradu://127.0.0.1:8889/abcd"
+        );
+        assert_eq!(room.summary.gm, "Master");
+        assert_eq!(room.summary.players, 2);
+        assert!(room.summary.password);
+
+        // Host has gone quiet
+        browser.poll(Instant::now() + ROOM_TIMEOUT);
+        assert!(browser.rooms().next().is_none());
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+
+use crate::net::{Message, MsgType};
+use crate::DraduError;
+
+// How often we PING the server
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+// If the server hasn't sent anything for this long, the connection is dead
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Keeps an eye on a networked connection: PINGs the server every PING_INTERVAL
+// and measures the round trip time from its PONGs. Any message from the server
+// shows that the connection is still alive, PONGs just make sure there is some.
+// Disabled if the server doesn't support "heartbeat"
+pub(super) struct Heartbeat {
+    enabled: bool,
+    timeout: Duration,
+    last_received: Instant,
+    next_ping: Instant,
+    // ID and time of the PING we are waiting an answer to
+    pending: Option<(String, Instant)>,
+    ping_counter: u32,
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(enabled: bool, now: Instant) -> Self {
+        Self {
+            enabled,
+            timeout: DEFAULT_TIMEOUT,
+            last_received: now,
+            next_ping: now,
+            pending: None,
+            ping_counter: 0,
+            rtt: None,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Last measured round trip time
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    // Takes PONGs out of the new messages. Returns the PING which should be sent
+    // now, if it's time for one
+    pub fn beat(
+        &mut self,
+        messages: &mut Vec<Message>,
+        now: Instant,
+    ) -> Result<Option<Message>, DraduError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        if !messages.is_empty() {
+            self.last_received = now;
+        }
+        messages.retain(|msg| {
+            if msg.msg_type() != MsgType::Pong {
+                return true;
+            }
+            match &self.pending {
+                Some((id, sent)) if msg.get_prop("pingId") == Some(id) => {
+                    self.rtt = Some(now.duration_since(*sent));
+                    self.pending = None;
+                }
+                // Answer to a PING we've given up on
+                _ => (),
+            }
+            false
+        });
+
+        if now.duration_since(self.last_received) > self.timeout {
+            return Err(DraduError::TimeoutExceeded);
+        }
+        if now < self.next_ping {
+            return Ok(None);
+        }
+        self.next_ping = now + PING_INTERVAL;
+        self.ping_counter += 1;
+        let id = self.ping_counter.to_string();
+        let mut ping = Message::new(MsgType::Ping).set_prop("pingId", &id);
+        // Server shares it with the other players
+        if let Some(rtt) = self.rtt {
+            ping = ping.set_prop("latency", &rtt.as_millis().to_string());
+        }
+        self.pending = Some((id, now));
+        Ok(Some(ping))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::Context;
+    use json::object;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{Heartbeat, PING_INTERVAL};
+    use crate::net::{Connection, JoinOptions, Message, MsgBody, MsgType, ServerConnection};
+    use crate::DraduError;
+
+    fn pong(ping: &Message) -> Message {
+        Message::new(MsgType::Pong).set_prop("pingId", ping.get_prop("pingId").unwrap())
+    }
+
+    #[test]
+    fn ping_and_timeout() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut heartbeat = Heartbeat::new(true, start);
+
+        let ping = heartbeat.beat(&mut Vec::new(), start).unwrap().unwrap();
+        assert_eq!(ping.get_prop("latency"), None);
+        assert!(heartbeat.beat(&mut Vec::new(), ms(10)).unwrap().is_none());
+
+        let mut messages = vec![Message::new(MsgType::Synced), pong(&ping)];
+        heartbeat.beat(&mut messages, ms(50)).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(50)));
+
+        let later = start + PING_INTERVAL;
+        let ping = heartbeat.beat(&mut Vec::new(), later).unwrap().unwrap();
+        assert_eq!(ping.get_prop("latency"), Some("50"));
+
+        // Server has been quiet since the first PONG
+        let timeout = heartbeat.timeout();
+        assert!(heartbeat.beat(&mut Vec::new(), ms(50) + timeout).is_ok());
+        assert!(matches!(
+            heartbeat.beat(&mut Vec::new(), ms(51) + timeout),
+            Err(DraduError::TimeoutExceeded)
+        ));
+
+        // Old servers don't know PING
+        let mut heartbeat = Heartbeat::new(false, start);
+        assert!(heartbeat.beat(&mut Vec::new(), start).unwrap().is_none());
+        assert!(heartbeat.beat(&mut Vec::new(), ms(60_000)).is_ok());
+    }
+
+    // Server which answers JOIN and then goes silent, like one behind a
+    // half-open socket
+    #[test]
+    fn silent_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            #[allow(unused)]
+            {
+                stream.read(&mut buf);
+            }
+            let mut ok = Message::new(MsgType::Ok);
+            ok.attach_body(MsgBody::Json(object! {
+                "userId": "id",
+                "userCookie": "cookie",
+                "version": "0.1",
+                "capabilities": ["heartbeat"],
+            }));
+            stream.write_all(&ok.into_bytes()).unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let mut conn = ServerConnection::join_room(
+            &addr.to_string(),
+            "room",
+            &JoinOptions::default(),
+            &Context::default(),
+        )
+        .unwrap();
+        conn.set_timeout(Duration::from_millis(200));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            match conn.new_messages() {
+                Ok(_) => assert!(Instant::now() < deadline, "Timeout hasn't been noticed"),
+                Err(DraduError::TimeoutExceeded) => break,
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
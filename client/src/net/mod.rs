@@ -1,7 +1,22 @@
+mod address;
 mod connection;
-mod message;
+mod discovery;
+mod heartbeat;
+mod replay;
+mod tls;
+mod websocket;
 
-pub use connection::{Connection, LoopbackConnection, ServerConnection};
-pub use message::{Message, MsgBody, MsgType};
+pub use address::{RoomAddr, ServerAddr};
+pub use connection::{Connection, JoinOptions, LoopbackConnection, Reconnector, ServerConnection};
+pub use discovery::{LanBrowser, LanHost, LanRoom, DISCOVERY_PORT, PROBE_INTERVAL};
+pub use heartbeat::{DEFAULT_TIMEOUT, PING_INTERVAL};
+pub use replay::{Playback, Recorder, Recording, ReplayConnection};
+pub use tls::{KnownHosts, TlsConnection};
+pub use websocket::WebSocketConnection;
 
-pub const PROTOCOL_VERSION: &str = "0.1";
+pub use protocol::{model, Message, MessageFramer, MsgBody, MsgType, PROTOCOL_VERSION};
+
+// Optional protocol features this client supports. They are sent to the server
+// in JOIN/INIT, and the server answers with the ones it supports. See
+// docs/dev/protocol.md for what each of them means
+pub const CAPABILITIES: &[&str] = &["resume", "deflate", "heartbeat", "transient"];
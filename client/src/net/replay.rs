@@ -0,0 +1,451 @@
+use eframe::egui::Color32;
+
+use json::{object, JsonValue};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::net::{Connection, Message, MessageFramer, MsgType};
+use crate::utils;
+use crate::DraduError;
+
+// First line of every recording. The number is bumped if the format changes
+const MAGIC: &str = "DRADU-RECORDING 1";
+
+// Writes every message of a session into a file. The file starts with MAGIC and
+// a line of JSON describing who we were in the room, then goes a record per
+// message: "<milliseconds since start> <in|out> <length>\n" followed by the
+// message itself. Outgoing messages are recorded before they are signed, so
+// the file doesn't contain our cookie
+pub struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(
+        path: &Path,
+        connection: &dyn Connection,
+        master: bool,
+    ) -> Result<Self, DraduError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let color = connection.get_user_color();
+        let header = object! {
+            "userId": connection.get_user_id(),
+            "nickname": connection.get_nickname(),
+            "color": [color.r(), color.g(), color.b()],
+            "master": master,
+            "roomAddress": connection.get_room_address().unwrap_or_default(),
+            "serverVersion": connection.get_server_version(),
+            "capabilities": connection.get_capabilities().to_vec(),
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}\n{}", MAGIC, header.dump())?;
+        file.flush()?;
+        Ok(Self {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, msg: &Message, inbound: bool) -> Result<(), DraduError> {
+        let bytes = msg.clone().into_bytes();
+        let direction = if inbound { "in" } else { "out" };
+        let time = self.started.elapsed().as_millis();
+        writeln!(self.file, "{} {} {}", time, direction, bytes.len())?;
+        self.file.write_all(&bytes)?;
+        // So that nothing is lost if we crash
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Entry {
+    pub time: Duration,
+    pub inbound: bool,
+    pub msg: Message,
+}
+
+// Recorded session, loaded into memory
+pub struct Recording {
+    header: JsonValue,
+    entries: Vec<Entry>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self, DraduError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, DraduError> {
+        if next_line(&mut bytes)? != MAGIC {
+            return Err(invalid("not a dradu recording"));
+        }
+        let header = json::parse(next_line(&mut bytes)?).map_err(|_| invalid("bad header"))?;
+
+        let mut entries = Vec::new();
+        while !bytes.is_empty() {
+            let line = next_line(&mut bytes)?;
+            let mut fields = line.split(' ');
+            let (time, direction, len) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(time), Some(direction), Some(len)) => (time, direction, len),
+                _ => return Err(invalid(line)),
+            };
+            let time = time.parse().map_err(|_| invalid(line))?;
+            let len: usize = len.parse().map_err(|_| invalid(line))?;
+            if len > bytes.len() {
+                return Err(invalid("recording is cut off"));
+            }
+            let mut framer = MessageFramer::new();
+            framer.push(&bytes[..len]);
+            bytes = &bytes[len..];
+            let msg = framer.next_message()?.ok_or_else(|| invalid(line))?;
+            entries.push(Entry {
+                time: Duration::from_millis(time),
+                inbound: direction == "in",
+                msg,
+            });
+        }
+        Ok(Self { header, entries })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.entries.last().map(|e| e.time).unwrap_or_default()
+    }
+
+    // Whether the recording was made by the master of the room
+    pub fn is_master(&self) -> bool {
+        self.header["master"].as_bool().unwrap_or(false)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+fn next_line<'a>(bytes: &mut &'a [u8]) -> Result<&'a str, DraduError> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("recording is cut off"))?;
+    let line = std::str::from_utf8(&bytes[..end]).map_err(|_| invalid("bad record header"))?;
+    *bytes = &bytes[end + 1..];
+    Ok(line)
+}
+
+fn invalid(reason: &str) -> DraduError {
+    DraduError::InvalidRecording(reason.to_string())
+}
+
+// Where we are in the recording. Time only goes while it isn't paused
+pub struct Playback {
+    position: Duration,
+    duration: Duration,
+    speed: f32,
+    paused: bool,
+    last_tick: Instant,
+}
+
+impl Playback {
+    fn new(duration: Duration) -> Self {
+        Self {
+            position: Duration::ZERO,
+            duration,
+            speed: 1.0,
+            paused: false,
+            last_tick: Instant::now(),
+        }
+    }
+
+    pub fn play(&mut self) {
+        // Starting over once the end is reached
+        if self.position >= self.duration {
+            self.position = Duration::ZERO;
+        }
+        self.paused = false;
+        self.last_tick = Instant::now();
+    }
+
+    pub fn pause(&mut self) {
+        self.tick();
+        self.paused = true;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.tick();
+        self.speed = speed.max(0.0);
+    }
+
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    // Use RoomState::seek to go back in time, the room has to be rebuilt then
+    pub fn seek(&mut self, position: Duration) {
+        self.position = position.min(self.duration);
+        self.last_tick = Instant::now();
+    }
+
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if !self.paused {
+            self.position += (now - self.last_tick).mul_f32(self.speed);
+            if self.position >= self.duration {
+                self.position = self.duration;
+                self.paused = true;
+            }
+        }
+        self.last_tick = now;
+    }
+}
+
+// Plays a recording back as if it was coming from the server. Only messages we
+// have received are replayed, and everything we send is ignored
+pub struct ReplayConnection {
+    recording: Recording,
+    // Index of the first entry which hasn't been replayed yet
+    next: usize,
+    playback: Playback,
+    user_color: Color32,
+    capabilities: Vec<String>,
+    // Path: ID of our latest request. Recorded chunks are answers to the old
+    // requests, so their IDs are replaced to match the new ones
+    file_requests: HashMap<String, String>,
+    closed: bool,
+}
+
+impl ReplayConnection {
+    pub fn new(recording: Recording) -> Self {
+        let header = &recording.header;
+        let user_color = utils::color32_from_json_value(&header["color"]).unwrap_or(Color32::WHITE);
+        let capabilities = header["capabilities"]
+            .members()
+            .filter_map(|c| c.as_str())
+            .map(|c| c.to_string())
+            .collect();
+        Self {
+            playback: Playback::new(recording.duration()),
+            recording,
+            next: 0,
+            user_color,
+            capabilities,
+            file_requests: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.recording.is_master()
+    }
+
+    fn header_str(&self, key: &str) -> &str {
+        self.recording.header[key].as_str().unwrap_or("")
+    }
+}
+
+impl Connection for ReplayConnection {
+    fn new_messages(&mut self) -> Result<Vec<Message>, DraduError> {
+        if self.closed {
+            return Err(DraduError::ChannelDisconnected);
+        }
+        self.playback.tick();
+        let position = self.playback.position();
+        let entries = &self.recording.entries;
+        // Seeking backwards, everything is replayed from the start
+        if self.next > 0 && entries[self.next - 1].time > position {
+            self.next = 0;
+        }
+
+        let mut messages = Vec::new();
+        while let Some(entry) = entries.get(self.next) {
+            if entry.time > position {
+                break;
+            }
+            self.next += 1;
+            if !entry.inbound {
+                continue;
+            }
+            let mut msg = entry.msg.clone();
+            if msg.msg_type() == MsgType::File {
+                let request_id = msg.get_prop("path").and_then(|p| self.file_requests.get(p));
+                if let Some(id) = request_id.cloned() {
+                    msg = msg.set_prop("requestId", &id);
+                }
+            }
+            messages.push(msg);
+        }
+        Ok(messages)
+    }
+
+    fn send_msg(&mut self, msg: Message) -> Result<usize, DraduError> {
+        if msg.msg_type() == MsgType::File {
+            if let (Some(path), Some(id)) = (msg.get_prop("path"), msg.get_prop("requestId")) {
+                self.file_requests.insert(path.to_string(), id.to_string());
+            }
+        }
+        Ok(0)
+    }
+
+    fn get_room_address(&self) -> Result<String, DraduError> {
+        Ok(self.header_str("roomAddress").to_string())
+    }
+
+    fn reconnect(&mut self) -> Result<(), DraduError> {
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn get_user_id(&self) -> &str {
+        self.header_str("userId")
+    }
+
+    fn get_nickname(&self) -> &str {
+        self.header_str("nickname")
+    }
+
+    fn get_user_color(&self) -> Color32 {
+        self.user_color
+    }
+
+    fn get_server_version(&self) -> &str {
+        self.header_str("serverVersion")
+    }
+
+    fn get_capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    fn playback(&mut self) -> Option<&mut Playback> {
+        Some(&mut self.playback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::Context;
+    use json::object;
+
+    use std::time::Duration;
+
+    use super::{Entry, Recorder, Recording, ReplayConnection};
+    use crate::net::{Connection, LoopbackConnection, Message, MsgBody, MsgType};
+
+    fn chat(text: &str) -> Message {
+        let mut msg = Message::new(MsgType::Msg).set_prop("userId", "abcd");
+        msg.attach_body(MsgBody::Text(text.to_string()));
+        msg
+    }
+
+    fn texts(messages: Vec<Message>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|mut msg| match msg.take_body() {
+                Some(MsgBody::Text(text)) => text,
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    // Records a few messages into a temporary file and loads them back
+    fn record(messages: &[(Message, bool)]) -> Recording {
+        let path = std::env::temp_dir().join(format!("{}.drec", crate::utils::random_id()));
+        let conn = LoopbackConnection::new(&Context::default());
+        let mut recorder = Recorder::create(&path, &conn, true).unwrap();
+        for (msg, inbound) in messages {
+            recorder.record(msg, *inbound).unwrap();
+        }
+        let recording = Recording::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        recording
+    }
+
+    #[test]
+    fn record_and_load() {
+        let mut big = Message::new(MsgType::File).set_prop("path", "map.png");
+        big.attach_body(MsgBody::Bin(vec![7; 100_000]));
+        let recording = record(&[(chat("hi"), true), (chat("hello"), false), (big, true)]);
+
+        assert!(recording.is_master());
+        let entries = recording.entries();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].inbound && !entries[1].inbound);
+        assert_eq!(entries[1].msg.get_prop("userId"), Some("abcd"));
+        match entries[2].msg.clone().take_body() {
+            Some(MsgBody::Bin(bytes)) => assert_eq!(bytes, vec![7; 100_000]),
+            _ => panic!("Body got lost"),
+        }
+
+        assert!(Recording::from_bytes(b"not a recording\n").is_err());
+        assert!(Recording::from_bytes(b"DRADU-RECORDING 1\n{}\n0 in 100\nMSG").is_err());
+    }
+
+    #[test]
+    fn seek_and_rewind() {
+        let entry = |secs, text, inbound| Entry {
+            time: Duration::from_secs(secs),
+            inbound,
+            msg: chat(text),
+        };
+        let recording = Recording {
+            header: object! {"nickname": "Bob"},
+            entries: vec![
+                entry(0, "one", true),
+                entry(5, "ignored", false),
+                entry(10, "two", true),
+                entry(20, "three", true),
+            ],
+        };
+        let mut conn = ReplayConnection::new(recording);
+        assert_eq!(conn.get_nickname(), "Bob");
+        let playback = conn.playback().unwrap();
+        assert_eq!(playback.duration(), Duration::from_secs(20));
+        playback.pause();
+        playback.seek(Duration::from_secs(1));
+        assert_eq!(texts(conn.new_messages().unwrap()), vec!["one"]);
+
+        conn.playback().unwrap().seek(Duration::from_secs(60));
+        assert_eq!(texts(conn.new_messages().unwrap()), vec!["two", "three"]);
+        assert!(conn.new_messages().unwrap().is_empty());
+        assert_eq!(conn.playback().unwrap().position(), Duration::from_secs(20));
+
+        // Going back replays everything before the new position again
+        conn.playback().unwrap().seek(Duration::from_secs(15));
+        assert_eq!(texts(conn.new_messages().unwrap()), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn file_chunks_follow_new_requests() {
+        let chunk = Message::new(MsgType::File)
+            .set_prop("path", "map.png")
+            .set_prop("requestId", "old");
+        let mut conn = ReplayConnection::new(record(&[(chunk, true)]));
+        conn.playback().unwrap().pause();
+
+        let request = Message::new(MsgType::File)
+            .set_prop("path", "map.png")
+            .set_prop("requestId", "new");
+        conn.send_msg(request).unwrap();
+        let msg = conn.new_messages().unwrap().pop().unwrap();
+        assert_eq!(msg.get_prop("requestId"), Some("new"));
+    }
+}
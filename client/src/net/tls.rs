@@ -0,0 +1,324 @@
+use eframe::egui::Context;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, ServerName, StreamOwned};
+
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::net::address;
+use crate::net::connection::{self, NetConnection, Transport, CONNECT_TIMEOUT};
+use crate::net::{JoinOptions, Message, MessageFramer, ServerAddr};
+use crate::utils;
+use crate::DraduError;
+
+// How long the IO thread waits for incoming data before checking whether there
+// is something to send
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+// Same thing as ServerConnection, but encrypted. Servers usually have
+// self-signed certificates, so instead of checking them against some CA we
+// remember the certificate we've seen the first time and expect it to stay the
+// same (See KnownHosts). Address should look like host:port
+pub type TlsConnection = NetConnection<TlsTransport>;
+
+impl TlsConnection {
+    pub fn join_room(
+        addr: &str,
+        room_id: &str,
+        options: &JoinOptions,
+        known_hosts: KnownHosts,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::join(TlsTransport::new(addr, known_hosts), room_id, options, ctx)
+    }
+
+    pub fn create_new_room(
+        addr: &str,
+        password: Option<&str>,
+        known_hosts: KnownHosts,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::create(TlsTransport::new(addr, known_hosts), password, ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsTransport {
+    addr: String,
+    known_hosts: KnownHosts,
+}
+
+impl TlsTransport {
+    fn new(addr: &str, known_hosts: KnownHosts) -> Self {
+        Self {
+            addr: addr.to_string(),
+            known_hosts,
+        }
+    }
+
+    // Connects and completes the handshake, checking the certificate
+    fn handshake(&self) -> Result<TlsStream, DraduError> {
+        let addr = &self.addr;
+        let (host, port) = address::split_host_port(addr)?;
+        let server_name = ServerName::try_from(host.as_str())
+            .map_err(|_| DraduError::InvalidAddress(addr.to_string()))?;
+        let stream = connection::connect_tcp(&host, port)?;
+
+        let verifier = Arc::new(PinningVerifier {
+            host: addr.to_string(),
+            known_hosts: self.known_hosts.clone(),
+            mismatch: Mutex::new(None),
+            unknown: Mutex::new(None),
+        });
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        let conn = ClientConnection::new(Arc::new(config), server_name)
+            .map_err(|e| DraduError::TlsError(e.to_string()))?;
+        let mut socket = StreamOwned::new(conn, stream);
+
+        socket.sock.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        while socket.conn.is_handshaking() {
+            if let Err(e) = socket.conn.complete_io(&mut socket.sock) {
+                return Err(match verifier.mismatch.lock().unwrap().take() {
+                    Some(fingerprint) => {
+                        DraduError::CertificateChanged(addr.to_string(), fingerprint)
+                    }
+                    None if e.kind() == ErrorKind::InvalidData => {
+                        DraduError::TlsError(e.to_string())
+                    }
+                    None => e.into(),
+                });
+            }
+        }
+        // Only a server which has finished the handshake, and so has the
+        // certificate's private key, gets pinned
+        if let Some(fingerprint) = verifier.unknown.lock().unwrap().take() {
+            self.known_hosts.pin(addr, &fingerprint)?;
+        }
+        socket.sock.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(socket)
+    }
+}
+
+impl Transport for TlsTransport {
+    fn open(
+        &self,
+        outgoing: Receiver<Vec<u8>>,
+        incoming: Sender<Result<Message, DraduError>>,
+        ctx: &Context,
+    ) -> Result<(), DraduError> {
+        spawn_io_thread(self.handshake()?, outgoing, incoming, ctx);
+        Ok(())
+    }
+
+    fn server_addr(&self) -> ServerAddr {
+        ServerAddr::Tls(self.addr.clone())
+    }
+}
+
+// Hex-encoded SHA-256 of a DER certificate
+fn fingerprint(cert: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(cert))
+}
+
+// Fingerprints of the certificates of servers we've connected to, stored as
+// "host:port fingerprint" lines. Without a place to store them (e.g. on the
+// web) every certificate is accepted
+#[derive(Clone)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+}
+
+impl KnownHosts {
+    // The file in the local data dir
+    pub fn new() -> Self {
+        Self {
+            path: utils::local_dir().map(|dir| dir.join("known_hosts")),
+        }
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        Self { path: Some(path) }
+    }
+
+    pub fn get(&self, host: &str) -> Option<String> {
+        self.load().remove(host)
+    }
+
+    // Remembers the fingerprint, replacing the old one if there was any
+    pub fn pin(&self, host: &str, fingerprint: &str) -> Result<(), DraduError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut hosts = self.load();
+        hosts.insert(host.to_string(), fingerprint.to_string());
+
+        let mut lines: Vec<String> = hosts
+            .into_iter()
+            .map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint))
+            .collect();
+        lines.sort();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, lines.concat())?;
+        Ok(())
+    }
+
+    fn load(&self) -> HashMap<String, String> {
+        let text = match &self.path {
+            Some(path) => fs::read_to_string(path).unwrap_or_default(),
+            None => String::new(),
+        };
+        text.lines()
+            .filter_map(|line| line.trim().split_once(' '))
+            .map(|(host, fingerprint)| (host.to_string(), fingerprint.trim().to_string()))
+            .collect()
+    }
+}
+
+impl Default for KnownHosts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Trust on first use: unknown certificates are pinned, known ones have to match.
+// Handshake signatures are still checked by the default trait methods
+struct PinningVerifier {
+    host: String,
+    known_hosts: KnownHosts,
+    // Fingerprint of the certificate we've got instead of the pinned one
+    mismatch: Mutex<Option<String>>,
+    // Fingerprint of a certificate we haven't seen before. It's pinned once
+    // the handshake succeeds
+    unknown: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(&end_entity.0);
+        match self.known_hosts.get(&self.host) {
+            Some(pinned) if pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(_) => {
+                *self.mismatch.lock().unwrap() = Some(fingerprint);
+                Err(rustls::Error::General(
+                    "server certificate has changed".to_string(),
+                ))
+            }
+            None => {
+                *self.unknown.lock().unwrap() = Some(fingerprint);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+}
+
+// A TLS stream can't be split into reading and writing halves, so one thread
+// does both, just like with WebSocket
+fn spawn_io_thread(
+    mut socket: TlsStream,
+    outgoing: Receiver<Vec<u8>>,
+    tx: Sender<Result<Message, DraduError>>,
+    ctx: &Context,
+) {
+    let ctx = ctx.clone();
+
+    thread::spawn(move || {
+        let mut framer = MessageFramer::new();
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match exchange(&mut socket, &outgoing, &mut framer, &mut buf, &tx) {
+                Ok(false) => (),
+                Ok(true) => ctx.request_repaint(),
+                Err(e) => {
+                    #[allow(unused)]
+                    {
+                        tx.send(Err(e));
+                        socket.conn.send_close_notify();
+                        socket.flush();
+                    }
+                    ctx.request_repaint();
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Returns true if something has been received
+fn exchange(
+    socket: &mut TlsStream,
+    outgoing: &Receiver<Vec<u8>>,
+    framer: &mut MessageFramer,
+    buf: &mut [u8],
+    tx: &Sender<Result<Message, DraduError>>,
+) -> Result<bool, DraduError> {
+    loop {
+        match outgoing.try_recv() {
+            Ok(bytes) => socket.write_all(&bytes)?,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => return Err(DraduError::ChannelDisconnected),
+        }
+    }
+    socket.flush()?;
+    let len = match socket.read(buf) {
+        Ok(0) => return Err(DraduError::ConnectionError),
+        Ok(len) => len,
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    framer.push(&buf[..len]);
+    connection::drain_framer(framer, tx)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KnownHosts;
+
+    use std::fs;
+
+    #[test]
+    fn known_hosts() {
+        let path = std::env::temp_dir().join(format!("known_hosts_{}", crate::utils::random_id()));
+        let hosts = KnownHosts::at(path.clone());
+        assert_eq!(hosts.get("example.com:8889"), None);
+
+        hosts.pin("example.com:8889", "aaaa").unwrap();
+        hosts.pin("127.0.0.1:8889", "bbbb").unwrap();
+        hosts.pin("example.com:8889", "cccc").unwrap();
+        assert_eq!(hosts.get("example.com:8889"), Some("cccc".to_string()));
+        assert_eq!(hosts.get("127.0.0.1:8889"), Some("bbbb".to_string()));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "127.0.0.1:8889 bbbb\nexample.com:8889 cccc\n"
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use eframe::egui::Context;
+
+use tungstenite::http::Uri;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::net::connection::{self, NetConnection, Transport};
+use crate::net::{JoinOptions, Message, MessageFramer, ServerAddr};
+use crate::DraduError;
+
+// How long the IO thread waits for incoming data before checking whether there
+// is something to send
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Same thing as ServerConnection, but every message is sent as one binary
+// WebSocket message. Address should look like ws://host:port/path
+pub type WebSocketConnection = NetConnection<WebSocketTransport>;
+
+impl WebSocketConnection {
+    pub fn join_room(
+        url: &str,
+        room_id: &str,
+        options: &JoinOptions,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::join(WebSocketTransport::new(url), room_id, options, ctx)
+    }
+
+    pub fn create_new_room(
+        url: &str,
+        password: Option<&str>,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        Self::create(WebSocketTransport::new(url), password, ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct WebSocketTransport {
+    url: String,
+}
+
+impl WebSocketTransport {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn open(
+        &self,
+        outgoing: Receiver<Vec<u8>>,
+        incoming: Sender<Result<Message, DraduError>>,
+        ctx: &Context,
+    ) -> Result<(), DraduError> {
+        let url = &self.url;
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|_| DraduError::InvalidAddress(url.to_string()))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| DraduError::InvalidAddress(url.to_string()))?;
+        // IPv6 addresses come in square brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = connection::connect_tcp(host, uri.port_u16().unwrap_or(80))?;
+
+        let (socket, _) = tungstenite::client(url.as_str(), stream)
+            .map_err(|e| DraduError::WebSocketError(e.to_string()))?;
+        socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+        spawn_io_thread(socket, outgoing, incoming, ctx);
+        Ok(())
+    }
+
+    fn server_addr(&self) -> ServerAddr {
+        ServerAddr::WebSocket(self.url.clone())
+    }
+}
+
+// WebSocket can't be split into reading and writing halves, so one thread does
+// both: it sends everything that has been queued, then waits for incoming data
+// for a short time, and so on
+fn spawn_io_thread(
+    mut socket: WebSocket<TcpStream>,
+    outgoing: Receiver<Vec<u8>>,
+    tx: Sender<Result<Message, DraduError>>,
+    ctx: &Context,
+) {
+    let ctx = ctx.clone();
+
+    thread::spawn(move || {
+        let mut framer = MessageFramer::new();
+        loop {
+            match exchange(&mut socket, &outgoing, &mut framer, &tx) {
+                Ok(false) => (),
+                Ok(true) => ctx.request_repaint(),
+                Err(e) => {
+                    #[allow(unused)]
+                    {
+                        tx.send(Err(e));
+                        socket.close(None);
+                    }
+                    ctx.request_repaint();
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Returns true if something has been received
+fn exchange(
+    socket: &mut WebSocket<TcpStream>,
+    outgoing: &Receiver<Vec<u8>>,
+    framer: &mut MessageFramer,
+    tx: &Sender<Result<Message, DraduError>>,
+) -> Result<bool, DraduError> {
+    loop {
+        match outgoing.try_recv() {
+            Ok(bytes) => socket.write_message(WsMessage::Binary(bytes))?,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => return Err(DraduError::ChannelDisconnected),
+        }
+    }
+    let bytes = match socket.read_message() {
+        Ok(WsMessage::Binary(bytes)) => bytes,
+        Ok(WsMessage::Text(text)) => text.into_bytes(),
+        Ok(WsMessage::Close(_)) => return Err(DraduError::ConnectionError),
+        // Pings are answered by tungstenite itself
+        Ok(_) => return Ok(false),
+        Err(tungstenite::Error::Io(e))
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+        {
+            return Ok(false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    framer.push(&bytes);
+    connection::drain_framer(framer, tx)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::Context;
+    use json::object;
+    use tungstenite::Message as WsMessage;
+
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::WebSocketConnection;
+    use crate::net::{Connection, Message, MessageFramer, MsgBody, MsgType};
+
+    // Answers INIT like a real server would, then echoes back every message
+    fn spawn_room_stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut framer = MessageFramer::new();
+            loop {
+                let bytes = match socket.read_message() {
+                    Ok(WsMessage::Binary(bytes)) => bytes,
+                    Ok(_) => continue,
+                    Err(_) => return,
+                };
+                framer.push(&bytes);
+                while let Some(msg) = framer.next_message().unwrap() {
+                    let reply = if msg.msg_type() == MsgType::Init {
+                        let mut reply = Message::new(MsgType::Ok);
+                        reply.attach_body(MsgBody::Json(object! {
+                            "userId": "abcd",
+                            "userCookie": "cookie",
+                            "nickname": "Master",
+                            "color": [255, 20, 20],
+                            "roomId": "room",
+                        }));
+                        reply.into_bytes()
+                    } else {
+                        // Bodies are always dropped by this stub
+                        let mut reply = Message::new(msg.msg_type());
+                        if let Some(id) = msg.get_prop("userId") {
+                            reply = reply.set_prop("userId", id);
+                        }
+                        reply.into_bytes()
+                    };
+                    socket.write_message(WsMessage::Binary(reply)).unwrap();
+                }
+            }
+        });
+        format!("ws://{}", addr)
+    }
+
+    #[test]
+    fn websocket_roundtrip() {
+        let url = spawn_room_stub();
+        let ctx = Context::default();
+        let mut conn = WebSocketConnection::create_new_room(&url, None, &ctx).unwrap();
+        assert_eq!(conn.get_user_id(), "abcd");
+        assert_eq!(conn.get_nickname(), "Master");
+        assert_eq!(conn.get_room_address().unwrap(), format!("{}#room", url));
+
+        conn.send_msg(Message::new(MsgType::Synced)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let msg = loop {
+            if let Some(msg) = conn.new_messages().unwrap().pop() {
+                break msg;
+            }
+            assert!(Instant::now() < deadline, "No echo from the stub");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(msg.msg_type(), MsgType::Synced);
+        // Messages are signed with our credentials
+        assert_eq!(msg.get_prop("userId"), Some("abcd"));
+
+        conn.close();
+        assert!(conn.new_messages().is_err());
+    }
+
+    #[test]
+    fn invalid_url() {
+        let ctx = Context::default();
+        assert!(WebSocketConnection::create_new_room("ws://", None, &ctx).is_err());
+    }
+}
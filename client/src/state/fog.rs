@@ -0,0 +1,273 @@
+use eframe::egui;
+use egui::{Pos2, Rect, Vec2};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::net::model::{self, FOG_CELL};
+
+// Rays are cast a bit to both sides of every wall end, to see past it
+const RAY_SPREAD: f32 = 0.0001;
+
+// [x, y] of a FOG_CELL wide square of the map
+pub type Cell = [i32; 2];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellState {
+    Visible,
+    // Has been seen before, but isn't now
+    Explored,
+    Unexplored,
+}
+
+// Fog of war as the GM has set it up, and what the tokens can see through it
+#[derive(Default)]
+pub struct Fog {
+    pub reveal_all: bool,
+    // Cells the GM has revealed (true) or hidden (false) by hand
+    pub painted: HashMap<Cell, bool>,
+    // Everything our tokens have seen so far. Each player has their own
+    explored: HashSet<Cell>,
+    // What the visible cells have been computed from, and the cells
+    sight: Option<(Sight, HashSet<Cell>)>,
+}
+
+#[derive(PartialEq)]
+struct Sight {
+    eyes: Vec<Pos2>,
+    walls: Vec<[Pos2; 2]>,
+    bounds: Rect,
+}
+
+impl Fog {
+    // Fog in which the cells explored before are still remembered
+    pub fn with_explored(explored: HashSet<Cell>) -> Self {
+        Fog {
+            explored,
+            ..Fog::default()
+        }
+    }
+
+    pub fn take_explored(&mut self) -> HashSet<Cell> {
+        std::mem::take(&mut self.explored)
+    }
+
+    // Explored cells are kept
+    pub fn update(&mut self, fog: &model::Fog) {
+        self.reveal_all = fog.reveal_all;
+        self.painted = fog.revealed.iter().map(|cell| (*cell, true)).collect();
+        self.painted
+            .extend(fog.hidden.iter().map(|cell| (*cell, false)));
+    }
+
+    pub fn as_entry(&self) -> model::Fog {
+        self.painted_over(&HashMap::new())
+    }
+
+    // Entry with some more cells painted
+    pub fn painted_over(&self, cells: &HashMap<Cell, bool>) -> model::Fog {
+        let mut painted = self.painted.clone();
+        painted.extend(cells);
+        let cells = |revealed: bool| -> Vec<Cell> {
+            let mut cells: Vec<Cell> = painted
+                .iter()
+                .filter(|(_, r)| **r == revealed)
+                .map(|(cell, _)| *cell)
+                .collect();
+            cells.sort_unstable();
+            cells
+        };
+        model::Fog {
+            reveal_all: self.reveal_all,
+            revealed: cells(true),
+            hidden: cells(false),
+        }
+    }
+
+    // Finds the cells which can be seen from the eyes. Walls block the sight,
+    // and nothing is seen out of bounds. The cells are only recomputed if
+    // something has changed. If `explore` is set, they are remembered
+    pub fn look(&mut self, eyes: Vec<Pos2>, walls: Vec<[Pos2; 2]>, bounds: Rect, explore: bool) {
+        let sight = Sight {
+            eyes,
+            walls,
+            bounds,
+        };
+        if !matches!(&self.sight, Some((last, _)) if *last == sight) {
+            let visible = visible_cells(&sight);
+            self.sight = Some((sight, visible));
+        }
+        if let (true, Some((_, visible))) = (explore, &self.sight) {
+            self.explored.extend(visible);
+        }
+    }
+
+    pub fn cell_state(&self, cell: Cell) -> CellState {
+        let visible = match &self.sight {
+            Some((_, visible)) => visible.contains(&cell),
+            None => false,
+        };
+        match self.painted.get(&cell) {
+            Some(true) => CellState::Visible,
+            Some(false) => CellState::Unexplored,
+            None if self.reveal_all || visible => CellState::Visible,
+            None if self.explored.contains(&cell) => CellState::Explored,
+            None => CellState::Unexplored,
+        }
+    }
+
+    pub fn is_visible(&self, pos: Pos2) -> bool {
+        self.cell_state(cell_at(pos)) == CellState::Visible
+    }
+}
+
+pub fn cell_at(pos: Pos2) -> Cell {
+    [
+        (pos.x / FOG_CELL).floor() as i32,
+        (pos.y / FOG_CELL).floor() as i32,
+    ]
+}
+
+pub fn cell_rect([x, y]: Cell) -> Rect {
+    let min = Pos2::new(x as f32 * FOG_CELL, y as f32 * FOG_CELL);
+    Rect::from_min_size(min, Vec2::splat(FOG_CELL))
+}
+
+// Cells which overlap the area. Rows go one after another
+pub fn cells_in(area: Rect) -> impl Iterator<Item = Cell> {
+    let [min_x, min_y] = cell_at(area.min);
+    let [max_x, max_y] = cell_at(area.max);
+    (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| [x, y]))
+}
+
+fn visible_cells(sight: &Sight) -> HashSet<Cell> {
+    let mut visible = HashSet::new();
+    for eye in &sight.eyes {
+        visible.insert(cell_at(*eye));
+        let polygon = visibility_polygon(*eye, &sight.walls, sight.bounds);
+        for cell in cells_in(Rect::from_points(&polygon)) {
+            if contains(&polygon, cell_rect(cell).center()) {
+                visible.insert(cell);
+            }
+        }
+    }
+    visible
+}
+
+// Part of the bounds which can be seen from the eye, as the points around it
+pub fn visibility_polygon(eye: Pos2, walls: &[[Pos2; 2]], bounds: Rect) -> Vec<Pos2> {
+    let bounds = bounds.union(Rect::from_center_size(eye, Vec2::splat(1.0)));
+    let corners = [
+        bounds.left_top(),
+        bounds.right_top(),
+        bounds.right_bottom(),
+        bounds.left_bottom(),
+    ];
+    let mut segments = walls.to_vec();
+    segments.extend((0..4).map(|i| [corners[i], corners[(i + 1) % 4]]));
+
+    let mut angles: Vec<f32> = segments
+        .iter()
+        .flatten()
+        .flat_map(|end| {
+            let angle = (*end - eye).angle();
+            [angle - RAY_SPREAD, angle, angle + RAY_SPREAD]
+        })
+        .collect();
+    angles.sort_by(|a, b| a.total_cmp(b));
+    angles.dedup();
+    angles
+        .into_iter()
+        .filter_map(|angle| cast_ray(eye, Vec2::angled(angle), &segments))
+        .collect()
+}
+
+// Where the ray hits the closest segment
+fn cast_ray(eye: Pos2, dir: Vec2, segments: &[[Pos2; 2]]) -> Option<Pos2> {
+    let cross = |a: Vec2, b: Vec2| a.x * b.y - a.y * b.x;
+    segments
+        .iter()
+        .filter_map(|[a, b]| {
+            let ab = *b - *a;
+            let denom = cross(dir, ab);
+            if denom.abs() < f32::EPSILON {
+                return None;
+            }
+            let to_a = *a - eye;
+            let t = cross(to_a, ab) / denom;
+            let u = cross(to_a, dir) / denom;
+            (t >= 0.0 && (0.0..=1.0).contains(&u)).then_some(t)
+        })
+        .min_by(|a, b| a.total_cmp(b))
+        .map(|t| eye + dir * t)
+}
+
+pub fn contains(polygon: &[Pos2], point: Pos2) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::{Pos2, Rect};
+
+    use super::{cell_at, CellState, Fog};
+    use crate::net::model;
+
+    #[test]
+    fn line_of_sight() {
+        let bounds = Rect::from_min_max(Pos2::ZERO, Pos2::new(200.0, 100.0));
+        // Wall across the middle of the map, with a gap at the top
+        let wall = [Pos2::new(100.0, 20.0), Pos2::new(100.0, 100.0)];
+        let eye = Pos2::new(50.0, 50.0);
+        let mut fog = Fog::default();
+        fog.look(vec![eye], vec![wall], bounds, true);
+        assert!(fog.is_visible(Pos2::new(10.0, 90.0)));
+        assert!(!fog.is_visible(Pos2::new(150.0, 50.0)));
+        assert!(fog.is_visible(Pos2::new(110.0, 5.0)));
+        assert!(!fog.is_visible(Pos2::new(250.0, 50.0)));
+
+        // Token has gone behind the wall
+        fog.look(vec![Pos2::new(150.0, 50.0)], vec![wall], bounds, true);
+        assert_eq!(
+            fog.cell_state(cell_at(Pos2::new(10.0, 90.0))),
+            CellState::Explored
+        );
+        assert_eq!(
+            fog.cell_state(cell_at(Pos2::new(190.0, 90.0))),
+            CellState::Visible
+        );
+
+        // GM has hidden one cell and revealed another
+        fog.update(&model::Fog {
+            reveal_all: false,
+            revealed: vec![cell_at(Pos2::new(10.0, 10.0))],
+            hidden: vec![cell_at(Pos2::new(190.0, 90.0))],
+        });
+        assert!(fog.is_visible(Pos2::new(10.0, 10.0)));
+        assert_eq!(
+            fog.cell_state(cell_at(Pos2::new(190.0, 90.0))),
+            CellState::Unexplored
+        );
+        assert_eq!(fog.as_entry().hidden, vec![[9, 4]]);
+    }
+
+    #[test]
+    fn explored_cells_carry_over() {
+        let bounds = Rect::from_min_max(Pos2::ZERO, Pos2::new(200.0, 100.0));
+        let mut fog = Fog::default();
+        fog.look(vec![Pos2::new(50.0, 50.0)], Vec::new(), bounds, true);
+        let cell = cell_at(Pos2::new(10.0, 10.0));
+
+        let mut fog = Fog::with_explored(fog.take_explored());
+        fog.update(&model::Fog::default());
+        assert_eq!(fog.cell_state(cell), CellState::Explored);
+    }
+}
@@ -1,20 +1,35 @@
 use eframe::egui;
-use egui::Pos2;
-
-use json::{object, JsonValue};
+use egui::{Pos2, Vec2};
 
 use indexmap::IndexMap;
 
 use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::utils;
+use crate::net::model::{EffectShape, Layer, MapDelta, MapEntry, ObjectKind, ObjectPatch};
+use crate::state::fog::{self, Fog};
 use crate::DraduError;
 
+// How long it takes an object to get to the next position it's been moved to
+const MOTION_TIME: Duration = Duration::from_millis(100);
+// If someone has stopped dragging an object and we haven't got its final
+// position in this time, it goes back to where it was
+const STALE_MOTION: Duration = Duration::from_secs(3);
+// Circular effects are drawn and hit-tested as polygons with this many corners
+const CIRCLE_POINTS: usize = 48;
+
 pub struct MapState {
     pub objects: IndexMap<String, MapObject>,
     pub background_image: Option<String>,
+    pub background_hash: Option<String>,
     // Columns, rows
     pub grid: Option<[u8; 2]>,
+    // None if there is no fog of war
+    pub fog: Option<Fog>,
+    // Objects which are moving smoothly to their new positions. ID: Motion
+    motions: HashMap<String, Motion>,
 }
 
 impl Default for MapState {
@@ -22,53 +37,265 @@ impl Default for MapState {
         MapState {
             objects: IndexMap::new(),
             background_image: None,
+            background_hash: None,
             grid: None,
+            fog: None,
+            motions: HashMap::new(),
         }
     }
 }
 
 impl MapState {
-    pub fn as_json(&self) -> JsonValue {
-        let mut json = object! {};
+    // The whole map as one MAP delta, which recreates it from scratch
+    pub fn as_delta(&self) -> MapDelta {
+        let mut changes = Vec::new();
         if let Some(grid) = self.grid {
-            json["grid"] = object! {
-                "size": grid.to_vec(),
-            };
+            changes.push(("grid".to_string(), MapEntry::Grid(Some(grid))));
         }
         if let Some(bg_path) = &self.background_image {
-            json["background"] = object! {
-                "path": bg_path.clone(),
-            };
+            changes.push((
+                "background".to_string(),
+                MapEntry::Background(bg_path.clone(), self.background_hash.clone()),
+            ));
+        }
+        if let Some(fog) = &self.fog {
+            changes.push(("fog".to_string(), MapEntry::Fog(Some(fog.as_entry()))));
         }
         for (id, obj) in self.objects.iter() {
-            json[id] = obj.as_json();
+            changes.push((id.clone(), MapEntry::Object(obj.as_patch())));
+        }
+        MapDelta::Changes(changes)
+    }
+
+    // Objects in the order they are drawn: layer by layer, and by z-index
+    // within a layer. Objects with the same z-index stay in the order they
+    // were added in
+    pub fn ordered_objects(&self) -> Vec<(&String, &MapObject)> {
+        let mut objects: Vec<_> = self.objects.iter().collect();
+        objects.sort_by_key(|(_, obj)| obj.order());
+        objects
+    }
+
+    // Z-index an object would need to be drawn over everything on the layer
+    pub fn top_of(&self, layer: Layer) -> i32 {
+        self.objects
+            .values()
+            .map(|obj| obj.order())
+            .filter(|(obj_layer, _)| *obj_layer == layer)
+            .map(|(_, z_index)| z_index + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // New z-indices of the objects on the same layer as `id` which change when
+    // it's moved. Objects on the layer are renumbered from 0 up. Walls are lines
+    // under everything else, so they are left out
+    pub fn restack(&self, id: &str, step: Restack) -> Vec<(String, i32)> {
+        let layer = match self.objects.get(id) {
+            Some(MapObject::Wall(_)) | None => return Vec::new(),
+            Some(obj) => obj.order().0,
+        };
+        let mut stack: Vec<&String> = self
+            .ordered_objects()
+            .into_iter()
+            .filter(|(_, obj)| obj.order().0 == layer && !matches!(obj, MapObject::Wall(_)))
+            .map(|(id, _)| id)
+            .collect();
+        let index = match stack.iter().position(|other| *other == id) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let moved = stack.remove(index);
+        let new_index = match step {
+            Restack::Forward => (index + 1).min(stack.len()),
+            Restack::Backward => index.saturating_sub(1),
+            Restack::ToFront => stack.len(),
+            Restack::ToBack => 0,
+        };
+        if new_index == index {
+            return Vec::new();
+        }
+        stack.insert(new_index, moved);
+        stack
+            .into_iter()
+            .zip(0..)
+            .filter(|(id, z_index)| self.objects[*id].order().1 != *z_index)
+            .map(|(id, z_index)| (id.clone(), z_index))
+            .collect()
+    }
+
+    // Every segment of every wall, in map coordinates
+    pub fn wall_segments(&self) -> Vec<[Pos2; 2]> {
+        let mut segments = Vec::new();
+        for obj in self.objects.values() {
+            if let MapObject::Wall(wall) = obj {
+                let points = wall.points();
+                segments.extend(points.windows(2).map(|segment| [segment[0], segment[1]]));
+            }
+        }
+        segments
+    }
+
+    // Where the object should be drawn. While someone else is dragging it, this
+    // isn't its real position
+    pub fn displayed_pos(&self, id: &str, now: Instant) -> Option<Pos2> {
+        match self.motions.get(id) {
+            Some(motion) => Some(motion.pos_at(now)),
+            None => self.objects.get(id).map(|obj| obj.pos()),
+        }
+    }
+
+    // Positions from a transient MAP (Somebody is dragging these objects).
+    // They only change where the objects are drawn
+    pub fn apply_moves(&mut self, delta: MapDelta, now: Instant) {
+        let changes = match delta {
+            MapDelta::Changes(changes) => changes,
+            MapDelta::Reset => return,
+        };
+        for (id, entry) in changes {
+            if let MapEntry::Object(ObjectPatch { pos: Some(pos), .. }) = entry {
+                self.start_motion(&id, to_pos(pos), true, now);
+            }
+        }
+    }
+
+    // Call after the object's real position has changed. If it has been moving,
+    // it moves on to the new position smoothly, otherwise it just jumps there
+    pub fn finish_motion(&mut self, id: &str, now: Instant) {
+        if let Some(pos) = self.objects.get(id).map(|obj| obj.pos()) {
+            if self.motions.contains_key(id) {
+                self.start_motion(id, pos, false, now);
+            }
         }
-        json
+    }
+
+    // Forgets motions which have ended or whose objects are gone. Objects which
+    // haven't been moved for STALE_MOTION go back to their real positions
+    pub fn update_motions(&mut self, now: Instant) {
+        let objects = &self.objects;
+        self.motions.retain(|id, motion| {
+            objects.contains_key(id) && (motion.transient || now < motion.start + MOTION_TIME)
+        });
+        let stale: Vec<String> = self
+            .motions
+            .iter()
+            .filter(|(_, motion)| now > motion.start + STALE_MOTION)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stale {
+            self.finish_motion(&id, now);
+        }
+    }
+
+    pub fn is_in_motion(&self) -> bool {
+        !self.motions.is_empty()
+    }
+
+    fn start_motion(&mut self, id: &str, to: Pos2, transient: bool, now: Instant) {
+        let from = match self.displayed_pos(id, now) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let motion = Motion {
+            from,
+            to,
+            start: now,
+            transient,
+        };
+        self.motions.insert(id.to_string(), motion);
+    }
+}
+
+// Object going from one position to another in MOTION_TIME. Transient motions
+// stay at their destination until the next one, others end there
+struct Motion {
+    from: Pos2,
+    to: Pos2,
+    start: Instant,
+    transient: bool,
+}
+
+impl Motion {
+    fn pos_at(&self, now: Instant) -> Pos2 {
+        let t = now.saturating_duration_since(self.start).as_secs_f32() / MOTION_TIME.as_secs_f32();
+        self.from + (self.to - self.from) * t.min(1.0)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Restack {
+    Forward,
+    Backward,
+    ToFront,
+    ToBack,
+}
+
 pub enum MapObject {
     Decal(Decal),
     Token(Token),
     Wall(Wall),
+    Effect(Effect),
 }
 
 impl MapObject {
-    pub fn update_from_json(&mut self, json: &JsonValue) -> Result<(), DraduError> {
+    pub fn update_from_patch(&mut self, patch: &ObjectPatch) {
+        match self {
+            MapObject::Decal(decal) => decal.update_from_patch(patch),
+            MapObject::Token(token) => token.update_from_patch(patch),
+            MapObject::Wall(wall) => wall.update_from_patch(patch),
+            MapObject::Effect(effect) => effect.update_from_patch(patch),
+        }
+        let (layer, z_index) = self.order_mut();
+        if let Some(new_layer) = patch.layer {
+            *layer = new_layer;
+        }
+        if let Some(new_z_index) = patch.z_index {
+            *z_index = new_z_index;
+        }
+    }
+
+    // `id` is only used in errors
+    pub fn create_from_patch(id: &str, patch: &ObjectPatch) -> Result<Self, DraduError> {
+        let missing =
+            |key: &str| DraduError::InvalidField(format!("{}.{}", id, key), "is missing".into());
+        let kind = patch.kind.ok_or_else(|| missing("type"))?;
+        let path = || patch.path.clone().ok_or_else(|| missing("path"));
+        let mut obj = match kind {
+            ObjectKind::Wall => {
+                if patch.nodes.is_none() {
+                    return Err(missing("nodes"));
+                }
+                Self::Wall(Wall::create_from_patch(patch))
+            }
+            ObjectKind::Effect => {
+                let shape = patch.shape.ok_or_else(|| missing("shape"))?;
+                Self::Effect(Effect::create_from_patch(shape, patch))
+            }
+            ObjectKind::Token => Self::Token(Token::create_from_patch(path()?, patch)),
+            ObjectKind::Decal => Self::Decal(Decal::create_from_patch(path()?, patch)),
+        };
+        let (layer, z_index) = obj.order_mut();
+        *layer = patch.layer.unwrap_or_else(|| kind.default_layer());
+        *z_index = patch.z_index.unwrap_or(0);
+        Ok(obj)
+    }
+
+    // Layer and z-index
+    pub fn order(&self) -> (Layer, i32) {
         match self {
-            MapObject::Decal(decal) => decal.update_from_json(json),
-            MapObject::Token(token) => token.update_from_json(json),
-            MapObject::Wall(_) => unimplemented!(),
+            Self::Decal(decal) => (decal.layer, decal.z_index),
+            Self::Token(token) => (token.layer, token.z_index),
+            Self::Wall(wall) => (wall.layer, wall.z_index),
+            Self::Effect(effect) => (effect.layer, effect.z_index),
         }
     }
 
-    pub fn create_from_json(json: &JsonValue) -> Result<Self, DraduError> {
-        match json["type"].as_str().ok_or(DraduError::ProtocolError)? {
-            "token" => Ok(Self::Token(Token::create_from_json(json)?)),
-            "decal" => Ok(Self::Decal(Decal::create_from_json(json)?)),
-            "wall" => unimplemented!(),
-            "effect" => unimplemented!(),
-            _ => Err(DraduError::ProtocolError),
+    fn order_mut(&mut self) -> (&mut Layer, &mut i32) {
+        match self {
+            Self::Decal(decal) => (&mut decal.layer, &mut decal.z_index),
+            Self::Token(token) => (&mut token.layer, &mut token.z_index),
+            Self::Wall(wall) => (&mut wall.layer, &mut wall.z_index),
+            Self::Effect(effect) => (&mut effect.layer, &mut effect.z_index),
         }
     }
 
@@ -77,6 +304,7 @@ impl MapObject {
             Self::Decal(decal) => decal.pos,
             Self::Token(token) => token.pos,
             Self::Wall(wall) => wall.pos,
+            Self::Effect(effect) => effect.pos,
         }
     }
 
@@ -84,7 +312,25 @@ impl MapObject {
         match self {
             Self::Decal(decal) => decal.scale,
             Self::Token(token) => token.scale,
-            Self::Wall(_) => 1.0,
+            Self::Wall(_) | Self::Effect(_) => 1.0,
+        }
+    }
+
+    // Degrees clockwise. Only images are turned
+    pub fn rotation(&self) -> f32 {
+        match self {
+            Self::Decal(decal) => decal.rotation,
+            Self::Token(token) => token.rotation,
+            Self::Wall(_) | Self::Effect(_) => 0.0,
+        }
+    }
+
+    // Whether the image is mirrored horizontally and vertically
+    pub fn flip(&self) -> [bool; 2] {
+        match self {
+            Self::Decal(decal) => [decal.flip_x, decal.flip_y],
+            Self::Token(token) => [token.flip_x, token.flip_y],
+            Self::Wall(_) | Self::Effect(_) => [false, false],
         }
     }
 
@@ -93,52 +339,116 @@ impl MapObject {
             Self::Decal(decal) => &decal.path,
             Self::Token(token) => &token.path,
             Self::Wall(wall) => &wall.path,
+            Self::Effect(_) => "",
         }
     }
 
-    pub fn as_json(&self) -> JsonValue {
+    // See ObjectPatch::hash
+    pub fn hash(&self) -> Option<&str> {
         match self {
-            Self::Decal(decal) => decal.as_json(),
-            Self::Token(token) => token.as_json(),
-            Self::Wall(_) => unimplemented!(),
+            Self::Decal(decal) => decal.hash.as_deref(),
+            Self::Token(token) => token.hash.as_deref(),
+            Self::Wall(_) | Self::Effect(_) => None,
+        }
+    }
+
+    // Patch which creates this object
+    pub fn as_patch(&self) -> ObjectPatch {
+        let patch = match self {
+            Self::Decal(decal) => decal.as_patch(),
+            Self::Token(token) => token.as_patch(),
+            Self::Wall(wall) => wall.as_patch(),
+            Self::Effect(effect) => effect.as_patch(),
+        };
+        let (layer, z_index) = self.order();
+        ObjectPatch {
+            layer: Some(layer),
+            z_index: Some(z_index),
+            ..patch
         }
     }
 }
 
+fn to_pos([x, y]: [f64; 2]) -> Pos2 {
+    Pos2::new(x as f32, y as f32)
+}
+
+pub fn from_pos(pos: Pos2) -> [f64; 2] {
+    [widen(pos.x), widen(pos.y)]
+}
+
+// Goes through the shortest decimal representation, so 0.1 is sent as 0.1 and
+// not as 0.10000000149011612
+pub fn widen(x: f32) -> f64 {
+    x.to_string().parse().unwrap_or_else(|_| x.into())
+}
+
+fn update_transform(patch: &ObjectPatch, rotation: &mut f32, flip_x: &mut bool, flip_y: &mut bool) {
+    if let Some(new_rotation) = patch.rotation {
+        *rotation = new_rotation as f32;
+    }
+    if let Some(new_flip_x) = patch.flip_x {
+        *flip_x = new_flip_x;
+    }
+    if let Some(new_flip_y) = patch.flip_y {
+        *flip_y = new_flip_y;
+    }
+}
+
 pub struct Decal {
     pub pos: Pos2,
     pub scale: f32,
+    // See ObjectPatch::rotation
+    pub rotation: f32,
+    pub flip_x: bool,
+    pub flip_y: bool,
     pub path: String,
+    pub hash: Option<String>,
+    pub layer: Layer,
+    pub z_index: i32,
 }
 
 impl Decal {
-    fn update_from_json(&mut self, json: &JsonValue) -> Result<(), DraduError> {
-        if let Ok(pos) = utils::json_to_pos(&json["pos"]) {
-            self.pos = pos;
+    fn update_from_patch(&mut self, patch: &ObjectPatch) {
+        if let Some(pos) = patch.pos {
+            self.pos = to_pos(pos);
         }
-        if let Some(scale) = json["scale"].as_f32() {
-            self.scale = scale;
+        if let Some(scale) = patch.scale {
+            self.scale = scale as f32;
         }
-        Ok(())
+        update_transform(
+            patch,
+            &mut self.rotation,
+            &mut self.flip_x,
+            &mut self.flip_y,
+        );
     }
 
-    fn create_from_json(json: &JsonValue) -> Result<Self, DraduError> {
-        Ok(Self {
-            pos: utils::json_to_pos(&json["pos"]).unwrap_or(Pos2::new(0.0, 0.0)),
-            scale: json["scale"].as_f32().unwrap_or(1.0),
-            path: json["path"]
-                .as_str()
-                .ok_or(DraduError::ProtocolError)?
-                .to_owned(),
-        })
+    fn create_from_patch(path: String, patch: &ObjectPatch) -> Self {
+        Self {
+            pos: patch.pos.map(to_pos).unwrap_or(Pos2::ZERO),
+            scale: patch.scale.unwrap_or(1.0) as f32,
+            rotation: patch.rotation.unwrap_or(0.0) as f32,
+            flip_x: patch.flip_x.unwrap_or(false),
+            flip_y: patch.flip_y.unwrap_or(false),
+            path,
+            hash: patch.hash.clone(),
+            layer: Layer::Map,
+            z_index: 0,
+        }
     }
 
-    fn as_json(&self) -> JsonValue {
-        object! {
-            "type": "decal",
-            "pos": [self.pos.x, self.pos.y],
-            "scale": self.scale,
-            "path": self.path.clone(),
+    fn as_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            kind: Some(ObjectKind::Decal),
+            path: Some(self.path.clone()),
+            hash: self.hash.clone(),
+            pos: Some(from_pos(self.pos)),
+            scale: Some(widen(self.scale)),
+            rotation: Some(widen(self.rotation)),
+            flip_x: Some(self.flip_x),
+            flip_y: Some(self.flip_y),
+            ..ObjectPatch::default()
         }
     }
 }
@@ -146,59 +456,521 @@ impl Decal {
 pub struct Token {
     pub pos: Pos2,
     pub scale: f32,
+    // See ObjectPatch::rotation
+    pub rotation: f32,
+    pub flip_x: bool,
+    pub flip_y: bool,
     pub path: String,
+    pub hash: Option<String>,
     // Additional things like health, armor, etc.
     pub properties: HashMap<String, String>,
+    pub layer: Layer,
+    pub z_index: i32,
 }
 
 impl Token {
-    fn update_from_json(&mut self, json: &JsonValue) -> Result<(), DraduError> {
-        if let Ok(pos) = utils::json_to_pos(&json["pos"]) {
-            self.pos = pos;
+    // "name" property, or the file name of the image
+    pub fn name(&self) -> &str {
+        match self.properties.get("name") {
+            Some(name) => name,
+            None => Path::new(&self.path)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(&self.path),
+        }
+    }
+
+    // Players see the map through the tokens they own. The "owner" property
+    // is the player's user ID, nicknames can be taken by anyone. No viewer
+    // stands for every player
+    pub fn is_owned_by(&self, viewer: Option<&str>) -> bool {
+        self.owner()
+            .is_some_and(|owner| viewer.is_none_or(|viewer| viewer == owner))
+    }
+
+    // User ID of the player the token belongs to
+    pub fn owner(&self) -> Option<&str> {
+        self.properties
+            .get("owner")
+            .map(|owner| owner.trim())
+            .filter(|owner| !owner.is_empty())
+    }
+
+    fn update_from_patch(&mut self, patch: &ObjectPatch) {
+        if let Some(pos) = patch.pos {
+            self.pos = to_pos(pos);
         }
-        if let Some(scale) = json["scale"].as_f32() {
-            self.scale = scale;
+        if let Some(scale) = patch.scale {
+            self.scale = scale as f32;
         }
-        for (k, v) in json["properties"].entries() {
+        update_transform(
+            patch,
+            &mut self.rotation,
+            &mut self.flip_x,
+            &mut self.flip_y,
+        );
+        for (k, v) in &patch.properties {
             if v.is_null() {
                 self.properties.remove(k);
             } else {
                 self.properties.insert(k.to_string(), v.to_string());
             }
         }
-        Ok(())
     }
 
-    fn create_from_json(json: &JsonValue) -> Result<Self, DraduError> {
-        let mut properties = HashMap::new();
-        for (k, v) in json["properties"].entries() {
-            properties.insert(k.to_string(), v.to_string());
-        }
-
-        Ok(Self {
-            pos: utils::json_to_pos(&json["pos"]).unwrap_or(Pos2::new(0.0, 0.0)),
-            scale: json["scale"].as_f32().unwrap_or(1.0),
-            path: json["path"]
-                .as_str()
-                .ok_or(DraduError::ProtocolError)?
-                .to_owned(),
-            properties,
-        })
+    fn create_from_patch(path: String, patch: &ObjectPatch) -> Self {
+        let mut token = Self {
+            pos: Pos2::ZERO,
+            scale: 1.0,
+            rotation: 0.0,
+            flip_x: false,
+            flip_y: false,
+            path,
+            hash: patch.hash.clone(),
+            properties: HashMap::new(),
+            layer: Layer::Tokens,
+            z_index: 0,
+        };
+        token.update_from_patch(patch);
+        token
     }
 
-    fn as_json(&self) -> JsonValue {
-        object! {
-            "type": "token",
-            "pos": [self.pos.x, self.pos.y],
-            "scale": self.scale,
-            "path": self.path.clone(),
-            "properties": self.properties.clone(),
+    fn as_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            kind: Some(ObjectKind::Token),
+            path: Some(self.path.clone()),
+            hash: self.hash.clone(),
+            pos: Some(from_pos(self.pos)),
+            scale: Some(widen(self.scale)),
+            rotation: Some(widen(self.rotation)),
+            flip_x: Some(self.flip_x),
+            flip_y: Some(self.flip_y),
+            properties: self
+                .properties
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone().into()))
+                .collect(),
+            ..ObjectPatch::default()
         }
     }
 }
 
 pub struct Wall {
     pub pos: Pos2,
+    // Relative to pos
     pub nodes: Vec<Pos2>,
+    // Walls are drawn as lines, so it's usually empty
     pub path: String,
+    pub layer: Layer,
+    pub z_index: i32,
+}
+
+impl Wall {
+    // Wall going through these points on the map
+    pub fn through(points: &[Pos2]) -> Self {
+        let pos = points.first().copied().unwrap_or(Pos2::ZERO);
+        Self {
+            pos,
+            nodes: points
+                .iter()
+                .map(|point| (*point - pos).to_pos2())
+                .collect(),
+            path: String::new(),
+            layer: Layer::Map,
+            z_index: 0,
+        }
+    }
+
+    // Nodes on the map
+    pub fn points(&self) -> Vec<Pos2> {
+        self.nodes
+            .iter()
+            .map(|node| self.pos + node.to_vec2())
+            .collect()
+    }
+
+    // Patch which only changes the nodes
+    pub fn nodes_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            nodes: Some(self.nodes.iter().map(|node| from_pos(*node)).collect()),
+            ..ObjectPatch::default()
+        }
+    }
+
+    fn update_from_patch(&mut self, patch: &ObjectPatch) {
+        if let Some(pos) = patch.pos {
+            self.pos = to_pos(pos);
+        }
+        if let Some(nodes) = &patch.nodes {
+            self.nodes = nodes.iter().copied().map(to_pos).collect();
+        }
+    }
+
+    fn create_from_patch(patch: &ObjectPatch) -> Self {
+        let mut wall = Self {
+            pos: Pos2::ZERO,
+            nodes: Vec::new(),
+            path: patch.path.clone().unwrap_or_default(),
+            layer: Layer::Map,
+            z_index: 0,
+        };
+        wall.update_from_patch(patch);
+        wall
+    }
+
+    fn as_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            kind: Some(ObjectKind::Wall),
+            path: Some(self.path.clone()).filter(|path| !path.is_empty()),
+            pos: Some(from_pos(self.pos)),
+            ..self.nodes_patch()
+        }
+    }
+}
+
+// Area of a spell or the like. Sizes are in grid units, their size on the map
+// is given by the `unit` of the methods
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    // Origin, see EffectShape
+    pub pos: Pos2,
+    pub shape: EffectShape,
+    pub size: f32,
+    pub color: [u8; 3],
+    // From 0 to 1
+    pub opacity: f32,
+    // Degrees, clockwise from the x axis
+    pub direction: f32,
+    pub layer: Layer,
+    pub z_index: i32,
+}
+
+impl Effect {
+    pub fn new(shape: EffectShape, pos: Pos2) -> Self {
+        Self {
+            pos,
+            shape,
+            size: 1.0,
+            color: [255, 120, 0],
+            opacity: 0.4,
+            direction: 0.0,
+            layer: Layer::Tokens,
+            z_index: 0,
+        }
+    }
+
+    // Corners of the area on the map, going around it
+    pub fn outline(&self, unit: f32) -> Vec<Pos2> {
+        let length = self.size * unit;
+        let dir = Vec2::angled(self.direction.to_radians());
+        let side = dir.rot90();
+        let pos = self.pos;
+        match self.shape {
+            EffectShape::Circle => (0..CIRCLE_POINTS)
+                .map(|i| pos + Vec2::angled(TAU * i as f32 / CIRCLE_POINTS as f32) * length)
+                .collect(),
+            EffectShape::Cone => {
+                let end = pos + dir * length;
+                vec![pos, end - side * length / 2.0, end + side * length / 2.0]
+            }
+            EffectShape::Line => {
+                let (along, across) = (dir * length, side * unit / 2.0);
+                vec![
+                    pos - across,
+                    pos - across + along,
+                    pos + across + along,
+                    pos + across,
+                ]
+            }
+            EffectShape::Square => {
+                let (along, across) = (dir * length / 2.0, side * length / 2.0);
+                vec![
+                    pos - along - across,
+                    pos + along - across,
+                    pos + along + across,
+                    pos - along + across,
+                ]
+            }
+        }
+    }
+
+    pub fn contains(&self, point: Pos2, unit: f32) -> bool {
+        match self.shape {
+            EffectShape::Circle => point.distance(self.pos) <= self.size * unit,
+            _ => fog::contains(&self.outline(unit), point),
+        }
+    }
+
+    // Far end of the area in its direction, where it's rotated from
+    pub fn tip(&self, unit: f32) -> Pos2 {
+        let length = match self.shape {
+            EffectShape::Circle | EffectShape::Square => self.size * unit / 2.0,
+            EffectShape::Cone | EffectShape::Line => self.size * unit,
+        };
+        self.pos + Vec2::angled(self.direction.to_radians()) * length
+    }
+
+    // Patch which changes everything but the position
+    pub fn template_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            shape: Some(self.shape),
+            size: Some(widen(self.size)),
+            color: Some(self.color),
+            opacity: Some(widen(self.opacity)),
+            direction: Some(widen(self.direction)),
+            ..ObjectPatch::default()
+        }
+    }
+
+    fn update_from_patch(&mut self, patch: &ObjectPatch) {
+        if let Some(pos) = patch.pos {
+            self.pos = to_pos(pos);
+        }
+        if let Some(shape) = patch.shape {
+            self.shape = shape;
+        }
+        if let Some(size) = patch.size {
+            self.size = size as f32;
+        }
+        if let Some(color) = patch.color {
+            self.color = color;
+        }
+        if let Some(opacity) = patch.opacity {
+            self.opacity = opacity as f32;
+        }
+        if let Some(direction) = patch.direction {
+            self.direction = direction as f32;
+        }
+    }
+
+    fn create_from_patch(shape: EffectShape, patch: &ObjectPatch) -> Self {
+        let mut effect = Self::new(shape, Pos2::ZERO);
+        effect.update_from_patch(patch);
+        effect
+    }
+
+    fn as_patch(&self) -> ObjectPatch {
+        ObjectPatch {
+            kind: Some(ObjectKind::Effect),
+            pos: Some(from_pos(self.pos)),
+            ..self.template_patch()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::Pos2;
+
+    use std::time::{Duration, Instant};
+
+    use super::{Effect, MapObject, MapState, Restack, Wall, MOTION_TIME, STALE_MOTION};
+    use crate::net::model::{EffectShape, Layer, MapDelta, MapEntry, ObjectKind, ObjectPatch};
+
+    fn moved_to(x: f64, y: f64) -> MapDelta {
+        let patch = ObjectPatch {
+            pos: Some([x, y]),
+            ..ObjectPatch::default()
+        };
+        MapDelta::single("orc", MapEntry::Object(patch))
+    }
+
+    #[test]
+    fn motions() {
+        let mut map = MapState::default();
+        let patch = ObjectPatch {
+            kind: Some(ObjectKind::Token),
+            path: Some("orc.png".to_string()),
+            ..ObjectPatch::default()
+        };
+        let orc = MapObject::create_from_patch("orc", &patch).unwrap();
+        map.objects.insert("orc".to_string(), orc);
+        let start = Instant::now();
+        let pos_after = |map: &MapState, time: Duration| map.displayed_pos("orc", start + time);
+
+        // Somebody is dragging the orc, it's drawn halfway there...
+        map.apply_moves(moved_to(10.0, 20.0), start);
+        assert_eq!(pos_after(&map, MOTION_TIME / 2), Some(Pos2::new(5.0, 10.0)));
+        // ...and stays there until the next move
+        map.update_motions(start + MOTION_TIME * 2);
+        assert!(map.is_in_motion());
+        assert_eq!(
+            pos_after(&map, MOTION_TIME * 2),
+            Some(Pos2::new(10.0, 20.0))
+        );
+        assert_eq!(map.objects["orc"].pos(), Pos2::ZERO);
+
+        // Final position
+        let now = start + MOTION_TIME * 2;
+        map.objects["orc"].update_from_patch(&ObjectPatch {
+            pos: Some([30.0, 20.0]),
+            ..ObjectPatch::default()
+        });
+        map.finish_motion("orc", now);
+        assert_eq!(
+            pos_after(&map, MOTION_TIME * 2),
+            Some(Pos2::new(10.0, 20.0))
+        );
+        assert_eq!(
+            pos_after(&map, MOTION_TIME * 3),
+            Some(Pos2::new(30.0, 20.0))
+        );
+        map.update_motions(now + MOTION_TIME);
+        assert!(!map.is_in_motion());
+
+        // Dragging player has disappeared without letting go of it
+        map.apply_moves(moved_to(50.0, 50.0), now);
+        map.update_motions(now + STALE_MOTION * 2);
+        map.update_motions(now + STALE_MOTION * 2 + MOTION_TIME);
+        assert!(!map.is_in_motion());
+        assert_eq!(
+            map.displayed_pos("orc", now + STALE_MOTION * 3),
+            Some(Pos2::new(30.0, 20.0))
+        );
+    }
+
+    #[test]
+    fn walls() {
+        let points = [Pos2::new(10.0, 10.0), Pos2::new(30.0, 10.0)];
+        let wall = Wall::through(&points);
+        assert_eq!(wall.nodes[1], Pos2::new(20.0, 0.0));
+
+        let patch = wall.as_patch();
+        assert_eq!(patch.kind, Some(ObjectKind::Wall));
+        assert_eq!(patch.path, None);
+        let mut wall = MapObject::create_from_patch("wall", &patch).unwrap();
+        assert_eq!(wall.pos(), Pos2::new(10.0, 10.0));
+        wall.update_from_patch(&ObjectPatch {
+            pos: Some([0.0, 0.0]),
+            ..ObjectPatch::default()
+        });
+        match wall {
+            MapObject::Wall(wall) => assert_eq!(wall.points()[1], Pos2::new(20.0, 0.0)),
+            _ => panic!("Expected a wall"),
+        }
+
+        let no_nodes = ObjectPatch {
+            nodes: None,
+            ..patch
+        };
+        assert!(MapObject::create_from_patch("wall", &no_nodes).is_err());
+    }
+
+    #[test]
+    fn effects() {
+        let unit = 10.0;
+        let mut cone = Effect::new(EffectShape::Cone, Pos2::new(100.0, 100.0));
+        cone.size = 3.0;
+        assert!(cone.contains(Pos2::new(125.0, 100.0), unit));
+        assert!(cone.contains(Pos2::new(128.0, 110.0), unit));
+        assert!(!cone.contains(Pos2::new(105.0, 110.0), unit));
+        assert!(!cone.contains(Pos2::new(75.0, 100.0), unit));
+
+        // Pointing down
+        cone.direction = 90.0;
+        assert!(cone.contains(Pos2::new(100.0, 125.0), unit));
+        assert!(!cone.contains(Pos2::new(125.0, 100.0), unit));
+        assert!(cone.tip(unit).distance(Pos2::new(100.0, 130.0)) < 0.001);
+
+        let mut line = Effect::new(EffectShape::Line, Pos2::ZERO);
+        line.size = 6.0;
+        assert!(line.contains(Pos2::new(55.0, 4.0), unit));
+        assert!(!line.contains(Pos2::new(55.0, 6.0), unit));
+        let mut square = Effect::new(EffectShape::Square, Pos2::ZERO);
+        square.size = 2.0;
+        square.direction = 45.0;
+        assert!(square.contains(Pos2::new(0.0, 13.0), unit));
+        assert!(!square.contains(Pos2::new(9.0, 9.0), unit));
+        let circle = Effect::new(EffectShape::Circle, Pos2::ZERO);
+        assert!(circle.contains(Pos2::new(6.0, 8.0), unit));
+        assert!(!circle.contains(Pos2::new(8.0, 8.0), unit));
+
+        let patch = MapObject::Effect(cone.clone()).as_patch();
+        assert_eq!(patch.path, None);
+        match MapObject::create_from_patch("cone", &patch).unwrap() {
+            MapObject::Effect(effect) => assert_eq!(effect, cone),
+            _ => panic!("Expected an effect"),
+        }
+        let no_shape = ObjectPatch {
+            shape: None,
+            ..patch
+        };
+        assert!(MapObject::create_from_patch("cone", &no_shape).is_err());
+    }
+
+    #[test]
+    fn layers() {
+        let mut map = MapState::default();
+        for (id, kind, z_index) in [
+            ("orc", ObjectKind::Token, 0),
+            ("rug", ObjectKind::Decal, 5),
+            ("elf", ObjectKind::Token, -1),
+            ("imp", ObjectKind::Token, 0),
+        ] {
+            let patch = ObjectPatch {
+                kind: Some(kind),
+                path: Some(format!("{}.png", id)),
+                z_index: Some(z_index),
+                ..ObjectPatch::default()
+            };
+            let obj = MapObject::create_from_patch(id, &patch).unwrap();
+            map.objects.insert(id.to_string(), obj);
+        }
+        let order = |map: &MapState| -> Vec<String> {
+            map.ordered_objects()
+                .into_iter()
+                .map(|(id, _)| id.clone())
+                .collect()
+        };
+        assert_eq!(order(&map), ["rug", "elf", "orc", "imp"]);
+        assert_eq!(map.top_of(Layer::Tokens), 1);
+        assert_eq!(map.top_of(Layer::Gm), 0);
+
+        let restack = |map: &mut MapState, id: &str, step: Restack| {
+            for (id, z_index) in map.restack(id, step) {
+                map.objects[&id].update_from_patch(&ObjectPatch {
+                    z_index: Some(z_index),
+                    ..ObjectPatch::default()
+                });
+            }
+        };
+        restack(&mut map, "orc", Restack::Forward);
+        assert_eq!(order(&map), ["rug", "elf", "imp", "orc"]);
+        restack(&mut map, "orc", Restack::ToBack);
+        assert_eq!(order(&map), ["rug", "orc", "elf", "imp"]);
+        restack(&mut map, "elf", Restack::Backward);
+        assert_eq!(order(&map), ["rug", "elf", "orc", "imp"]);
+        assert!(map.restack("rug", Restack::ToFront).is_empty());
+        assert!(map.restack("imp", Restack::Forward).is_empty());
+
+        map.objects["rug"].update_from_patch(&ObjectPatch {
+            layer: Some(Layer::Gm),
+            ..ObjectPatch::default()
+        });
+        assert_eq!(order(&map)[3], "rug");
+        assert_eq!(map.objects["rug"].as_patch().layer, Some(Layer::Gm));
+    }
+
+    #[test]
+    fn transforms() {
+        let patch = ObjectPatch {
+            kind: Some(ObjectKind::Decal),
+            path: Some("door.png".to_string()),
+            rotation: Some(90.0),
+            flip_x: Some(true),
+            ..ObjectPatch::default()
+        };
+        let mut door = MapObject::create_from_patch("door", &patch).unwrap();
+        assert_eq!(door.rotation(), 90.0);
+        assert_eq!(door.flip(), [true, false]);
+        door.update_from_patch(&ObjectPatch {
+            rotation: Some(135.0),
+            flip_y: Some(true),
+            ..ObjectPatch::default()
+        });
+        assert_eq!(door.flip(), [true, true]);
+        let patch = door.as_patch();
+        assert_eq!(patch.rotation, Some(135.0));
+        assert_eq!((patch.flip_x, patch.flip_y), (Some(true), Some(true)));
+    }
 }
@@ -1,5 +1,8 @@
+pub mod fog;
 pub mod map_state;
 pub use map_state as map;
 
 mod room_state;
+pub(crate) mod transfer;
 pub use room_state::RoomState;
+pub use transfer::checksum;
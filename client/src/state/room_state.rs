@@ -1,19 +1,35 @@
 use eframe::egui;
-use egui::{Color32, Context, Pos2};
+use egui::{Color32, Context, Pos2, Rect};
 use egui_extras::RetainedImage;
 
-use json::{object, JsonValue};
+use json::JsonValue;
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::cache::AssetCache;
 use crate::fs::AssetDirHandler;
-use crate::net::{Connection, LoopbackConnection, Message, MsgBody, MsgType, ServerConnection};
-use crate::state::map::{MapObject, MapState};
+use crate::net::model::{
+    self as model, ErrResponse, ErrorCode, FileRequest, Invite, Layer, MapDelta, MapEntry,
+    ObjectKind, ObjectPatch, PlayerDelta,
+};
+use crate::net::{
+    Connection, JoinOptions, KnownHosts, LanHost, LoopbackConnection, Message, MsgBody, MsgType,
+    Playback, Recorder, Recording, ReplayConnection, RoomAddr, ServerAddr, ServerConnection,
+    TlsConnection, WebSocketConnection, DISCOVERY_PORT,
+};
+use crate::state::fog::{Cell, Fog};
+use crate::state::map::{self, Effect, MapObject, MapState, Restack, Token, Wall};
+use crate::state::transfer::{self, Download, Upload};
 use crate::utils;
 use crate::DraduError;
 
+// Older errors are dropped if the user doesn't dismiss them
+const MAX_ERRORS: usize = 5;
+
 // This struct monitors and provides access to things like chat log,
 // map, images, list of players and permissions. It also manages the
 // server connection, updating all of these things when new messages
@@ -23,25 +39,104 @@ pub struct RoomState {
     // permissions
     chat_log: Vec<ChatMessage>,
     master: bool,
+    // We can only watch. Messages which would change something aren't sent
+    spectator: bool,
     connection: Box<dyn Connection>,
     fs: AssetDirHandler,
+    // Images we've received in earlier sessions
+    cache: AssetCache,
 
     players: HashMap<String, (String, Color32)>, // Id: (Nickname, Color)
+    // IDs of the players who only watch
+    spectators: HashSet<String>,
+    // Round trip times other players have reported. Id: Latency
+    latencies: HashMap<String, Duration>,
     // It promises that all images referenced in map *will* be here,
     // however, there is a placeholder image which will be returned otherwise
     images: HashMap<String, RetainedImage>,
     map: MapState,
+    // Images which are being received from the master. Path: Download
+    downloads: HashMap<String, Download>,
+    // Files which are being sent to other players (Master only)
+    uploads: VecDeque<Upload>,
+
+    // Set while the connection is lost and we're trying to get it back
+    reconnecting: Option<Reconnecting>,
+    // Messages sent while we were offline. They are sent after reconnecting
+    outbox: Vec<Message>,
+    // Cells our tokens had explored before the session was resumed. The server
+    // doesn't know them, so they are put back into the fog it sends again
+    explored: HashSet<Cell>,
+    // Set after we've quit the room, so losing connection is expected
+    quitting: bool,
+    // Writes down everything we send and receive, if the session is recorded
+    recorder: Option<Recorder>,
+    // Errors reported by the server, in messages from it which have been
+    // skipped, or our own ones. Shown until the user dismisses them
+    errors: VecDeque<ErrResponse>,
+    // Set until the initial state of the room has been received
+    loading: Option<Loading>,
+    // Answers LAN discovery probes about our room, if we're its master
+    lan_host: Option<LanHost>,
+    // Latest invite link the server has given us, and when it expires
+    invite: Option<(String, Instant)>,
+    // Whether the room was created with a password
+    has_password: bool,
 }
 
 impl<'a> RoomState {
-    pub fn join_room(addr: SocketAddr, room_id: &str, ctx: &Context) -> Result<Self, DraduError> {
-        let connection = ServerConnection::join_room(addr, room_id, ctx)?;
-        Ok(Self::with_connection(Box::new(connection), false))
+    // Spectators get a read-only view of the room
+    pub fn join_room(
+        addr: ServerAddr,
+        room_id: &str,
+        options: &JoinOptions,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        let connection: Box<dyn Connection> = match addr {
+            ServerAddr::Tcp(addr) => {
+                Box::new(ServerConnection::join_room(&addr, room_id, options, ctx)?)
+            }
+            ServerAddr::WebSocket(url) => {
+                Box::new(WebSocketConnection::join_room(&url, room_id, options, ctx)?)
+            }
+            ServerAddr::Tls(addr) => Box::new(TlsConnection::join_room(
+                &addr,
+                room_id,
+                options,
+                KnownHosts::new(),
+                ctx,
+            )?),
+        };
+        let mut state = Self::with_connection(connection, false);
+        state.loading = Some(Loading::default());
+        state.spectator = options.spectator;
+        Ok(state)
     }
 
-    pub fn create_new_room(addr: SocketAddr, ctx: &Context) -> Result<Self, DraduError> {
-        let connection = ServerConnection::create_new_room(addr, ctx)?;
-        Ok(Self::with_connection(Box::new(connection), true))
+    // Without a password, anyone who knows the room address can join
+    pub fn create_new_room(
+        addr: ServerAddr,
+        password: Option<&str>,
+        ctx: &Context,
+    ) -> Result<Self, DraduError> {
+        let connection: Box<dyn Connection> = match addr {
+            ServerAddr::Tcp(addr) => {
+                Box::new(ServerConnection::create_new_room(&addr, password, ctx)?)
+            }
+            ServerAddr::WebSocket(url) => {
+                Box::new(WebSocketConnection::create_new_room(&url, password, ctx)?)
+            }
+            ServerAddr::Tls(addr) => Box::new(TlsConnection::create_new_room(
+                &addr,
+                password,
+                KnownHosts::new(),
+                ctx,
+            )?),
+        };
+        let mut state = Self::with_connection(connection, true);
+        state.loading = Some(Loading::default());
+        state.has_password = password.is_some();
+        Ok(state)
     }
 
     pub fn create_local_server(ctx: &Context) -> Self {
@@ -49,6 +144,13 @@ impl<'a> RoomState {
         Self::with_connection(Box::new(connection), true)
     }
 
+    // Watching a recorded session. See RoomState::playback
+    pub fn replay(recording: Recording) -> Self {
+        let connection = ReplayConnection::new(recording);
+        let master = connection.is_master();
+        Self::with_connection(Box::new(connection), master)
+    }
+
     fn with_connection(connection: Box<dyn Connection>, master: bool) -> Self {
         let mut images = HashMap::new();
         images.insert("placeholder".to_string(), utils::get_placeholder_image());
@@ -65,28 +167,138 @@ impl<'a> RoomState {
         RoomState {
             chat_log: Vec::new(),
             master,
+            spectator: false,
             connection,
             fs: AssetDirHandler::new(),
+            cache: AssetCache::new(),
             players,
+            spectators: HashSet::new(),
+            latencies: HashMap::new(),
             images,
             map: MapState::default(),
+            downloads: HashMap::new(),
+            uploads: VecDeque::new(),
+            reconnecting: None,
+            outbox: Vec::new(),
+            explored: HashSet::new(),
+            quitting: false,
+            recorder: None,
+            errors: VecDeque::new(),
+            loading: None,
+            lan_host: None,
+            invite: None,
+            has_password: false,
+        }
+    }
+
+    // Session has been resumed, over `connection` if it's a new one. The server
+    // will send us the whole map again, so the local one is reset. Only the
+    // explored cells are kept
+    fn resume(&mut self, connection: Option<Box<dyn Connection>>) -> Result<(), DraduError> {
+        if let Some(connection) = connection {
+            self.connection.close();
+            self.connection = connection;
         }
+        self.reconnecting = None;
+        if let Some(fog) = &mut self.map.fog {
+            self.explored = fog.take_explored();
+        }
+        self.map = MapState::default();
+        self.reset_players();
+        // Interrupted downloads continue where they have stopped
+        for (path, download) in self.downloads.iter_mut() {
+            self.connection.send_msg(download.request(path))?;
+        }
+        for msg in std::mem::take(&mut self.outbox) {
+            self.connection.send_msg(msg)?;
+        }
+        Ok(())
     }
 
-    pub fn reconnect(&mut self) {
-        todo!();
+    // Returns Err only when we've given up on reconnecting. Attempts are made
+    // on another thread, connecting may take a few seconds
+    fn keep_reconnecting(&mut self) -> Result<(), DraduError> {
+        if self.quitting {
+            return Err(DraduError::ChannelDisconnected);
+        }
+        let r = match &mut self.reconnecting {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let result = match &r.pending {
+            Some(pending) => match pending.try_recv() {
+                Ok(result) => result.map(|connection| Some(connection as Box<dyn Connection>)),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => Err(DraduError::ChannelDisconnected),
+            },
+            None if Instant::now() < r.next_attempt => return Ok(()),
+            None => match self.connection.reconnector() {
+                Some(reconnector) => {
+                    let (tx, rx) = mpsc::channel();
+                    thread::spawn(move || {
+                        #[allow(unused)]
+                        {
+                            tx.send(reconnector());
+                        }
+                    });
+                    r.pending = Some(rx);
+                    return Ok(());
+                }
+                // Nothing to reconnect to, e.g. a recording
+                None => Ok(None),
+            },
+        };
+        if let Err(e) = result.and_then(|connection| self.resume(connection)) {
+            let r = self.reconnecting.as_mut().unwrap();
+            r.pending = None;
+            r.attempt += 1;
+            if r.attempt >= MAX_RECONNECT_ATTEMPTS {
+                return Err(e);
+            }
+            r.next_attempt = Instant::now() + r.backoff();
+            r.reason = e.to_string();
+        }
+        Ok(())
     }
 
     // Call this every frame to keep states up to date
     pub fn update_self(&mut self) -> Result<(), DraduError> {
-        let new_messages = self.connection.new_messages()?;
+        if self.reconnecting.is_some() {
+            return self.keep_reconnecting();
+        }
+        let new_messages = match self.connection.new_messages() {
+            Ok(messages) => messages,
+            Err(e) if self.quitting || !self.connection.supports("resume") => return Err(e),
+            Err(e) => {
+                self.reconnecting = Some(Reconnecting::new(e));
+                return Ok(());
+            }
+        };
+        for error in self.connection.take_rejected() {
+            self.reject_message(error);
+        }
         for mut msg in new_messages {
+            self.record(&msg, true);
             match (msg.msg_type(), msg.take_body()) {
-                (MsgType::Map, Some(MsgBody::Json(json))) => {
-                    self.update_map(json)?;
+                // An invalid delta only costs us that one message, e.g. one
+                // from a newer server
+                (MsgType::Map, Some(MsgBody::Json(json)))
+                    if msg.get_prop("transient").is_some() =>
+                {
+                    match MapDelta::from_json(&json) {
+                        Ok(delta) => self.map.apply_moves(delta, Instant::now()),
+                        Err(e) => self.reject_message(e.into()),
+                    }
                 }
+                (MsgType::Map, Some(MsgBody::Json(json))) => match MapDelta::from_json(&json) {
+                    Ok(delta) => self.update_map(delta)?,
+                    Err(e) => self.reject_message(e.into()),
+                },
                 (MsgType::Player, Some(MsgBody::Json(json))) => {
-                    self.update_players(json)?;
+                    match PlayerDelta::from_json(&json) {
+                        Ok(delta) => self.update_players(delta)?,
+                        Err(e) => self.reject_message(e.into()),
+                    }
                 }
                 (MsgType::Msg, Some(MsgBody::Text(text))) => {
                     if let Some(user_id) = msg.get_prop("userId") {
@@ -98,26 +310,118 @@ impl<'a> RoomState {
                 }
                 (MsgType::File, body) => {
                     if self.master {
-                        if let Some(s) = msg.get_prop("path") {
-                            if let Ok(bytes) = self.fs.read_file(s) {
-                                let mut response = Message::new(MsgType::File)
-                                    .set_prop("path", s)
-                                    .set_prop("contentType", "image");
-                                response.attach_body(MsgBody::Bin(bytes));
-                                self.send_msg(response)?;
+                        // Requests we can't answer are just ignored
+                        if let Ok(request) = FileRequest::from_message(&msg) {
+                            if let Ok(bytes) = self.fs.read_file(&request.path) {
+                                self.uploads.push_back(Upload::new(&request, bytes));
                             }
                         }
-                    } else {
-                        if let Some(MsgBody::Image(image)) = body {
-                            self.add_image(msg.get_prop("path").unwrap_or(""), image)
-                        }
+                    } else if let Some(path) = msg.get_prop("path") {
+                        let chunk = match body {
+                            Some(MsgBody::Bin(bytes)) => bytes,
+                            _ => Vec::new(),
+                        };
+                        self.receive_chunk(path, &msg, chunk)?;
                     }
                 }
-                (MsgType::Synced, _) => {}
-                (MsgType::Err, _) => (),
+                (MsgType::Synced, _) => {
+                    if let Some(loading) = &mut self.loading {
+                        loading.synced = true;
+                    }
+                    // The map has no fog now, if it hasn't come back
+                    self.explored.clear();
+                }
+                (MsgType::Invite, Some(MsgBody::Json(json))) => {
+                    self.receive_invite(Invite::from_json(&json)?);
+                }
+                (MsgType::Err, body) => {
+                    let json = match body {
+                        Some(MsgBody::Json(json)) => json,
+                        _ => JsonValue::Null,
+                    };
+                    // A malformed ERR is still worth telling the user about,
+                    // but not worth leaving the room over
+                    let err = ErrResponse::from_json(&json).unwrap_or_else(|_| {
+                        ErrResponse::new(
+                            ErrorCode::Other("unknown".to_string()),
+                            "Server has reported an error",
+                        )
+                    });
+                    self.push_error(err);
+                }
                 _ => (),
             }
         }
+        if matches!(&self.loading, Some(l) if l.synced && self.downloads.is_empty()) {
+            self.loading = None;
+        }
+        self.map.update_motions(Instant::now());
+        self.answer_lan_probes();
+        self.send_chunks()
+    }
+
+    // Sends the next chunk of a few files that are being uploaded. The rest is
+    // sent on the next updates, so other messages don't have to wait for it.
+    // Nothing is sent while the connection can't keep up
+    fn send_chunks(&mut self) -> Result<(), DraduError> {
+        if self.connection.is_congested() {
+            return Ok(());
+        }
+        for _ in 0..CHUNKS_PER_UPDATE {
+            let mut upload = match self.uploads.pop_front() {
+                Some(upload) => upload,
+                None => break,
+            };
+            if let Some(msg) = upload.next_chunk() {
+                self.send_msg(msg)?;
+            }
+            if !upload.is_finished() {
+                self.uploads.push_back(upload);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_chunk(
+        &mut self,
+        path: &str,
+        msg: &Message,
+        chunk: Vec<u8>,
+    ) -> Result<(), DraduError> {
+        let download = match self.downloads.get_mut(path) {
+            Some(download) => download,
+            None => return Ok(()),
+        };
+        let result = match download.push_chunk(msg, chunk) {
+            Ok(None) => return Ok(()),
+            Ok(Some(bytes)) => {
+                let image = RetainedImage::from_image_bytes(path, &bytes)
+                    .map_err(DraduError::ImageLoadError);
+                if image.is_ok() {
+                    #[allow(unused)]
+                    {
+                        self.cache.insert(&bytes);
+                    }
+                }
+                image
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(image) => {
+                self.downloads.remove(path);
+                self.add_image(path, image);
+            }
+            Err(DraduError::TransferError(_)) if download.restart() => {
+                let request = download.request(path);
+                self.send_msg(request)?;
+            }
+            // Either the image itself is broken, or it just can't be
+            // downloaded. Placeholder is shown instead
+            Err(_) => {
+                self.downloads.remove(path);
+            }
+        }
         Ok(())
     }
 
@@ -129,152 +433,420 @@ impl<'a> RoomState {
         self.connection.get_room_address()
     }
 
+    // While reconnecting, messages are queued and sent later
     pub fn send_msg(&mut self, message: Message) -> Result<usize, DraduError> {
-        self.connection.send_msg(message)
+        if self.spectator && message.msg_type() == MsgType::Map {
+            return Err(DraduError::ReadOnly);
+        }
+        self.record(&message, false);
+        if self.reconnecting.is_some() && !self.quitting {
+            self.outbox.push(message);
+            return Ok(0);
+        }
+        match self.connection.send_msg(message) {
+            Err(e) if !self.quitting && self.connection.supports("resume") => {
+                self.reconnecting = Some(Reconnecting::new(e));
+                Ok(0)
+            }
+            result => result,
+        }
     }
 
-    pub fn request_file(&mut self, path: &str) -> Result<(), DraduError> {
+    // Records the rest of the session into a file. See net::Recorder
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), DraduError> {
+        self.recorder = Some(Recorder::create(path, &*self.connection, self.master)?);
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Recording stops after the first error, the session itself goes on
+    fn record(&mut self, msg: &Message, inbound: bool) {
+        if let Some(recorder) = &mut self.recorder {
+            if recorder.record(msg, inbound).is_err() {
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Only recorded sessions have playback controls
+    pub fn playback(&mut self) -> Option<&mut Playback> {
+        self.connection.playback()
+    }
+
+    // Going back in time means replaying the recording from the start, so the
+    // map, players and chat are reset first
+    pub fn seek(&mut self, position: Duration) {
+        let playback = match self.connection.playback() {
+            Some(playback) => playback,
+            None => return,
+        };
+        let rewind = position < playback.position();
+        playback.seek(position);
+        if rewind {
+            self.map = MapState::default();
+            self.chat_log.clear();
+            self.downloads.clear();
+            self.reset_players();
+        }
+    }
+
+    // Only we are left in the list
+    fn reset_players(&mut self) {
+        self.players.clear();
+        self.latencies.clear();
+        self.spectators.clear();
+        self.players.insert(
+            self.connection.get_user_id().to_string(),
+            (
+                self.connection.get_nickname().to_string(),
+                self.connection.get_user_color(),
+            ),
+        );
+    }
+
+    // Some(reason) if the connection was lost and we're trying to get it back
+    pub fn reconnecting(&self) -> Option<&Reconnecting> {
+        self.reconnecting.as_ref()
+    }
+
+    // Images which are in the cache aren't requested from the master
+    pub fn request_file(&mut self, path: &str, hash: Option<&str>) -> Result<(), DraduError> {
+        match &mut self.loading {
+            Some(loading) if !self.downloads.contains_key(path) => loading.assets += 1,
+            _ => (),
+        }
         if self.master {
             if !self.images.contains_key(path) {
                 let image = self.fs.get_retained_image(path)?;
                 self.add_image(path, image);
             }
-        } else {
-            let msg = Message::new(MsgType::File).set_prop("path", path);
+        } else if !self.downloads.contains_key(path) {
+            if let Some(bytes) = hash.and_then(|hash| self.cache.get(hash)) {
+                if let Ok(image) = RetainedImage::from_image_bytes(path, &bytes) {
+                    self.add_image(path, image);
+                    return Ok(());
+                }
+            }
+            let mut download = Download::new();
+            let msg = download.request(path);
+            self.downloads.insert(path.to_string(), download);
             self.send_msg(msg)?;
         }
         Ok(())
     }
 
     pub fn quit_room(&mut self) {
-        self.send_msg(Message::new(MsgType::Quit));
+        if self.reconnecting.is_none() {
+            #[allow(unused)]
+            {
+                self.send_msg(Message::new(MsgType::Quit));
+            }
+        }
+        self.quitting = true;
         self.connection.close()
     }
 
-    pub fn send_chat_message(&mut self, text: &str) {
+    // Spectators can only use the commands which change their nickname or color
+    pub fn send_chat_message(&mut self, text: &str) -> Result<(), DraduError> {
+        let command = text.split_whitespace().next();
+        if self.spectator && !matches!(command, Some("/nick" | "/nickname" | "/color")) {
+            return Ok(());
+        }
         let mut msg = Message::new(MsgType::Msg);
         msg.attach_body(MsgBody::Text(text.to_owned()));
-        self.send_msg(msg);
+        self.send_msg(msg)?;
+        Ok(())
     }
 
     pub fn insert_from_path<P: AsRef<Path>>(
         &mut self,
         path: P,
-        obj_type: &str,
+        obj_type: ObjectKind,
     ) -> Result<(), DraduError> {
         let path = path.as_ref();
         let path_str = path.to_str().unwrap();
-        if !self.images.contains_key(path_str) {
-            let image = self.fs.get_retained_image(path)?;
-            self.add_image(path_str, image);
-        }
-        let mut msg = Message::new(MsgType::Map);
-        let inner_json = object! {
-            "type": obj_type,
-            "path": path_str
+        let hash = self.load_asset(path_str)?;
+        let patch = ObjectPatch {
+            kind: Some(obj_type),
+            path: Some(path_str.to_string()),
+            hash: Some(hash),
+            ..ObjectPatch::default()
         };
-        let mut json = JsonValue::new_object();
-        json[utils::random_id()] = inner_json;
-
-        msg.attach_body(MsgBody::Json(json));
-        self.send_msg(msg);
-        Ok(())
+        self.send_map_delta(MapDelta::single(
+            &utils::random_id(),
+            MapEntry::Object(patch),
+        ))
     }
 
     pub fn set_background_image<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DraduError> {
         let path = path.as_ref();
         let path_str = path.to_str().unwrap();
-        if !self.images.contains_key(path_str) {
-            let image = self.fs.get_retained_image(path)?;
-            self.add_image(path_str, image);
+        let hash = self.load_asset(path_str)?;
+        let entry = MapEntry::Background(path_str.to_string(), Some(hash));
+        self.send_map_delta(MapDelta::single("background", entry))
+    }
+
+    // Loads an image from the asset directory, unless it's loaded already.
+    // Returns its hash, which is sent along with the path
+    fn load_asset(&mut self, path: &str) -> Result<String, DraduError> {
+        let bytes = self.fs.read_file(path)?;
+        if !self.images.contains_key(path) {
+            let image = RetainedImage::from_image_bytes(path, &bytes)
+                .map_err(DraduError::ImageLoadError)?;
+            self.add_image(path, image);
         }
+        Ok(transfer::checksum(&bytes))
+    }
+
+    pub fn send_map_delta(&mut self, delta: MapDelta) -> Result<(), DraduError> {
         let mut msg = Message::new(MsgType::Map);
-        let json = json::object! {
-            "background": {
-                "path": path_str,
-            }
-        };
-        msg.attach_body(MsgBody::Json(json));
-        self.send_msg(msg);
+        msg.attach_body(MsgBody::Json(delta.to_json()));
+        self.send_msg(msg)?;
         Ok(())
     }
 
-    pub fn move_map_object(&mut self, id: &str, pos: Pos2) {
+    // Sends the patch if there is such an object on the map
+    fn patch_map_object(&mut self, id: &str, patch: ObjectPatch) -> Result<(), DraduError> {
         if self.map.objects.contains_key(id) {
-            let mut msg = Message::new(MsgType::Map);
-            let inner_json = object! {"pos": [pos.x, pos.y]};
-            let mut json = JsonValue::new_object();
-            json[id] = inner_json;
-            msg.attach_body(MsgBody::Json(json));
+            self.send_map_delta(MapDelta::single(id, MapEntry::Object(patch)))?;
+        }
+        Ok(())
+    }
+
+    pub fn move_map_object(&mut self, id: &str, pos: Pos2) -> Result<(), DraduError> {
+        let patch = ObjectPatch {
+            pos: Some(map::from_pos(pos)),
+            ..ObjectPatch::default()
+        };
+        self.patch_map_object(id, patch)
+    }
+
+    // Where the object is while it's being dragged. These messages are sent
+    // often, so they are dropped if the connection can't keep up. Servers
+    // without "transient" would take them for real moves
+    pub fn stream_map_object_pos(&mut self, id: &str, pos: Pos2) {
+        let congested = self.reconnecting.is_some() || self.connection.is_congested();
+        if congested || !self.supports("transient") || !self.map.objects.contains_key(id) {
+            return;
+        }
+        let patch = ObjectPatch {
+            pos: Some(map::from_pos(pos)),
+            ..ObjectPatch::default()
+        };
+        let mut msg = Message::new(MsgType::Map).set_prop("transient", "true");
+        msg.attach_body(MsgBody::Json(
+            MapDelta::single(id, MapEntry::Object(patch)).to_json(),
+        ));
+        #[allow(unused)]
+        {
             self.send_msg(msg);
         }
     }
 
-    pub fn delete_map_object(&mut self, id: &str) {
+    pub fn delete_map_object(&mut self, id: &str) -> Result<(), DraduError> {
         if self.map.objects.contains_key(id) {
-            let mut msg = Message::new(MsgType::Map);
-            let mut json = JsonValue::new_object();
-            json[id] = object! {};
-            msg.attach_body(MsgBody::Json(json));
-            self.send_msg(msg);
+            self.send_map_delta(MapDelta::single(id, MapEntry::Remove))?;
         }
+        Ok(())
     }
 
-    pub fn clear_map(&mut self) {
-        let mut msg = Message::new(MsgType::Map);
-        msg.attach_body(MsgBody::Json(JsonValue::Null));
-        self.send_msg(msg);
+    pub fn clear_map(&mut self) -> Result<(), DraduError> {
+        self.send_map_delta(MapDelta::Reset)
     }
 
-    pub fn rescale_map_object(&mut self, id: &str, scale: f32) {
-        if self.map.objects.contains_key(id) {
-            let mut msg = Message::new(MsgType::Map);
-            let inner_json = object! {"scale": scale};
-            let mut json = JsonValue::new_object();
-            json[id] = inner_json;
-            msg.attach_body(MsgBody::Json(json));
-            self.send_msg(msg);
+    pub fn rescale_map_object(&mut self, id: &str, scale: f32) -> Result<(), DraduError> {
+        let patch = ObjectPatch {
+            scale: Some(map::widen(scale)),
+            ..ObjectPatch::default()
+        };
+        self.patch_map_object(id, patch)
+    }
+
+    // Degrees clockwise, kept between 0 and 360
+    pub fn rotate_map_object(&mut self, id: &str, rotation: f32) -> Result<(), DraduError> {
+        let patch = ObjectPatch {
+            rotation: Some(map::widen(rotation.rem_euclid(360.0))),
+            ..ObjectPatch::default()
+        };
+        self.patch_map_object(id, patch)
+    }
+
+    pub fn flip_map_object(
+        &mut self,
+        id: &str,
+        [flip_x, flip_y]: [bool; 2],
+    ) -> Result<(), DraduError> {
+        let patch = ObjectPatch {
+            flip_x: Some(flip_x),
+            flip_y: Some(flip_y),
+            ..ObjectPatch::default()
+        };
+        self.patch_map_object(id, patch)
+    }
+
+    pub fn restack_map_object(&mut self, id: &str, step: Restack) -> Result<(), DraduError> {
+        let changes: Vec<_> = self
+            .map
+            .restack(id, step)
+            .into_iter()
+            .map(|(id, z_index)| {
+                let patch = ObjectPatch {
+                    z_index: Some(z_index),
+                    ..ObjectPatch::default()
+                };
+                (id, MapEntry::Object(patch))
+            })
+            .collect();
+        if !changes.is_empty() {
+            self.send_map_delta(MapDelta::Changes(changes))?;
         }
+        Ok(())
     }
 
-    pub fn update_token_property(&mut self, id: &str, key: &str, val: &str) {
+    // The object goes on top of everything on the layer
+    pub fn move_to_layer(&mut self, id: &str, layer: Layer) -> Result<(), DraduError> {
+        let patch = ObjectPatch {
+            layer: Some(layer),
+            z_index: Some(self.map.top_of(layer)),
+            ..ObjectPatch::default()
+        };
+        self.patch_map_object(id, patch)
+    }
+
+    // New wall going through the points, which are in map coordinates
+    pub fn add_wall(&mut self, points: &[Pos2]) -> Result<(), DraduError> {
+        if points.len() >= 2 {
+            let patch = MapObject::Wall(Wall::through(points)).as_patch();
+            self.send_map_delta(MapDelta::single(
+                &utils::random_id(),
+                MapEntry::Object(patch),
+            ))?;
+        }
+        Ok(())
+    }
+
+    // Replaces all nodes of the wall
+    pub fn change_wall(&mut self, id: &str, points: &[Pos2]) -> Result<(), DraduError> {
+        if let Some(MapObject::Wall(_)) = self.map.objects.get(id) {
+            if points.len() >= 2 {
+                let wall = Wall::through(points);
+                let patch = ObjectPatch {
+                    pos: Some(map::from_pos(wall.pos)),
+                    ..wall.nodes_patch()
+                };
+                self.patch_map_object(id, patch)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_effect(&mut self, effect: Effect) -> Result<(), DraduError> {
+        let patch = MapObject::Effect(effect).as_patch();
+        self.send_map_delta(MapDelta::single(
+            &utils::random_id(),
+            MapEntry::Object(patch),
+        ))
+    }
+
+    // Changes everything but the position, which is moved like other objects
+    pub fn change_effect(&mut self, id: &str, effect: &Effect) -> Result<(), DraduError> {
+        if let Some(MapObject::Effect(_)) = self.map.objects.get(id) {
+            self.patch_map_object(id, effect.template_patch())?;
+        }
+        Ok(())
+    }
+
+    // None turns the fog of war off
+    pub fn set_fog(&mut self, fog: Option<model::Fog>) -> Result<(), DraduError> {
+        self.send_map_delta(MapDelta::single("fog", MapEntry::Fog(fog)))
+    }
+
+    // Reveals (true) or hides (false) the cells, whatever the tokens see
+    pub fn paint_fog(&mut self, cells: &HashMap<Cell, bool>) -> Result<(), DraduError> {
+        if let Some(fog) = &self.map.fog {
+            let entry = fog.painted_over(cells);
+            self.set_fog(Some(entry))?;
+        }
+        Ok(())
+    }
+
+    // Updates what can be seen through the fog of war. The eyes are the
+    // centers of the tokens whose "owner" is the viewer's user ID, or of all
+    // tokens with an owner if there is no viewer. `bounds` is the part of the
+    // map they can see, in map coordinates. Only players remember what they've
+    // seen
+    pub fn look_through_fog(&mut self, viewer: Option<&str>, bounds: Rect) {
+        if self.map.fog.is_none() {
+            return;
+        }
+        let eyes = self.eyes(viewer);
+        let walls = self.map.wall_segments();
+        let explore = !self.master;
+        if let Some(fog) = &mut self.map.fog {
+            fog.look(eyes, walls, bounds, explore);
+        }
+    }
+
+    fn eyes(&self, viewer: Option<&str>) -> Vec<Pos2> {
+        let mut eyes = Vec::new();
+        for obj in self.map.objects.values() {
+            match obj {
+                MapObject::Token(token) if token.is_owned_by(viewer) => {
+                    eyes.push(self.token_center(token))
+                }
+                _ => (),
+            }
+        }
+        eyes
+    }
+
+    pub fn token_center(&self, token: &Token) -> Pos2 {
+        let size = self.get_image(&token.path).size_vec2() * token.scale;
+        token.pos + size / 2.0
+    }
+
+    pub fn update_token_property(
+        &mut self,
+        id: &str,
+        key: &str,
+        val: &str,
+    ) -> Result<(), DraduError> {
         self.change_token_property(id, key, JsonValue::from(val.trim()))
     }
 
-    pub fn remove_token_property(&mut self, id: &str, key: &str) {
-        self.change_token_property(id, key, JsonValue::Null);
+    pub fn remove_token_property(&mut self, id: &str, key: &str) -> Result<(), DraduError> {
+        self.change_token_property(id, key, JsonValue::Null)
     }
 
-    fn change_token_property(&mut self, id: &str, key: &str, val: JsonValue) {
+    fn change_token_property(
+        &mut self,
+        id: &str,
+        key: &str,
+        val: JsonValue,
+    ) -> Result<(), DraduError> {
         if let Some(MapObject::Token(_)) = self.map.objects.get(id) {
             let key = key.trim();
             if !key.is_empty() {
-                let mut msg = Message::new(MsgType::Map);
-                let mut inner_json = object! {"properties": {}};
-                inner_json["properties"][key] = val;
-                let mut json = JsonValue::new_object();
-                json[id] = inner_json;
-                msg.attach_body(MsgBody::Json(json));
-                self.send_msg(msg);
+                let patch = ObjectPatch {
+                    properties: vec![(key.to_string(), val)],
+                    ..ObjectPatch::default()
+                };
+                self.patch_map_object(id, patch)?;
             }
         }
+        Ok(())
     }
 
-    pub fn change_grid_size(&mut self, size: [u8; 2]) {
-        let mut msg = Message::new(MsgType::Map);
-        let json = if size[0] >= 2 && size[1] >= 2 {
-            object! {
-                "grid": {
-                    "size": [size[0], size[1]],
-                }
-            }
+    pub fn change_grid_size(&mut self, size: [u8; 2]) -> Result<(), DraduError> {
+        let size = if size[0] >= 2 && size[1] >= 2 {
+            Some(size)
         } else {
-            object! {
-                "grid": {}
-            }
+            None
         };
-        msg.attach_body(MsgBody::Json(json));
-        self.send_msg(msg);
+        self.send_map_delta(MapDelta::single("grid", MapEntry::Grid(size)))
     }
 
     pub fn chat_log_ref(&self) -> &Vec<ChatMessage> {
@@ -289,91 +861,269 @@ impl<'a> RoomState {
         self.connection.get_user_id()
     }
 
+    // Whether both we and the server support an optional protocol feature
+    pub fn supports(&self, capability: &str) -> bool {
+        self.connection.supports(capability)
+    }
+
+    pub fn get_server_version(&self) -> &str {
+        self.connection.get_server_version()
+    }
+
+    pub fn get_capabilities(&self) -> &[String] {
+        self.connection.get_capabilities()
+    }
+
     pub fn is_master(&self) -> bool {
         self.master
     }
 
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    // Whether the player with this ID only watches
+    pub fn is_spectator_id(&self, id: &str) -> bool {
+        if id == self.connection.get_user_id() {
+            self.spectator
+        } else {
+            self.spectators.contains(id)
+        }
+    }
+
     pub fn get_player_by_id(&self, id: &str) -> Option<&(String, Color32)> {
         self.players.get(id)
     }
 
-    fn update_map(&mut self, json: JsonValue) -> Result<(), DraduError> {
-        // Reset entire map
-        if json.is_null() {
-            self.map = MapState::default();
+    // Player's round trip time to the server. Ours is measured locally, so it's
+    // always fresher than the one the server tells us
+    pub fn get_latency(&self, id: &str) -> Option<Duration> {
+        if id == self.connection.get_user_id() {
+            if let Some(latency) = self.connection.latency() {
+                return Some(latency);
+            }
+        }
+        self.latencies.get(id).copied()
+    }
+
+    // Messages we couldn't make sense of are skipped, the user is just told
+    // about them
+    fn reject_message(&mut self, error: DraduError) {
+        let message = format!("Skipped a message from the server: {}", error);
+        self.push_error(ErrResponse::new(ErrorCode::BadRequest, &message));
+    }
+
+    // Our own errors which don't end the session are shown along with the ones
+    // from the server
+    pub fn show_error(&mut self, message: &str) {
+        let code = ErrorCode::Other("client".to_string());
+        self.push_error(ErrResponse::new(code, message));
+    }
+
+    fn push_error(&mut self, error: ErrResponse) {
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error);
+    }
+
+    pub fn errors(&self) -> &VecDeque<ErrResponse> {
+        &self.errors
+    }
+
+    pub fn dismiss_error(&mut self, index: usize) {
+        self.errors.remove(index);
+    }
+
+    // Asks the server for a single-use invite link. Only the master can do
+    // that. See RoomState::invite
+    pub fn create_invite(&mut self, expires_in: Duration) -> Result<(), DraduError> {
+        let invite = Invite {
+            token: None,
+            expires_in: expires_in.as_secs(),
+        };
+        let mut msg = Message::new(MsgType::Invite);
+        msg.attach_body(MsgBody::Json(invite.to_json()));
+        self.send_msg(msg)?;
+        Ok(())
+    }
+
+    fn receive_invite(&mut self, invite: Invite) {
+        let room = self
+            .connection
+            .get_room_address()
+            .map(|a| a.parse::<RoomAddr>());
+        if let (Some(token), Ok(Ok(room))) = (invite.token, room) {
+            let link = RoomAddr {
+                invite: Some(token),
+                ..room
+            };
+            let expires = Instant::now() + Duration::from_secs(invite.expires_in);
+            self.invite = Some((link.to_string(), expires));
+        }
+    }
+
+    // Only the master of a networked room can invite players
+    pub fn can_invite(&self) -> bool {
+        let room = self
+            .connection
+            .get_room_address()
+            .map(|a| a.parse::<RoomAddr>());
+        self.master && matches!(room, Ok(Ok(_)))
+    }
+
+    // Link and when it stops working
+    pub fn invite(&self) -> Option<(&str, Instant)> {
+        self.invite
+            .as_ref()
+            .map(|(link, expires)| (link.as_str(), *expires))
+    }
+
+    // Lets players on the LAN find the room. Only the master of a networked
+    // room can do that, and only if no server on this machine has taken the
+    // discovery port. Servers announce their own rooms anyway
+    pub fn announce_on_lan(&mut self) {
+        if !self.master {
+            return;
+        }
+        // Loopback rooms have no address to announce
+        if let Ok(Ok(room)) = self.connection.get_room_address().map(|a| a.parse()) {
+            self.lan_host = LanHost::bind(DISCOVERY_PORT, room, self.has_password).ok();
         }
-        for (id, entry) in json.entries() {
-            // Firstly checking for special-case scenarios
-            if id == "grid" {
-                self.update_grid(entry)?;
-            } else if id == "background" {
-                self.update_background(entry)?;
-            } else if entry.is_empty() {
-                self.map.objects.remove(id);
-            } else {
-                // Otherwise just normally updating all the objects
-                if let Some(obj) = self.map.objects.get_mut(id) {
-                    obj.update_from_json(&entry)?;
-                } else {
-                    let obj = MapObject::create_from_json(&entry)?;
-                    if !self.images.contains_key(obj.path()) {
-                        self.request_file(obj.path())?;
+    }
+
+    fn answer_lan_probes(&self) {
+        let host = match &self.lan_host {
+            Some(host) => host,
+            None => return,
+        };
+        let gm = match self.players.get(self.connection.get_user_id()) {
+            Some((nickname, _)) => nickname,
+            None => return,
+        };
+        let players = self
+            .players
+            .keys()
+            .filter(|id| !self.is_spectator_id(id))
+            .count();
+        host.poll(gm, players as u32);
+    }
+
+    // In bytes
+    pub fn set_cache_limit(&mut self, limit: u64) {
+        self.cache.set_limit(limit);
+    }
+
+    // See Connection::set_timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.connection.set_timeout(timeout);
+    }
+
+    fn update_map(&mut self, delta: MapDelta) -> Result<(), DraduError> {
+        let changes = match delta {
+            MapDelta::Reset => {
+                self.map = MapState::default();
+                return Ok(());
+            }
+            MapDelta::Changes(changes) => changes,
+        };
+        for (id, entry) in changes {
+            match entry {
+                MapEntry::Grid(size) => self.map.grid = size,
+                MapEntry::Background(path, hash) => self.update_background(path, hash)?,
+                MapEntry::Fog(Some(fog)) => {
+                    let explored = &mut self.explored;
+                    self.map
+                        .fog
+                        .get_or_insert_with(|| Fog::with_explored(std::mem::take(explored)))
+                        .update(&fog)
+                }
+                MapEntry::Fog(None) => self.map.fog = None,
+                MapEntry::Remove => {
+                    self.map.objects.remove(&id);
+                }
+                MapEntry::Object(patch) => {
+                    if let Some(obj) = self.map.objects.get_mut(&id) {
+                        obj.update_from_patch(&patch);
+                        if patch.pos.is_some() {
+                            self.map.finish_motion(&id, Instant::now());
+                        }
+                        // Image may be needed now that the object has left the
+                        // GM layer
+                        if patch.path.is_none() && patch.layer.is_none() {
+                            continue;
+                        }
+                    } else {
+                        let obj = MapObject::create_from_patch(&id, &patch)?;
+                        self.map.objects.insert(id.clone(), obj);
                     }
-                    self.map.objects.insert(id.to_string(), obj);
+                    self.request_image(&id)?;
                 }
             }
         }
         Ok(())
     }
 
-    fn update_grid(&mut self, json: &JsonValue) -> Result<(), DraduError> {
-        if json.is_empty() {
-            self.map.grid = None;
-        } else {
-            let size = &json["size"];
-            let columns = size[0].as_u8().ok_or(DraduError::ProtocolError)?;
-            let rows = size[1].as_u8().ok_or(DraduError::ProtocolError)?;
-            self.map.grid = Some([columns, rows]);
+    // Walls usually have no image, and players don't get to see the ones on
+    // the GM layer
+    fn request_image(&mut self, id: &str) -> Result<(), DraduError> {
+        let obj = match self.map.objects.get(id) {
+            Some(obj) => obj,
+            None => return Ok(()),
+        };
+        let hidden = obj.order().0 == Layer::Gm && !self.master;
+        if hidden || obj.path().is_empty() || self.images.contains_key(obj.path()) {
+            return Ok(());
         }
-        Ok(())
+        let (path, hash) = (obj.path().to_string(), obj.hash().map(str::to_string));
+        self.request_file(&path, hash.as_deref())
     }
 
-    fn update_background(&mut self, json: &JsonValue) -> Result<(), DraduError> {
-        let path = json["path"].as_str().ok_or(DraduError::ProtocolError)?;
-        self.map.background_image = Some(String::from(path));
-        if !self.images.contains_key(path) {
-            self.request_file(path)?;
+    fn update_background(&mut self, path: String, hash: Option<String>) -> Result<(), DraduError> {
+        if !self.images.contains_key(&path) {
+            self.request_file(&path, hash.as_deref())?;
         }
+        self.map.background_image = Some(path);
+        self.map.background_hash = hash;
         Ok(())
     }
 
-    fn update_players(&mut self, json: JsonValue) -> Result<(), DraduError> {
-        for (k, v) in json.entries() {
-            if v.is_empty() {
-                self.players.remove(k);
-            } else {
-                match self.players.get_mut(k) {
-                    Some(player) => {
-                        if let Some(s) = v["nickname"].as_str() {
-                            player.0 = s.to_string();
-                        }
-                        if v.has_key("color") {
-                            player.1 = utils::color32_from_json_value(&v["color"])?;
-                        }
+    fn update_players(&mut self, delta: PlayerDelta) -> Result<(), DraduError> {
+        for (id, patch) in delta.0 {
+            let patch = match patch {
+                Some(patch) => patch,
+                None => {
+                    self.players.remove(&id);
+                    self.latencies.remove(&id);
+                    self.spectators.remove(&id);
+                    continue;
+                }
+            };
+            if patch.spectator {
+                self.spectators.insert(id.clone());
+            }
+            if let Some(ms) = patch.latency {
+                self.latencies
+                    .insert(id.clone(), Duration::from_millis(ms.into()));
+            }
+            let color = patch.color.map(|[r, g, b]| Color32::from_rgb(r, g, b));
+            match self.players.get_mut(&id) {
+                Some(player) => {
+                    if let Some(nickname) = patch.nickname {
+                        player.0 = nickname;
                     }
-                    _ => {
-                        self.players.insert(
-                            k.to_string(),
-                            (
-                                v["nickname"]
-                                    .as_str()
-                                    .ok_or(DraduError::ProtocolError)?
-                                    .to_string(),
-                                utils::color32_from_json_value(&v["color"])?,
-                            ),
-                        );
+                    if let Some(color) = color {
+                        player.1 = color;
                     }
                 }
+                None => {
+                    let missing = |key: &str| {
+                        DraduError::InvalidField(format!("{}.{}", id, key), "is missing".into())
+                    };
+                    let nickname = patch.nickname.ok_or_else(|| missing("nickname"))?;
+                    let color = color.ok_or_else(|| missing("color"))?;
+                    self.players.insert(id, (nickname, color));
+                }
             }
         }
         Ok(())
@@ -393,6 +1143,36 @@ impl<'a> RoomState {
         &self.map
     }
 
+    // Some while the initial state of the room is being received
+    pub fn loading(&self) -> Option<&Loading> {
+        self.loading.as_ref()
+    }
+
+    // Images referenced by the initial state which have been fetched already
+    // (Or have failed to)
+    pub fn loaded_assets(&self) -> usize {
+        match &self.loading {
+            Some(loading) => loading.assets.saturating_sub(self.downloads.len()),
+            None => 0,
+        }
+    }
+
+    // Images which are still being downloaded will pop in later
+    pub fn skip_loading(&mut self) {
+        self.loading = None;
+    }
+
+    // Some(0.0..=1.0) while the image is being downloaded
+    pub fn download_progress(&self, path: &str) -> Option<f32> {
+        self.downloads.get(path).map(|d| d.progress())
+    }
+
+    // While this is true, .update_self() has to be called even if nothing
+    // else happens
+    pub fn is_uploading(&self) -> bool {
+        !self.uploads.is_empty() || self.connection.is_congested()
+    }
+
     // Will return a placeholder image if key doesn't exist
     pub fn get_image(&self, key: &str) -> &RetainedImage {
         match self.images.get(key) {
@@ -402,6 +1182,46 @@ impl<'a> RoomState {
     }
 }
 
+// Each chunk is transfer::CHUNK_SIZE bytes
+const CHUNKS_PER_UPDATE: usize = 4;
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Everything the server sends before SYNCED is the initial state of the room.
+// It's loaded once SYNCED has come and all the images it references are here
+#[derive(Default)]
+pub struct Loading {
+    pub synced: bool,
+    // How many images the initial state references
+    pub assets: usize,
+}
+
+pub struct Reconnecting {
+    pub attempt: u32,
+    pub next_attempt: Instant,
+    // Why the last attempt (Or the connection itself) has failed
+    pub reason: String,
+    // Result of the attempt which is being made
+    pending: Option<Receiver<Result<Box<dyn Connection + Send>, DraduError>>>,
+}
+
+impl Reconnecting {
+    fn new(reason: DraduError) -> Self {
+        Self {
+            attempt: 0,
+            // First attempt is made right away
+            next_attempt: Instant::now(),
+            reason: reason.to_string(),
+            pending: None,
+        }
+    }
+
+    // 1s, 2s, 4s, ... up to MAX_BACKOFF
+    fn backoff(&self) -> Duration {
+        Duration::from_secs(1 << self.attempt.min(5)).min(MAX_BACKOFF)
+    }
+}
+
 pub struct ChatMessage {
     pub sender_id: String,
     pub text: String,
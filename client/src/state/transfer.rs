@@ -0,0 +1,274 @@
+use sha2::{Digest, Sha256};
+
+use crate::net::model::{FileChunk, FileRequest};
+use crate::net::{Message, MsgBody};
+use crate::utils;
+use crate::DraduError;
+
+// Files are sent in pieces of this size, so a big one doesn't block all the
+// other messages until it's done
+pub const CHUNK_SIZE: usize = 256 * 1024;
+// How many times a broken download is started over before we give up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+// Hex-encoded SHA-256 of the whole file
+pub fn checksum(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// File which is being received chunk by chunk
+pub struct Download {
+    // Chunks which were sent in response to other (older) requests are ignored
+    request_id: String,
+    total_size: usize,
+    checksum: String,
+    bytes: Vec<u8>,
+    attempts: u32,
+}
+
+impl Download {
+    pub fn new() -> Self {
+        Self {
+            request_id: String::new(),
+            total_size: 0,
+            checksum: String::new(),
+            bytes: Vec::new(),
+            attempts: 1,
+        }
+    }
+
+    // Asks for the part of the file we don't have yet. If the file has changed
+    // since then, the sender will start from the beginning
+    pub fn request(&mut self, path: &str) -> Message {
+        self.request_id = utils::random_id();
+        let resume_from = if self.bytes.is_empty() {
+            None
+        } else {
+            Some((self.bytes.len(), self.checksum.clone()))
+        };
+        FileRequest {
+            path: path.to_string(),
+            request_id: Some(self.request_id.clone()),
+            resume_from,
+        }
+        .to_message()
+    }
+
+    // Throws away everything received so far. Returns false if we've already
+    // tried too many times
+    pub fn restart(&mut self) -> bool {
+        self.bytes.clear();
+        self.total_size = 0;
+        self.checksum.clear();
+        self.attempts += 1;
+        self.attempts <= MAX_ATTEMPTS
+    }
+
+    // From 0.0 to 1.0
+    pub fn progress(&self) -> f32 {
+        if self.total_size == 0 {
+            0.0
+        } else {
+            self.bytes.len() as f32 / self.total_size as f32
+        }
+    }
+
+    // Returns the whole file once the last chunk has arrived and the checksum
+    // matches. Err means that the download has to be restarted
+    pub fn push_chunk(
+        &mut self,
+        msg: &Message,
+        chunk: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, DraduError> {
+        if msg.get_prop("requestId") != Some(&self.request_id) {
+            return Ok(None);
+        }
+        let info = FileChunk::from_message(msg)?;
+
+        if info.offset == 0 {
+            self.bytes.clear();
+            self.total_size = info.total_size;
+            self.checksum = info.checksum;
+        } else if info.offset != self.bytes.len()
+            || info.total_size != self.total_size
+            || info.checksum != self.checksum
+        {
+            return Err(DraduError::TransferError(format!(
+                "unexpected chunk at offset {}",
+                info.offset
+            )));
+        }
+        self.bytes.extend_from_slice(&chunk);
+
+        if self.bytes.len() > self.total_size {
+            Err(DraduError::TransferError(
+                "file is bigger than expected".to_string(),
+            ))
+        } else if self.bytes.len() < self.total_size {
+            Ok(None)
+        } else if self::checksum(&self.bytes) != self.checksum {
+            Err(DraduError::TransferError("checksum mismatch".to_string()))
+        } else {
+            Ok(Some(std::mem::take(&mut self.bytes)))
+        }
+    }
+}
+
+// File which is being sent in response to a FILE request
+pub struct Upload {
+    path: String,
+    request_id: Option<String>,
+    bytes: Vec<u8>,
+    checksum: String,
+    offset: usize,
+    finished: bool,
+}
+
+impl Upload {
+    // If the request continues an earlier transfer of the same file, picks up
+    // where that one has stopped
+    pub fn new(request: &FileRequest, bytes: Vec<u8>) -> Self {
+        let checksum = checksum(&bytes);
+        let offset = match &request.resume_from {
+            Some((offset, old_checksum)) if *old_checksum == checksum => *offset,
+            _ => 0,
+        };
+        Self {
+            path: request.path.clone(),
+            request_id: request.request_id.clone(),
+            offset: offset.min(bytes.len()),
+            bytes,
+            checksum,
+            finished: false,
+        }
+    }
+
+    pub fn next_chunk(&mut self) -> Option<Message> {
+        if self.finished {
+            return None;
+        }
+        let end = (self.offset + CHUNK_SIZE).min(self.bytes.len());
+        let mut msg = FileChunk {
+            path: self.path.clone(),
+            request_id: self.request_id.clone(),
+            chunk: self.offset / CHUNK_SIZE,
+            offset: self.offset,
+            total_size: self.bytes.len(),
+            checksum: self.checksum.clone(),
+        }
+        .to_message();
+        msg.attach_body(MsgBody::Bin(self.bytes[self.offset..end].to_vec()));
+        self.offset = end;
+        self.finished = end == self.bytes.len();
+        Some(msg)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Download, Upload, CHUNK_SIZE};
+    use crate::net::model::FileRequest;
+    use crate::net::{Message, MsgBody};
+
+    fn new_upload(request: &Message, bytes: Vec<u8>) -> Upload {
+        Upload::new(&FileRequest::from_message(request).unwrap(), bytes)
+    }
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Feeds chunks into the download until it's done or `limit` chunks were sent
+    fn transfer(download: &mut Download, upload: &mut Upload, limit: usize) -> Option<Vec<u8>> {
+        for _ in 0..limit {
+            let mut msg = upload.next_chunk()?;
+            let chunk = match msg.take_body() {
+                Some(MsgBody::Bin(bytes)) => bytes,
+                _ => panic!("Expected a binary body"),
+            };
+            if let Some(bytes) = download.push_chunk(&msg, chunk).unwrap() {
+                return Some(bytes);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn chunked_transfer() {
+        let bytes = file(CHUNK_SIZE * 2 + 100);
+        let mut download = Download::new();
+        let request = download.request("map.png");
+        let mut upload = new_upload(&request, bytes.clone());
+
+        assert_eq!(transfer(&mut download, &mut upload, 1), None);
+        assert!(download.progress() > 0.45 && download.progress() < 0.55);
+        assert_eq!(transfer(&mut download, &mut upload, 10), Some(bytes));
+        assert!(upload.is_finished());
+        assert!(upload.next_chunk().is_none());
+    }
+
+    #[test]
+    fn resumed_transfer() {
+        let bytes = file(CHUNK_SIZE * 3);
+        let mut download = Download::new();
+        let request = download.request("map.png");
+        let mut upload = new_upload(&request, bytes.clone());
+        transfer(&mut download, &mut upload, 2);
+
+        // Connection is lost, the rest of the old transfer is ignored
+        let request = download.request("map.png");
+        assert_eq!(
+            request.get_prop("offset"),
+            Some(&*(CHUNK_SIZE * 2).to_string())
+        );
+        assert_eq!(transfer(&mut download, &mut upload, 10), None);
+
+        let mut upload = new_upload(&request, bytes.clone());
+        assert_eq!(transfer(&mut download, &mut upload, 1), Some(bytes));
+    }
+
+    #[test]
+    fn file_changed_while_resuming() {
+        let mut download = Download::new();
+        let request = download.request("map.png");
+        let mut upload = new_upload(&request, file(CHUNK_SIZE * 2));
+        transfer(&mut download, &mut upload, 1);
+
+        let request = download.request("map.png");
+        let new_file = vec![7; CHUNK_SIZE + 1];
+        let mut upload = new_upload(&request, new_file.clone());
+        assert_eq!(transfer(&mut download, &mut upload, 10), Some(new_file));
+    }
+
+    #[test]
+    fn corrupted_transfer() {
+        let mut download = Download::new();
+        let request = download.request("map.png");
+        let mut upload = new_upload(&request, file(CHUNK_SIZE + 10));
+
+        let msg = upload.next_chunk().unwrap();
+        assert!(download
+            .push_chunk(&msg, vec![0; CHUNK_SIZE])
+            .unwrap()
+            .is_none());
+        let msg = upload.next_chunk().unwrap();
+        assert!(download.push_chunk(&msg, vec![0; 10]).is_err());
+        assert!(download.restart());
+
+        // Chunk that doesn't continue what we have
+        let request = download.request("map.png");
+        let mut upload = new_upload(&request, file(CHUNK_SIZE * 3));
+        upload.next_chunk();
+        let msg = upload.next_chunk().unwrap();
+        assert!(download.push_chunk(&msg, vec![0; CHUNK_SIZE]).is_err());
+
+        let msg = Message::new(crate::net::MsgType::File).set_prop("requestId", "abc");
+        assert!(download.push_chunk(&msg, Vec::new()).unwrap().is_none());
+        assert!(download.restart());
+        assert!(!download.restart());
+    }
+}
@@ -1,8 +1,11 @@
 use eframe::egui;
 use egui::containers::CentralPanel;
-use egui::widgets::Spinner;
+use egui::widgets::{ProgressBar, Spinner};
 use egui::Context;
 
+use crate::state::RoomState;
+
+#[derive(Default)]
 pub struct LoadingScreenUi {}
 
 impl LoadingScreenUi {
@@ -12,12 +15,35 @@ impl LoadingScreenUi {
 }
 
 impl LoadingScreenUi {
-    pub fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+    // Shown until the initial state of the room has been received
+    pub fn update(&mut self, ctx: &Context, room_state: &mut RoomState) {
+        let loading = match room_state.loading() {
+            Some(loading) => loading,
+            None => return,
+        };
+        let (synced, total) = (loading.synced, loading.assets);
+        let loaded = room_state.loaded_assets();
         CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
-                ui.add_space(ui.available_height() / 2.0 - 20.0);
-                ui.heading("Connecting to the server...");
-                ui.add(Spinner::new());
+                ui.add_space(ui.available_height() / 2.0 - 40.0);
+                if !synced {
+                    ui.heading("Connecting to the server...");
+                    ui.add(Spinner::new());
+                } else {
+                    ui.heading("Loading images...");
+                    ui.add(
+                        ProgressBar::new(loaded as f32 / total.max(1) as f32)
+                            .desired_width(200.0)
+                            .text(format!("{} of {}", loaded, total)),
+                    );
+                    // The master may take a while to answer, or never do
+                    if ui.button("Skip").clicked() {
+                        room_state.skip_loading();
+                    }
+                }
+                if ui.button("Leave").clicked() {
+                    room_state.quit_room();
+                }
             });
         });
     }
@@ -1,19 +1,22 @@
 use eframe::egui;
-use egui::containers::panel::{CentralPanel, SidePanel};
-use egui::containers::ScrollArea;
+use egui::containers::panel::{CentralPanel, SidePanel, TopBottomPanel};
+use egui::containers::{ComboBox, ScrollArea};
 
 use egui::widget_text::RichText;
-use egui::widgets::{Button, DragValue, ImageButton, Label};
-use egui::{Align, Align2, Area, Color32, Context, Frame, Key, Layout, Ui};
+use egui::widgets::{Button, DragValue, ImageButton, Label, Slider, Spinner};
+use egui::{Align, Align2, Area, Color32, Context, Frame, Grid, Key, Layout, Order, Ui};
 
 use clipboard::{ClipboardContext, ClipboardProvider};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+use crate::net::model::{Fog, Layer, ObjectKind};
+use crate::state::map::{MapObject, Restack};
 use crate::state::RoomState;
 use crate::textures::Textures;
-use crate::ui::widgets::RelArea;
+use crate::ui::widgets::{self, RelArea};
 use crate::ui::{MapUi, Window};
 use crate::DraduError;
 
@@ -21,6 +24,15 @@ use crate::ui::window_tools::MapManager;
 
 //const HEADER: &str = concat!("DRADU ", env!("CARGO_PKG_VERSION"));
 const HEADER: &str = "DRADU ALPHA";
+const PLAYBACK_SPEEDS: [f32; 4] = [0.5, 1.0, 2.0, 4.0];
+// Degrees
+const ROTATION_SNAPS: [f32; 3] = [15.0, 45.0, 90.0];
+// Name and seconds
+const INVITE_DURATIONS: [(&str, u64); 3] = [
+    ("15 minutes", 15 * 60),
+    ("1 hour", 60 * 60),
+    ("1 day", 24 * 60 * 60),
+];
 
 pub struct MainUi {
     map_ui: MapUi,
@@ -80,9 +92,10 @@ impl MainUi {
                         {
                             self.current_tab = Tab::Info;
                         }
-                        if ui
-                            .add(ImageButton::new(&self.textures["tools"], [22.0, 22.0]))
-                            .clicked()
+                        if !room_state.is_spectator()
+                            && ui
+                                .add(ImageButton::new(&self.textures["tools"], [22.0, 22.0]))
+                                .clicked()
                         {
                             self.current_tab = Tab::Tools;
                         }
@@ -107,9 +120,9 @@ impl MainUi {
                 ui.add_space(5.0);
 
                 match self.current_tab {
-                    Tab::Chat => self.display_chat(ui, room_state),
+                    Tab::Chat => self.display_chat(ui, room_state)?,
                     Tab::Info => self.display_info(ui, room_state)?,
-                    Tab::Tools => self.display_tools(ui, room_state),
+                    Tab::Tools => self.display_tools(ui, room_state)?,
                     Tab::Images => self.display_images_dialog(ui, room_state),
                     Tab::Settings => self.display_settings(ui, room_state),
                 };
@@ -117,14 +130,17 @@ impl MainUi {
             })
             .inner?;
 
-        CentralPanel::default().show(ctx, |ui| {
-            ScrollArea::both()
-                .auto_shrink([false, false])
-                .always_show_scroll(true)
-                .show(ui, |ui| {
-                    self.map_ui.update(ui, room_state);
-                })
-        });
+        self.display_playback_controls(ctx, room_state);
+
+        CentralPanel::default()
+            .show(ctx, |ui| {
+                ScrollArea::both()
+                    .auto_shrink([false, false])
+                    .always_show_scroll(true)
+                    .show(ui, |ui| self.map_ui.update(ui, room_state))
+                    .inner
+            })
+            .inner?;
         self.display_map_overlay_ui(ctx);
 
         for tool in self.windowed_tools.values_mut() {
@@ -133,9 +149,110 @@ impl MainUi {
             }
         }
 
+        self.display_reconnecting_overlay(ctx, room_state);
+        self.display_errors(ctx, room_state);
+
         Ok(())
     }
 
+    fn display_reconnecting_overlay(&mut self, ctx: &Context, room_state: &mut RoomState) {
+        let (attempt, reason) = match room_state.reconnecting() {
+            Some(r) => (r.attempt, r.reason.clone()),
+            None => return,
+        };
+        Area::new("ma1")
+            .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+            .order(Order::Foreground)
+            .show(ctx, |ui| {
+                Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.vertical_centered(|ui| {
+                        ui.heading("Reconnecting...");
+                        ui.add(Spinner::new());
+                        ui.label(format!("Attempt {}", attempt + 1));
+                        ui.label(RichText::new(reason).weak());
+                        if ui.button("Leave").clicked() {
+                            room_state.quit_room();
+                        }
+                    });
+                });
+            });
+        // Attempts are scheduled by time, so we have to keep updating
+        ctx.request_repaint_after(Duration::from_millis(200));
+    }
+
+    // Errors reported by the server or about its messages, newest at the bottom
+    fn display_errors(&mut self, ctx: &Context, room_state: &mut RoomState) {
+        if room_state.errors().is_empty() {
+            return;
+        }
+        let mut dismissed = None;
+        Area::new("ma2")
+            .anchor(Align2::RIGHT_TOP, (-5.0, 5.0))
+            .order(Order::Foreground)
+            .show(ctx, |ui| {
+                for (i, error) in room_state.errors().iter().enumerate() {
+                    Frame::popup(ui.style()).show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label(RichText::new(&error.message).color(Color32::LIGHT_RED));
+                            if ui.small_button("✖").clicked() {
+                                dismissed = Some(i);
+                            }
+                        });
+                    });
+                }
+            });
+        if let Some(i) = dismissed {
+            room_state.dismiss_error(i);
+        }
+    }
+
+    // Only shown while watching a recording
+    fn display_playback_controls(&mut self, ctx: &Context, room_state: &mut RoomState) {
+        let playback = match room_state.playback() {
+            Some(playback) => playback,
+            None => return,
+        };
+        let mut seek_to = None;
+        TopBottomPanel::bottom("pb0").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if playback.is_paused() {
+                    if ui.button("Play").clicked() {
+                        playback.play();
+                    }
+                } else if ui.button("Pause").clicked() {
+                    playback.pause();
+                }
+                for speed in PLAYBACK_SPEEDS {
+                    let text = format!("{}x", speed);
+                    if ui
+                        .selectable_label(playback.speed() == speed, text)
+                        .clicked()
+                    {
+                        playback.set_speed(speed);
+                    }
+                }
+                let duration = playback.duration().as_secs_f32();
+                let mut position = playback.position().as_secs_f32();
+                ui.label(format!(
+                    "{} / {}",
+                    format_time(position),
+                    format_time(duration)
+                ));
+                ui.spacing_mut().slider_width = ui.available_width();
+                let slider = Slider::new(&mut position, 0.0..=duration).show_value(false);
+                if ui.add(slider).changed() {
+                    seek_to = Some(Duration::from_secs_f32(position));
+                }
+            });
+        });
+        if !playback.is_paused() {
+            ctx.request_repaint_after(Duration::from_millis(50));
+        }
+        if let Some(position) = seek_to {
+            room_state.seek(position);
+        }
+    }
+
     fn display_map_overlay_ui(&mut self, ctx: &Context) {
         // UI to change scale of the map
         Area::new("ma0")
@@ -158,28 +275,14 @@ impl MainUi {
         self.map_ui.global_scale = self.map_ui.global_scale.clamp(0.01, 10.0);
     }
 
-    fn display_chat(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
+    fn display_chat(&mut self, ui: &mut Ui, room_state: &mut RoomState) -> Result<(), DraduError> {
+        let mut result = Ok(());
         ui.with_layout(Layout::bottom_up(Align::Min), |ui| {
             ui.add_space(5.0);
-            let response = ui.add(Button::image_and_text(
-                self.textures["send"].id(),
-                [18.0, 18.0],
-                "Send",
-            ));
-            if response.clicked() {
-                room_state.send_chat_message(&self.buffers.chat_input);
-                self.buffers.chat_input.clear();
-            }
-            response.on_hover_text("You can also use Ctrl+Enter");
-            ui.add_space(5.0);
-            if ui
-                .text_edit_multiline(&mut self.buffers.chat_input)
-                .has_focus()
-            {
-                if ui.input().modifiers.ctrl && ui.input().key_pressed(Key::Enter) {
-                    room_state.send_chat_message(&self.buffers.chat_input);
-                    self.buffers.chat_input.clear();
-                }
+            if room_state.is_spectator() {
+                ui.label(RichText::new("Spectators can't chat").weak());
+            } else {
+                result = self.display_chat_input(ui, room_state);
             }
             ui.add_space(5.0);
             // FIXME: Text in log isn't selectable
@@ -205,32 +308,138 @@ impl MainUi {
                     });
             });
         });
+        result
+    }
+
+    fn display_chat_input(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        let response = ui.add(Button::image_and_text(
+            self.textures["send"].id(),
+            [18.0, 18.0],
+            "Send",
+        ));
+        if response.clicked() {
+            room_state.send_chat_message(&self.buffers.chat_input)?;
+            self.buffers.chat_input.clear();
+        }
+        response.on_hover_text("You can also use Ctrl+Enter");
+        ui.add_space(5.0);
+        if ui
+            .text_edit_multiline(&mut self.buffers.chat_input)
+            .has_focus()
+        {
+            if ui.input().modifiers.ctrl && ui.input().key_pressed(Key::Enter) {
+                room_state.send_chat_message(&self.buffers.chat_input)?;
+                self.buffers.chat_input.clear();
+            }
+        }
+        Ok(())
     }
 
-    fn display_info(&mut self, ui: &mut Ui, room_state: &RoomState) -> Result<(), DraduError> {
+    fn display_info(&mut self, ui: &mut Ui, room_state: &mut RoomState) -> Result<(), DraduError> {
         ui.heading("Room address:");
         ui.horizontal(|ui| -> Result<(), DraduError> {
             let room_address = room_state.get_room_address()?;
             ui.label(&room_address);
             if ui.button("Copy").clicked() {
                 let mut clipboard: ClipboardContext = ClipboardProvider::new().unwrap();
-                clipboard.set_contents(room_address);
+                if let Err(e) = clipboard.set_contents(room_address) {
+                    room_state.show_error(&format!("Could not copy the address: {}", e));
+                }
             }
             Ok(())
         })
         .inner?;
-        ui.group(|ui| {
-            for (id, (nickname, color)) in room_state.players_ref().iter() {
-                ui.add(repr_player(color.clone(), nickname, id));
+        if room_state.can_invite() {
+            self.display_invite(ui, room_state)?;
+        }
+        let (spectators, players): (Vec<_>, Vec<_>) = room_state
+            .players_ref()
+            .iter()
+            .partition(|(id, _)| room_state.is_spectator_id(id));
+        ui.group(|ui| display_player_list(ui, room_state, &players));
+        if !spectators.is_empty() {
+            ui.heading("Spectators:");
+            ui.group(|ui| display_player_list(ui, room_state, &spectators));
+        }
+        ui.label(
+            RichText::new(format!(
+                "Protocol version: {}\nFeatures: {}",
+                room_state.get_server_version(),
+                room_state.get_capabilities().join(", ")
+            ))
+            .weak(),
+        );
+        Ok(())
+    }
+
+    // Single-use links which let players in without the room password
+    fn display_invite(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        ui.horizontal(|ui| -> Result<(), DraduError> {
+            let selected = &mut self.buffers.invite_duration;
+            ComboBox::from_id_source("invite_duration")
+                .selected_text(INVITE_DURATIONS[*selected].0)
+                .show_ui(ui, |ui| {
+                    for (i, (name, _)) in INVITE_DURATIONS.iter().enumerate() {
+                        ui.selectable_value(selected, i, *name);
+                    }
+                });
+            if ui.button("Create invite").clicked() {
+                let secs = INVITE_DURATIONS[*selected].1;
+                room_state.create_invite(Duration::from_secs(secs))?;
             }
-        });
+            Ok(())
+        })
+        .inner?;
+        if let Some((link, expires)) = room_state.invite() {
+            ui.horizontal(|ui| {
+                ui.label(link);
+                if ui.button("Copy").clicked() {
+                    let mut clipboard: ClipboardContext = ClipboardProvider::new().unwrap();
+                    #[allow(unused)]
+                    {
+                        clipboard.set_contents(link.to_string());
+                    }
+                }
+            });
+            let left = expires.saturating_duration_since(Instant::now());
+            let text = match left.as_secs() / 60 {
+                0 if left.is_zero() => "Expired".to_string(),
+                0 => "Single use, expires in less than a minute".to_string(),
+                mins if mins < 60 => format!("Single use, expires in {} min", mins),
+                mins => format!("Single use, expires in {} h {} min", mins / 60, mins % 60),
+            };
+            ui.label(RichText::new(text).weak());
+        }
         Ok(())
     }
 
-    fn display_tools(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
-        self.display_grid_settings(ui, room_state);
+    fn display_tools(&mut self, ui: &mut Ui, room_state: &mut RoomState) -> Result<(), DraduError> {
+        self.display_grid_settings(ui, room_state)?;
         ui.add_space(10.0);
+        if room_state.is_master() {
+            self.display_wall_tools(ui, room_state)?;
+            ui.add_space(10.0);
+            self.display_fog_tools(ui, room_state)?;
+            ui.add_space(10.0);
+        }
+        if !room_state.is_spectator() {
+            self.display_effect_tools(ui);
+            ui.add_space(10.0);
+            self.display_layer_tools(ui, room_state)?;
+            ui.add_space(10.0);
+            self.display_rotation_tools(ui, room_state)?;
+            ui.add_space(10.0);
+        }
         self.display_windowed_tools(ui, room_state);
+        Ok(())
     }
 
     fn display_windowed_tools(&mut self, ui: &mut Ui, _room_state: &mut RoomState) {
@@ -245,14 +454,251 @@ impl MainUi {
         });
     }
 
-    fn display_grid_settings(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
+    fn display_wall_tools(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        ui.heading("Walls");
+        let mut result = Ok(());
+        ui.indent("ui2", |ui| {
+            if !self.map_ui.is_drawing_wall() {
+                if ui.button("Draw a wall").clicked() {
+                    self.map_ui.start_wall();
+                }
+                ui.weak("Select a wall to drag its nodes. Double-click it to add a node, right-click a node to remove it");
+                return;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Finish").clicked() {
+                    if let Some(points) = self.map_ui.finish_wall() {
+                        result = room_state.add_wall(&points);
+                    }
+                }
+                if ui.button("Cancel").clicked() {
+                    self.map_ui.cancel_wall();
+                }
+            });
+            ui.weak("Click on the map to add nodes. Enter or a double click finishes the wall, Escape cancels it");
+        });
+        result
+    }
+
+    fn display_fog_tools(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        let fog = room_state.map().fog.as_ref().map(|fog| fog.as_entry());
+        let mut result = Ok(());
+        ui.horizontal(|ui| {
+            ui.heading("Fog of war");
+            let mut enabled = fog.is_some();
+            if ui.checkbox(&mut enabled, "").changed() {
+                result = room_state.set_fog(enabled.then(Fog::default));
+            }
+        });
+        let fog = match fog {
+            Some(fog) => fog,
+            None => return result,
+        };
+        let tool = &mut self.map_ui.fog_tool;
+        ui.indent("ui3", |ui| {
+            let mut reveal_all = fog.reveal_all;
+            if ui.checkbox(&mut reveal_all, "Reveal all").changed() {
+                result = room_state.set_fog(Some(Fog {
+                    reveal_all,
+                    ..fog.clone()
+                }));
+            }
+            ui.horizontal(|ui| {
+                ui.label("Brush:");
+                ui.selectable_value(&mut tool.brush, None, "Off");
+                ui.selectable_value(&mut tool.brush, Some(true), "Reveal");
+                ui.selectable_value(&mut tool.brush, Some(false), "Hide");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Brush radius:");
+                ui.add(DragValue::new(&mut tool.brush_radius).clamp_range(0..=10));
+            });
+            if ui.button("Clear painted fog").clicked() {
+                result = room_state.set_fog(Some(Fog {
+                    reveal_all: fog.reveal_all,
+                    ..Fog::default()
+                }));
+            }
+
+            // Players' tokens are the ones whose "owner" is their user ID
+            let my_id = room_state.get_user_id();
+            let players = room_state.players_ref();
+            let selected = match &tool.preview {
+                Some(id) => players
+                    .get(id)
+                    .map_or(id.as_str(), |(nickname, _)| nickname),
+                None => "GM",
+            };
+            ComboBox::from_label("View as")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut tool.preview, None, "GM");
+                    for (id, (nickname, _)) in players {
+                        if id != my_id {
+                            let value = Some(id.clone());
+                            ui.selectable_value(&mut tool.preview, value, nickname);
+                        }
+                    }
+                });
+        });
+        result
+    }
+
+    fn display_effect_tools(&mut self, ui: &mut Ui) {
+        ui.heading("Effects");
+        let tool = &mut self.map_ui.effect_tool;
+        ui.indent("ui4", |ui| {
+            widgets::effect_settings(ui, "new effect", &mut tool.template);
+            if tool.placing {
+                if ui.button("Cancel").clicked() {
+                    tool.placing = false;
+                }
+                ui.weak("Click on the map to place the effect, Escape cancels it");
+            } else {
+                if ui.button("Place an effect").clicked() {
+                    tool.placing = true;
+                }
+                ui.weak("Drag an effect to move it, and the handle at its tip to turn it. Sizes are in grid squares");
+            }
+        });
+    }
+
+    fn display_layer_tools(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        ui.heading("Layers");
+        let master = room_state.is_master();
+        let mut result = Ok(());
+        ui.indent("ui5", |ui| {
+            let selected = self.map_ui.selected_object().and_then(|id| {
+                let obj = room_state.map().objects.get(id)?;
+                Some((id.to_string(), obj.order().0))
+            });
+            match selected {
+                Some((id, layer)) => {
+                    ComboBox::from_label("Layer of the selected object")
+                        .selected_text(layer.to_string())
+                        .show_ui(ui, |ui| {
+                            for other in Layer::ALL {
+                                if other == Layer::Gm && !master {
+                                    continue;
+                                }
+                                if ui
+                                    .selectable_label(other == layer, other.to_string())
+                                    .clicked()
+                                    && other != layer
+                                {
+                                    result = room_state.move_to_layer(&id, other);
+                                }
+                            }
+                        });
+                    ui.horizontal(|ui| {
+                        for (text, step) in [
+                            ("To back", Restack::ToBack),
+                            ("Backward", Restack::Backward),
+                            ("Forward", Restack::Forward),
+                            ("To front", Restack::ToFront),
+                        ] {
+                            if ui.button(text).clicked() {
+                                result = room_state.restack_map_object(&id, step);
+                            }
+                        }
+                    });
+                }
+                None => {
+                    ui.weak("Select an object to move it between the layers");
+                }
+            }
+            if !master {
+                return;
+            }
+            let layers = &mut self.map_ui.layers;
+            Grid::new("layers").show(ui, |ui| {
+                for layer in Layer::ALL {
+                    ui.label(layer.to_string());
+                    let mut visible = !layers.hidden.contains(&layer);
+                    if ui.checkbox(&mut visible, "Visible").changed() {
+                        toggle(&mut layers.hidden, layer, !visible);
+                    }
+                    let mut locked = layers.locked.contains(&layer);
+                    if ui.checkbox(&mut locked, "Locked").changed() {
+                        toggle(&mut layers.locked, layer, locked);
+                    }
+                    ui.end_row();
+                }
+            });
+            ui.weak("Only the GM sees the gm layer");
+        });
+        result
+    }
+
+    fn display_rotation_tools(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        ui.heading("Rotation");
+        let mut result = Ok(());
+        ui.indent("ui6", |ui| {
+            let snap = &mut self.map_ui.rotation_snap;
+            ui.horizontal(|ui| {
+                ui.label("Snap to:");
+                ui.selectable_value(snap, None, "Off");
+                for step in ROTATION_SNAPS {
+                    ui.selectable_value(snap, Some(step), format!("{}°", step));
+                }
+            });
+            let selected = self.map_ui.selected_object().and_then(|id| {
+                match room_state.map().objects.get(id)? {
+                    obj @ (MapObject::Decal(_) | MapObject::Token(_)) => {
+                        Some((id.to_string(), obj.rotation(), obj.flip()))
+                    }
+                    _ => None,
+                }
+            });
+            let (id, rotation, [flip_x, flip_y]) = match selected {
+                Some(selected) => selected,
+                None => {
+                    ui.weak("Select a decal or a token to turn it with the handle next to the resize one, or with Q and E");
+                    return;
+                }
+            };
+            ui.label(format!("Selected object is turned by {}°", rotation));
+            ui.horizontal(|ui| {
+                if ui.button("Flip horizontally").clicked() {
+                    result = room_state.flip_map_object(&id, [!flip_x, flip_y]);
+                }
+                if ui.button("Flip vertically").clicked() {
+                    result = room_state.flip_map_object(&id, [flip_x, !flip_y]);
+                }
+            });
+        });
+        result
+    }
+
+    fn display_grid_settings(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
+        let mut result = Ok(());
         ui.horizontal(|ui| {
             ui.heading("Grid");
             if ui.checkbox(&mut self.buffers.grid_enabled, "").changed() {
                 if self.buffers.grid_enabled {
-                    room_state.change_grid_size(self.buffers.grid_size);
+                    result = room_state.change_grid_size(self.buffers.grid_size);
                 } else {
-                    room_state.change_grid_size([0, 0]);
+                    result = room_state.change_grid_size([0, 0]);
                 }
             }
         });
@@ -266,7 +712,7 @@ impl MainUi {
                         ui.add(DragValue::new(&mut self.buffers.grid_size[1]).clamp_range(2..=255));
                     let union = r1.union(r2);
                     if union.changed() {
-                        room_state.change_grid_size(self.buffers.grid_size);
+                        result = room_state.change_grid_size(self.buffers.grid_size);
                     } else if !union.dragged() {
                         if let Some(grid_size) = room_state.map().grid {
                             self.buffers.grid_enabled = true;
@@ -278,6 +724,14 @@ impl MainUi {
                 });
             });
         });
+        result
+    }
+
+    // Failures with local files are shown in the room, they don't end the session
+    fn report<T>(room_state: &mut RoomState, context: &str, result: Result<T, DraduError>) {
+        if let Err(e) = result {
+            room_state.show_error(&format!("{}: {}", context, e));
+        }
     }
 
     fn display_images_dialog(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
@@ -289,9 +743,10 @@ impl MainUi {
                 });
         }
         for file in ui.input().raw.dropped_files.iter() {
-            room_state
+            let copied = room_state
                 .fs_ref()
                 .copy_into(file.path.as_ref().unwrap(), &self.cwd);
+            Self::report(room_state, "Could not add the file", copied);
         }
         ScrollArea::horizontal().show(ui, |ui| {
             ui.label(format!("{}", self.cwd.display()));
@@ -310,15 +765,20 @@ impl MainUi {
                     if is_dir {
                         self.cwd = self.cwd.join(filename.as_ref());
                     } else {
-                        room_state.insert_from_path(self.cwd.join(filename.as_ref()), "decal");
+                        let path = self.cwd.join(filename.as_ref());
+                        let added = room_state.insert_from_path(path, ObjectKind::Decal);
+                        Self::report(room_state, "Could not add the image", added);
                     }
                 }
                 resp.context_menu(|ui| {
                     if ui.button("Add as token").clicked() {
-                        room_state.insert_from_path(self.cwd.join(filename.as_ref()), "token");
+                        let path = self.cwd.join(filename.as_ref());
+                        let added = room_state.insert_from_path(path, ObjectKind::Token);
+                        Self::report(room_state, "Could not add the token", added);
                     }
                     if ui.button("Set as BG image").clicked() {
-                        room_state.set_background_image(self.cwd.join(filename.as_ref()));
+                        let set = room_state.set_background_image(self.cwd.join(filename.as_ref()));
+                        Self::report(room_state, "Could not set the background", set);
                     }
                 });
             }
@@ -326,7 +786,8 @@ impl MainUi {
                 let resp = ui.text_edit_singleline(string);
                 if resp.lost_focus() {
                     if ui.input().key_pressed(Key::Enter) {
-                        room_state.fs_ref().create_dir(&self.cwd.join(string));
+                        let created = room_state.fs_ref().create_dir(&self.cwd.join(string));
+                        Self::report(room_state, "Could not create the folder", created);
                     }
                     self.buffers.create_dir = None;
                 } else {
@@ -366,6 +827,8 @@ struct Buffers {
     tab_panel_width: Option<f32>,
     create_dir: Option<String>,
     chat_input: String,
+    // Index into INVITE_DURATIONS
+    invite_duration: usize,
 }
 
 impl Default for Buffers {
@@ -376,6 +839,7 @@ impl Default for Buffers {
             tab_panel_width: None,
             create_dir: None,
             chat_input: String::new(),
+            invite_duration: 1,
         }
     }
 }
@@ -391,6 +855,20 @@ impl WindowedTool {
     }
 }
 
+// mm:ss
+fn toggle(layers: &mut HashSet<Layer>, layer: Layer, on: bool) {
+    if on {
+        layers.insert(layer);
+    } else {
+        layers.remove(&layer);
+    }
+}
+
+fn format_time(secs: f32) -> String {
+    let secs = secs as u32;
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
 fn repr_player(color: Color32, nick: &str, id: &str) -> Label {
     Label::new(
         RichText::new(format!("{}#{}", nick, &id.get(..4).unwrap_or("")))
@@ -398,3 +876,18 @@ fn repr_player(color: Color32, nick: &str, id: &str) -> Label {
             .strong(),
     )
 }
+
+fn display_player_list(
+    ui: &mut Ui,
+    room_state: &RoomState,
+    players: &[(&String, &(String, Color32))],
+) {
+    for (id, (nickname, color)) in players {
+        ui.horizontal(|ui| {
+            ui.add(repr_player(*color, nickname, id));
+            if let Some(latency) = room_state.get_latency(id) {
+                ui.label(RichText::new(format!("{} ms", latency.as_millis())).weak());
+            }
+        });
+    }
+}
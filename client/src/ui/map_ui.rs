@@ -1,29 +1,115 @@
 use eframe::egui;
 use egui::containers::Window;
 use egui::{
-    hex_color, Area, Color32, Frame, Image, Key, Pos2, Rect, Response, Rounding, Sense, Shape,
-    Stroke, TextEdit, Ui, Vec2,
+    hex_color, Area, Color32, ComboBox, Frame, Image, Key, Pos2, Rect, Response, Rounding, Sense,
+    Shape, Stroke, TextEdit, Ui, Vec2,
 };
 
 use egui::epaint::RectShape;
 
 use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
-use crate::state::map::{MapObject, Token};
+use crate::net::model::{EffectShape, Layer, FOG_CELL};
+use crate::state::fog::{self, Cell, CellState};
+use crate::state::map::{Effect, MapObject, Token, Wall};
 use crate::state::RoomState;
+use crate::textures::Textures;
 use crate::ui::widgets::{self, Dragging, RelArea, RelAreaResponse};
-use crate::Textures;
+use crate::DraduError;
+
+// How often the position of an object is sent to other players while we're
+// dragging it, in seconds
+const DRAG_STREAM_INTERVAL: f64 = 0.1;
+// Walls are drawn and hit-tested in screen pixels, whatever the map scale is
+const WALL_WIDTH: f32 = 4.0;
+const WALL_COLOR: Color32 = Color32::from_rgb(60, 60, 60);
+const SELECTED_WALL_COLOR: Color32 = Color32::from_rgb(230, 140, 30);
+// How far from a wall a click still selects it
+const WALL_REACH: f32 = 6.0;
+const NODE_RADIUS: f32 = 5.0;
+// What the fog of war covers when there is no background
+const DEFAULT_MAP_SIZE: Vec2 = Vec2::new(2000.0, 2000.0);
+// Size of the grid unit effects are measured in, when the map has no grid
+const DEFAULT_GRID_UNIT: f32 = 50.0;
+// Degrees Q and E turn the selected object by when rotation isn't snapped
+const KEY_ROTATION_STEP: f32 = 15.0;
 
 pub struct MapUi {
     pub global_scale: f32,
     textures: Textures,
     last_dragged_pos: Pos2,
+    // Time and position of the last transient move we've sent
+    last_streamed: Option<(f64, Pos2)>,
     selected_object: Option<String>,
     selected_object_scale: f32,
+    // Degrees the selected object has been turned by with the rotation handle,
+    // and the angle of the pointer when it was grabbed
+    selected_object_rotation: f32,
+    rotation_drag_start: Option<f32>,
+    // Rotations are rounded to this many degrees. Otherwise to whole degrees
+    pub rotation_snap: Option<f32>,
     snapping_enabled: bool,
     snap_to: Option<Pos2>, // Where to snap curently dragged item?
     map_size: Option<Vec2>,
     display_object_ui_state: DisplayObjectUiState,
+    // Nodes of the wall the GM is drawing, in map coordinates
+    new_wall: Option<Vec<Pos2>>,
+    // Index and position of the node of the selected wall which is being dragged
+    dragged_node: Option<(usize, Pos2)>,
+    pub fog_tool: FogTool,
+    // Cells painted since the GM has pressed the brush. They are sent when
+    // it's released
+    fog_stroke: HashMap<Cell, bool>,
+    pub effect_tool: EffectTool,
+    // ID and state of the effect which is being dragged or rotated
+    dragged_effect: Option<(String, Effect)>,
+    pub layers: LayerSettings,
+}
+
+// Layers the GM has hidden or locked. It only changes what they see
+#[derive(Default)]
+pub struct LayerSettings {
+    pub hidden: HashSet<Layer>,
+    // Objects on locked layers can't be selected or dragged
+    pub locked: HashSet<Layer>,
+}
+
+// Template of the effects players put on the map
+pub struct EffectTool {
+    pub template: Effect,
+    // The next click on the map places the template there
+    pub placing: bool,
+}
+
+impl Default for EffectTool {
+    fn default() -> Self {
+        let mut template = Effect::new(EffectShape::Circle, Pos2::ZERO);
+        template.size = 4.0;
+        Self {
+            template,
+            placing: false,
+        }
+    }
+}
+
+// GM's controls of the fog of war
+#[derive(Default)]
+pub struct FogTool {
+    // Some(true) reveals the cells under the brush, Some(false) hides them
+    pub brush: Option<bool>,
+    // In cells, 0 paints just one
+    pub brush_radius: u8,
+    // User ID of the player whose view the GM is looking at
+    pub preview: Option<String>,
+}
+
+enum FogView {
+    // No fog, or the GM who sees through it
+    Clear,
+    // What the player with this user ID sees. None is every player at once
+    Player(Option<String>),
 }
 
 impl MapUi {
@@ -32,39 +118,108 @@ impl MapUi {
             global_scale: 1.0,
             textures,
             last_dragged_pos: Pos2::new(0.0, 0.0),
+            last_streamed: None,
             selected_object: None,
             selected_object_scale: 1.0,
+            selected_object_rotation: 0.0,
+            rotation_drag_start: None,
+            rotation_snap: None,
             snapping_enabled: true,
             snap_to: None,
             map_size: None,
             display_object_ui_state: DisplayObjectUiState::default(),
+            new_wall: None,
+            dragged_node: None,
+            fog_tool: FogTool::default(),
+            fog_stroke: HashMap::new(),
+            effect_tool: EffectTool::default(),
+            dragged_effect: None,
+            layers: LayerSettings::default(),
         }
     }
+
+    pub fn selected_object(&self) -> Option<&str> {
+        self.selected_object.as_deref()
+    }
+
+    pub fn is_drawing_wall(&self) -> bool {
+        self.new_wall.is_some()
+    }
+
+    pub fn start_wall(&mut self) {
+        self.new_wall = Some(Vec::new());
+        self.selected_object = None;
+    }
+
+    pub fn cancel_wall(&mut self) {
+        self.new_wall = None;
+    }
+
+    // Nodes of the new wall, if there are enough of them
+    pub fn finish_wall(&mut self) -> Option<Vec<Pos2>> {
+        self.new_wall.take().filter(|nodes| nodes.len() >= 2)
+    }
 }
 
 impl MapUi {
-    pub fn update(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
+    pub fn update(&mut self, ui: &mut Ui, room_state: &mut RoomState) -> Result<(), DraduError> {
         ui.scope(|ui| {
-            self.map_ui(ui, room_state);
+            let result = self.map_ui(ui, room_state);
             if ui
                 .interact(ui.min_rect(), ui.id(), Sense::click())
                 .clicked()
             {
                 self.selected_object = None;
                 self.selected_object_scale = 1.0;
+                self.selected_object_rotation = 0.0;
+                self.rotation_drag_start = None;
             }
-        });
+            result
+        })
+        .inner
     }
 
-    fn map_ui(&mut self, ui: &mut Ui, room_state: &mut RoomState) {
+    fn map_ui(&mut self, ui: &mut Ui, room_state: &mut RoomState) -> Result<(), DraduError> {
         let bg_resp = self.draw_bg_image(ui, room_state);
         self.map_size = bg_resp.and_then(|v| Some(v.rect.size()));
         if let Some(grid_size) = room_state.map().grid {
             widgets::draw_grid(grid_size, ui);
         }
 
+        // Walls go over the background, but under everything else. They are
+        // hit-tested last, so that they don't take clicks from other objects
+        let walls_shape = ui.painter().add(Shape::Noop);
+
+        let unit = self.grid_unit(room_state);
         let mut map_action = MapAction::None;
-        for (id, obj) in room_state.map().objects.iter() {
+        if self.new_wall.is_some() {
+            map_action = self.draw_new_wall(ui);
+        }
+        if self.effect_tool.placing && !room_state.is_spectator() {
+            map_action = map_action.or(self.draw_new_effect(ui, unit));
+        }
+        let painting_fog = room_state.is_master() && room_state.map().fog.is_some();
+        if let (true, Some(reveal)) = (painting_fog, self.fog_tool.brush) {
+            map_action = map_action.or(self.paint_fog(ui, reveal));
+        }
+        let fog_view = self.fog_view(room_state);
+        let bounds = Rect::from_min_size(
+            Pos2::ZERO,
+            self.map_size
+                .map_or(DEFAULT_MAP_SIZE, |size| size / self.global_scale),
+        );
+        if let FogView::Player(viewer) = &fog_view {
+            room_state.look_through_fog(viewer.as_deref(), bounds);
+        }
+
+        for (id, obj) in room_state.map().ordered_objects() {
+            match obj {
+                MapObject::Wall(_) | MapObject::Effect(_) => continue,
+                MapObject::Token(token) if hidden_by_fog(room_state, &fog_view, token) => continue,
+                _ if !self.is_shown(room_state, obj.order().0) => continue,
+                _ => (),
+            }
+            let read_only = self.is_read_only(room_state, obj.order().0);
             let mut display_object = DisplayObject {
                 id: &id,
                 global_scale: self.global_scale,
@@ -72,11 +227,21 @@ impl MapUi {
                 map_object: obj,
                 room_state: room_state,
                 is_selected: false,
+                read_only,
+                rotation: obj.rotation(),
             };
+            if read_only {
+                display_object.place(ui);
+                continue;
+            }
             let resp = match &self.selected_object {
                 Some(sel_id) if id == sel_id => {
                     display_object.rescale_factor = self.selected_object_scale;
                     display_object.is_selected = true;
+                    if self.rotation_drag_start.is_some() {
+                        display_object.rotation =
+                            self.snap_rotation(obj.rotation() + self.selected_object_rotation);
+                    }
                     if let Some(snap_to) = self.snap_to {
                         display_object.place_as_snapping_guide(ui, snap_to)
                     }
@@ -88,13 +253,535 @@ impl MapUi {
                         &mut self.display_object_ui_state,
                     ));
                     map_action = map_action.or(self.draw_resize_slider(&display_object, ui, &resp));
+                    map_action =
+                        map_action.or(self.draw_rotation_handle(&display_object, ui, &resp));
+                    map_action = map_action.or(self.rotate_with_keys(ui, &display_object));
                     resp
                 }
                 _ => display_object.place(ui),
             };
             map_action = map_action.or(self.process_object_response(&display_object, resp));
         }
-        map_action.apply(room_state);
+
+        // Effects are see-through, so they go over the tokens
+        let mut effect_shapes = Vec::new();
+        for (id, obj) in room_state.map().ordered_objects() {
+            match obj {
+                MapObject::Effect(effect) if self.is_shown(room_state, effect.layer) => {
+                    map_action = map_action.or(self.place_effect(
+                        ui,
+                        room_state,
+                        id,
+                        effect,
+                        unit,
+                        &mut effect_shapes,
+                    ));
+                }
+                _ => (),
+            }
+        }
+        ui.painter().extend(effect_shapes);
+
+        let mut wall_shapes = Vec::new();
+        for (id, obj) in room_state.map().ordered_objects() {
+            match obj {
+                MapObject::Wall(wall) if self.is_shown(room_state, wall.layer) => {
+                    let read_only = self.is_read_only(room_state, wall.layer);
+                    map_action =
+                        map_action.or(self.place_wall(ui, id, wall, read_only, &mut wall_shapes));
+                }
+                _ => (),
+            }
+        }
+        ui.painter().set(walls_shape, Shape::Vec(wall_shapes));
+        self.draw_fog(ui, room_state, &fog_view, bounds);
+        map_action.apply(room_state)
+    }
+
+    // Only the GM sees the GM layer
+    fn is_shown(&self, room_state: &RoomState, layer: Layer) -> bool {
+        (layer != Layer::Gm || room_state.is_master()) && !self.layers.hidden.contains(&layer)
+    }
+
+    // Spectators can only look at the map
+    fn is_read_only(&self, room_state: &RoomState, layer: Layer) -> bool {
+        room_state.is_spectator() || self.layers.locked.contains(&layer)
+    }
+
+    fn fog_view(&self, room_state: &RoomState) -> FogView {
+        match &room_state.map().fog {
+            Some(fog) if !fog.reveal_all => (),
+            _ => return FogView::Clear,
+        }
+        if room_state.is_master() {
+            match &self.fog_tool.preview {
+                Some(id) => FogView::Player(Some(id.clone())),
+                None => FogView::Clear,
+            }
+        } else if room_state.is_spectator() {
+            FogView::Player(None)
+        } else {
+            // Our ID is empty until the server has let us in, which owns no
+            // tokens, so there's nothing to see through
+            FogView::Player(Some(room_state.get_user_id().to_string()))
+        }
+    }
+
+    // Players get darkness over everything their tokens can't see, and a bit
+    // less of it where they have already been. The GM only gets the cells
+    // they have painted highlighted
+    fn draw_fog(&self, ui: &mut Ui, room_state: &RoomState, view: &FogView, bounds: Rect) {
+        let fog = match &room_state.map().fog {
+            Some(fog) => fog,
+            None => return,
+        };
+        let color = |cell: Cell| match view {
+            FogView::Player(_) => match fog.cell_state(cell) {
+                CellState::Visible => Color32::TRANSPARENT,
+                CellState::Explored => Color32::from_black_alpha(170),
+                CellState::Unexplored => Color32::BLACK,
+            },
+            FogView::Clear => {
+                match self
+                    .fog_stroke
+                    .get(&cell)
+                    .or_else(|| fog.painted.get(&cell))
+                {
+                    Some(true) => Color32::from_white_alpha(30),
+                    Some(false) => Color32::from_black_alpha(120),
+                    None => Color32::TRANSPARENT,
+                }
+            }
+        };
+
+        // Cells of the same color next to each other in a row make one
+        // rectangle. Color and the last cell of every rectangle are kept
+        let mut runs: Vec<(Rect, Color32, Cell)> = Vec::new();
+        for cell in fog::cells_in(bounds) {
+            let color = color(cell);
+            let rect = fog::cell_rect(cell);
+            let rect =
+                Rect::from_min_max(self.to_screen(ui, rect.min), self.to_screen(ui, rect.max));
+            match runs.last_mut() {
+                Some((last, last_color, [x, y]))
+                    if *last_color == color && *y == cell[1] && *x + 1 == cell[0] =>
+                {
+                    *last = last.union(rect);
+                    *x = cell[0];
+                }
+                _ => runs.push((rect, color, cell)),
+            }
+        }
+        let shapes = runs
+            .into_iter()
+            .filter(|(_, color, _)| *color != Color32::TRANSPARENT)
+            .map(|(rect, color, _)| rect_shape(rect, color, Stroke::none()))
+            .collect();
+        ui.painter().extend(shapes);
+    }
+
+    // Dragging over the map paints the cells under the brush
+    fn paint_fog(&mut self, ui: &mut Ui, reveal: bool) -> MapAction {
+        let resp = ui.interact(
+            ui.max_rect(),
+            ui.id().with("fog brush"),
+            Sense::click_and_drag(),
+        );
+        let radius = self.fog_tool.brush_radius as i32;
+        if let Some(hovered) = resp.hover_pos() {
+            let brush = (radius as f32 + 0.5) * FOG_CELL * self.global_scale;
+            ui.painter()
+                .circle_stroke(hovered, brush, Stroke::new(1.0, Color32::WHITE));
+        }
+        if let Some(pointer) = resp
+            .interact_pointer_pos()
+            .filter(|_| resp.is_pointer_button_down_on())
+        {
+            let [x, y] = fog::cell_at(self.to_map(ui, pointer));
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if dx * dx + dy * dy <= radius * radius {
+                        self.fog_stroke.insert([x + dx, y + dy], reveal);
+                    }
+                }
+            }
+        }
+        if (resp.drag_released() || resp.clicked()) && !self.fog_stroke.is_empty() {
+            return MapAction::PaintFog(std::mem::take(&mut self.fog_stroke));
+        }
+        MapAction::None
+    }
+
+    fn to_screen(&self, ui: &Ui, pos: Pos2) -> Pos2 {
+        ui.max_rect().min + pos.to_vec2() * self.global_scale
+    }
+
+    fn to_map(&self, ui: &Ui, pos: Pos2) -> Pos2 {
+        ((pos - ui.max_rect().min) / self.global_scale).to_pos2()
+    }
+
+    // Walls are lines, so instead of being areas they are hit-tested by hand
+    fn place_wall(
+        &mut self,
+        ui: &mut Ui,
+        id: &str,
+        wall: &Wall,
+        read_only: bool,
+        shapes: &mut Vec<Shape>,
+    ) -> MapAction {
+        let is_selected = !read_only && self.selected_object.as_deref() == Some(id);
+        let mut points = wall.points();
+        if points.len() < 2 {
+            return MapAction::None;
+        }
+        match self.dragged_node {
+            Some((index, pos)) if is_selected && index < points.len() => points[index] = pos,
+            _ => (),
+        }
+        let on_screen: Vec<Pos2> = points
+            .iter()
+            .map(|point| self.to_screen(ui, *point))
+            .collect();
+        let color = if is_selected {
+            SELECTED_WALL_COLOR
+        } else {
+            WALL_COLOR
+        };
+        shapes.push(Shape::line(
+            on_screen.clone(),
+            Stroke::new(WALL_WIDTH, color),
+        ));
+        if read_only {
+            return MapAction::None;
+        }
+
+        let mut action = MapAction::None;
+        if is_selected {
+            action = self.draw_wall_nodes(ui, id, &points, shapes);
+            // Lowest node, so that the button doesn't cover the wall
+            let below = on_screen
+                .iter()
+                .fold(on_screen[0], |a, b| if b.y > a.y { *b } else { a });
+            RelArea::new((id, 1))
+                .set_dragging(Dragging::Disabled)
+                .set_pos((below - ui.max_rect().min + Vec2::new(0.0, 2.0 * WALL_REACH)).to_pos2())
+                .show_inside(ui, |ui| {
+                    if ui.button("Delete").clicked() {
+                        action = MapAction::Delete(id.to_string());
+                    }
+                });
+        }
+
+        let rect = Rect::from_points(&on_screen).expand(WALL_REACH);
+        let resp = ui.interact(rect, ui.id().with(("wall", id)), Sense::click());
+        let pointer = match resp.interact_pointer_pos() {
+            Some(pointer) if resp.clicked() => pointer,
+            _ => return action,
+        };
+        let segment = nearest_segment(&on_screen, pointer);
+        if resp.double_clicked() && is_selected {
+            // New node where the wall has been clicked
+            if let Some(index) = segment {
+                points.insert(index + 1, self.to_map(ui, pointer));
+                action = MapAction::ChangeWall(id.to_string(), points);
+            }
+        } else {
+            // The click has only hit the empty space around the wall
+            self.selected_object = segment.map(|_| id.to_string());
+        }
+        action
+    }
+
+    // Handles of the selected wall. Dragging one moves its node, right-clicking
+    // removes it
+    fn draw_wall_nodes(
+        &mut self,
+        ui: &mut Ui,
+        id: &str,
+        points: &[Pos2],
+        shapes: &mut Vec<Shape>,
+    ) -> MapAction {
+        let mut action = MapAction::None;
+        for (index, point) in points.iter().enumerate() {
+            let center = self.to_screen(ui, *point);
+            shapes.push(Shape::circle_filled(center, NODE_RADIUS, Color32::WHITE));
+            shapes.push(Shape::circle_stroke(
+                center,
+                NODE_RADIUS,
+                Stroke::new(1.0, SELECTED_WALL_COLOR),
+            ));
+            let rect = Rect::from_center_size(center, Vec2::splat(2.0 * NODE_RADIUS + 4.0));
+            let resp = ui.interact(
+                rect,
+                ui.id().with(("wall node", id, index)),
+                Sense::click_and_drag(),
+            );
+            if resp.dragged() {
+                if let Some(pointer) = resp.interact_pointer_pos() {
+                    self.dragged_node = Some((index, self.to_map(ui, pointer)));
+                }
+            } else if resp.drag_released() {
+                self.dragged_node = None;
+                action = MapAction::ChangeWall(id.to_string(), points.to_vec());
+            } else if resp.secondary_clicked() && points.len() > 2 {
+                let mut points = points.to_vec();
+                points.remove(index);
+                action = MapAction::ChangeWall(id.to_string(), points);
+            }
+        }
+        action
+    }
+
+    // While the GM is drawing a wall, clicks on the map add its nodes. Enter or
+    // a double click finishes it, Escape cancels it
+    fn draw_new_wall(&mut self, ui: &mut Ui) -> MapAction {
+        let resp = ui.interact(ui.max_rect(), ui.id().with("new wall"), Sense::click());
+        let pointer = resp.interact_pointer_pos().filter(|_| resp.clicked());
+        let pointer = pointer.map(|pointer| self.to_map(ui, pointer));
+        let hovered = resp.hover_pos();
+        let points = match &mut self.new_wall {
+            Some(points) => points,
+            None => return MapAction::None,
+        };
+        // The second click of a double click lands on the same spot
+        if let Some(pointer) = pointer {
+            if !points
+                .last()
+                .is_some_and(|last| last.distance(pointer) <= 1.0)
+            {
+                points.push(pointer);
+            }
+        }
+
+        let (origin, scale) = (ui.max_rect().min, self.global_scale);
+        let mut preview: Vec<Pos2> = points
+            .iter()
+            .map(|point| origin + point.to_vec2() * scale)
+            .collect();
+        preview.extend(hovered);
+        ui.painter().add(Shape::line(
+            preview,
+            Stroke::new(WALL_WIDTH, SELECTED_WALL_COLOR),
+        ));
+
+        let typing = ui.memory().focus().is_some();
+        let (enter, escape) = {
+            let input = ui.input();
+            (
+                input.key_pressed(Key::Enter),
+                input.key_pressed(Key::Escape),
+            )
+        };
+        if resp.double_clicked() || (enter && !typing) {
+            match self.finish_wall() {
+                Some(points) => MapAction::AddWall(points),
+                None => MapAction::None,
+            }
+        } else {
+            if escape && !typing {
+                self.cancel_wall();
+            }
+            MapAction::None
+        }
+    }
+
+    // Size of a grid cell in map units
+    fn grid_unit(&self, room_state: &RoomState) -> f32 {
+        match (self.map_size, room_state.map().grid) {
+            (Some(size), Some([columns, _])) => size.x / self.global_scale / columns as f32,
+            _ => DEFAULT_GRID_UNIT,
+        }
+    }
+
+    fn effect_shape(&self, ui: &Ui, effect: &Effect, unit: f32, is_selected: bool) -> Shape {
+        let points = effect
+            .outline(unit)
+            .iter()
+            .map(|point| self.to_screen(ui, *point))
+            .collect();
+        let [r, g, b] = effect.color;
+        let fill = Color32::from_rgba_unmultiplied(r, g, b, (effect.opacity * 255.0) as u8);
+        let stroke = if is_selected {
+            Stroke::new(2.0, SELECTED_WALL_COLOR)
+        } else {
+            Stroke::new(1.0, Color32::from_rgb(r, g, b))
+        };
+        Shape::convex_polygon(points, fill, stroke)
+    }
+
+    // Like walls, effects are hit-tested by hand, so that the corners of their
+    // bounding boxes don't take clicks
+    fn place_effect(
+        &mut self,
+        ui: &mut Ui,
+        room_state: &RoomState,
+        id: &str,
+        effect: &Effect,
+        unit: f32,
+        shapes: &mut Vec<Shape>,
+    ) -> MapAction {
+        let read_only = self.is_read_only(room_state, effect.layer);
+        let is_selected = !read_only && self.selected_object.as_deref() == Some(id);
+        let mut effect = match &self.dragged_effect {
+            Some((dragged_id, dragged)) if dragged_id == id => dragged.clone(),
+            _ => Effect {
+                pos: room_state
+                    .map()
+                    .displayed_pos(id, Instant::now())
+                    .unwrap_or(effect.pos),
+                ..effect.clone()
+            },
+        };
+        let shape = self.effect_shape(ui, &effect, unit, is_selected);
+        let rect = shape.visual_bounding_rect();
+        shapes.push(shape);
+        if read_only {
+            return MapAction::None;
+        }
+
+        let mut action = MapAction::None;
+        if is_selected {
+            action = self.draw_effect_handle(ui, id, &effect, unit, shapes);
+            action = action.or(self.draw_effect_ui(ui, room_state, id, &effect, unit, rect));
+        }
+        let is_dragged = matches!(&self.dragged_effect, Some((dragged_id, _)) if dragged_id == id);
+        let is_hovered = ui
+            .ctx()
+            .pointer_hover_pos()
+            .is_some_and(|pointer| effect.contains(self.to_map(ui, pointer), unit));
+        if !is_dragged && !is_hovered {
+            return action;
+        }
+        let resp = ui.interact(rect, ui.id().with(("effect", id)), Sense::click_and_drag());
+        if resp.clicked() || resp.drag_started() {
+            self.selected_object = Some(id.to_string());
+        }
+        if resp.dragged() {
+            effect.pos += resp.drag_delta() / self.global_scale;
+            let now = ui.input().time;
+            action = action.or(self.stream_pos(id, now, effect.pos));
+            self.dragged_effect = Some((id.to_string(), effect));
+        } else if resp.drag_released() {
+            self.dragged_effect = None;
+            self.last_streamed = None;
+            action = action.or(MapAction::Move(id.to_string(), effect.pos));
+        }
+        action
+    }
+
+    // Dragging the handle at the tip of the selected effect turns it around
+    // its origin
+    fn draw_effect_handle(
+        &mut self,
+        ui: &mut Ui,
+        id: &str,
+        effect: &Effect,
+        unit: f32,
+        shapes: &mut Vec<Shape>,
+    ) -> MapAction {
+        if effect.shape == EffectShape::Circle {
+            return MapAction::None;
+        }
+        let center = self.to_screen(ui, effect.tip(unit));
+        shapes.push(Shape::circle_filled(center, NODE_RADIUS, Color32::WHITE));
+        shapes.push(Shape::circle_stroke(
+            center,
+            NODE_RADIUS,
+            Stroke::new(1.0, SELECTED_WALL_COLOR),
+        ));
+        let rect = Rect::from_center_size(center, Vec2::splat(2.0 * NODE_RADIUS + 4.0));
+        let resp = ui.interact(rect, ui.id().with(("effect handle", id)), Sense::drag());
+        if resp.dragged() {
+            if let Some(pointer) = resp.interact_pointer_pos() {
+                let angle = (self.to_map(ui, pointer) - effect.pos).angle();
+                let effect = Effect {
+                    direction: angle.to_degrees().round().rem_euclid(360.0),
+                    ..effect.clone()
+                };
+                self.dragged_effect = Some((id.to_string(), effect));
+            }
+        } else if resp.drag_released() {
+            self.dragged_effect = None;
+            return MapAction::ChangeEffect(id.to_string(), effect.clone());
+        }
+        MapAction::None
+    }
+
+    // Settings of the selected effect, and the tokens in its area
+    fn draw_effect_ui(
+        &self,
+        ui: &mut Ui,
+        room_state: &RoomState,
+        id: &str,
+        effect: &Effect,
+        unit: f32,
+        rect: Rect,
+    ) -> MapAction {
+        let view = self.fog_view(room_state);
+        let mut inside: Vec<&str> = room_state
+            .map()
+            .objects
+            .values()
+            .filter_map(|obj| match obj {
+                MapObject::Token(token)
+                    if self.is_shown(room_state, token.layer)
+                        && !hidden_by_fog(room_state, &view, token) =>
+                {
+                    Some(token)
+                }
+                _ => None,
+            })
+            .filter(|token| effect.contains(room_state.token_center(token), unit))
+            .map(|token| token.name())
+            .collect();
+        inside.sort_unstable();
+
+        let mut action = MapAction::None;
+        let mut changed = effect.clone();
+        RelArea::new((id, 1))
+            .set_dragging(Dragging::Disabled)
+            .set_pos((rect.left_bottom() - ui.max_rect().min + Vec2::new(0.0, 4.0)).to_pos2())
+            .show_inside(ui, |ui| {
+                Frame::popup(ui.style()).show(ui, |ui| {
+                    widgets::effect_settings(ui, id, &mut changed);
+                    if inside.is_empty() {
+                        ui.weak("No tokens inside");
+                    } else {
+                        ui.label(format!("Inside: {}", inside.join(", ")));
+                    }
+                    if ui.button("Delete").clicked() {
+                        action = MapAction::Delete(id.to_string());
+                    }
+                });
+            });
+        if changed != *effect {
+            action = action.or(MapAction::ChangeEffect(id.to_string(), changed));
+        }
+        action
+    }
+
+    // While an effect is being placed, it follows the pointer until the map is
+    // clicked. Escape cancels it
+    fn draw_new_effect(&mut self, ui: &mut Ui, unit: f32) -> MapAction {
+        let resp = ui.interact(ui.max_rect(), ui.id().with("new effect"), Sense::click());
+        let at = |pos: Pos2| Effect {
+            pos: self.to_map(ui, pos),
+            ..self.effect_tool.template.clone()
+        };
+        if let Some(hovered) = resp.hover_pos() {
+            let shape = self.effect_shape(ui, &at(hovered), unit, true);
+            ui.painter().add(shape);
+        }
+        if let Some(pointer) = resp.interact_pointer_pos().filter(|_| resp.clicked()) {
+            let effect = at(pointer);
+            self.effect_tool.placing = false;
+            return MapAction::AddEffect(effect);
+        }
+        let typing = ui.memory().focus().is_some();
+        if ui.input().key_pressed(Key::Escape) && !typing {
+            self.effect_tool.placing = false;
+        }
+        MapAction::None
     }
 
     fn draw_resize_slider(
@@ -126,6 +813,70 @@ impl MapUi {
         }
     }
 
+    // Dragging the handle next to the resize one turns the object around its
+    // center
+    fn draw_rotation_handle(
+        &mut self,
+        obj: &DisplayObject,
+        ui: &mut Ui,
+        resp: &RelAreaResponse<()>,
+    ) -> MapAction {
+        let rect = resp.response.rect;
+        let handle_resp = Area::new("rotate")
+            .current_pos(rect.max + Vec2::new(4.0, -16.0))
+            .show(ui.ctx(), |ui| {
+                let (handle, _) = ui.allocate_exact_size(Vec2::splat(16.0), Sense::hover());
+                ui.painter().circle(
+                    handle.center(),
+                    NODE_RADIUS,
+                    Color32::WHITE,
+                    Stroke::new(2.0, SELECTED_WALL_COLOR),
+                );
+            })
+            .response
+            .on_hover_text("Drag to rotate, or press Q and E");
+        if handle_resp.dragged() {
+            if let Some(pointer) = handle_resp.interact_pointer_pos() {
+                let angle = (pointer - rect.center()).angle().to_degrees();
+                let start = *self.rotation_drag_start.get_or_insert(angle);
+                self.selected_object_rotation = angle - start;
+            }
+        } else if handle_resp.drag_released() {
+            let rotation =
+                self.snap_rotation(obj.map_object.rotation() + self.selected_object_rotation);
+            self.selected_object_rotation = 0.0;
+            self.rotation_drag_start = None;
+            return MapAction::Rotate(obj.id.to_string(), rotation);
+        }
+        MapAction::None
+    }
+
+    // Q turns the selected object counterclockwise, E clockwise
+    fn rotate_with_keys(&self, ui: &Ui, obj: &DisplayObject) -> MapAction {
+        if ui.memory().focus().is_some() {
+            return MapAction::None;
+        }
+        let step = self.rotation_snap.unwrap_or(KEY_ROTATION_STEP);
+        let (q, e) = {
+            let input = ui.input();
+            (input.key_pressed(Key::Q), input.key_pressed(Key::E))
+        };
+        let turn = match (q, e) {
+            (true, false) => -step,
+            (false, true) => step,
+            _ => return MapAction::None,
+        };
+        let rotation = self.snap_rotation(obj.map_object.rotation() + turn);
+        MapAction::Rotate(obj.id.to_string(), rotation)
+    }
+
+    fn snap_rotation(&self, rotation: f32) -> f32 {
+        match self.rotation_snap {
+            Some(step) => (rotation / step).round() * step,
+            None => rotation.round(),
+        }
+    }
+
     fn process_object_response<T>(
         &mut self,
         obj: &DisplayObject,
@@ -147,7 +898,9 @@ impl MapUi {
                     ));
                 }
             };
+            return self.stream_drag(obj, &resp);
         } else if resp.response.drag_released() {
+            self.last_streamed = None;
             let pos = self.snap_to.take().unwrap_or(self.last_dragged_pos);
             return MapAction::Move(
                 obj.id.to_owned(),
@@ -157,17 +910,37 @@ impl MapUi {
         MapAction::None
     }
 
-    fn draw_bg_image(&self, ui: &mut Ui, room_state: &RoomState) -> Option<Response> {
-        match &room_state.map().background_image {
-            Some(path) => Some(
-                room_state
-                    .get_image(&path)
-                    .show_scaled(ui, self.global_scale),
-            ),
-            None => None,
+    // Other players see the object moving while it's being dragged, but not
+    // more often than every DRAG_STREAM_INTERVAL
+    fn stream_drag<T>(&mut self, obj: &DisplayObject, resp: &RelAreaResponse<T>) -> MapAction {
+        let now = resp.response.ctx.input().time;
+        let pos = (resp.current_pos.to_vec2() / self.global_scale).to_pos2();
+        self.stream_pos(obj.id, now, pos)
+    }
+
+    // `pos` is in map coordinates
+    fn stream_pos(&mut self, id: &str, now: f64, pos: Pos2) -> MapAction {
+        match self.last_streamed {
+            Some((time, last_pos)) if now - time < DRAG_STREAM_INTERVAL || last_pos == pos => {
+                MapAction::None
+            }
+            _ => {
+                self.last_streamed = Some((now, pos));
+                MapAction::Drag(id.to_owned(), pos)
+            }
         }
     }
 
+    fn draw_bg_image(&self, ui: &mut Ui, room_state: &RoomState) -> Option<Response> {
+        let path = room_state.map().background_image.as_ref()?;
+        Some(match room_state.download_progress(path) {
+            Some(progress) => widgets::draw_loading_tile(ui, self.global_scale, progress),
+            None => room_state
+                .get_image(path)
+                .show_scaled(ui, self.global_scale),
+        })
+    }
+
     fn calculate_snap_pos(&self, grid_size: [u8; 2], bg_size: Vec2, pos: Pos2, size: Vec2) -> Pos2 {
         let image_center = pos + (size / 2.0);
         let column_width = bg_size.x / grid_size[0] as f32;
@@ -182,10 +955,21 @@ impl MapUi {
 
 enum MapAction {
     Move(String, Pos2),
+    // Intermediate position of an object which is being dragged
+    Drag(String, Pos2),
     Delete(String),
     Rescale(String, f32),
+    // Degrees clockwise
+    Rotate(String, f32),
     UpdateTokenProperty(String, String, String),
     RemoveTokenProperty(String, String),
+    // Nodes of the wall, in map coordinates
+    AddWall(Vec<Pos2>),
+    ChangeWall(String, Vec<Pos2>),
+    // Cells to reveal (true) or hide (false)
+    PaintFog(HashMap<Cell, bool>),
+    AddEffect(Effect),
+    ChangeEffect(String, Effect),
     None,
 }
 
@@ -199,15 +983,25 @@ impl MapAction {
 }
 
 impl MapAction {
-    fn apply(self, room_state: &mut RoomState) {
+    fn apply(self, room_state: &mut RoomState) -> Result<(), DraduError> {
         match self {
             Self::Move(id, pos) => room_state.move_map_object(&id, pos),
+            Self::Drag(id, pos) => {
+                room_state.stream_map_object_pos(&id, pos);
+                Ok(())
+            }
             Self::Delete(id) => room_state.delete_map_object(&id),
             Self::Rescale(id, scale) => room_state.rescale_map_object(&id, scale),
+            Self::Rotate(id, rotation) => room_state.rotate_map_object(&id, rotation),
             Self::UpdateTokenProperty(id, k, v) => room_state.update_token_property(&id, &k, &v),
             Self::RemoveTokenProperty(id, k) => room_state.remove_token_property(&id, &k),
-            Self::None => (),
-        };
+            Self::AddWall(points) => room_state.add_wall(&points),
+            Self::ChangeWall(id, points) => room_state.change_wall(&id, &points),
+            Self::PaintFog(cells) => room_state.paint_fog(&cells),
+            Self::AddEffect(effect) => room_state.add_effect(effect),
+            Self::ChangeEffect(id, effect) => room_state.change_effect(&id, &effect),
+            Self::None => Ok(()),
+        }
     }
 }
 
@@ -218,6 +1012,10 @@ struct DisplayObject<'a> {
     pub map_object: &'a MapObject,
     pub room_state: &'a RoomState,
     pub is_selected: bool,
+    // Spectators and locked layers
+    pub read_only: bool,
+    // Degrees clockwise. It's changed while the object is being rotated
+    pub rotation: f32,
 }
 
 impl<'a> DisplayObject<'a> {
@@ -225,15 +1023,22 @@ impl<'a> DisplayObject<'a> {
         let image = self.room_state.get_image(self.map_object.path());
         let resp = match self.map_object {
             MapObject::Decal(_) | MapObject::Token(_) => RelArea::new(self.id)
-                .set_dragging(Dragging::Prioritized)
-                .set_pos((self.map_object.pos().to_vec2() * self.global_scale).to_pos2())
+                .set_dragging(if self.read_only {
+                    Dragging::Disabled
+                } else {
+                    Dragging::Prioritized
+                })
+                .set_pos((self.displayed_pos().to_vec2() * self.global_scale).to_pos2())
+                .rotate(self.rotation.to_radians())
                 .show_inside(ui, |ui| {
-                    image.show_scaled(
-                        ui,
-                        self.map_object.scale() * self.global_scale * self.rescale_factor,
-                    );
+                    let scale = self.map_object.scale() * self.global_scale * self.rescale_factor;
+                    match self.room_state.download_progress(self.map_object.path()) {
+                        Some(progress) => widgets::draw_loading_tile(ui, scale, progress),
+                        None => ui.add(self.image(ui, image.size_vec2() * scale)),
+                    };
                 }),
-            _ => unimplemented!(),
+            // See MapUi::place_wall and MapUi::place_effect
+            MapObject::Wall(_) | MapObject::Effect(_) => unreachable!("Only images are areas"),
         };
         if self.is_selected {
             RelArea::new((self.id, 0))
@@ -249,12 +1054,35 @@ impl<'a> DisplayObject<'a> {
         resp
     }
 
+    // Image of the object, mirrored and turned around its center
+    fn image(&self, ui: &Ui, size: Vec2) -> Image {
+        let image = self.room_state.get_image(self.map_object.path());
+        let [flip_x, flip_y] = self.map_object.flip();
+        let (left, right) = if flip_x { (1.0, 0.0) } else { (0.0, 1.0) };
+        let (top, bottom) = if flip_y { (1.0, 0.0) } else { (0.0, 1.0) };
+        Image::new(image.texture_id(ui.ctx()), size)
+            .uv(Rect::from_min_max(
+                Pos2::new(left, top),
+                Pos2::new(right, bottom),
+            ))
+            .rotate(self.rotation.to_radians(), Vec2::splat(0.5))
+    }
+
+    // Someone else may be dragging it right now
+    fn displayed_pos(&self) -> Pos2 {
+        self.room_state
+            .map()
+            .displayed_pos(self.id, Instant::now())
+            .unwrap_or_else(|| self.map_object.pos())
+    }
+
     // This will make the object non-interactive and slightly transparent
     pub fn place_as_snapping_guide(&self, ui: &mut Ui, pos: Pos2) {
         let image = self.room_state.get_image(self.map_object.path());
         let size =
             image.size_vec2() * self.map_object.scale() * self.global_scale * self.rescale_factor;
-        let opaque_image = Image::new(image.texture_id(ui.ctx()), size)
+        let opaque_image = self
+            .image(ui, size)
             .tint(Color32::from_rgba_unmultiplied(255, 255, 255, 120));
         let pos = ui.min_rect().min + pos.to_vec2();
         opaque_image.paint_at(ui, Rect::from_min_max(pos, pos + size));
@@ -270,7 +1098,7 @@ impl<'a> DisplayObject<'a> {
         match self.map_object {
             MapObject::Decal(_) => self.draw_delete_button(ui, resp),
             MapObject::Token(token) => self.draw_token_ui(ui, resp, token, ui_state),
-            _ => unimplemented!(),
+            MapObject::Wall(_) | MapObject::Effect(_) => MapAction::None,
         }
     }
 
@@ -303,12 +1131,14 @@ impl<'a> DisplayObject<'a> {
             ui_state.edited_value = None;
         }
 
+        let mut owner_action = MapAction::None;
         Window::new("Token properties")
             .show(ui.ctx(), |ui| {
+                owner_action = self.draw_token_owner(ui, token);
                 if let Some(ref mut key) = ui_state.edited_key {
                     if let Some(ref mut val) = ui_state.edited_value {
                         // Editing value
-                        for (k, v) in token.properties.iter() {
+                        for (k, v) in shown_properties(token) {
                             if k == key {
                                 ui.horizontal(|ui| {
                                     ui.label(format!("{}:", k));
@@ -333,7 +1163,7 @@ impl<'a> DisplayObject<'a> {
                         }
                     } else {
                         // Adding value
-                        for (k, v) in token.properties.iter() {
+                        for (k, v) in shown_properties(token) {
                             ui.label(format!("{}: {}", k, v));
                         }
                         ui.horizontal(|ui| {
@@ -352,7 +1182,7 @@ impl<'a> DisplayObject<'a> {
                     }
                 } else {
                     // Normal display
-                    for (k, v) in token.properties.iter() {
+                    for (k, v) in shown_properties(token) {
                         ui.horizontal(|ui| {
                             ui.label(format!("{}: {}", k, v));
                             if ui.button("✏").clicked() {
@@ -373,6 +1203,7 @@ impl<'a> DisplayObject<'a> {
                 }
             });
 
+        map_action = map_action.or(owner_action);
         map_action = map_action.or(self.draw_token_bars(ui, resp, token));
 
         if let MapAction::UpdateTokenProperty(_, _, _) = map_action {
@@ -383,6 +1214,44 @@ impl<'a> DisplayObject<'a> {
         map_action
     }
 
+    // Only the GM decides who sees through the token, everyone else just
+    // gets to know it
+    fn draw_token_owner(&self, ui: &mut Ui, token: &Token) -> MapAction {
+        let players = self.room_state.players_ref();
+        let name = |id: &str| {
+            players
+                .get(id)
+                .map_or(id.to_string(), |(nickname, _)| nickname.clone())
+        };
+        let owner = token.owner();
+        if !self.room_state.is_master() {
+            if let Some(owner) = owner {
+                ui.label(format!("Owner: {}", name(owner)));
+            }
+            return MapAction::None;
+        }
+        let mut selected = owner.map(|owner| owner.to_string());
+        ComboBox::from_label("Owner")
+            .selected_text(owner.map_or("Nobody".to_string(), name))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut selected, None, "Nobody");
+                for (id, (nickname, _)) in players {
+                    if id != self.room_state.get_user_id() {
+                        ui.selectable_value(&mut selected, Some(id.clone()), nickname);
+                    }
+                }
+            });
+        match selected {
+            Some(id) if Some(id.as_str()) != owner => {
+                MapAction::UpdateTokenProperty(self.id.to_string(), "owner".to_string(), id)
+            }
+            None if owner.is_some() => {
+                MapAction::RemoveTokenProperty(self.id.to_string(), "owner".to_string())
+            }
+            _ => MapAction::None,
+        }
+    }
+
     pub fn draw_token_bars(
         &self,
         ui: &mut Ui,
@@ -467,7 +1336,42 @@ struct DisplayObjectUiState {
     edited_value: Option<String>,
 }
 
-fn narrow_text_edit(buf: &mut String) -> TextEdit {
+// Owner has its own row in the token properties
+fn shown_properties(token: &Token) -> impl Iterator<Item = (&String, &String)> {
+    token.properties.iter().filter(|(k, _)| *k != "owner")
+}
+
+// Players don't see tokens in the fog, unless these are their own
+fn hidden_by_fog(room_state: &RoomState, view: &FogView, token: &Token) -> bool {
+    match (view, &room_state.map().fog) {
+        (FogView::Player(viewer), Some(fog)) => {
+            !token.is_owned_by(viewer.as_deref()) && !fog.is_visible(room_state.token_center(token))
+        }
+        _ => false,
+    }
+}
+
+// Index of the segment of the line closest to the point, if it's within
+// WALL_REACH
+fn nearest_segment(line: &[Pos2], point: Pos2) -> Option<usize> {
+    line.windows(2)
+        .map(|segment| distance_to_segment(point, segment[0], segment[1]))
+        .enumerate()
+        .filter(|(_, distance)| *distance <= WALL_REACH)
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+}
+
+fn distance_to_segment(point: Pos2, a: Pos2, b: Pos2) -> f32 {
+    let ab = b - a;
+    let t = match ab.length_sq() {
+        len if len > 0.0 => ((point - a).dot(ab) / len).clamp(0.0, 1.0),
+        _ => 0.0,
+    };
+    point.distance(a + ab * t)
+}
+
+fn narrow_text_edit<'a>(buf: &'a mut String) -> TextEdit<'a> {
     TextEdit::singleline(buf).desired_width(120.0)
 }
 
@@ -1,19 +1,32 @@
 use eframe::egui;
 use egui::containers::CentralPanel;
 
-use egui::Context;
+use egui::{Color32, Context, TextEdit};
 
-use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use crate::config::Config;
+use crate::net::{JoinOptions, KnownHosts, LanBrowser, RoomAddr, ServerAddr, PROBE_INTERVAL};
 use crate::textures::Textures;
 use crate::ui::SettingsUi;
+use crate::DraduError;
 
 pub struct MenuUi {
     textures: Textures,
     join_addr: String,
+    // Used for rooms from the LAN list too. Both passwords are optional
+    join_password: String,
     new_game_addr: String,
+    new_game_password: String,
+    recording_path: String,
     settings_ui: SettingsUi,
+    // Why we couldn't connect or got disconnected last time
+    error: Option<String>,
+    // Host and fingerprint of a certificate which doesn't match the pinned one
+    changed_cert: Option<(String, String)>,
+    // Finds rooms on the LAN. None if we couldn't get a UDP socket
+    lan_browser: Option<LanBrowser>,
 }
 
 impl MenuUi {
@@ -21,10 +34,24 @@ impl MenuUi {
         MenuUi {
             textures,
             join_addr: String::new(),
+            join_password: String::new(),
             new_game_addr: String::new(),
+            new_game_password: String::new(),
+            recording_path: String::new(),
             settings_ui: SettingsUi::new(config),
+            error: None,
+            changed_cert: None,
+            lan_browser: LanBrowser::new().ok(),
         }
     }
+
+    pub fn set_error(&mut self, error: DraduError) {
+        self.error = Some(error.to_string());
+        self.changed_cert = match error {
+            DraduError::CertificateChanged(host, fingerprint) => Some((host, fingerprint)),
+            _ => None,
+        };
+    }
 }
 
 impl MenuUi {
@@ -42,31 +69,100 @@ impl MenuUi {
             .show(ctx, |ui| {
                 ui.heading("DRADU");
 
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                if let Some((host, fingerprint)) = &self.changed_cert {
+                    // Server could've just got a new certificate. Only the user
+                    // can tell whether that's the case
+                    if ui.button("Trust the new certificate").clicked() {
+                        self.error = KnownHosts::new()
+                            .pin(host, fingerprint)
+                            .err()
+                            .map(|e| e.to_string());
+                        self.changed_cert = None;
+                    }
+                }
+
                 let mut response = MenuAction::None;
 
                 ui.horizontal(|ui| {
                     ui.text_edit_singleline(&mut self.join_addr);
-                    if ui.button("Join").clicked() {
-                        if let Some((addr, room_id)) = self.join_addr.split_once('#') {
-                            if let Ok(addr) = addr.parse::<SocketAddr>() {
-                                response = MenuAction::JoinRoom(addr, room_id.to_string());
-                            }
+                    ui.add(
+                        TextEdit::singleline(&mut self.join_password)
+                            .password(true)
+                            .hint_text("Password")
+                            .desired_width(100.0),
+                    );
+                    let join = ui.button("Join").clicked();
+                    let spectate = ui.button("Spectate").clicked();
+                    if join || spectate {
+                        match self.join_addr.parse::<RoomAddr>() {
+                            Ok(room) => response = join_action(room, &self.join_password, spectate),
+                            Err(e) => self.set_error(e),
                         }
                     }
                 });
+                if let Some(browser) = &mut self.lan_browser {
+                    browser.poll(Instant::now());
+                    // Answers come in without any input from the user
+                    ctx.request_repaint_after(PROBE_INTERVAL);
+                    ui.group(|ui| {
+                        ui.label("Rooms on your network");
+                        let mut rooms = browser.rooms().peekable();
+                        if rooms.peek().is_none() {
+                            ui.weak("Looking for rooms...");
+                        }
+                        for room in rooms {
+                            let players = match room.summary.players {
+                                1 => "1 player".to_string(),
+                                n => format!("{} players", n),
+                            };
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "{} — {}, {}",
+                                    room.summary.name, room.summary.gm, players
+                                ));
+                                if room.summary.password {
+                                    ui.label("🔒")
+                                        .on_hover_text("Takes the password from the field above");
+                                }
+                                if ui.button("Join").clicked() {
+                                    match room.room_address() {
+                                        Ok(room) => {
+                                            response = join_action(room, &self.join_password, false)
+                                        }
+                                        Err(e) => self.error = Some(e.to_string()),
+                                    }
+                                }
+                            });
+                        }
+                    });
+                }
                 ui.horizontal(|ui| {
                     ui.text_edit_singleline(&mut self.new_game_addr);
+                    ui.add(
+                        TextEdit::singleline(&mut self.new_game_password)
+                            .password(true)
+                            .hint_text("Room password")
+                            .desired_width(100.0),
+                    );
                     if ui.button("New game").clicked() {
-                        let server_address = if self.new_game_addr.contains(':') {
-                            self.new_game_addr.parse::<SocketAddr>()
-                        } else {
-                            format!("{}:8889", self.new_game_addr).parse::<SocketAddr>()
-                        };
-                        if let Ok(addr) = server_address {
-                            response = MenuAction::NewRoom(addr);
+                        match self.new_game_addr.parse::<ServerAddr>() {
+                            Ok(addr) => {
+                                let password = non_empty(&self.new_game_password);
+                                response = MenuAction::NewRoom(addr, password);
+                            }
+                            Err(e) => self.set_error(e),
                         }
                     }
                 });
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.recording_path);
+                    if ui.button("Watch recording").clicked() {
+                        response = MenuAction::Replay(PathBuf::from(self.recording_path.trim()));
+                    }
+                });
                 if ui.button("Map creator").clicked() {
                     response = MenuAction::MapCreator;
                 }
@@ -83,8 +179,28 @@ impl MenuUi {
 }
 
 pub enum MenuAction {
-    JoinRoom(SocketAddr, String),
-    NewRoom(SocketAddr),
+    // Address, room ID and what we tell the server when joining
+    JoinRoom(ServerAddr, String, JoinOptions),
+    // Address and the password of the new room
+    NewRoom(ServerAddr, Option<String>),
+    Replay(PathBuf),
     MapCreator,
     None,
 }
+
+// Invite comes with the address, if it's an invite link
+fn join_action(room: RoomAddr, password: &str, spectator: bool) -> MenuAction {
+    let options = JoinOptions {
+        spectator,
+        password: non_empty(password),
+        invite: room.invite,
+    };
+    MenuAction::JoinRoom(room.server, room.room_id, options)
+}
+
+fn non_empty(password: &str) -> Option<String> {
+    match password {
+        "" => None,
+        password => Some(password.to_string()),
+    }
+}
@@ -1,6 +1,6 @@
 use eframe::egui;
 use egui::containers::CentralPanel;
-use egui::{Context, Visuals};
+use egui::{Context, DragValue, Visuals};
 
 use crate::config::{Config, Theme};
 
@@ -44,6 +44,27 @@ impl SettingsUi {
                 config.color = self.color;
             }
 
+            ui.checkbox(&mut config.record_sessions, "Record sessions");
+            ui.checkbox(&mut config.announce_rooms, "Announce my rooms on the LAN");
+
+            ui.horizontal(|ui| {
+                ui.label("Connection timeout");
+                ui.add(
+                    DragValue::new(&mut config.timeout_secs)
+                        .clamp_range(3..=120)
+                        .suffix(" s"),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Image cache size");
+                ui.add(
+                    DragValue::new(&mut config.cache_size_mb)
+                        .clamp_range(0..=16384)
+                        .suffix(" MB"),
+                );
+            });
+
             if ui.button("Back to menu").clicked() {
                 self.is_opened = false;
             }
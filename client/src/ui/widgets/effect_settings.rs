@@ -0,0 +1,46 @@
+use eframe::egui;
+use egui::widgets::{DragValue, Slider};
+use egui::{ComboBox, Ui};
+
+use crate::net::model::EffectShape;
+use crate::state::map::Effect;
+
+const SHAPES: [EffectShape; 4] = [
+    EffectShape::Circle,
+    EffectShape::Cone,
+    EffectShape::Line,
+    EffectShape::Square,
+];
+
+// Everything about an effect but where it is
+pub fn effect_settings(ui: &mut Ui, id_source: &str, effect: &mut Effect) {
+    ComboBox::from_id_source(id_source)
+        .selected_text(effect.shape.to_string())
+        .show_ui(ui, |ui| {
+            for shape in SHAPES {
+                ui.selectable_value(&mut effect.shape, shape, shape.to_string());
+            }
+        });
+    ui.horizontal(|ui| {
+        ui.label("Size:");
+        ui.add(
+            DragValue::new(&mut effect.size)
+                .speed(0.1)
+                .clamp_range(0.5..=100.0)
+                .suffix(" squares"),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Direction:");
+        ui.add(
+            DragValue::new(&mut effect.direction)
+                .clamp_range(0.0..=360.0)
+                .suffix("°"),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Color:");
+        ui.color_edit_button_srgb(&mut effect.color);
+        ui.add(Slider::new(&mut effect.opacity, 0.0..=1.0).text("opacity"));
+    });
+}
@@ -0,0 +1,33 @@
+use eframe::egui;
+use egui::{Align2, Color32, FontId, Rect, Response, Rounding, Sense, Ui, Vec2};
+
+// Same size as the placeholder image
+const TILE_SIZE: f32 = 128.0;
+const FILL_COLOR: Color32 = Color32::from_gray(40);
+const BAR_COLOR: Color32 = Color32::from_gray(80);
+const PROGRESS_COLOR: Color32 = Color32::LIGHT_BLUE;
+
+// Drawn in place of an image which is still being downloaded
+pub fn draw_loading_tile(ui: &mut Ui, scale: f32, progress: f32) -> Response {
+    let (rect, response) = ui.allocate_exact_size(Vec2::splat(TILE_SIZE * scale), Sense::hover());
+    let painter = ui.painter();
+    painter.rect_filled(rect, Rounding::same(4.0), FILL_COLOR);
+
+    let margin = rect.width() / 8.0;
+    let bar = Rect::from_min_max(
+        rect.left_center() + Vec2::new(margin, -margin / 4.0),
+        rect.right_center() + Vec2::new(-margin, margin / 4.0),
+    );
+    let mut filled = bar;
+    filled.set_width(bar.width() * progress.clamp(0.0, 1.0));
+    painter.rect_filled(bar, Rounding::same(2.0), BAR_COLOR);
+    painter.rect_filled(filled, Rounding::same(2.0), PROGRESS_COLOR);
+    painter.text(
+        bar.center_top() - Vec2::new(0.0, margin / 2.0),
+        Align2::CENTER_BOTTOM,
+        format!("{:.0}%", progress * 100.0),
+        FontId::proportional(margin),
+        Color32::WHITE,
+    );
+    response
+}
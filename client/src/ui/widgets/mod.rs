@@ -1,5 +1,9 @@
+mod effect_settings;
 mod grid;
+mod loading_tile;
 mod relarea;
 
+pub use effect_settings::effect_settings;
 pub use grid::draw_grid;
+pub use loading_tile::draw_loading_tile;
 pub use relarea::{Dragging, RelArea, RelAreaResponse};
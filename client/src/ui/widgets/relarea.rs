@@ -2,6 +2,7 @@
 
 use std::{fmt::Debug, hash::Hash};
 
+use eframe::egui::emath::Rot2;
 use eframe::egui::{Align, Align2, Id, Layout, Pos2, Rect, Response, Sense, Ui};
 
 // Can it be dragged with mouse? If set to Prioritized, it will prioritize
@@ -31,6 +32,8 @@ pub struct RelArea {
     // Set with .pos method
     new_pos: Option<Pos2>,
     ignore_bounds: bool,
+    // Radians clockwise, around the center
+    rotation: f32,
 }
 
 impl RelArea {
@@ -47,6 +50,7 @@ impl RelArea {
             default_pos: Pos2 { x: 0.0, y: 0.0 },
             new_pos: None,
             ignore_bounds: false,
+            rotation: 0.0,
         }
     }
 
@@ -114,6 +118,13 @@ impl RelArea {
         self.ignore_bounds = true;
         self
     }
+
+    /// Only the area turned by this many radians around its center can be
+    /// clicked and dragged. The contents have to be drawn turned by it too
+    pub fn rotate(mut self, rotation: f32) -> Self {
+        self.rotation = rotation;
+        self
+    }
 }
 
 impl RelArea {
@@ -173,7 +184,11 @@ impl RelArea {
             Dragging::Disabled => Sense::click(),
             _ => Sense::click_and_drag(),
         };
-        let response = ui.interact(ui.min_rect(), self.id, sense);
+        let response = if self.rotation == 0.0 {
+            ui.interact(ui.min_rect(), self.id, sense)
+        } else {
+            self.interact_turned(&ui, sense)
+        };
         if response.dragged() {
             match self.dragging {
                 Dragging::Enabled => {
@@ -208,6 +223,34 @@ impl RelArea {
             current_pos: pos,
         }
     }
+
+    // The pointer is turned back into the frame of the area to hit-test it.
+    // Response's rect is the unturned one, like for other areas
+    fn interact_turned(&self, ui: &Ui, sense: Sense) -> Response {
+        let rect = ui.min_rect();
+        let rot = Rot2::from_angle(self.rotation);
+        let turn = |pos: Pos2, rot: Rot2| rect.center() + rot * (pos - rect.center());
+        let corners = [
+            rect.left_top(),
+            rect.right_top(),
+            rect.right_bottom(),
+            rect.left_bottom(),
+        ];
+        let bounds = Rect::from_points(&corners.map(|corner| turn(corner, rot)));
+        let hit = ui
+            .ctx()
+            .pointer_hover_pos()
+            .is_some_and(|pointer| rect.contains(turn(pointer, rot.inverse())));
+        let sense = if hit || ui.memory().is_being_dragged(self.id) {
+            sense
+        } else {
+            Sense::hover()
+        };
+        let mut response = ui.interact(bounds, self.id, sense);
+        response.hovered &= hit;
+        response.rect = rect;
+        response
+    }
 }
 
 pub struct RelAreaResponse<T> {
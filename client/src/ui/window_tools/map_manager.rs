@@ -5,10 +5,11 @@ use egui::{Align, Context, Layout, Ui};
 use std::fs::{self, File, ReadDir};
 use std::path::{Path, PathBuf};
 
-use crate::net::{Message, MsgBody, MsgType};
+use crate::net::model::MapDelta;
 use crate::state::{map::MapState, RoomState};
 use crate::ui::Window;
 use crate::utils;
+use crate::DraduError;
 
 pub struct MapManager {
     save_map_input: String,
@@ -39,7 +40,10 @@ impl MapManager {
                             if let Some(stem) = fname.unwrap().path().file_stem() {
                                 ui.label(stem.to_str().unwrap_or("Error"));
                                 if ui.button("Load").clicked() {
-                                    map_handler.load_map(&stem, room_state);
+                                    if let Err(e) = map_handler.load_map(&stem, room_state) {
+                                        room_state
+                                            .show_error(&format!("Could not load the map: {}", e));
+                                    }
                                 }
                             }
                         });
@@ -72,7 +76,10 @@ impl MapManager {
                         ui.label("Overwrite?");
                     }
                     Confirm::Confirmed => {
-                        map_handler.save_map(&self.save_map_input, room_state.map());
+                        if let Err(e) = map_handler.save_map(&self.save_map_input, room_state.map())
+                        {
+                            room_state.show_error(&format!("Could not save the map: {}", e));
+                        }
                         self.save_overwrite_confirm = Confirm::None;
                         self.save_map_input = String::new();
                     }
@@ -106,7 +113,9 @@ impl MapManager {
                 });
             }
             Confirm::Confirmed => {
-                room_state.clear_map();
+                if let Err(e) = room_state.clear_map() {
+                    room_state.show_error(&format!("Could not clear the map: {}", e));
+                }
                 self.map_delete_confirm = Confirm::None;
             }
         }
@@ -179,23 +188,23 @@ impl MapHandler {
 
     fn save_map(&self, name: &str, map: &MapState) -> std::io::Result<()> {
         let path = self.get_map_path_by_name(name)?;
-        let json = map.as_json();
+        let json = map.as_delta().to_json();
         let mut file = File::create(path)?;
         json.write_pretty(&mut file, 2)?;
         Ok(())
     }
 
-    fn load_map<T: AsRef<Path>>(&self, name: T, room_state: &mut RoomState) -> std::io::Result<()> {
+    fn load_map<T: AsRef<Path>>(
+        &self,
+        name: T,
+        room_state: &mut RoomState,
+    ) -> Result<(), DraduError> {
         let path = self.get_map_path_by_name(name)?;
-        let json = match json::parse(&fs::read_to_string(path)?) {
-            Ok(j) => j,
-            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
-        };
-        let mut msg = Message::new(MsgType::Map);
-        msg.attach_body(MsgBody::Json(json));
-        room_state.clear_map();
-        room_state.send_msg(msg);
-        Ok(())
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let json = json::parse(&fs::read_to_string(path)?).map_err(|e| invalid(e.to_string()))?;
+        let delta = MapDelta::from_json(&json).map_err(|e| invalid(e.to_string()))?;
+        room_state.clear_map()?;
+        room_state.send_map_delta(delta)
     }
 
     fn get_map_path_by_name<T: AsRef<Path>>(&self, name: T) -> std::io::Result<PathBuf> {
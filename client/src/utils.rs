@@ -1,4 +1,4 @@
-use eframe::egui::{Color32, ColorImage, Pos2};
+use eframe::egui::{Color32, ColorImage};
 use egui_extras::RetainedImage;
 
 use directories::ProjectDirs;
@@ -7,10 +7,8 @@ use json::JsonValue;
 
 use rand::{distributions::Alphanumeric, Rng};
 
-use std::io::Read;
 use std::path::{Component, Path, PathBuf};
 
-use crate::net::PROTOCOL_VERSION;
 use crate::DraduError;
 
 // Returns a light-purple 128x128 rectangle
@@ -53,16 +51,6 @@ pub fn rgb_to_string(rgb: [u8; 3]) -> String {
     format!("{} {} {}", rgb[0], rgb[1], rgb[2])
 }
 
-pub fn is_compatible_ver(ver: &str) -> Result<bool, ()> {
-    Ok(ver.split_once('.').ok_or(())?.0 == PROTOCOL_VERSION.split_once('.').unwrap().0)
-}
-
-pub fn read_byte(stream: &mut impl Read) -> std::io::Result<u8> {
-    let mut buf = [0];
-    stream.read_exact(&mut buf)?;
-    Ok(buf[0])
-}
-
 pub fn directory_traversal<P: AsRef<Path>>(path: P) -> bool {
     path.as_ref()
         .components()
@@ -78,16 +66,6 @@ pub fn random_id() -> String {
         .collect()
 }
 
-// Accepts Json array with two elements
-pub fn json_to_pos(json: &JsonValue) -> Result<Pos2, ()> {
-    if json.len() == 2 {
-        if let [x, y] = json.members().take(2).collect::<Vec<&JsonValue>>()[..2] {
-            return Ok(Pos2::new(x.as_f32().ok_or(())?, y.as_f32().ok_or(())?));
-        }
-    }
-    Err(())
-}
-
 pub fn local_dir() -> Option<PathBuf> {
     ProjectDirs::from("com.github", "vinegret43", "dradu")
         .and_then(|p| Some(p.data_dir().to_path_buf()))
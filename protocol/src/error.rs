@@ -0,0 +1,47 @@
+use std::error;
+use std::fmt::{Display, Formatter};
+
+use crate::PROTOCOL_VERSION;
+
+#[derive(Debug)]
+pub enum Error {
+    MalformedHeader,
+    BodyTooLarge(usize),
+    BadContentType(String),
+    MalformedBody(String),
+    IncompatibleVersion(String),
+    UnsupportedEncoding(String),
+    // Name of the field and what's wrong with it. See model
+    InvalidField(String, String),
+}
+
+impl Error {
+    // After these the stream can't be split into messages anymore, so the
+    // connection has to be closed. See MessageFramer::next_message()
+    pub fn breaks_framing(&self) -> bool {
+        matches!(
+            self,
+            Self::MalformedHeader | Self::BodyTooLarge(_) | Self::IncompatibleVersion(_)
+        )
+    }
+}
+
+impl error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::MalformedHeader => write!(f, "Received a malformed message header"),
+            Self::BodyTooLarge(len) => write!(f, "Message body is too large ({} bytes)", len),
+            Self::BadContentType(t) => write!(f, "Unknown content type: {}", t),
+            Self::MalformedBody(err) => write!(f, "Malformed message body: {}", err),
+            Self::UnsupportedEncoding(e) => write!(f, "Unsupported content encoding: {}", e),
+            Self::InvalidField(field, reason) => write!(f, "Invalid field `{}`: {}", field, reason),
+            Self::IncompatibleVersion(ver) => write!(
+                f,
+                "Other side uses protocol version {}, which is incompatible with ours ({})",
+                ver, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::str::FromStr;
+
+use crate::message;
+use crate::{Error, Message, MsgBody};
+
+// Headers are a couple hundred bytes at most. If we've buffered this much and
+// still haven't found the empty line, the stream is garbage
+pub const MAX_HEADER_LEN: usize = 16 * 1024;
+// Largest body we're willing to buffer in memory by default (Background images
+// can be big). See MessageFramer::set_max_body_len
+pub const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
+
+// Splits a stream of bytes into messages. Feed it with whatever you've read
+// from the socket using .push(), then call .next_message() until it returns
+// Ok(None). Bytes can be split at any point - a message may arrive in many
+// small pieces, or several messages may arrive in one piece
+pub struct MessageFramer {
+    buf: Vec<u8>,
+    // Header which has already been parsed, but its body hasn't fully arrived yet
+    pending: Option<(Message, usize)>,
+    max_body_len: usize,
+}
+
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageFramer {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            pending: None,
+            max_body_len: MAX_BODY_LEN,
+        }
+    }
+
+    // Bodies longer than this, before or after decompression, are BodyTooLarge
+    pub fn set_max_body_len(&mut self, len: usize) {
+        self.max_body_len = len;
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // Errors about the header (MalformedHeader, BodyTooLarge, IncompatibleVersion)
+    // mean that the framer has lost track of where messages begin, so you
+    // shouldn't use it after that. Body errors (Bad JSON, bad image, unknown
+    // contentType or contentEncoding) are reported after the body has been
+    // consumed, so framing stays intact
+    pub fn next_message(&mut self) -> Result<Option<Message>, Error> {
+        if self.pending.is_none() {
+            match self.take_header()? {
+                Some(header) => self.pending = Some(header),
+                None => return Ok(None),
+            }
+        }
+
+        let len = self.pending.as_ref().unwrap().1;
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+        let (mut msg, _) = self.pending.take().unwrap();
+        let body: Vec<u8> = self.buf.drain(..len).collect();
+        if len > 0 {
+            let encoding = msg.get_prop("contentEncoding").unwrap_or("identity");
+            let body = message::decode_body(encoding, body, self.max_body_len)?;
+            let content_type = msg.get_prop("contentType").unwrap_or("").to_owned();
+            msg.attach_body(MsgBody::from_bytes(&content_type, body)?);
+        }
+        Ok(Some(msg))
+    }
+
+    fn take_header(&mut self) -> Result<Option<(Message, usize)>, Error> {
+        // Tolerating stray newlines between messages
+        let skip = self.buf.iter().take_while(|b| **b == b'\n').count();
+        self.buf.drain(..skip);
+
+        let end = match self.buf.windows(2).position(|w| w == b"\n\n") {
+            Some(i) => i + 2,
+            None if self.buf.len() > MAX_HEADER_LEN => return Err(Error::MalformedHeader),
+            None => return Ok(None),
+        };
+        let header: Vec<u8> = self.buf.drain(..end).collect();
+        let header = std::str::from_utf8(&header).map_err(|_| Error::MalformedHeader)?;
+        check_version(header)?;
+        let msg = Message::from_str(header).map_err(|_| Error::MalformedHeader)?;
+
+        let len = match msg.get_prop("contentLength") {
+            Some(s) => s.parse::<usize>().map_err(|_| Error::MalformedHeader)?,
+            None => 0,
+        };
+        if len > self.max_body_len {
+            return Err(Error::BodyTooLarge(len));
+        }
+        Ok(Some((msg, len)))
+    }
+}
+
+// Message::from_str() rejects incompatible versions too, but we want to tell
+// the user why exactly we can't talk to the server
+fn check_version(header: &str) -> Result<(), Error> {
+    let version = header
+        .strip_prefix("dradu/")
+        .and_then(|s| s.split_whitespace().next())
+        .ok_or(Error::MalformedHeader)?;
+    match crate::is_compatible_ver(version) {
+        Some(true) => Ok(()),
+        Some(false) => Err(Error::IncompatibleVersion(version.to_string())),
+        None => Err(Error::MalformedHeader),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageFramer, MAX_BODY_LEN};
+    use crate::{Error, Message, MsgBody, MsgType};
+
+    fn text_msg(text: &str) -> Vec<u8> {
+        let mut msg = Message::new(MsgType::Msg).set_prop("userId", "123");
+        msg.attach_body(MsgBody::Text(text.to_string()));
+        msg.into_bytes()
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let mut framer = MessageFramer::new();
+        let bytes = text_msg("hello");
+        for b in &bytes[..bytes.len() - 1] {
+            framer.push(&[*b]);
+            assert!(framer.next_message().unwrap().is_none());
+        }
+        framer.push(&bytes[bytes.len() - 1..]);
+        let mut msg = framer.next_message().unwrap().unwrap();
+        assert_eq!(msg.msg_type(), MsgType::Msg);
+        assert_eq!(msg.get_prop("userId"), Some("123"));
+        match msg.take_body() {
+            Some(MsgBody::Text(s)) => assert_eq!(s, "hello"),
+            _ => panic!("Expected a text body"),
+        }
+        assert!(framer.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn concatenated() {
+        let mut framer = MessageFramer::new();
+        let mut bytes = text_msg("first");
+        bytes.extend(Message::new(MsgType::Synced).into_bytes());
+        bytes.extend(text_msg("second"));
+        // Cutting off the last message in the middle of its body
+        let (head, tail) = bytes.split_at(bytes.len() - 3);
+        framer.push(head);
+
+        let mut msg = framer.next_message().unwrap().unwrap();
+        assert!(matches!(msg.take_body(), Some(MsgBody::Text(s)) if s == "first"));
+        let msg = framer.next_message().unwrap().unwrap();
+        assert_eq!(msg.msg_type(), MsgType::Synced);
+        assert!(framer.next_message().unwrap().is_none());
+
+        framer.push(tail);
+        let mut msg = framer.next_message().unwrap().unwrap();
+        assert!(matches!(msg.take_body(), Some(MsgBody::Text(s)) if s == "second"));
+        assert!(framer.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn json_body() {
+        let mut framer = MessageFramer::new();
+        framer.push(b"dradu/0.1 Map\ncontentType:json\ncontentLength:13\n\n{\"grid\": {}}\n");
+        let mut msg = framer.next_message().unwrap().unwrap();
+        match msg.take_body() {
+            Some(MsgBody::Json(json)) => assert!(json.has_key("grid")),
+            _ => panic!("Expected a json body"),
+        }
+    }
+
+    #[test]
+    fn malformed_header() {
+        let mut framer = MessageFramer::new();
+        framer.push(b"http/1.1 GET\n\n");
+        assert!(matches!(framer.next_message(), Err(Error::MalformedHeader)));
+
+        let mut framer = MessageFramer::new();
+        framer.push(b"dradu/0.1 Msg\ncontentLength:lots\n\n");
+        assert!(matches!(framer.next_message(), Err(Error::MalformedHeader)));
+
+        let mut framer = MessageFramer::new();
+        framer.push(&vec![b'a'; super::MAX_HEADER_LEN + 1]);
+        assert!(matches!(framer.next_message(), Err(Error::MalformedHeader)));
+    }
+
+    #[test]
+    fn incompatible_version() {
+        let mut framer = MessageFramer::new();
+        framer.push(b"dradu/1.0 Ok\n\n");
+        assert!(matches!(
+            framer.next_message(),
+            Err(Error::IncompatibleVersion(v)) if v == "1.0"
+        ));
+    }
+
+    #[test]
+    fn oversized_body() {
+        let mut framer = MessageFramer::new();
+        framer.push(format!("dradu/0.1 File\ncontentLength:{}\n\n", MAX_BODY_LEN + 1).as_bytes());
+        assert!(matches!(framer.next_message(), Err(Error::BodyTooLarge(_))));
+
+        let mut framer = MessageFramer::new();
+        framer.set_max_body_len(2);
+        framer.push(b"dradu/0.1 Msg\ncontentLength:3\n\nabc");
+        assert!(matches!(framer.next_message(), Err(Error::BodyTooLarge(3))));
+    }
+
+    #[test]
+    fn bad_body_keeps_framing() {
+        let mut framer = MessageFramer::new();
+        framer.push(b"dradu/0.1 Msg\ncontentType:video\ncontentLength:3\n\nabc");
+        framer.push(b"dradu/0.1 Map\ncontentType:json\ncontentLength:3\n\n{{{");
+        framer.push(&Message::new(MsgType::Synced).into_bytes());
+        assert!(matches!(
+            framer.next_message(),
+            Err(Error::BadContentType(s)) if s == "video"
+        ));
+        assert!(matches!(
+            framer.next_message(),
+            Err(Error::MalformedBody(_))
+        ));
+        let msg = framer.next_message().unwrap().unwrap();
+        assert_eq!(msg.msg_type(), MsgType::Synced);
+    }
+}
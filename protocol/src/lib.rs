@@ -0,0 +1,65 @@
+// Message format of the dradu protocol, shared by the client and the server.
+// See docs/dev/protocol.md
+
+mod error;
+mod framer;
+mod message;
+pub mod model;
+
+use std::net::IpAddr;
+
+pub use error::Error;
+pub use framer::MessageFramer;
+pub use message::{Message, MsgBody, MsgType};
+
+pub const PROTOCOL_VERSION: &str = "0.1";
+// UDP port which servers and hosting clients listen on for LAN discovery
+// probes. See model::Announcement
+pub const DISCOVERY_PORT: u16 = 8890;
+
+// Loopback, private or link-local. Rooms are only announced to these
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            // Unique local fc00::/7 and link-local fe80::/10
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// Versions are compatible if their MAJOR parts are the same. None if `ver`
+// isn't a version at all
+pub fn is_compatible_ver(ver: &str) -> Option<bool> {
+    Some(ver.split_once('.')?.0 == PROTOCOL_VERSION.split_once('.').unwrap().0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_local;
+
+    #[test]
+    fn local_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.20",
+            "169.254.0.5",
+            "::1",
+            "fd12::1",
+            "fe80::1",
+            "::ffff:192.168.1.20",
+        ] {
+            assert!(is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use json::JsonValue;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::string::ToString;
+
+use crate::{Error, PROTOCOL_VERSION};
+
+// Bodies smaller than this aren't worth compressing
+pub const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+#[derive(Clone)]
+pub struct Message {
+    msg_type: MsgType,
+    props: HashMap<String, String>,
+    body: Option<MsgBody>,
+}
+
+impl Message {
+    pub fn new(msg_type: MsgType) -> Self {
+        Message {
+            msg_type,
+            props: HashMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn msg_type(&self) -> MsgType {
+        self.msg_type
+    }
+
+    // Note that this automatically sets contentLength and contentEncoding
+    // properties, so a received message can be sent on as is. Bodies bigger than
+    // COMPRESSION_THRESHOLD are compressed, so only use this if the other side
+    // supports "deflate" capability. Otherwise use .into_plain_bytes()
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.serialize(true)
+    }
+
+    // Same as .into_bytes(), but the body is never compressed
+    pub fn into_plain_bytes(self) -> Vec<u8> {
+        self.serialize(false)
+    }
+
+    fn serialize(self, compress: bool) -> Vec<u8> {
+        let mut string = format!("dradu/{} {}\n", PROTOCOL_VERSION, self.msg_type);
+        for (key, val) in self.props.iter() {
+            if key != "contentEncoding" && key != "contentLength" {
+                string.push_str(&format!("{}:{}\n", key, val));
+            }
+        }
+        let mut body_bytes = match self.body {
+            Some(body) => {
+                if !self.props.contains_key("contentType") {
+                    string.push_str(&format!("contentType:{}\n", body.content_type()));
+                }
+                body.into_bytes()
+            }
+            None => Vec::new(),
+        };
+        if compress && body_bytes.len() >= COMPRESSION_THRESHOLD {
+            string.push_str("contentEncoding:deflate\n");
+            body_bytes = deflate(&body_bytes);
+        }
+        string.push_str(&format!("contentLength:{}\n", body_bytes.len()));
+        string.push('\n');
+
+        let mut bytes = string.into_bytes();
+        bytes.append(&mut body_bytes);
+        bytes
+    }
+
+    pub fn get_prop(&self, prop: &str) -> Option<&str> {
+        self.props.get(prop).map(|s| s.as_str())
+    }
+
+    pub fn set_prop(mut self, key: &str, val: &str) -> Self {
+        self.props.insert(key.to_string(), val.to_string());
+        self
+    }
+
+    pub fn take_body(&mut self) -> Option<MsgBody> {
+        self.body.take()
+    }
+
+    pub fn attach_body(&mut self, body: MsgBody) {
+        self.body = Some(body);
+    }
+}
+
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec can't fail
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Undoes "contentEncoding" of a received body. Output is limited to `max_len`
+// bytes so a tiny malicious body can't eat all the memory
+pub fn decode_body(encoding: &str, bytes: Vec<u8>, max_len: usize) -> Result<Vec<u8>, Error> {
+    match encoding {
+        "identity" => Ok(bytes),
+        "deflate" => {
+            let mut decoded = Vec::new();
+            ZlibDecoder::new(&bytes[..])
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut decoded)
+                .map_err(|e| Error::MalformedBody(e.to_string()))?;
+            if decoded.len() > max_len {
+                return Err(Error::BodyTooLarge(decoded.len()));
+            }
+            Ok(decoded)
+        }
+        _ => Err(Error::UnsupportedEncoding(encoding.to_string())),
+    }
+}
+
+// This only parses the *header*. You'll have to use .attach_body() to add
+// content into the message
+impl FromStr for Message {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.trim().lines();
+        // Parsing the heading
+        let first_line = lines.next().ok_or(())?;
+        let msg_type = match first_line.split_once('/') {
+            Some(("dradu", s)) => match s.split_once(' ') {
+                Some((ver, msg_type)) if crate::is_compatible_ver(ver).ok_or(())? => {
+                    match MsgType::from_str(msg_type) {
+                        Ok(s) => s,
+                        Err(_) => return Err(()),
+                    }
+                }
+                _ => return Err(()),
+            },
+            _ => return Err(()),
+        };
+
+        // Parsing properties
+        let mut props = HashMap::new();
+        for l in lines {
+            match l.split_once(':') {
+                Some((k, v)) => props.insert(k.to_string(), v.trim_start().to_string()),
+                None => break,
+            };
+        }
+
+        Ok(Message {
+            msg_type,
+            props,
+            body: None,
+        })
+    }
+}
+
+// Images come as Bin, since they are decoded only after the whole file has
+// been received
+#[derive(Clone)]
+pub enum MsgBody {
+    Json(JsonValue),
+    Bin(Vec<u8>),
+    Text(String),
+}
+
+impl MsgBody {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Bin(b) => b,
+            Self::Json(json) => json.to_string().into_bytes(),
+            Self::Text(t) => t.into_bytes(),
+        }
+    }
+
+    // Decodes a received body according to its "contentType" property
+    pub fn from_bytes(content_type: &str, bytes: Vec<u8>) -> Result<Self, Error> {
+        match content_type {
+            "json" => {
+                let s =
+                    std::str::from_utf8(&bytes).map_err(|e| Error::MalformedBody(e.to_string()))?;
+                Ok(Self::Json(
+                    json::parse(s).map_err(|e| Error::MalformedBody(e.to_string()))?,
+                ))
+            }
+            "text" => Ok(Self::Text(String::from_utf8_lossy(&bytes).to_string())),
+            "image" | "bin" => Ok(Self::Bin(bytes)),
+            _ => Err(Error::BadContentType(content_type.to_string())),
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match self {
+            MsgBody::Json(_) => "json",
+            MsgBody::Text(_) => "text",
+            MsgBody::Bin(_) => "bin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::Display, strum_macros::EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum MsgType {
+    Join,
+    Init,
+    Quit,
+    Map,
+    Player,
+    Msg,
+    Perm,
+    File,
+    Err,
+    Synced,
+    Ok,
+    Ping,
+    Pong,
+    Invite,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_body, deflate};
+    use crate::{Error, Message, MessageFramer, MsgBody, MsgType, PROTOCOL_VERSION};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    #[test]
+    fn message_into_bytes() {
+        let mut props = HashMap::new();
+        props.insert("userId".to_string(), "123".to_string());
+        let msg = Message {
+            msg_type: MsgType::File,
+            props,
+            body: None,
+        };
+        let bytes = format!(
+            "dradu/{} File\ncontentLength:0\nuserId:123\n\n",
+            PROTOCOL_VERSION
+        )
+        .into_bytes();
+        let bytes2 = format!(
+            "dradu/{} File\nuserId:123\ncontentLength:0\n\n",
+            PROTOCOL_VERSION
+        )
+        .into_bytes();
+        let msg_bytes = msg.into_bytes();
+        assert!(msg_bytes == bytes || msg_bytes == bytes2);
+    }
+
+    fn parse(bytes: &[u8]) -> Message {
+        let mut framer = MessageFramer::new();
+        framer.push(bytes);
+        framer.next_message().unwrap().unwrap()
+    }
+
+    #[test]
+    fn compressed_roundtrip() {
+        let text = "Roll for initiative! ".repeat(1000);
+        let mut msg = Message::new(MsgType::Msg);
+        msg.attach_body(MsgBody::Text(text.clone()));
+        let bytes = msg.into_bytes();
+        assert!(bytes.len() < text.len());
+
+        let mut msg = parse(&bytes);
+        assert_eq!(msg.get_prop("contentEncoding"), Some("deflate"));
+        assert!(matches!(msg.take_body(), Some(MsgBody::Text(s)) if s == text));
+
+        let mut msg = Message::new(MsgType::Msg);
+        msg.attach_body(MsgBody::Text(text.clone()));
+        let mut msg = parse(&msg.into_plain_bytes());
+        assert_eq!(msg.get_prop("contentEncoding"), None);
+        assert!(matches!(msg.take_body(), Some(MsgBody::Text(s)) if s == text));
+    }
+
+    #[test]
+    fn small_bodies_arent_compressed() {
+        let mut msg = Message::new(MsgType::Map);
+        msg.attach_body(MsgBody::Json(json::object! {"grid": {"size": [4, 4]}}));
+        let mut msg = parse(&msg.into_bytes());
+        assert_eq!(msg.get_prop("contentEncoding"), None);
+        match msg.take_body() {
+            Some(MsgBody::Json(json)) => assert_eq!(json["grid"]["size"][0], 4),
+            _ => panic!("Expected a json body"),
+        }
+    }
+
+    #[test]
+    fn bad_encoding() {
+        assert!(matches!(
+            decode_body("zstd", vec![1, 2, 3], 100),
+            Err(Error::UnsupportedEncoding(_))
+        ));
+        assert!(matches!(
+            decode_body("deflate", vec![1, 2, 3], 100),
+            Err(Error::MalformedBody(_))
+        ));
+        // Decompression bombs are rejected
+        let bomb = deflate(&vec![0; 1000]);
+        assert!(matches!(
+            decode_body("deflate", bomb, 100),
+            Err(Error::BodyTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn message_from_str() {
+        let string = "dradu/0.1 File\ncontentLength:0\nuserId:123\n\n\n";
+        let msg = Message::from_str(string).unwrap();
+        assert_eq!(msg.msg_type, MsgType::File);
+        assert_eq!(msg.props["contentLength"], "0");
+        assert_eq!(msg.props["userId"], "123");
+    }
+}
@@ -0,0 +1,1342 @@
+// Typed bodies (And for FILE, properties) of the messages described in
+// docs/dev/protocol.md. Everything is parsed with from_json() and turned back
+// with to_json(). If something is wrong, the error names the offending field,
+// e.g. "abcd.pos"
+
+use json::{object, JsonValue};
+
+use crate::{Error, Message, MsgType};
+
+// Body of JOIN and INIT. Older clients send INIT without a body, which is the
+// same as an empty one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hello {
+    // Only in JOIN
+    pub room_id: Option<String>,
+    // Only set if the client wants to resume its session
+    pub user_id: Option<String>,
+    pub user_cookie: Option<String>,
+    pub version: Option<String>,
+    pub capabilities: Vec<String>,
+    // Only in JOIN. Spectators can watch, but can't change the map or chat
+    pub spectator: bool,
+    // INIT sets the password of the new room, JOIN has to match it
+    pub password: Option<String>,
+    // Only in JOIN. Lets the player in without the password, once
+    pub invite: Option<String>,
+}
+
+impl Hello {
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        if json.is_null() {
+            return Ok(Self::default());
+        }
+        expect_object(json, "")?;
+        Ok(Self {
+            room_id: opt_string(json, "", "roomId")?,
+            user_id: opt_string(json, "", "userId")?,
+            user_cookie: opt_string(json, "", "userCookie")?,
+            version: opt_string(json, "", "version")?,
+            capabilities: string_list(json, "", "capabilities")?,
+            spectator: opt_bool(json, "", "spectator")?.unwrap_or(false),
+            password: opt_string(json, "", "password")?,
+            invite: opt_string(json, "", "invite")?,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {};
+        set_opt(&mut json, "roomId", &self.room_id);
+        set_opt(&mut json, "userId", &self.user_id);
+        set_opt(&mut json, "userCookie", &self.user_cookie);
+        set_opt(&mut json, "version", &self.version);
+        json["capabilities"] = self.capabilities.clone().into();
+        if self.spectator {
+            json["spectator"] = true.into();
+        }
+        set_opt(&mut json, "password", &self.password);
+        set_opt(&mut json, "invite", &self.invite);
+        json
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+// Body of the OK which answers JOIN/INIT
+#[derive(Debug, Clone, PartialEq)]
+pub struct OkResponse {
+    pub user_id: String,
+    pub user_cookie: String,
+    pub nickname: Option<String>,
+    pub color: Option<[u8; 3]>,
+    // Only in response to INIT
+    pub room_id: Option<String>,
+    // Servers which don't send it are the old 0.1 ones
+    pub version: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl OkResponse {
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        expect_object(json, "")?;
+        Ok(Self {
+            user_id: req_string(json, "", "userId")?,
+            user_cookie: req_string(json, "", "userCookie")?,
+            nickname: opt_string(json, "", "nickname")?,
+            color: opt_color(json, "", "color")?,
+            room_id: opt_string(json, "", "roomId")?,
+            version: opt_string(json, "", "version")?,
+            capabilities: string_list(json, "", "capabilities")?,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "userId": self.user_id.clone(),
+            "userCookie": self.user_cookie.clone(),
+        };
+        set_opt(&mut json, "nickname", &self.nickname);
+        if let Some(color) = self.color {
+            json["color"] = color.to_vec().into();
+        }
+        set_opt(&mut json, "roomId", &self.room_id);
+        set_opt(&mut json, "version", &self.version);
+        json["capabilities"] = self.capabilities.clone().into();
+        json
+    }
+}
+
+// Body of ERR. Old servers send ERR without a body
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrResponse {
+    pub code: ErrorCode,
+    // Human readable, can be shown to the user as is
+    pub message: String,
+    // Request which has caused the error
+    pub reference: Option<ErrorRef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorCode {
+    BadRequest,
+    RoomNotFound,
+    PermissionDenied,
+    // JOIN had no password or a wrong one
+    WrongPassword,
+    // Invite was a real one, but has expired
+    InviteExpired,
+    // Invite has let someone in already
+    InviteUsed,
+    // Invite wasn't issued by this room, e.g. a forged or mistyped one
+    InviteInvalid,
+    // Codes we don't know about, e.g. from newer servers
+    Other(String),
+}
+
+// Type of the offending message and the ID of the thing it was about (Object,
+// room, file path, etc.), if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRef {
+    pub msg_type: MsgType,
+    pub id: Option<String>,
+}
+
+impl ErrResponse {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            reference: None,
+        }
+    }
+
+    pub fn about(mut self, msg_type: MsgType, id: Option<&str>) -> Self {
+        self.reference = Some(ErrorRef {
+            msg_type,
+            id: id.map(|id| id.to_string()),
+        });
+        self
+    }
+
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        if json.is_null() {
+            return Ok(Self::new(
+                ErrorCode::Other("unknown".to_string()),
+                "Server has reported an error",
+            ));
+        }
+        expect_object(json, "")?;
+        let reference = match &json["ref"] {
+            JsonValue::Null => None,
+            reference => {
+                expect_object(reference, "ref")?;
+                let msg_type = req_string(reference, "ref", "type")?;
+                Some(ErrorRef {
+                    msg_type: msg_type
+                        .parse()
+                        .map_err(|_| invalid("ref.type", "unknown message type"))?,
+                    id: opt_string(reference, "ref", "id")?,
+                })
+            }
+        };
+        Ok(Self {
+            code: ErrorCode::parse(&req_string(json, "", "code")?),
+            message: req_string(json, "", "message")?,
+            reference,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "code": self.code.as_str(),
+            "message": self.message.clone(),
+        };
+        if let Some(reference) = &self.reference {
+            json["ref"] = object! {"type": reference.msg_type.to_string().to_uppercase()};
+            set_opt(&mut json["ref"], "id", &reference.id);
+        }
+        json
+    }
+}
+
+impl ErrorCode {
+    pub fn parse(code: &str) -> Self {
+        match code {
+            "bad_request" => Self::BadRequest,
+            "room_not_found" => Self::RoomNotFound,
+            "permission_denied" => Self::PermissionDenied,
+            "wrong_password" => Self::WrongPassword,
+            "invite_expired" => Self::InviteExpired,
+            "invite_used" => Self::InviteUsed,
+            "invite_invalid" => Self::InviteInvalid,
+            other => Self::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::BadRequest => "bad_request",
+            Self::RoomNotFound => "room_not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::WrongPassword => "wrong_password",
+            Self::InviteExpired => "invite_expired",
+            Self::InviteUsed => "invite_used",
+            Self::InviteInvalid => "invite_invalid",
+            Self::Other(code) => code,
+        }
+    }
+}
+
+// Body of INVITE. The GM asks for an invite which expires in `expires_in`
+// seconds, the server answers with the same body and the token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub token: Option<String>,
+    pub expires_in: u64,
+}
+
+impl Invite {
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        expect_object(json, "")?;
+        let expires_in = match &json["expiresIn"] {
+            JsonValue::Null => return Err(invalid("expiresIn", "is missing")),
+            val => val
+                .as_u64()
+                .ok_or_else(|| invalid("expiresIn", "expected seconds"))?,
+        };
+        Ok(Self {
+            token: opt_string(json, "", "token")?,
+            expires_in,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {"expiresIn": self.expires_in};
+        set_opt(&mut json, "token", &self.token);
+        json
+    }
+}
+
+// Body of MAP. Null body resets the whole map
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapDelta {
+    Reset,
+    // In the same order as in the message
+    Changes(Vec<(String, MapEntry)>),
+}
+
+// What happens to one ID of the map. See "Special map IDs"
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapEntry {
+    // None removes the grid
+    Grid(Option<[u8; 2]>),
+    // Path to the background image and its hash (See ObjectPatch::hash)
+    Background(String, Option<String>),
+    // None turns the fog of war off
+    Fog(Option<Fog>),
+    // Empty object
+    Remove,
+    Object(ObjectPatch),
+}
+
+impl MapDelta {
+    // Delta with just one entry
+    pub fn single(id: &str, entry: MapEntry) -> Self {
+        Self::Changes(vec![(id.to_string(), entry)])
+    }
+
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        if json.is_null() {
+            return Ok(Self::Reset);
+        }
+        expect_object(json, "")?;
+        json.entries()
+            .map(|(id, entry)| Ok((id.to_string(), MapEntry::from_json(id, entry)?)))
+            .collect::<Result<_, _>>()
+            .map(Self::Changes)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Self::Reset => JsonValue::Null,
+            Self::Changes(changes) => {
+                let mut json = object! {};
+                for (id, entry) in changes {
+                    json[id.as_str()] = entry.to_json();
+                }
+                json
+            }
+        }
+    }
+}
+
+impl MapEntry {
+    fn from_json(id: &str, json: &JsonValue) -> Result<Self, Error> {
+        expect_object(json, id)?;
+        match id {
+            "grid" if json.is_empty() => Ok(Self::Grid(None)),
+            "grid" => {
+                let size = pair(&json["size"], &field(id, "size"))?;
+                let size = size.map(|n| n.as_u8());
+                match size {
+                    [Some(columns), Some(rows)] => Ok(Self::Grid(Some([columns, rows]))),
+                    _ => Err(invalid(
+                        &field(id, "size"),
+                        "expected two integers from 0 to 255",
+                    )),
+                }
+            }
+            "background" => Ok(Self::Background(
+                req_string(json, id, "path")?,
+                opt_string(json, id, "hash")?,
+            )),
+            "fog" if json.is_empty() => Ok(Self::Fog(None)),
+            "fog" => Ok(Self::Fog(Some(Fog::from_json(json)?))),
+            _ if json.is_empty() => Ok(Self::Remove),
+            _ => Ok(Self::Object(ObjectPatch::from_json(id, json)?)),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match self {
+            Self::Grid(None) | Self::Fog(None) | Self::Remove => object! {},
+            Self::Grid(Some(size)) => object! { "size": size.to_vec() },
+            Self::Background(path, hash) => {
+                let mut json = object! { "path": path.clone() };
+                set_opt(&mut json, "hash", hash);
+                json
+            }
+            Self::Fog(Some(fog)) => fog.to_json(),
+            Self::Object(patch) => patch.to_json(),
+        }
+    }
+}
+
+// Width of a fog cell in map units. Cell [x, y] is the square which starts at
+// [x * FOG_CELL, y * FOG_CELL]
+pub const FOG_CELL: f32 = 20.0;
+
+// Fog of war. It's always sent whole
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fog {
+    // Players see the whole map, as if there was no fog
+    pub reveal_all: bool,
+    // Cells the GM has revealed or hidden by hand, whatever the tokens see
+    pub revealed: Vec<[i32; 2]>,
+    pub hidden: Vec<[i32; 2]>,
+}
+
+impl Fog {
+    fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        Ok(Self {
+            reveal_all: opt_bool(json, "fog", "revealAll")?.unwrap_or(false),
+            revealed: cell_list(json, "revealed")?,
+            hidden: cell_list(json, "hidden")?,
+        })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let cells = |cells: &[[i32; 2]]| -> JsonValue {
+            cells
+                .iter()
+                .map(|cell| cell.to_vec())
+                .collect::<Vec<_>>()
+                .into()
+        };
+        object! {
+            "revealAll": self.reveal_all,
+            "revealed": cells(&self.revealed),
+            "hidden": cells(&self.hidden),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::Display, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ObjectKind {
+    Decal,
+    Token,
+    Wall,
+    Effect,
+}
+
+impl ObjectKind {
+    // Layer of the objects which haven't been given one
+    pub fn default_layer(self) -> Layer {
+        match self {
+            Self::Decal | Self::Wall => Layer::Map,
+            Self::Token | Self::Effect => Layer::Tokens,
+        }
+    }
+}
+
+// Objects are drawn layer by layer, in this order, and by their z-index within
+// a layer. Objects on the GM layer are only shown to the GM
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    strum_macros::Display,
+    strum_macros::EnumString,
+)]
+#[strum(serialize_all = "lowercase")]
+pub enum Layer {
+    Background,
+    Map,
+    Tokens,
+    Gm,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 4] = [Layer::Background, Layer::Map, Layer::Tokens, Layer::Gm];
+}
+
+// Area of an effect object, relative to its origin (`pos`) and direction. Sizes
+// are in grid units
+#[derive(Debug, Clone, Copy, PartialEq, strum_macros::Display, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum EffectShape {
+    // Centered on the origin, `size` is the radius
+    Circle,
+    // Starts at the origin, as wide at the end as it's long
+    Cone,
+    // Starts at the origin, one unit wide
+    Line,
+    // Centered on the origin, `size` is the side
+    Square,
+}
+
+// New map object or changes to an existing one. Only the fields which are set
+// are changed. A new object needs at least `kind` and `path`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectPatch {
+    pub kind: Option<ObjectKind>,
+    pub path: Option<String>,
+    // Hex-encoded SHA-256 of the image. Players who have it cached don't have
+    // to request it from the master
+    pub hash: Option<String>,
+    pub pos: Option<[f64; 2]>,
+    pub scale: Option<f64>,
+    // Decals and tokens are turned around their centers, by degrees clockwise,
+    // after they've been mirrored
+    pub rotation: Option<f64>,
+    pub flip_x: Option<bool>,
+    pub flip_y: Option<bool>,
+    // Wall corners, relative to `pos`. Always sent all at once
+    pub nodes: Option<Vec<[f64; 2]>>,
+    // Effect template fields. `direction` is in degrees, clockwise from the
+    // x axis, and `opacity` goes from 0 to 1
+    pub shape: Option<EffectShape>,
+    pub size: Option<f64>,
+    pub color: Option<[u8; 3]>,
+    pub opacity: Option<f64>,
+    pub direction: Option<f64>,
+    pub layer: Option<Layer>,
+    // Objects with a higher z-index are drawn over the ones on the same layer
+    // with a lower one
+    pub z_index: Option<i32>,
+    // Token properties. Null removes the property
+    pub properties: Vec<(String, JsonValue)>,
+}
+
+impl ObjectPatch {
+    fn from_json(id: &str, json: &JsonValue) -> Result<Self, Error> {
+        let kind = match opt_string(json, id, "type")? {
+            Some(kind) => Some(kind.parse().map_err(|_| {
+                invalid(&field(id, "type"), "expected decal, token, wall or effect")
+            })?),
+            None => None,
+        };
+        let shape = match opt_string(json, id, "shape")? {
+            Some(shape) => Some(shape.parse().map_err(|_| {
+                invalid(&field(id, "shape"), "expected circle, cone, line or square")
+            })?),
+            None => None,
+        };
+        let layer = match opt_string(json, id, "layer")? {
+            Some(layer) => Some(layer.parse().map_err(|_| {
+                invalid(
+                    &field(id, "layer"),
+                    "expected background, map, tokens or gm",
+                )
+            })?),
+            None => None,
+        };
+        let z_index = match &json["zIndex"] {
+            JsonValue::Null => None,
+            z => Some(
+                z.as_i32()
+                    .ok_or_else(|| invalid(&field(id, "zIndex"), "expected an integer"))?,
+            ),
+        };
+        let pos = match &json["pos"] {
+            JsonValue::Null => None,
+            pos => Some(point(pos, &field(id, "pos"))?),
+        };
+        let nodes = match &json["nodes"] {
+            JsonValue::Null => None,
+            JsonValue::Array(nodes) => Some(
+                nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, node)| point(node, &format!("{}.nodes.{}", id, i)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(invalid(&field(id, "nodes"), "expected a list of [x, y]")),
+        };
+        let properties = &json["properties"];
+        if !properties.is_null() {
+            expect_object(properties, &field(id, "properties"))?;
+        }
+        Ok(Self {
+            kind,
+            path: opt_string(json, id, "path")?,
+            hash: opt_string(json, id, "hash")?,
+            pos,
+            scale: opt_number(json, id, "scale")?,
+            rotation: opt_number(json, id, "rotation")?,
+            flip_x: opt_bool(json, id, "flipX")?,
+            flip_y: opt_bool(json, id, "flipY")?,
+            nodes,
+            shape,
+            size: opt_number(json, id, "size")?,
+            color: opt_color(json, id, "color")?,
+            opacity: opt_number(json, id, "opacity")?,
+            direction: opt_number(json, id, "direction")?,
+            layer,
+            z_index,
+            properties: properties
+                .entries()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {};
+        if let Some(kind) = self.kind {
+            json["type"] = kind.to_string().into();
+        }
+        set_opt(&mut json, "path", &self.path);
+        set_opt(&mut json, "hash", &self.hash);
+        if let Some(pos) = self.pos {
+            json["pos"] = pos.to_vec().into();
+        }
+        if let Some(scale) = self.scale {
+            json["scale"] = scale.into();
+        }
+        if let Some(rotation) = self.rotation {
+            json["rotation"] = rotation.into();
+        }
+        for (key, flip) in [("flipX", self.flip_x), ("flipY", self.flip_y)] {
+            if let Some(flip) = flip {
+                json[key] = flip.into();
+            }
+        }
+        if let Some(nodes) = &self.nodes {
+            json["nodes"] = nodes
+                .iter()
+                .map(|node| node.to_vec())
+                .collect::<Vec<_>>()
+                .into();
+        }
+        if let Some(shape) = self.shape {
+            json["shape"] = shape.to_string().into();
+        }
+        if let Some(color) = self.color {
+            json["color"] = color.to_vec().into();
+        }
+        for (key, val) in [
+            ("size", self.size),
+            ("opacity", self.opacity),
+            ("direction", self.direction),
+        ] {
+            if let Some(val) = val {
+                json[key] = val.into();
+            }
+        }
+        if let Some(layer) = self.layer {
+            json["layer"] = layer.to_string().into();
+        }
+        if let Some(z_index) = self.z_index {
+            json["zIndex"] = z_index.into();
+        }
+        if !self.properties.is_empty() {
+            let mut properties = object! {};
+            for (k, v) in &self.properties {
+                properties[k.as_str()] = v.clone();
+            }
+            json["properties"] = properties;
+        }
+        json
+    }
+}
+
+// Body of PLAYER. None means that the player has left
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerDelta(pub Vec<(String, Option<PlayerPatch>)>);
+
+// New player or changes to an existing one. New players have both nickname
+// and color set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayerPatch {
+    pub nickname: Option<String>,
+    pub color: Option<[u8; 3]>,
+    // Round trip time to the server in milliseconds, as measured by the player
+    pub latency: Option<u32>,
+    // Only sent along with new players
+    pub spectator: bool,
+}
+
+impl PlayerDelta {
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        expect_object(json, "")?;
+        json.entries()
+            .map(|(id, player)| {
+                expect_object(player, id)?;
+                if player.is_empty() {
+                    return Ok((id.to_string(), None));
+                }
+                let patch = PlayerPatch {
+                    nickname: opt_string(player, id, "nickname")?,
+                    color: opt_color(player, id, "color")?,
+                    latency: opt_latency(player, id)?,
+                    spectator: opt_bool(player, id, "spectator")?.unwrap_or(false),
+                };
+                Ok((id.to_string(), Some(patch)))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {};
+        for (id, patch) in &self.0 {
+            let mut player = object! {};
+            if let Some(patch) = patch {
+                set_opt(&mut player, "nickname", &patch.nickname);
+                if let Some(color) = patch.color {
+                    player["color"] = color.to_vec().into();
+                }
+                if let Some(latency) = patch.latency {
+                    player["latency"] = latency.into();
+                }
+                if patch.spectator {
+                    player["spectator"] = true.into();
+                }
+            }
+            json[id.as_str()] = player;
+        }
+        json
+    }
+}
+
+// Properties of a FILE which requests a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRequest {
+    pub path: String,
+    pub request_id: Option<String>,
+    // How many bytes we already have and the checksum of the file they came
+    // from, if an interrupted transfer is being continued
+    pub resume_from: Option<(usize, String)>,
+}
+
+impl FileRequest {
+    pub fn from_message(msg: &Message) -> Result<Self, Error> {
+        let resume_from = match msg.get_prop("offset") {
+            Some(_) => Some((req_number_prop(msg, "offset")?, req_prop(msg, "checksum")?)),
+            None => None,
+        };
+        Ok(Self {
+            path: req_prop(msg, "path")?,
+            request_id: msg.get_prop("requestId").map(|s| s.to_string()),
+            resume_from,
+        })
+    }
+
+    pub fn to_message(&self) -> Message {
+        let mut msg = Message::new(MsgType::File).set_prop("path", &self.path);
+        if let Some(id) = &self.request_id {
+            msg = msg.set_prop("requestId", id);
+        }
+        if let Some((offset, checksum)) = &self.resume_from {
+            msg = msg
+                .set_prop("offset", &offset.to_string())
+                .set_prop("checksum", checksum);
+        }
+        msg
+    }
+}
+
+// Properties of a FILE which carries a chunk of a file. The chunk itself is
+// the body
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChunk {
+    pub path: String,
+    pub request_id: Option<String>,
+    pub chunk: usize,
+    pub offset: usize,
+    pub total_size: usize,
+    // Hex-encoded SHA-256 of the whole file
+    pub checksum: String,
+}
+
+impl FileChunk {
+    pub fn from_message(msg: &Message) -> Result<Self, Error> {
+        Ok(Self {
+            path: req_prop(msg, "path")?,
+            request_id: msg.get_prop("requestId").map(|s| s.to_string()),
+            chunk: req_number_prop(msg, "chunk")?,
+            offset: req_number_prop(msg, "offset")?,
+            total_size: req_number_prop(msg, "totalSize")?,
+            checksum: req_prop(msg, "checksum")?,
+        })
+    }
+
+    pub fn to_message(&self) -> Message {
+        let msg = Message::new(MsgType::File)
+            .set_prop("path", &self.path)
+            .set_prop("chunk", &self.chunk.to_string())
+            .set_prop("offset", &self.offset.to_string())
+            .set_prop("totalSize", &self.total_size.to_string())
+            .set_prop("checksum", &self.checksum);
+        match &self.request_id {
+            Some(id) => msg.set_prop("requestId", id),
+            None => msg,
+        }
+    }
+}
+
+// Answer to a LAN discovery probe, sent over UDP by a server or by a client
+// which hosts a room on a remote server. See "LAN discovery" in
+// docs/dev/protocol.md
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Announcement {
+    // What players should connect to, e.g. "tls://example.com:8889". Without
+    // it, it's the sender's IP and `port`, so one of them has to be there
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    // Whether `port` expects TLS
+    pub tls: bool,
+    pub rooms: Vec<RoomSummary>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomSummary {
+    pub id: String,
+    pub name: String,
+    // GM's nickname
+    pub gm: String,
+    // Not counting spectators
+    pub players: u32,
+    // Whether joining takes a password
+    pub password: bool,
+}
+
+impl Announcement {
+    // What gets broadcast to find the rooms
+    pub fn probe() -> JsonValue {
+        object! {"discover": crate::PROTOCOL_VERSION}
+    }
+
+    // Probes from incompatible versions are left unanswered
+    pub fn is_probe(json: &JsonValue) -> bool {
+        match json["discover"].as_str() {
+            Some(ver) => crate::is_compatible_ver(ver).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    pub fn from_json(json: &JsonValue) -> Result<Self, Error> {
+        expect_object(json, "")?;
+        let address = opt_string(json, "", "address")?;
+        let port = match opt_number(json, "", "port")? {
+            Some(port) if port.fract() == 0.0 && (1.0..=65535.0).contains(&port) => {
+                Some(port as u16)
+            }
+            Some(_) => return Err(invalid("port", "expected a port number")),
+            None if address.is_none() => return Err(invalid("port", "is missing")),
+            None => None,
+        };
+        let rooms = match &json["rooms"] {
+            JsonValue::Null => Vec::new(),
+            JsonValue::Array(rooms) => rooms
+                .iter()
+                .enumerate()
+                .map(|(i, room)| RoomSummary::from_json(room, &format!("rooms.{}", i)))
+                .collect::<Result<_, _>>()?,
+            _ => return Err(invalid("rooms", "expected a list of rooms")),
+        };
+        Ok(Self {
+            address,
+            port,
+            tls: opt_bool(json, "", "tls")?.unwrap_or(false),
+            rooms,
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "rooms": self.rooms.iter().map(RoomSummary::to_json).collect::<Vec<_>>(),
+        };
+        set_opt(&mut json, "address", &self.address);
+        if let Some(port) = self.port {
+            json["port"] = port.into();
+        }
+        if self.tls {
+            json["tls"] = true.into();
+        }
+        json
+    }
+}
+
+impl RoomSummary {
+    fn from_json(json: &JsonValue, name: &str) -> Result<Self, Error> {
+        expect_object(json, name)?;
+        let players = match opt_number(json, name, "players")? {
+            Some(n) if n >= 0.0 && n.fract() == 0.0 => n as u32,
+            Some(_) => return Err(invalid(&field(name, "players"), "expected a count")),
+            None => 0,
+        };
+        Ok(Self {
+            id: req_string(json, name, "id")?,
+            name: opt_string(json, name, "name")?.unwrap_or_default(),
+            gm: opt_string(json, name, "gm")?.unwrap_or_default(),
+            players,
+            password: opt_bool(json, name, "password")?.unwrap_or(false),
+        })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut json = object! {
+            "id": self.id.clone(),
+            "name": self.name.clone(),
+            "gm": self.gm.clone(),
+            "players": self.players,
+        };
+        if self.password {
+            json["password"] = true.into();
+        }
+        json
+    }
+}
+
+fn invalid(field: &str, reason: &str) -> Error {
+    Error::InvalidField(field.to_string(), reason.to_string())
+}
+
+// Full name of a field, e.g. "abcd.pos"
+fn field(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+fn expect_object(json: &JsonValue, name: &str) -> Result<(), Error> {
+    if json.is_object() {
+        Ok(())
+    } else if name.is_empty() {
+        Err(invalid("body", "expected an object"))
+    } else {
+        Err(invalid(name, "expected an object"))
+    }
+}
+
+fn opt_string(json: &JsonValue, parent: &str, key: &str) -> Result<Option<String>, Error> {
+    match &json[key] {
+        JsonValue::Null => Ok(None),
+        val => match val.as_str() {
+            Some(s) => Ok(Some(s.to_string())),
+            None => Err(invalid(&field(parent, key), "expected a string")),
+        },
+    }
+}
+
+fn req_string(json: &JsonValue, parent: &str, key: &str) -> Result<String, Error> {
+    opt_string(json, parent, key)?.ok_or_else(|| invalid(&field(parent, key), "is missing"))
+}
+
+fn opt_number(json: &JsonValue, parent: &str, key: &str) -> Result<Option<f64>, Error> {
+    match &json[key] {
+        JsonValue::Null => Ok(None),
+        val => match val.as_f64() {
+            Some(n) => Ok(Some(n)),
+            None => Err(invalid(&field(parent, key), "expected a number")),
+        },
+    }
+}
+
+fn opt_bool(json: &JsonValue, parent: &str, key: &str) -> Result<Option<bool>, Error> {
+    match &json[key] {
+        JsonValue::Null => Ok(None),
+        val => match val.as_bool() {
+            Some(b) => Ok(Some(b)),
+            None => Err(invalid(&field(parent, key), "expected true or false")),
+        },
+    }
+}
+
+fn opt_latency(json: &JsonValue, parent: &str) -> Result<Option<u32>, Error> {
+    match &json["latency"] {
+        JsonValue::Null => Ok(None),
+        val => match val.as_u32() {
+            Some(ms) => Ok(Some(ms)),
+            None => Err(invalid(&field(parent, "latency"), "expected milliseconds")),
+        },
+    }
+}
+
+fn opt_color(json: &JsonValue, parent: &str, key: &str) -> Result<Option<[u8; 3]>, Error> {
+    let name = field(parent, key);
+    let reason = "expected [r, g, b] with values from 0 to 255";
+    match &json[key] {
+        JsonValue::Null => Ok(None),
+        JsonValue::Array(rgb) if rgb.len() == 3 => {
+            match [rgb[0].as_u8(), rgb[1].as_u8(), rgb[2].as_u8()] {
+                [Some(r), Some(g), Some(b)] => Ok(Some([r, g, b])),
+                _ => Err(invalid(&name, reason)),
+            }
+        }
+        _ => Err(invalid(&name, reason)),
+    }
+}
+
+// Array of exactly two elements
+fn pair<'a>(json: &'a JsonValue, name: &str) -> Result<[&'a JsonValue; 2], Error> {
+    match json {
+        JsonValue::Array(arr) if arr.len() == 2 => Ok([&arr[0], &arr[1]]),
+        _ => Err(invalid(name, "expected an array of two elements")),
+    }
+}
+
+fn point(json: &JsonValue, name: &str) -> Result<[f64; 2], Error> {
+    match pair(json, name)?.map(|n| n.as_f64()) {
+        [Some(x), Some(y)] => Ok([x, y]),
+        _ => Err(invalid(name, "expected [x, y]")),
+    }
+}
+
+// Missing list is the same as an empty one
+fn cell_list(json: &JsonValue, key: &str) -> Result<Vec<[i32; 2]>, Error> {
+    match &json[key] {
+        JsonValue::Null => Ok(Vec::new()),
+        JsonValue::Array(arr) => arr
+            .iter()
+            .map(|cell| match cell {
+                JsonValue::Array(xy) if xy.len() == 2 => Some([xy[0].as_i32()?, xy[1].as_i32()?]),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(&field("fog", key), "expected a list of [x, y] integers")),
+        _ => Err(invalid(
+            &field("fog", key),
+            "expected a list of [x, y] integers",
+        )),
+    }
+}
+
+// Missing list is the same as an empty one
+fn string_list(json: &JsonValue, parent: &str, key: &str) -> Result<Vec<String>, Error> {
+    match &json[key] {
+        JsonValue::Null => Ok(Vec::new()),
+        JsonValue::Array(arr) => arr
+            .iter()
+            .map(|s| s.as_str().map(|s| s.to_string()))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(&field(parent, key), "expected a list of strings")),
+        _ => Err(invalid(&field(parent, key), "expected a list of strings")),
+    }
+}
+
+fn set_opt(json: &mut JsonValue, key: &str, val: &Option<String>) {
+    if let Some(val) = val {
+        json[key] = val.clone().into();
+    }
+}
+
+fn req_prop(msg: &Message, key: &str) -> Result<String, Error> {
+    msg.get_prop(key)
+        .map(|s| s.to_string())
+        .ok_or_else(|| invalid(key, "is missing"))
+}
+
+fn req_number_prop(msg: &Message, key: &str) -> Result<usize, Error> {
+    req_prop(msg, key)?
+        .parse()
+        .map_err(|_| invalid(key, "expected a non-negative integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use json::JsonValue;
+
+    use super::{
+        Announcement, EffectShape, ErrResponse, ErrorCode, FileChunk, FileRequest, Hello, Invite,
+        Layer, MapDelta, MapEntry, ObjectKind, OkResponse, PlayerDelta,
+    };
+    use crate::{Error, Message, MsgType};
+
+    // Parses the text, turns it into a typed body and back, and checks that
+    // nothing has changed
+    fn roundtrip<T>(text: &str, from: fn(&JsonValue) -> Result<T, Error>, to: fn(&T) -> JsonValue) {
+        let json = json::parse(text).unwrap();
+        let body = from(&json).unwrap();
+        assert_eq!(to(&body), json, "{}", text);
+    }
+
+    fn error_field<T: std::fmt::Debug>(result: Result<T, Error>) -> String {
+        match result {
+            Err(Error::InvalidField(field, _)) => field,
+            other => panic!("Expected an InvalidField error, got {:?}", other),
+        }
+    }
+
+    // The examples below are the ones from docs/dev/protocol.md, with the
+    // placeholders filled in
+
+    #[test]
+    fn join_and_init() {
+        roundtrip(
+            r#"{"roomId": "room", "userId": "abcd", "userCookie": "cookie",
+                "version": "0.1", "capabilities": ["resume", "deflate"]}"#,
+            Hello::from_json,
+            Hello::to_json,
+        );
+        roundtrip(
+            r#"{"version": "0.1", "capabilities": ["resume"]}"#,
+            Hello::from_json,
+            Hello::to_json,
+        );
+        roundtrip(
+            r#"{"roomId": "room", "capabilities": [], "spectator": true}"#,
+            Hello::from_json,
+            Hello::to_json,
+        );
+        roundtrip(
+            r#"{"roomId": "room", "capabilities": [], "password": "swordfish",
+                "invite": "token"}"#,
+            Hello::from_json,
+            Hello::to_json,
+        );
+        // Older clients send INIT without a body
+        assert_eq!(
+            Hello::from_json(&JsonValue::Null).unwrap(),
+            Hello::default()
+        );
+        let hello = json::parse(r#"{"capabilities": "resume"}"#).unwrap();
+        assert_eq!(error_field(Hello::from_json(&hello)), "capabilities");
+    }
+
+    #[test]
+    fn ok() {
+        // JOIN
+        roundtrip(
+            r#"{"userId": "abcd", "userCookie": "cookie", "color": [255, 20, 20],
+                "nickname": "Bob", "version": "0.1", "capabilities": ["resume"]}"#,
+            OkResponse::from_json,
+            OkResponse::to_json,
+        );
+        // INIT
+        roundtrip(
+            r#"{"userId": "abcd", "userCookie": "cookie", "color": [255, 20, 20],
+                "nickname": "Master", "roomId": "room", "version": "0.1",
+                "capabilities": ["resume", "deflate"]}"#,
+            OkResponse::from_json,
+            OkResponse::to_json,
+        );
+        let ok = json::parse(r#"{"userId": "abcd"}"#).unwrap();
+        assert_eq!(error_field(OkResponse::from_json(&ok)), "userCookie");
+        let ok = json::parse(r#"{"userId": "a", "userCookie": "c", "color": [1, 2]}"#).unwrap();
+        assert_eq!(error_field(OkResponse::from_json(&ok)), "color");
+    }
+
+    #[test]
+    fn err() {
+        roundtrip(
+            r#"{"code": "room_not_found", "message": "Room abcd doesn't exist",
+                "ref": {"type": "JOIN", "id": "abcd"}}"#,
+            ErrResponse::from_json,
+            ErrResponse::to_json,
+        );
+        roundtrip(
+            r#"{"code": "from_the_future", "message": "Something went wrong"}"#,
+            ErrResponse::from_json,
+            ErrResponse::to_json,
+        );
+        let err = json::parse(r#"{"code": "invite_expired", "message": ""}"#).unwrap();
+        assert_eq!(
+            ErrResponse::from_json(&err).unwrap().code,
+            ErrorCode::InviteExpired
+        );
+        let err = json::parse(r#"{"code": "invite_invalid", "message": ""}"#).unwrap();
+        assert_eq!(
+            ErrResponse::from_json(&err).unwrap().code,
+            ErrorCode::InviteInvalid
+        );
+        let old = ErrResponse::from_json(&JsonValue::Null).unwrap();
+        assert_eq!(old.code, ErrorCode::Other("unknown".to_string()));
+        let err = json::parse(r#"{"code": "bad_request", "message": "", "ref": {"type": "JUMP"}}"#);
+        assert_eq!(
+            error_field(ErrResponse::from_json(&err.unwrap())),
+            "ref.type"
+        );
+    }
+
+    #[test]
+    fn map() {
+        let text = r#"{
+            "itemIdThatDoesntExistYet": {
+                "type": "token",
+                "path": "path/to/image.png",
+                "layer": "gm",
+                "zIndex": -2,
+                "scale": 1.5,
+                "pos": [10, 20.5],
+                "properties": {
+                    "health": 10,
+                    "max_health": 10,
+                    "armor": 10,
+                    "conditions": ["shocked", "bleeding", "etc."]
+                }
+            },
+            "movedItemId": {"pos": [30, 40]},
+            "rescaledItemId": {"pos": [30, 40], "scale": 0.5},
+            "deletedItemId": {},
+            "decalId": {"type": "decal", "path": "decal.png", "hash": "ab12", "rotation": 22.5,
+                "flipX": true, "flipY": false},
+            "wallId": {"type": "wall", "pos": [5, 5], "nodes": [[0, 0], [10, 0], [10, 20.5]]},
+            "background": {"path": "background.png", "hash": "cd34"},
+            "grid": {"size": [16, 9]},
+            "effectId": {"type": "effect", "pos": [50, 60], "shape": "cone", "size": 6,
+                "color": [255, 128, 0], "opacity": 0.5, "direction": 90}
+        }"#;
+        roundtrip(text, MapDelta::from_json, MapDelta::to_json);
+        roundtrip(r#"{"grid": {}}"#, MapDelta::from_json, MapDelta::to_json);
+        roundtrip(r#"{"fog": {}}"#, MapDelta::from_json, MapDelta::to_json);
+        roundtrip(
+            r#"{"fog": {"revealAll": false, "revealed": [[0, 1]], "hidden": [[-2, 3], [4, 5]]}}"#,
+            MapDelta::from_json,
+            MapDelta::to_json,
+        );
+        roundtrip("null", MapDelta::from_json, MapDelta::to_json);
+
+        let delta = MapDelta::from_json(&json::parse(text).unwrap()).unwrap();
+        let changes = match delta {
+            MapDelta::Changes(changes) => changes,
+            MapDelta::Reset => panic!("Not a reset"),
+        };
+        assert_eq!(changes[0].0, "itemIdThatDoesntExistYet");
+        match &changes[0].1 {
+            MapEntry::Object(patch) => {
+                assert_eq!(patch.kind, Some(ObjectKind::Token));
+                assert_eq!(patch.layer, Some(Layer::Gm));
+                assert_eq!(patch.z_index, Some(-2));
+                assert_eq!(patch.pos, Some([10.0, 20.5]));
+            }
+            _ => panic!("Expected an object"),
+        }
+        assert_eq!(changes[3].1, MapEntry::Remove);
+        match &changes[4].1 {
+            MapEntry::Object(patch) => {
+                assert_eq!(patch.rotation, Some(22.5));
+                assert_eq!((patch.flip_x, patch.flip_y), (Some(true), Some(false)));
+            }
+            _ => panic!("Expected an object"),
+        }
+        match &changes[5].1 {
+            MapEntry::Object(patch) => {
+                assert_eq!(patch.kind, Some(ObjectKind::Wall));
+                assert_eq!(patch.nodes.as_ref().unwrap()[2], [10.0, 20.5]);
+            }
+            _ => panic!("Expected an object"),
+        }
+        assert_eq!(
+            changes[6].1,
+            MapEntry::Background("background.png".to_string(), Some("cd34".to_string()))
+        );
+        assert_eq!(changes[7].1, MapEntry::Grid(Some([16, 9])));
+        match &changes[8].1 {
+            MapEntry::Object(patch) => {
+                assert_eq!(patch.shape, Some(EffectShape::Cone));
+                assert_eq!(patch.color, Some([255, 128, 0]));
+                assert_eq!(patch.direction, Some(90.0));
+            }
+            _ => panic!("Expected an object"),
+        }
+
+        let errors = [
+            (r#"{"abcd": {"pos": [1]}}"#, "abcd.pos"),
+            (r#"{"abcd": {"pos": ["a", 1]}}"#, "abcd.pos"),
+            (r#"{"abcd": {"type": "dragon"}}"#, "abcd.type"),
+            (r#"{"abcd": {"scale": "big"}}"#, "abcd.scale"),
+            (r#"{"abcd": {"properties": []}}"#, "abcd.properties"),
+            (r#"{"abcd": {"nodes": [0, 0]}}"#, "abcd.nodes.0"),
+            (r#"{"abcd": {"nodes": [[0, 0], [1]]}}"#, "abcd.nodes.1"),
+            (r#"{"abcd": {"nodes": {}}}"#, "abcd.nodes"),
+            (r#"{"abcd": {"shape": "star"}}"#, "abcd.shape"),
+            (r#"{"abcd": {"layer": "top"}}"#, "abcd.layer"),
+            (r#"{"abcd": {"rotation": "left"}}"#, "abcd.rotation"),
+            (r#"{"abcd": {"flipX": 1}}"#, "abcd.flipX"),
+            (r#"{"abcd": {"zIndex": 1.5}}"#, "abcd.zIndex"),
+            (r#"{"abcd": {"color": [0, 0, 256]}}"#, "abcd.color"),
+            (r#"{"abcd": {"direction": "up"}}"#, "abcd.direction"),
+            (r#"{"abcd": 5}"#, "abcd"),
+            (r#"{"grid": {"size": [300, 2]}}"#, "grid.size"),
+            (r#"{"background": {}}"#, "background.path"),
+            (r#"{"fog": {"revealAll": 1}}"#, "fog.revealAll"),
+            (r#"{"fog": {"hidden": [[1.5, 2]]}}"#, "fog.hidden"),
+            (r#"{"fog": {"revealed": [1, 2]}}"#, "fog.revealed"),
+            ("[]", "body"),
+        ];
+        for (text, field) in errors {
+            let json = json::parse(text).unwrap();
+            assert_eq!(error_field(MapDelta::from_json(&json)), field, "{}", text);
+        }
+    }
+
+    #[test]
+    fn players() {
+        roundtrip(
+            r#"{
+                "playerId": {"nickname": "player_nickname", "color": [1, 2, 3]},
+                "renamedPlayerId": {"nickname": "new_nickname"},
+                "recoloredPlayerId": {"color": [4, 5, 6]},
+                "pingedPlayerId": {"latency": 42},
+                "spectatorId": {"nickname": "Eve", "color": [7, 8, 9], "spectator": true},
+                "leftPlayerId": {}
+            }"#,
+            PlayerDelta::from_json,
+            PlayerDelta::to_json,
+        );
+        let json = json::parse(r#"{"abcd": {"nickname": 5}}"#).unwrap();
+        assert_eq!(error_field(PlayerDelta::from_json(&json)), "abcd.nickname");
+        let json = json::parse(r#"{"abcd": {"spectator": "yes"}}"#).unwrap();
+        assert_eq!(error_field(PlayerDelta::from_json(&json)), "abcd.spectator");
+        let json = json::parse(r#"{"abcd": {"latency": -1}}"#).unwrap();
+        assert_eq!(error_field(PlayerDelta::from_json(&json)), "abcd.latency");
+    }
+
+    #[test]
+    fn file_props() {
+        let request = Message::new(MsgType::File)
+            .set_prop("path", "map.png")
+            .set_prop("requestId", "req")
+            .set_prop("offset", "1024")
+            .set_prop("checksum", "abcd");
+        let parsed = FileRequest::from_message(&request).unwrap();
+        assert_eq!(parsed.resume_from, Some((1024, "abcd".to_string())));
+        assert_eq!(
+            FileRequest::from_message(&parsed.to_message()).unwrap(),
+            parsed
+        );
+
+        let chunk = Message::new(MsgType::File)
+            .set_prop("path", "map.png")
+            .set_prop("requestId", "req")
+            .set_prop("chunk", "1")
+            .set_prop("offset", "262144")
+            .set_prop("totalSize", "300000")
+            .set_prop("checksum", "abcd");
+        let parsed = FileChunk::from_message(&chunk).unwrap();
+        assert_eq!(parsed.total_size, 300000);
+        assert_eq!(
+            FileChunk::from_message(&parsed.to_message()).unwrap(),
+            parsed
+        );
+
+        let broken = Message::new(MsgType::File)
+            .set_prop("path", "map.png")
+            .set_prop("offset", "-5");
+        assert_eq!(error_field(FileRequest::from_message(&broken)), "offset");
+        assert_eq!(error_field(FileChunk::from_message(&broken)), "chunk");
+    }
+
+    #[test]
+    fn invite() {
+        roundtrip(r#"{"expiresIn": 3600}"#, Invite::from_json, Invite::to_json);
+        roundtrip(
+            r#"{"expiresIn": 3600, "token": "abcd"}"#,
+            Invite::from_json,
+            Invite::to_json,
+        );
+        let invite = json::parse(r#"{"expiresIn": -5}"#).unwrap();
+        assert_eq!(error_field(Invite::from_json(&invite)), "expiresIn");
+    }
+
+    #[test]
+    fn discovery() {
+        assert!(Announcement::is_probe(&Announcement::probe()));
+        assert!(!Announcement::is_probe(
+            &json::parse(r#"{"discover": "5.0"}"#).unwrap()
+        ));
+        roundtrip(
+            r#"{"port": 8889, "rooms": [{"id": "abcd", "name": "Dungeon", "gm": "Master",
+                "players": 3, "password": true}]}"#,
+            Announcement::from_json,
+            Announcement::to_json,
+        );
+        roundtrip(
+            r#"{"address": "tls://example.com:8889", "rooms": []}"#,
+            Announcement::from_json,
+            Announcement::to_json,
+        );
+        let announcement = json::parse(r#"{"port": 70000}"#).unwrap();
+        assert_eq!(error_field(Announcement::from_json(&announcement)), "port");
+        let announcement = json::parse(r#"{"rooms": []}"#).unwrap();
+        assert_eq!(error_field(Announcement::from_json(&announcement)), "port");
+        let announcement = json::parse(r#"{"port": 1, "rooms": [{"name": "x"}]}"#).unwrap();
+        assert_eq!(
+            error_field(Announcement::from_json(&announcement)),
+            "rooms.0.id"
+        );
+    }
+}
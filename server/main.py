@@ -2,6 +2,8 @@
 
 
 PROTOCOL_VERSION = "0.1"
+# Optional protocol features this server supports. See docs/dev/protocol.md
+CAPABILITIES = ["resume", "deflate"]
 
 
 import socket
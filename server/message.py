@@ -1,7 +1,13 @@
+import zlib
 import socket
 
 from main import PROTOCOL_VERSION
 
+# Bodies smaller than this aren't worth compressing
+COMPRESSION_THRESHOLD = 4 * 1024
+# Largest body we're willing to inflate
+MAX_BODY_LEN = 256 * 1024 * 1024
+
 
 class Message:
     def __init__(self, msg_type: str, props={}, body: bytes = b""):
@@ -35,12 +41,27 @@ class Message:
         msg.content_length = content_length
         return msg
 
-    def send(self, sock: socket.socket) -> int:
+    # Undoes contentEncoding of a received body
+    def decompress(self):
+        encoding = self.props.pop("contentEncoding", "identity")
+        if encoding == "deflate":
+            decompressor = zlib.decompressobj()
+            self.body = decompressor.decompress(self.body, MAX_BODY_LEN)
+            assert not decompressor.unconsumed_tail, "Body is too large"
+        else:
+            assert encoding == "identity", f"Unsupported encoding {encoding}"
+
+    # Only pass compress=True if the receiver supports "deflate" capability
+    def send(self, sock: socket.socket, compress: bool = False):
+        body = self.body
         header = f"dradu/{PROTOCOL_VERSION} {self.msg_type}\n"
         for key, val in self.props.items():
             header += f"{key}:{val}\n"
-        header += f"contentLength:{len(self.body)}"
-        return sock.send(header.encode() + b"\n\n" + self.body)
+        if compress and len(body) >= COMPRESSION_THRESHOLD:
+            body = zlib.compress(body)
+            header += "contentEncoding:deflate\n"
+        header += f"contentLength:{len(body)}"
+        sock.sendall(header.encode() + b"\n\n" + body)
 
 
 def is_compatible_protocol_ver(ver: str):
@@ -2,6 +2,7 @@ import json
 import socket
 
 import utils
+from main import PROTOCOL_VERSION, CAPABILITIES
 
 
 class Player:
@@ -13,16 +14,32 @@ class Player:
         self.nickname = ""
         self.color = [255, 255, 255]
         self.is_connected = True
+        # Capabilities supported by both the server and this player's client
+        self.capabilities = []
+
+    # Takes client's capabilities from the body of JOIN/INIT
+    def negotiate(self, body: dict):
+        self.capabilities = [
+            c for c in body.get("capabilities", []) if c in CAPABILITIES
+        ]
+
+    def supports(self, capability: str) -> bool:
+        return capability in self.capabilities
+
+    # Body of the OK message which is sent in response to JOIN/INIT
+    def ok_json(self, **extra) -> dict:
+        return {
+            "userId": self.id,
+            "userCookie": self.cookie,
+            "nickname": self.nickname,
+            "color": self.color,
+            "version": PROTOCOL_VERSION,
+            "capabilities": CAPABILITIES,
+            **extra,
+        }
 
     def to_json(self) -> str:
-        return json.dumps(
-            {
-                "userId": self.id,
-                "userCookie": self.cookie,
-                "nickname": self.nickname,
-                "color": self.color,
-            }
-        )
+        return json.dumps(self.ok_json())
 
 
 def generate_id() -> str:
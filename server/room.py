@@ -1,12 +1,17 @@
 import json
+import time
 import select
 import socket
 
 import utils
-from player import Player, generate_id
+from player import Player, generate_id, generate_cookie
 from message import Message
 
 
+# How long players who lost connection can come back and resume their session
+RESUME_TIMEOUT = 60.0
+
+
 class Room:
     def __init__(self, master: Player, room_id: str):
         self.id = room_id
@@ -15,15 +20,7 @@ class Room:
         Message(
             "Ok",
             {"contentType": "json"},
-            json.dumps(
-                {
-                    "userId": master.id,
-                    "userCookie": master.cookie,
-                    "color": master.color,
-                    "nickname": master.nickname,
-                    "roomId": room_id,
-                }
-            ).encode(),
+            json.dumps(master.ok_json(roomId=room_id)).encode(),
         ).send(master.sock)
         # TODO: Send default permissions
         Message("Synced").send(master.sock)
@@ -32,8 +29,11 @@ class Room:
         self.players = [master]
         self.player_sockets = [master.sock]
         self.pending_players = []
+        # Players who lost connection: id -> (Player, time of disconnect)
+        self.departed = {}
         self.player_counter = 1
-        self.file_requests = []
+        # Server's request ID -> (Requester's socket, Requester's request ID)
+        self.file_requests = {}
         self.map = {}
         self.permissions = {}
 
@@ -42,6 +42,8 @@ class Room:
         while 1:
             for _ in range(len(self.pending_players)):
                 self.process_new_player(self.pending_players.pop())
+            if self.is_abandoned():
+                return
             # Setting timeout since we also have to process new players
             socks = select.select(self.player_sockets, [], [], 1.0)[0]
             for sock in socks:
@@ -54,6 +56,7 @@ class Room:
                         if msg.content_length
                         else b""
                     )
+                    msg.decompress()
                     if msg.msg_type == "Map":
                         # TODO: Check permissions
                         delta = self.update_map(json.loads(msg.body))
@@ -64,28 +67,15 @@ class Room:
                         )
                         for sock in self.player_sockets:
                             # FIXME: Try/except for socket disconnect
-                            new_msg.send(sock)
+                            new_msg.send(sock, self.can_compress(sock))
                     elif msg.msg_type == "File":
-                        path = msg.props["path"]
                         if sock is not self.master.sock:
-                            request = Message("File", {"path": path})
-                            self.file_requests.append((request, sock))
-                            request.send(self.master.sock)
+                            self.forward_file_request(msg, sock)
                         else:
-                            requests = filter(
-                                lambda r: r[0].props["path"] == path,
-                                self.file_requests,
-                            )
-                            for r in requests:
-                                Message(
-                                    "File",
-                                    {"path": path, "contentType": "image"},
-                                    msg.body,
-                                ).send(r[1])
-                                self.file_requests.remove(r)
+                            self.relay_chunk(msg)
                     elif msg.msg_type == "Quit":
                         self.remove_player(index)
-                        if not self.players:
+                        if self.is_abandoned():
                             return
                     elif msg.msg_type == "Msg":
                         if msg.body.startswith(b"/"):
@@ -104,8 +94,8 @@ class Room:
                                 msg_to_send.send(i)
                 except BaseException as e:
                     print(e)
-                    self.remove_player(index)
-                    if not self.players:
+                    self.remove_player(index, resumable=True)
+                    if self.is_abandoned():
                         return
                     continue
 
@@ -166,15 +156,54 @@ class Room:
                 for sock in self.player_sockets:
                     msg.send(sock)
 
+    # Requests get IDs of their own, so players can't mess with each other's
+    # transfers
+    def forward_file_request(self, msg: Message, sock: socket.socket):
+        request_id = utils.random_string(16)
+        props = {"path": msg.props["path"], "requestId": request_id}
+        for key in ("offset", "checksum"):
+            if key in msg.props:
+                props[key] = msg.props[key]
+        self.file_requests[request_id] = (sock, msg.props.get("requestId", ""))
+        Message("File", props).send(self.master.sock)
+
+    # Master answers every FILE request with a series of chunks, which are sent
+    # only to the player who has requested the file
+    def relay_chunk(self, msg: Message):
+        request_id = msg.props.get("requestId")
+        if request_id not in self.file_requests:
+            return
+        sock, client_request_id = self.file_requests[request_id]
+        # Only copying what's needed, master's cookie must not get through
+        keys = ("path", "chunk", "offset", "totalSize", "checksum", "contentType")
+        props = {k: msg.props[k] for k in keys if k in msg.props}
+        props["requestId"] = client_request_id
+        offset = int(props["offset"])
+        done = offset + len(msg.body) >= int(props["totalSize"])
+        if sock in self.player_sockets:
+            Message("File", props, msg.body).send(sock, self.can_compress(sock))
+        else:
+            # Player has left, they will ask again if they come back
+            done = True
+        if done:
+            del self.file_requests[request_id]
+
+    def can_compress(self, sock: socket.socket) -> bool:
+        return self.players[self.player_sockets.index(sock)].supports("deflate")
+
     # This is called from another thread to indicate that a new player
     # has connected to this room
     def add_player(self, player: Player):
         self.pending_players.append(player)
 
-    def remove_player(self, index: int):
+    # If the player just lost connection (Didn't QUIT), they can come back
+    # later with the same ID and cookie. See process_new_player()
+    def remove_player(self, index: int, resumable: bool = False):
         player = self.players.pop(index)
         sock = self.player_sockets.pop(index)
         utils.close_conn(sock)
+        if resumable:
+            self.departed[player.id] = (player, time.monotonic())
         for s in self.player_sockets:
             Message(
                 "Player",
@@ -182,9 +211,30 @@ class Room:
                 json.dumps({player.id: {}}).encode(),
             ).send(s)
 
+    # Room is closed when there's nobody left and nobody is going to come back
+    def is_abandoned(self) -> bool:
+        now = time.monotonic()
+        for id, (_, departed_at) in list(self.departed.items()):
+            if now - departed_at > RESUME_TIMEOUT:
+                del self.departed[id]
+        return not self.players and not self.departed
+
     def process_new_player(self, player: Player):
-        player.nickname = f"Player{self.player_counter}"
-        player.color = utils.choose_random_color()
+        departed = self.departed.get(player.id)
+        if departed and departed[0].cookie == player.cookie:
+            # Resuming the session
+            old = departed[0]
+            del self.departed[player.id]
+            player.nickname = old.nickname
+            player.color = old.color
+            if old is self.master:
+                self.master = player
+        else:
+            player.id = generate_id()
+            player.cookie = generate_cookie()
+            player.nickname = f"Player{self.player_counter}"
+            player.color = utils.choose_random_color()
+            self.player_counter += 1
         Message("Ok", {"contentType": "json"}, player.to_json().encode()).send(
             player.sock
         )
@@ -201,7 +251,7 @@ class Room:
         ).send(player.sock)
         Message(
             "Map", {"contentType": "json"}, json.dumps(self.map).encode()
-        ).send(player.sock)
+        ).send(player.sock, player.supports("deflate"))
         Message("Synced").send(player.sock)
         for s in self.player_sockets:
             Message(
@@ -218,7 +268,6 @@ class Room:
             ).send(s)
         self.players.append(player)
         self.player_sockets = [i.sock for i in self.players]
-        self.player_counter += 1
 
     def update_map(self, json: dict) -> dict:
         delta = {}
@@ -26,8 +26,11 @@ class Server:
                     del self.room_threads[i]
             try:
                 msg = Message.from_str(utils.recv_message_header(conn))
-                body_bytes = conn.recv(msg.content_length)
+                body_bytes = utils.recv_exact(conn, msg.content_length)
+                # Old clients send INIT without a body
+                body = json.loads(body_bytes.decode()) if body_bytes else {}
                 player = Player(conn)
+                player.negotiate(body)
                 if msg.msg_type == "Init":
                     room_id = utils.random_string(12)
                     self.rooms[room_id] = Room(player, room_id)
@@ -36,7 +39,6 @@ class Server:
                     )
                     self.room_threads[-1][1].start()
                 elif msg.msg_type == "Join":
-                    body = json.loads(body_bytes.decode())
                     if body.get("userId"):
                         player.id = body["userId"]
                     if body.get("userCookie"):
@@ -0,0 +1,132 @@
+use rand::Rng;
+
+// Rolling a million dice would block the whole room
+const MAX_ROLLS: u32 = 1000;
+
+// Chat messages starting with '/'
+pub enum Command {
+    Roll(String),
+    Color([u8; 3]),
+    Nickname(String),
+    // Name shown in LAN discovery. Only the GM can set it
+    RoomName(String),
+}
+
+impl Command {
+    // None if it's not a command we know or its arguments are wrong
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut argv = text.split_whitespace();
+        let args: Vec<&str> = argv.clone().skip(1).collect();
+        match argv.next()? {
+            "/roll" | "/r" => Some(Self::Roll(args.join(" "))),
+            "/color" if args.len() == 3 => {
+                let mut rgb = [0; 3];
+                for (c, arg) in rgb.iter_mut().zip(args) {
+                    *c = arg.parse().ok()?;
+                }
+                Some(Self::Color(rgb))
+            }
+            "/nickname" | "/nick" if !args.is_empty() => Some(Self::Nickname(args.join(" "))),
+            "/roomname" if !args.is_empty() => Some(Self::RoomName(args.join(" "))),
+            _ => None,
+        }
+    }
+}
+
+// Queries look like "2d20 + 3 - d4". None if there's any error in the query
+pub fn roll_dice(query: &str) -> Option<i64> {
+    let mut tokens = tokenize(query).into_iter().peekable();
+    let mut sign: i64 = 1;
+    if tokens.peek().map(|t| t.as_str()) == Some("-") {
+        sign = -1;
+        tokens.next();
+    }
+
+    let mut result = 0;
+    loop {
+        let operand = tokens.next()?;
+        let value: i64 = match operand.split_once('d') {
+            Some((rolls, dice)) => {
+                let rolls: u32 = rolls.parse().ok()?;
+                let dice: i64 = dice.parse().ok()?;
+                if rolls > MAX_ROLLS || dice < 1 {
+                    return None;
+                }
+                let mut rng = rand::thread_rng();
+                (0..rolls).map(|_| rng.gen_range(1..=dice)).sum()
+            }
+            None => operand.parse().ok()?,
+        };
+        result += sign * value;
+        sign = match tokens.next().as_deref() {
+            Some("+") => 1,
+            Some("-") => -1,
+            None => return Some(result),
+            Some(_) => return None,
+        };
+    }
+}
+
+// Splits on whitespace and keeps '+' and '-' as separate tokens
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in query.chars() {
+        if c.is_whitespace() || c == '+' || c == '-' {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{roll_dice, Command};
+
+    #[test]
+    fn dice() {
+        assert_eq!(roll_dice("2d1 + 3"), Some(5));
+        assert_eq!(roll_dice("-1d1-2"), Some(-3));
+        assert_eq!(roll_dice("10"), Some(10));
+        let roll = roll_dice("3d6").unwrap();
+        assert!((3..=18).contains(&roll));
+
+        assert_eq!(roll_dice(""), None);
+        assert_eq!(roll_dice("d20"), None);
+        assert_eq!(roll_dice("2d0"), None);
+        assert_eq!(roll_dice("2 3"), None);
+        assert_eq!(roll_dice("2 +"), None);
+        assert_eq!(roll_dice("100000d6"), None);
+    }
+
+    #[test]
+    fn commands() {
+        assert!(matches!(Command::parse("/r 2d6  +1"), Some(Command::Roll(q)) if q == "2d6 +1"));
+        assert!(matches!(
+            Command::parse("/color 1 2 3"),
+            Some(Command::Color([1, 2, 3]))
+        ));
+        assert!(Command::parse("/color 1 2 256").is_none());
+        assert!(Command::parse("/color 1 2").is_none());
+        assert!(matches!(
+            Command::parse("/nick  Sir Robin"),
+            Some(Command::Nickname(n)) if n == "Sir Robin"
+        ));
+        assert!(Command::parse("/nick").is_none());
+        assert!(matches!(
+            Command::parse("/roomname The Keep"),
+            Some(Command::RoomName(n)) if n == "The Keep"
+        ));
+        assert!(Command::parse("/dance").is_none());
+    }
+}
@@ -0,0 +1,196 @@
+use json::JsonValue;
+use tokio::io::{self as aio, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::{self, Duration};
+
+use protocol::model::{ErrResponse, ErrorCode, Hello};
+use protocol::{Message, MessageFramer, MsgBody, MsgType};
+
+use std::io;
+
+use crate::player::Outbox;
+use crate::room::{self, Room, RoomEvent};
+use crate::{utils, Rooms, CAPABILITIES};
+
+const READ_BUF_SIZE: usize = 64 * 1024;
+// JOIN/INIT is a small JSON, anything bigger comes from someone who isn't
+// a client
+const MAX_HELLO_LEN: usize = 16 * 1024;
+// Largest message a player can send once they're in: a FILE chunk is 256 KiB,
+// the rest is room for big MAP changes
+const MAX_BODY_LEN: usize = 512 * 1024;
+// Clients that support "heartbeat" PING us every few seconds, so if one of them
+// has been silent for this long, its connection is dead
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Reads the JOIN/INIT, hands the player over to their room, and then forwards
+// everything they send to that room. Stream is either plain TCP or TLS
+pub async fn handle_connection<S>(stream: S, conn_id: u64, rooms: Rooms)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = aio::split(stream);
+    let mut reader = MessageReader::new(reader, MAX_HELLO_LEN);
+
+    let mut first_msg = match reader.next().await {
+        Ok(msg) => msg,
+        Err(e) => {
+            println!("Connection {} dropped before JOIN/INIT: {}", conn_id, e);
+            return;
+        }
+    };
+    // Old clients send INIT without a body
+    let body = match first_msg.take_body() {
+        Some(MsgBody::Json(json)) => json,
+        _ => JsonValue::Null,
+    };
+    let mut hello = Hello::from_json(&body);
+    if let Ok(hello) = &mut hello {
+        hello
+            .capabilities
+            .retain(|c| CAPABILITIES.contains(&c.as_str()));
+    }
+    let compress = matches!(&hello, Ok(hello) if hello.supports("deflate"));
+    let outbox = spawn_writer(writer, compress);
+    let hello = match hello {
+        Ok(hello) => hello,
+        Err(e) => {
+            println!("Connection {} sent a bad JOIN/INIT: {}", conn_id, e);
+            let err = ErrResponse::new(ErrorCode::BadRequest, &e.to_string())
+                .about(first_msg.msg_type(), None);
+            #[allow(unused)]
+            {
+                outbox.send(room::err_msg(err));
+            }
+            return;
+        }
+    };
+
+    let room = match first_msg.msg_type() {
+        MsgType::Init => {
+            let room_id = utils::random_string(12);
+            let (room, sender) = Room::new(room_id.clone(), rooms.clone());
+            rooms.lock().unwrap().insert(room_id, sender.clone());
+            tokio::spawn(room.run());
+            sender
+        }
+        MsgType::Join => {
+            let room_id = hello.room_id.as_deref().unwrap_or("");
+            match rooms.lock().unwrap().get(room_id) {
+                Some(sender) => sender.clone(),
+                None => {
+                    let message = format!("Room {} doesn't exist", room_id);
+                    let err = ErrResponse::new(ErrorCode::RoomNotFound, &message)
+                        .about(MsgType::Join, Some(room_id));
+                    #[allow(unused)]
+                    {
+                        outbox.send(room::err_msg(err));
+                    }
+                    return;
+                }
+            }
+        }
+        _ => return,
+    };
+
+    let timeout = if hello.supports("heartbeat") {
+        CLIENT_TIMEOUT
+    } else {
+        Duration::MAX
+    };
+    let joined = RoomEvent::Join {
+        conn_id,
+        hello,
+        outbox,
+    };
+    if room.send(joined).is_err() {
+        // Room has just closed
+        return;
+    }
+    reader.framer.set_max_body_len(MAX_BODY_LEN);
+    loop {
+        let result = match time::timeout(timeout, reader.next()).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        };
+        match result {
+            Ok(msg) => {
+                if room.send(RoomEvent::Message(conn_id, msg)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                println!("Connection {} closed: {}", conn_id, e);
+                #[allow(unused)]
+                {
+                    room.send(RoomEvent::Disconnected(conn_id));
+                }
+                return;
+            }
+        }
+    }
+}
+
+// Splits what a player sends into messages, reusing one buffer for the whole
+// connection
+struct MessageReader<R> {
+    reader: R,
+    framer: MessageFramer,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    fn new(reader: R, max_body_len: usize) -> Self {
+        let mut framer = MessageFramer::new();
+        framer.set_max_body_len(max_body_len);
+        Self {
+            reader,
+            framer,
+            buf: vec![0; READ_BUF_SIZE],
+        }
+    }
+
+    // Bad bodies are skipped, only broken framing or a closed socket are errors
+    async fn next(&mut self) -> io::Result<Message> {
+        loop {
+            match self.framer.next_message() {
+                Ok(Some(msg)) => return Ok(msg),
+                Ok(None) => (),
+                Err(e) if e.breaks_framing() => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                }
+                Err(e) => {
+                    println!("Skipping a message: {}", e);
+                    continue;
+                }
+            }
+            match self.reader.read(&mut self.buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.framer.push(&self.buf[..n]),
+            }
+        }
+    }
+}
+
+// Messages are written by a separate task, so the room never waits for a slow
+// client. Dropping all the senders closes the connection
+fn spawn_writer(mut writer: impl AsyncWrite + Unpin + Send + 'static, compress: bool) -> Outbox {
+    let (outbox, mut queue): (Outbox, UnboundedReceiver<Message>) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(msg) = queue.recv().await {
+            let bytes = if compress {
+                msg.into_bytes()
+            } else {
+                msg.into_plain_bytes()
+            };
+            if writer.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+        #[allow(unused)]
+        {
+            writer.shutdown().await;
+        }
+    });
+    outbox
+}
@@ -0,0 +1,67 @@
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+use protocol::model::Announcement;
+
+use std::net::SocketAddr;
+
+use crate::room::RoomEvent;
+use crate::Rooms;
+
+// Keeps the answer well within a single datagram
+const MAX_ROOMS: usize = 50;
+
+// Answers LAN discovery probes with the rooms of this server. `port` and `tls`
+// are what players should use to connect
+pub async fn answer_probes(socket: UdpSocket, rooms: Rooms, port: u16, tls: bool) {
+    let mut buf = [0; 1024];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // E.g. an ICMP "port unreachable" from an earlier answer
+            Err(e) => {
+                println!("Discovery socket error: {}", e);
+                continue;
+            }
+        };
+        // Room list is only for the LAN, even if the port is reachable from
+        // the outside
+        if !protocol::is_local(from.ip()) {
+            continue;
+        }
+        let is_probe = std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|text| json::parse(text).ok())
+            .is_some_and(|json| Announcement::is_probe(&json));
+        if is_probe {
+            answer(&socket, &rooms, from, port, tls).await;
+        }
+    }
+}
+
+async fn answer(socket: &UdpSocket, rooms: &Rooms, to: SocketAddr, port: u16, tls: bool) {
+    let senders: Vec<_> = rooms.lock().unwrap().values().cloned().collect();
+    let mut announcement = Announcement {
+        address: None,
+        port: Some(port),
+        tls,
+        rooms: Vec::new(),
+    };
+    for room in senders.into_iter().take(MAX_ROOMS) {
+        let (reply, summary) = oneshot::channel();
+        if room.send(RoomEvent::Describe(reply)).is_err() {
+            continue;
+        }
+        // Room may close before answering
+        if let Ok(summary) = summary.await {
+            announcement.rooms.push(summary);
+        }
+    }
+    announcement.rooms.sort_by(|a, b| a.name.cmp(&b.name));
+    #[allow(unused)]
+    {
+        socket
+            .send_to(announcement.to_json().dump().as_bytes(), to)
+            .await;
+    }
+}
@@ -0,0 +1,107 @@
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils;
+
+// Invites can't be valid for longer than a week
+pub const MAX_INVITE_AGE: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum InviteError {
+    Expired,
+    Used,
+    Invalid,
+}
+
+// Issues and checks the invites of a room. Tokens look like
+// "<nonce>.<expiry>.<signature>": expiry is a UNIX timestamp, and the signature
+// is a SHA-256 of the room's secret and the rest, so tokens can't be forged or
+// used for other rooms. Nonces are remembered until their tokens expire, so
+// every invite only works once
+pub struct Invites {
+    secret: [u8; 32],
+    // Nonce: Expiry
+    used: HashMap<String, u64>,
+}
+
+impl Invites {
+    pub fn new() -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+            used: HashMap::new(),
+        }
+    }
+
+    // Valid for `expires_in` seconds, up to MAX_INVITE_AGE
+    pub fn issue(&self, expires_in: u64) -> String {
+        let expiry = unix_time() + expires_in.min(MAX_INVITE_AGE);
+        self.token(&utils::random_string(16), expiry)
+    }
+
+    fn token(&self, nonce: &str, expiry: u64) -> String {
+        format!("{}.{}.{}", nonce, expiry, self.sign(nonce, expiry))
+    }
+
+    // Uses the invite up if it's valid
+    pub fn redeem(&mut self, token: &str) -> Result<(), InviteError> {
+        let now = unix_time();
+        self.used.retain(|_, expiry| *expiry >= now);
+        let parts: Vec<&str> = token.split('.').collect();
+        let (nonce, expiry, signature) = match parts[..] {
+            [nonce, expiry, signature] => (nonce, expiry, signature),
+            _ => return Err(InviteError::Invalid),
+        };
+        let expiry = expiry.parse().map_err(|_| InviteError::Invalid)?;
+        if signature != self.sign(nonce, expiry) {
+            return Err(InviteError::Invalid);
+        }
+        if expiry < now {
+            return Err(InviteError::Expired);
+        }
+        if self.used.insert(nonce.to_string(), expiry).is_some() {
+            return Err(InviteError::Used);
+        }
+        Ok(())
+    }
+
+    fn sign(&self, nonce: &str, expiry: u64) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        hasher.update(format!("{}.{}", nonce, expiry));
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unix_time, InviteError, Invites};
+
+    #[test]
+    fn single_use() {
+        let mut invites = Invites::new();
+        let token = invites.issue(60);
+        assert_eq!(invites.redeem(&token), Ok(()));
+        assert_eq!(invites.redeem(&token), Err(InviteError::Used));
+
+        let expired = invites.token("abcd", unix_time() - 1);
+        assert_eq!(invites.redeem(&expired), Err(InviteError::Expired));
+        // Expiry is part of what's signed
+        let signature = expired.rsplit_once('.').unwrap().1;
+        let forged = format!("abcd.{}.{}", unix_time() + 60, signature);
+        assert_eq!(invites.redeem(&forged), Err(InviteError::Invalid));
+        assert_eq!(invites.redeem("abcd"), Err(InviteError::Invalid));
+
+        let token = invites.issue(60);
+        // Other rooms have other secrets
+        assert_eq!(Invites::new().redeem(&token), Err(InviteError::Invalid));
+    }
+}
@@ -0,0 +1,76 @@
+// Async dradu server. Every connection is handled by its own task, and so is
+// every room: connection tasks turn incoming messages into RoomEvents, and the
+// room task owns all the state of the room and answers them. See
+// docs/dev/protocol.md
+
+mod commands;
+mod connection;
+mod discovery;
+mod invites;
+mod map;
+mod player;
+mod room;
+mod tls;
+mod utils;
+
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_rustls::TlsAcceptor;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::room::RoomEvent;
+
+pub use crate::tls::{load_tls_acceptor, tls_acceptor};
+
+pub const DEFAULT_PORT: u16 = 8889;
+
+// Optional protocol features this server supports
+pub const CAPABILITIES: &[&str] = &["resume", "deflate", "heartbeat", "transient"];
+
+// Room ID: channel to the room's task. Rooms remove themselves from here once
+// everybody has left
+type Rooms = Arc<Mutex<HashMap<String, UnboundedSender<RoomEvent>>>>;
+
+// Accepts connections until the listener fails. With an acceptor, every
+// connection has to start with a TLS handshake. With a discovery socket, LAN
+// discovery probes are answered on it
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    discovery: Option<UdpSocket>,
+) -> io::Result<()> {
+    let rooms = Rooms::default();
+    if let Some(socket) = discovery {
+        let port = listener.local_addr()?.port();
+        tokio::spawn(discovery::answer_probes(
+            socket,
+            rooms.clone(),
+            port,
+            tls.is_some(),
+        ));
+    }
+    let mut conn_counter = 0;
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        println!("Connection from {}", addr);
+        conn_counter += 1;
+        let (conn_id, rooms) = (conn_counter, rooms.clone());
+        match &tls {
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => connection::handle_connection(stream, conn_id, rooms).await,
+                        Err(e) => println!("TLS handshake with {} failed: {}", addr, e),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(connection::handle_connection(stream, conn_id, rooms));
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use tokio::net::{TcpListener, UdpSocket};
+
+use std::env;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "Usage: server [-p|--port <PORT>] [--cert <PEM> --key <PEM>] \
+                     [--discovery [--discovery-port <PORT>]]";
+
+struct Args {
+    port: u16,
+    // Certificate chain and private key, if connections should be encrypted
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    // Whether to answer LAN discovery probes. Off by default, since it tells
+    // anyone on the network which rooms there are
+    discovery: bool,
+    discovery_port: u16,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => match server::load_tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                eprintln!("Could not load the certificate: {}", e);
+                process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            eprintln!("--cert and --key go together\n{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let port = args.port;
+
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not bind {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    // Server works fine without discovery, e.g. when another one on the same
+    // machine has taken the port
+    let discovery = if args.discovery {
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, args.discovery_port));
+        match UdpSocket::bind(addr).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!("LAN discovery is off, could not bind UDP {}: {}", addr, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let encryption = if tls.is_some() { " (TLS)" } else { "" };
+    println!("Starting server on {}{}", addr, encryption);
+    if let Err(e) = server::serve(listener, tls, discovery).await {
+        println!("\nShutting down\nReason: {}", e);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        port: server::DEFAULT_PORT,
+        cert: None,
+        key: None,
+        discovery: false,
+        discovery_port: protocol::DISCOVERY_PORT,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => {
+                let val = args.next().ok_or("Missing port number")?;
+                parsed.port = val.parse().map_err(|_| format!("Invalid port: {}", val))?;
+            }
+            "--cert" => parsed.cert = Some(args.next().ok_or("Missing certificate path")?.into()),
+            "--key" => parsed.key = Some(args.next().ok_or("Missing key path")?.into()),
+            "--discovery" => parsed.discovery = true,
+            // Implies --discovery
+            "--discovery-port" => {
+                let val = args.next().ok_or("Missing port number")?;
+                parsed.discovery_port =
+                    val.parse().map_err(|_| format!("Invalid port: {}", val))?;
+                parsed.discovery = true;
+            }
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,630 @@
+use json::{object, JsonValue};
+
+use std::collections::HashSet;
+
+// Server's copy of the map. It's kept as JSON in the same form it's sent to
+// the players joining the room. See MAP in docs/dev/protocol.md
+pub struct Map {
+    json: JsonValue,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self {
+            json: JsonValue::new_object(),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        self.json.clone()
+    }
+
+    // Map without the GM layer, for everyone but the GM
+    pub fn players_view(&self) -> JsonValue {
+        let mut view = self.json.clone();
+        for id in self.gm_objects() {
+            view.remove(&id);
+        }
+        view
+    }
+
+    pub fn is_on_gm_layer(&self, id: &str) -> bool {
+        self.json[id]["layer"] == "gm"
+    }
+
+    // IDs of the objects only the GM sees
+    fn gm_objects(&self) -> Vec<String> {
+        self.json
+            .entries()
+            .filter(|(id, _)| self.is_on_gm_layer(id))
+            .map(|(id, _)| id.to_string())
+            .collect()
+    }
+
+    // Objects changed by the delta which players other than the GM can see
+    pub fn seen_by_players(&self, delta: &JsonValue) -> HashSet<String> {
+        delta
+            .entries()
+            .filter(|(id, _)| self.json.has_key(id) && !self.is_on_gm_layer(id))
+            .map(|(id, _)| id.to_string())
+            .collect()
+    }
+
+    // Part of a delta returned by .apply() which players other than the GM
+    // get. `seen` is .seen_by_players() from before it was applied. Objects
+    // leaving the GM layer are sent whole, as if they were new, and the ones
+    // put on it are removed
+    pub fn players_delta(&self, delta: &JsonValue, seen: &HashSet<String>) -> JsonValue {
+        if delta.is_null() {
+            return JsonValue::Null;
+        }
+        let mut result = JsonValue::new_object();
+        for (id, entry) in delta.entries() {
+            let was_seen = seen.contains(id);
+            if !self.json.has_key(id) {
+                // Removed
+                if was_seen {
+                    result[id] = entry.clone();
+                }
+                continue;
+            }
+            match (was_seen, self.is_on_gm_layer(id)) {
+                (true, false) => result[id] = entry.clone(),
+                (false, false) => result[id] = self.json[id].clone(),
+                (true, true) => result[id] = object! {},
+                (false, true) => (),
+            }
+        }
+        result
+    }
+
+    // Applies changes sent by a player and returns the ones which have to be
+    // sent to everybody. Null resets the whole map. If any part of the delta is
+    // wrong, the map isn't changed at all
+    pub fn apply(&mut self, delta: &JsonValue) -> Result<JsonValue, String> {
+        if delta.is_null() {
+            self.json = JsonValue::new_object();
+            return Ok(JsonValue::Null);
+        }
+        if !delta.is_object() {
+            return Err("MAP body must be an object".to_string());
+        }
+
+        let mut map = self.json.clone();
+        let mut result = JsonValue::new_object();
+        for (id, entry) in delta.entries() {
+            if entry.is_empty() && id != "background" {
+                map.remove(id);
+                result[id] = object! {};
+                continue;
+            }
+            let changes = match id {
+                "background" => {
+                    let path = entry["path"].as_str().ok_or("background.path is missing")?;
+                    map[id] = object! {"path": path};
+                    copy_hash(&mut map[id], entry).map_err(|field| format!("{}.{}", id, field))?;
+                    map[id].clone()
+                }
+                "grid" => {
+                    let size = &entry["size"];
+                    let (columns, rows) = match (size[0].as_u8(), size[1].as_u8()) {
+                        (Some(c), Some(r)) if c >= 2 && r >= 2 => (c, r),
+                        _ => return Err("grid.size must be two numbers from 2 to 255".to_string()),
+                    };
+                    map[id] = object! {"size": [columns, rows]};
+                    map[id].clone()
+                }
+                "fog" => {
+                    map[id] = fog(entry).map_err(|field| format!("{}.{}", id, field))?;
+                    map[id].clone()
+                }
+                _ if map.has_key(id) => update_object(&mut map[id], entry)
+                    .map_err(|field| format!("{}.{}", id, field))?,
+                _ => {
+                    map[id] = new_object(entry).map_err(|field| format!("{}.{}", id, field))?;
+                    map[id].clone()
+                }
+            };
+            if !changes.is_empty() {
+                result[id] = changes;
+            }
+        }
+        self.json = map;
+        Ok(result)
+    }
+
+    // First wall the delta adds or changes, if there is one
+    pub fn wall_in<'a>(&self, delta: &'a JsonValue) -> Option<&'a str> {
+        delta
+            .entries()
+            .find(|(id, entry)| entry["type"] == "wall" || self.json[*id]["type"] == "wall")
+            .map(|(id, _)| id)
+    }
+
+    // First object on the GM layer, or put there, which the delta changes
+    pub fn gm_layer_in<'a>(&self, delta: &'a JsonValue) -> Option<&'a str> {
+        delta
+            .entries()
+            .find(|(id, entry)| entry["layer"] == "gm" || self.is_on_gm_layer(id))
+            .map(|(id, _)| id)
+    }
+
+    // First token whose owner the delta sets
+    pub fn owner_in<'a>(&self, delta: &'a JsonValue) -> Option<&'a str> {
+        delta
+            .entries()
+            .find(|(_, entry)| entry["properties"].has_key("owner"))
+            .map(|(id, _)| id)
+    }
+
+    // Transient MAP only moves objects around while they are being dragged, so
+    // it's checked, but the map isn't changed. Returns ID and position of every
+    // object that has been moved
+    pub fn check_moves(&self, delta: &JsonValue) -> Result<Vec<(String, JsonValue)>, String> {
+        if !delta.is_object() {
+            return Err("Transient MAP body must be an object".to_string());
+        }
+        delta
+            .entries()
+            .map(|(id, entry)| {
+                if !self.json.has_key(id) || ["background", "grid", "fog"].contains(&id) {
+                    return Err(format!("{} is not an object on the map", id));
+                }
+                let pos = pos(&entry["pos"]).ok_or(format!("{}.pos", id))?;
+                Ok((id.to_string(), pos))
+            })
+            .collect()
+    }
+}
+
+// On error returns the name of the wrong field
+fn new_object(entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let obj_type = entry["type"].as_str().ok_or("type")?;
+    let layer = match obj_type {
+        "token" | "effect" => "tokens",
+        _ => "map",
+    };
+    let mut obj = object! {
+        "type": obj_type,
+        "pos": [0.0, 0.0],
+        "scale": 1.0,
+        "layer": layer,
+        "zIndex": 0,
+    };
+    // Walls and effects are drawn as shapes, an image is optional for them
+    match entry["path"].as_str() {
+        Some(path) => obj["path"] = path.into(),
+        None if obj_type == "wall" || obj_type == "effect" => (),
+        None => return Err("path"),
+    }
+    copy_hash(&mut obj, entry)?;
+    if !entry["pos"].is_null() {
+        obj["pos"] = pos(&entry["pos"]).ok_or("pos")?;
+    }
+    if !entry["scale"].is_null() {
+        obj["scale"] = entry["scale"].as_f32().ok_or("scale")?.into();
+    }
+    update_order(&mut obj, entry)?;
+    if obj_type == "decal" || obj_type == "token" {
+        obj["rotation"] = 0.0.into();
+        obj["flipX"] = false.into();
+        obj["flipY"] = false.into();
+        update_transform(&mut obj, entry)?;
+    }
+    if obj_type == "token" {
+        obj["properties"] = match &entry["properties"] {
+            JsonValue::Null => JsonValue::new_object(),
+            props if props.is_object() => props.clone(),
+            _ => return Err("properties"),
+        };
+    }
+    if obj_type == "wall" {
+        obj["nodes"] = nodes(&entry["nodes"]).ok_or("nodes")?;
+    }
+    if obj_type == "effect" {
+        if entry["shape"].is_null() {
+            return Err("shape");
+        }
+        obj["size"] = 1.0.into();
+        obj["color"] = json::array![255, 120, 0];
+        obj["opacity"] = 0.4.into();
+        obj["direction"] = 0.0.into();
+        update_effect(&mut obj, entry)?;
+    }
+    Ok(obj)
+}
+
+// Returns only the fields which were changed
+fn update_object(obj: &mut JsonValue, entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let mut changes = JsonValue::new_object();
+    if !entry["pos"].is_null() {
+        obj["pos"] = pos(&entry["pos"]).ok_or("pos")?;
+        changes["pos"] = obj["pos"].clone();
+    }
+    if !entry["scale"].is_null() {
+        obj["scale"] = entry["scale"].as_f32().ok_or("scale")?.into();
+        changes["scale"] = obj["scale"].clone();
+    }
+    for (key, val) in update_order(obj, entry)?.entries() {
+        changes[key] = val.clone();
+    }
+    if obj["type"] == "decal" || obj["type"] == "token" {
+        for (key, val) in update_transform(obj, entry)?.entries() {
+            changes[key] = val.clone();
+        }
+    }
+    if obj["type"] == "wall" && !entry["nodes"].is_null() {
+        obj["nodes"] = nodes(&entry["nodes"]).ok_or("nodes")?;
+        changes["nodes"] = obj["nodes"].clone();
+    }
+    if obj["type"] == "effect" {
+        for (key, val) in update_effect(obj, entry)?.entries() {
+            changes[key] = val.clone();
+        }
+    }
+    if obj["type"] == "token" && entry["properties"].is_object() {
+        // Null removes a property
+        for (key, val) in entry["properties"].entries() {
+            if val.is_null() {
+                obj["properties"].remove(key);
+            } else {
+                obj["properties"][key] = val.clone();
+            }
+            changes["properties"][key] = val.clone();
+        }
+    }
+    Ok(changes)
+}
+
+// Copies the layer and z-index if they are set, returns them
+fn update_order(obj: &mut JsonValue, entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let mut changes = JsonValue::new_object();
+    if !entry["layer"].is_null() {
+        changes["layer"] = entry["layer"]
+            .as_str()
+            .filter(|layer| ["background", "map", "tokens", "gm"].contains(layer))
+            .ok_or("layer")?
+            .into();
+    }
+    if !entry["zIndex"].is_null() {
+        changes["zIndex"] = entry["zIndex"].as_i32().ok_or("zIndex")?.into();
+    }
+    for (key, val) in changes.entries() {
+        obj[key] = val.clone();
+    }
+    Ok(changes)
+}
+
+// Copies the rotation and flips of an image if they are set, returns them
+fn update_transform(obj: &mut JsonValue, entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let mut changes = JsonValue::new_object();
+    if !entry["rotation"].is_null() {
+        changes["rotation"] = entry["rotation"].as_f64().ok_or("rotation")?.into();
+    }
+    for key in ["flipX", "flipY"] {
+        if !entry[key].is_null() {
+            changes[key] = entry[key].as_bool().ok_or(key)?.into();
+        }
+    }
+    for (key, val) in changes.entries() {
+        obj[key] = val.clone();
+    }
+    Ok(changes)
+}
+
+// Copies the effect template fields which are set, returns them
+fn update_effect(obj: &mut JsonValue, entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let mut changes = JsonValue::new_object();
+    if !entry["shape"].is_null() {
+        changes["shape"] = entry["shape"]
+            .as_str()
+            .filter(|shape| ["circle", "cone", "line", "square"].contains(shape))
+            .ok_or("shape")?
+            .into();
+    }
+    if !entry["size"].is_null() {
+        changes["size"] = entry["size"]
+            .as_f64()
+            .filter(|size| *size > 0.0)
+            .ok_or("size")?
+            .into();
+    }
+    if !entry["opacity"].is_null() {
+        changes["opacity"] = entry["opacity"]
+            .as_f64()
+            .filter(|opacity| (0.0..=1.0).contains(opacity))
+            .ok_or("opacity")?
+            .into();
+    }
+    if !entry["direction"].is_null() {
+        changes["direction"] = entry["direction"].as_f64().ok_or("direction")?.into();
+    }
+    if !entry["color"].is_null() {
+        changes["color"] = color(&entry["color"]).ok_or("color")?;
+    }
+    for (key, val) in changes.entries() {
+        obj[key] = val.clone();
+    }
+    Ok(changes)
+}
+
+// Hash of the image is optional, old clients don't send it
+fn copy_hash(obj: &mut JsonValue, entry: &JsonValue) -> Result<(), &'static str> {
+    match &entry["hash"] {
+        JsonValue::Null => (),
+        hash => obj["hash"] = hash.as_str().ok_or("hash")?.into(),
+    }
+    Ok(())
+}
+
+fn pos(json: &JsonValue) -> Option<JsonValue> {
+    Some(json::array![json[0].as_f32()?, json[1].as_f32()?])
+}
+
+fn color(json: &JsonValue) -> Option<JsonValue> {
+    match json {
+        JsonValue::Array(rgb) if rgb.len() == 3 => Some(json::array![
+            rgb[0].as_u8()?,
+            rgb[1].as_u8()?,
+            rgb[2].as_u8()?
+        ]),
+        _ => None,
+    }
+}
+
+// Fog of war is always replaced whole
+fn fog(entry: &JsonValue) -> Result<JsonValue, &'static str> {
+    let reveal_all = match &entry["revealAll"] {
+        JsonValue::Null => false,
+        val => val.as_bool().ok_or("revealAll")?,
+    };
+    let mut fog = object! {"revealAll": reveal_all};
+    for key in ["revealed", "hidden"] {
+        let cells = match &entry[key] {
+            JsonValue::Null => Some(Vec::new()),
+            JsonValue::Array(cells) => cells.iter().map(cell).collect(),
+            _ => None,
+        };
+        fog[key] = cells.ok_or(key)?.into();
+    }
+    Ok(fog)
+}
+
+fn cell(json: &JsonValue) -> Option<JsonValue> {
+    match json {
+        JsonValue::Array(xy) if xy.len() == 2 => {
+            Some(json::array![xy[0].as_i32()?, xy[1].as_i32()?])
+        }
+        _ => None,
+    }
+}
+
+// A wall needs at least two nodes to be a line
+fn nodes(json: &JsonValue) -> Option<JsonValue> {
+    if !json.is_array() || json.len() < 2 {
+        return None;
+    }
+    let nodes: Option<Vec<_>> = json.members().map(pos).collect();
+    Some(nodes?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Map;
+    use json::object;
+
+    #[test]
+    fn map_deltas() {
+        let mut map = Map::new();
+        let delta = map
+            .apply(&object! {
+                "abc": {"type": "token", "path": "orc.png"},
+                "grid": {"size": [10, 12]},
+            })
+            .unwrap();
+        assert_eq!(delta["abc"]["pos"], json::array![0.0, 0.0]);
+        assert!(delta["abc"]["properties"].is_object());
+        assert_eq!(delta["grid"]["size"], json::array![10, 12]);
+
+        let delta = map
+            .apply(&object! {"abc": {"pos": [1.5, 2.0], "properties": {"HP": "10"}, "junk": 1}})
+            .unwrap();
+        assert_eq!(
+            delta,
+            object! {"abc": {"pos": [1.5, 2.0], "properties": {"HP": "10"}}}
+        );
+        map.apply(&object! {"abc": {"properties": {"HP": null}}})
+            .unwrap();
+        assert!(map.to_json()["abc"]["properties"].is_empty());
+
+        // Nothing is changed if a part of the delta is wrong
+        let err = map
+            .apply(&object! {"abc": {}, "def": {"type": "decal", "pos": "here"}})
+            .unwrap_err();
+        assert_eq!(err, "def.path");
+        assert!(map.to_json().has_key("abc"));
+
+        // Moves of things being dragged aren't applied
+        let moves = map.check_moves(&object! {"abc": {"pos": [3, 4]}}).unwrap();
+        assert_eq!(moves, vec![("abc".to_string(), json::array![3.0, 4.0])]);
+        assert_eq!(map.to_json()["abc"]["pos"], json::array![1.5, 2.0]);
+        assert!(map.check_moves(&object! {"abc": {"pos": "here"}}).is_err());
+        assert!(map.check_moves(&object! {"grid": {"pos": [1, 1]}}).is_err());
+
+        map.apply(&object! {"abc": {}, "grid": {}}).unwrap();
+        assert!(map.to_json().is_empty());
+        map.apply(&object! {"background": {"path": "bg.png", "hash": "ab12"}})
+            .unwrap();
+        assert_eq!(map.to_json()["background"]["hash"], "ab12");
+        assert!(map
+            .apply(&object! {"def": {"type": "decal", "path": "a.png", "hash": 5}})
+            .is_err());
+        assert_eq!(
+            map.apply(&json::JsonValue::Null).unwrap(),
+            json::JsonValue::Null
+        );
+        assert!(map.to_json().is_empty());
+    }
+
+    #[test]
+    fn fog() {
+        let mut map = Map::new();
+        let delta = map
+            .apply(&object! {"fog": {"hidden": [[1, 2]], "junk": 1}})
+            .unwrap();
+        assert_eq!(
+            delta,
+            object! {"fog": {"revealAll": false, "revealed": [], "hidden": [[1, 2]]}}
+        );
+        assert_eq!(
+            map.apply(&object! {"fog": {"revealed": [[1.5, 2]]}})
+                .unwrap_err(),
+            "fog.revealed"
+        );
+        assert!(map.check_moves(&object! {"fog": {"pos": [1, 1]}}).is_err());
+        map.apply(&object! {"fog": {}}).unwrap();
+        assert!(map.to_json().is_empty());
+    }
+
+    #[test]
+    fn walls() {
+        let mut map = Map::new();
+        let wall = object! {"type": "wall", "pos": [1, 2], "nodes": [[0, 0], [10, 0]]};
+        let delta = map.apply(&object! {"wall": wall.clone()}).unwrap();
+        assert_eq!(
+            delta["wall"]["nodes"],
+            json::array![[0.0, 0.0], [10.0, 0.0]]
+        );
+        assert!(!delta["wall"].has_key("path"));
+        assert_eq!(
+            map.wall_in(&object! {"wall": {"pos": [0, 0]}}),
+            Some("wall")
+        );
+        assert_eq!(map.wall_in(&object! {"new": wall}), Some("new"));
+        assert_eq!(map.wall_in(&object! {"abc": {"type": "token"}}), None);
+
+        let nodes = object! {"wall": {"nodes": [[0, 0], [5, 5], [10, 0]]}};
+        assert_eq!(map.apply(&nodes).unwrap(), nodes);
+        for nodes in [
+            json::array![[0, 0]],
+            json::array![[0, 0], "here"],
+            object! {},
+        ] {
+            assert_eq!(
+                map.apply(&object! {"wall": {"nodes": nodes}}).unwrap_err(),
+                "wall.nodes"
+            );
+        }
+        assert_eq!(
+            map.apply(&object! {"def": {"type": "wall"}}).unwrap_err(),
+            "def.nodes"
+        );
+    }
+
+    #[test]
+    fn layers() {
+        let mut map = Map::new();
+        let delta = map
+            .apply(&object! {"abc": {"type": "token", "path": "orc.png"}})
+            .unwrap();
+        assert_eq!(delta["abc"]["layer"], "tokens");
+        assert_eq!(delta["abc"]["zIndex"], 0);
+        assert_eq!(map.gm_layer_in(&object! {"abc": {"zIndex": 3}}), None);
+
+        let change = object! {"abc": {"layer": "gm", "zIndex": -1}};
+        assert_eq!(map.apply(&change).unwrap(), change);
+        assert_eq!(map.gm_layer_in(&object! {"abc": {}}), Some("abc"));
+        assert_eq!(
+            map.owner_in(&object! {"abc": {"properties": {"hp": "3"}}}),
+            None
+        );
+        assert_eq!(
+            map.owner_in(&object! {"abc": {"properties": {"owner": null}}}),
+            Some("abc")
+        );
+        for (change, field) in [
+            (object! {"layer": "top"}, "abc.layer"),
+            (object! {"zIndex": 0.5}, "abc.zIndex"),
+        ] {
+            assert_eq!(map.apply(&object! {"abc": change}).unwrap_err(), field);
+        }
+    }
+
+    #[test]
+    fn gm_layer_for_players() {
+        let mut map = Map::new();
+        let mut apply = |change: json::JsonValue| {
+            let seen = map.seen_by_players(&change);
+            let delta = map.apply(&change).unwrap();
+            (map.players_delta(&delta, &seen), map.players_view())
+        };
+        let trap = object! {"trap": {"type": "decal", "path": "trap.png", "layer": "gm"}};
+        let (delta, view) = apply(trap);
+        assert!(delta.is_empty());
+        assert!(view.is_empty());
+
+        // Trap is sprung, and players see all of it
+        let (delta, view) = apply(object! {"trap": {"layer": "map"}});
+        assert_eq!(delta["trap"]["path"], "trap.png");
+        assert_eq!(view["trap"], delta["trap"]);
+        let (delta, _) = apply(object! {"trap": {"scale": 2.0}});
+        assert_eq!(delta, object! {"trap": {"scale": 2.0}});
+
+        // Hidden again
+        let (delta, view) = apply(object! {"trap": {"layer": "gm"}});
+        assert_eq!(delta, object! {"trap": {}});
+        assert!(view.is_empty());
+        let (delta, _) = apply(object! {"trap": {}});
+        assert!(delta.is_empty());
+    }
+
+    #[test]
+    fn transforms() {
+        let mut map = Map::new();
+        let delta = map
+            .apply(&object! {"abc": {"type": "decal", "path": "door.png", "flipY": true}})
+            .unwrap();
+        assert_eq!(delta["abc"]["rotation"], 0.0);
+        assert_eq!(delta["abc"]["flipX"], false);
+        assert_eq!(delta["abc"]["flipY"], true);
+
+        let change = object! {"abc": {"rotation": 90.0, "flipX": true}};
+        assert_eq!(map.apply(&change).unwrap(), change);
+        assert_eq!(
+            map.apply(&object! {"abc": {"rotation": "left"}})
+                .unwrap_err(),
+            "abc.rotation"
+        );
+        assert_eq!(
+            map.apply(&object! {"abc": {"flipY": 1}}).unwrap_err(),
+            "abc.flipY"
+        );
+    }
+
+    #[test]
+    fn effects() {
+        let mut map = Map::new();
+        let delta = map
+            .apply(&object! {"fx": {"type": "effect", "pos": [5, 5], "shape": "cone", "size": 3}})
+            .unwrap();
+        assert!(!delta["fx"].has_key("path"));
+        assert_eq!(delta["fx"]["size"], 3.0);
+        assert_eq!(delta["fx"]["opacity"], 0.4);
+
+        let change = object! {"fx": {"direction": 45.0, "color": [0, 0, 255]}};
+        assert_eq!(map.apply(&change).unwrap(), change);
+        assert_eq!(map.to_json()["fx"]["shape"], "cone");
+        for (change, field) in [
+            (object! {"shape": "star"}, "fx.shape"),
+            (object! {"size": 0}, "fx.size"),
+            (object! {"opacity": 1.5}, "fx.opacity"),
+            (object! {"color": [0, 0]}, "fx.color"),
+        ] {
+            assert_eq!(map.apply(&object! {"fx": change}).unwrap_err(), field);
+        }
+        assert_eq!(
+            map.apply(&object! {"new": {"type": "effect"}}).unwrap_err(),
+            "new.shape"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use json::{object, JsonValue};
+use tokio::sync::mpsc::UnboundedSender;
+
+use protocol::model::OkResponse;
+use protocol::{Message, PROTOCOL_VERSION};
+
+use std::time::Instant;
+
+use crate::utils;
+use crate::CAPABILITIES;
+
+// Messages put here are sent to the player by their connection's writer task
+pub type Outbox = UnboundedSender<Message>;
+
+pub struct Player {
+    pub id: String,
+    pub cookie: String,
+    pub nickname: String,
+    pub color: [u8; 3],
+    // Round trip time in milliseconds the player has reported in their last PING
+    pub latency: Option<u32>,
+    // Can only watch: MAP and chat messages are refused
+    pub spectator: bool,
+    // Connection this player is using right now
+    pub conn_id: u64,
+    outbox: Outbox,
+}
+
+impl Player {
+    pub fn new(conn_id: u64, outbox: Outbox) -> Self {
+        Self {
+            id: utils::random_string(16),
+            cookie: utils::random_string(32),
+            nickname: String::new(),
+            color: [255, 255, 255],
+            latency: None,
+            spectator: false,
+            conn_id,
+            outbox,
+        }
+    }
+
+    // If the connection is already closed, the message is just dropped
+    pub fn send(&self, msg: Message) {
+        #[allow(unused)]
+        {
+            self.outbox.send(msg);
+        }
+    }
+
+    // Body of the OK message which is sent in response to JOIN/INIT
+    pub fn ok_response(&self) -> OkResponse {
+        OkResponse {
+            user_id: self.id.clone(),
+            user_cookie: self.cookie.clone(),
+            nickname: Some(self.nickname.clone()),
+            color: Some(self.color),
+            room_id: None,
+            version: Some(PROTOCOL_VERSION.to_string()),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    // How other players see this one in PLAYER messages
+    pub fn info_json(&self) -> JsonValue {
+        let mut info = object! {
+            "nickname": self.nickname.clone(),
+            "color": self.color.to_vec(),
+        };
+        if let Some(latency) = self.latency {
+            info["latency"] = latency.into();
+        }
+        if self.spectator {
+            info["spectator"] = true.into();
+        }
+        info
+    }
+}
+
+// Player who has lost connection, but can still come back. See Room::add_player()
+pub struct Departed {
+    pub cookie: String,
+    pub nickname: String,
+    pub color: [u8; 3],
+    pub spectator: bool,
+    pub since: Instant,
+}
+
+impl Departed {
+    pub fn new(player: Player) -> Self {
+        Self {
+            cookie: player.cookie,
+            nickname: player.nickname,
+            color: player.color,
+            spectator: player.spectator,
+            since: Instant::now(),
+        }
+    }
+}
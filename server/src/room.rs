@@ -0,0 +1,576 @@
+use json::{object, JsonValue};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration, Instant};
+
+use protocol::model::{ErrResponse, ErrorCode, Hello, Invite, OkResponse, RoomSummary};
+use protocol::{Message, MsgBody, MsgType};
+
+use std::collections::HashMap;
+
+use crate::commands::{self, Command};
+use crate::invites::{InviteError, Invites};
+use crate::map::Map;
+use crate::player::{Departed, Outbox, Player};
+use crate::{utils, Rooms};
+
+// How long players who lost connection can come back and resume their session
+const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+const MASTER_COLOR: [u8; 3] = [255, 20, 20];
+// Transient moves are collected and sent out this often, only the latest
+// position of every object gets through
+const MOVES_INTERVAL: Duration = Duration::from_millis(100);
+const CHUNK_PROPS: [&str; 6] = [
+    "path",
+    "chunk",
+    "offset",
+    "totalSize",
+    "checksum",
+    "contentType",
+];
+
+pub enum RoomEvent {
+    // Player has sent JOIN/INIT. The first one to join becomes the master
+    Join {
+        conn_id: u64,
+        hello: Hello,
+        outbox: Outbox,
+    },
+    Message(u64, Message),
+    // Connection was lost without QUIT
+    Disconnected(u64),
+    // Someone on the LAN wants to know about the room
+    Describe(oneshot::Sender<RoomSummary>),
+}
+
+pub struct Room {
+    id: String,
+    rooms: Rooms,
+    events: UnboundedReceiver<RoomEvent>,
+    master_id: Option<String>,
+    // Set by the master with /roomname
+    name: Option<String>,
+    // Set by the master's INIT. Players need it or an invite to join
+    password: Option<String>,
+    invites: Invites,
+    players: Vec<Player>,
+    // Players who lost connection: ID -> Departed
+    departed: HashMap<String, Departed>,
+    player_counter: u32,
+    // Server's request ID -> (Requester's ID, Requester's request ID)
+    file_requests: HashMap<String, (String, String)>,
+    map: Map,
+    // Objects which are being dragged right now. Object ID -> (Dragging
+    // player's ID, Position)
+    moves: HashMap<String, (String, JsonValue)>,
+}
+
+impl Room {
+    pub fn new(id: String, rooms: Rooms) -> (Self, UnboundedSender<RoomEvent>) {
+        let (sender, events) = mpsc::unbounded_channel();
+        let room = Self {
+            id,
+            rooms,
+            events,
+            master_id: None,
+            name: None,
+            password: None,
+            invites: Invites::new(),
+            players: Vec::new(),
+            departed: HashMap::new(),
+            player_counter: 1,
+            file_requests: HashMap::new(),
+            map: Map::new(),
+            moves: HashMap::new(),
+        };
+        (room, sender)
+    }
+
+    // Runs until everybody has left and nobody is going to come back
+    pub async fn run(mut self) {
+        let mut ticks = time::interval_at(Instant::now() + RESUME_TIMEOUT, Duration::from_secs(1));
+        let mut move_ticks = time::interval(MOVES_INTERVAL);
+        loop {
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Some(event) => self.handle_event(event),
+                    None => break,
+                },
+                _ = ticks.tick() => (),
+                _ = move_ticks.tick() => self.send_moves(),
+            }
+            if self.is_abandoned() {
+                break;
+            }
+        }
+        self.rooms.lock().unwrap().remove(&self.id);
+        println!("Room {} is closed", self.id);
+    }
+
+    fn handle_event(&mut self, event: RoomEvent) {
+        match event {
+            RoomEvent::Join {
+                conn_id,
+                hello,
+                outbox,
+            } => self.add_player(Player::new(conn_id, outbox), hello),
+            RoomEvent::Message(conn_id, msg) => {
+                if let Some(index) = self.players.iter().position(|p| p.conn_id == conn_id) {
+                    self.handle_message(index, msg);
+                }
+            }
+            RoomEvent::Disconnected(conn_id) => {
+                if let Some(index) = self.players.iter().position(|p| p.conn_id == conn_id) {
+                    self.remove_player(index, true);
+                }
+            }
+            RoomEvent::Describe(reply) => {
+                #[allow(unused)]
+                {
+                    reply.send(self.summary());
+                }
+            }
+        }
+    }
+
+    fn summary(&self) -> RoomSummary {
+        let gm = self
+            .players
+            .iter()
+            .find(|p| Some(&p.id) == self.master_id.as_ref())
+            .map(|p| &p.nickname)
+            // Master may be reconnecting
+            .or_else(|| Some(&self.departed.get(self.master_id.as_ref()?)?.nickname))
+            .cloned()
+            .unwrap_or_default();
+        RoomSummary {
+            id: self.id.clone(),
+            name: match &self.name {
+                Some(name) => name.clone(),
+                None => format!("{}'s room", gm),
+            },
+            gm,
+            players: self.players.iter().filter(|p| !p.spectator).count() as u32,
+            password: self.password.is_some(),
+        }
+    }
+
+    fn handle_message(&mut self, index: usize, mut msg: Message) {
+        match (msg.msg_type(), msg.take_body()) {
+            (MsgType::Map, Some(MsgBody::Json(json))) if msg.get_prop("transient").is_some() => {
+                // No ERR for these, the MAP sent after the drag gets one
+                if self.check_map_permissions(index, &json).is_some() {
+                    return;
+                }
+                let sender = &self.players[index].id;
+                match self.map.check_moves(&json) {
+                    Ok(moves) => {
+                        for (id, pos) in moves {
+                            self.moves.insert(id, (sender.clone(), pos));
+                        }
+                    }
+                    Err(e) => println!("Bad transient MAP from {}: {}", sender, e),
+                }
+            }
+            (MsgType::Map, Some(MsgBody::Json(json))) => {
+                if let Some(err) = self.check_map_permissions(index, &json) {
+                    self.players[index].send(err_msg(err));
+                    return;
+                }
+                let seen = self.map.seen_by_players(&json);
+                match self.map.apply(&json) {
+                    Ok(delta) => {
+                        // Final positions must not be overwritten by the moves
+                        // which haven't been sent yet
+                        if delta.is_null() {
+                            self.moves.clear();
+                        } else {
+                            self.moves.retain(|id, _| !delta.has_key(id));
+                        }
+                        // Players don't hear about the changes they can't see
+                        let players_delta = self.map.players_delta(&delta, &seen);
+                        let seen = delta.is_empty() || !players_delta.is_empty();
+                        for player in &self.players {
+                            if Some(&player.id) == self.master_id.as_ref() {
+                                player.send(json_msg(MsgType::Map, delta.clone()));
+                            } else if seen {
+                                player.send(json_msg(MsgType::Map, players_delta.clone()));
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let err =
+                            ErrResponse::new(ErrorCode::BadRequest, &format!("Bad MAP: {}", e))
+                                .about(MsgType::Map, None);
+                        self.players[index].send(err_msg(err));
+                    }
+                }
+            }
+            (MsgType::Msg, Some(MsgBody::Text(text))) => {
+                // Spectators can only change their own nickname and color
+                let personal = matches!(
+                    Command::parse(&text),
+                    Some(Command::Color(_) | Command::Nickname(_))
+                );
+                if self.players[index].spectator && !personal {
+                    let err =
+                        ErrResponse::new(ErrorCode::PermissionDenied, "Spectators can't chat")
+                            .about(MsgType::Msg, None);
+                    self.players[index].send(err_msg(err));
+                } else if text.starts_with('/') {
+                    self.run_command(index, &text);
+                } else {
+                    let sender = self.players[index].id.clone();
+                    self.broadcast(chat_msg(&sender, text));
+                }
+            }
+            (MsgType::File, body) => {
+                if self.is_master(index) {
+                    self.relay_chunk(&msg, body);
+                } else {
+                    self.forward_file_request(index, &msg);
+                }
+            }
+            (MsgType::Invite, Some(MsgBody::Json(json))) => self.create_invite(index, &json),
+            (MsgType::Ping, _) => self.answer_ping(index, &msg),
+            (MsgType::Quit, _) => self.remove_player(index, false),
+            _ => (),
+        }
+    }
+
+    fn create_invite(&mut self, index: usize, json: &JsonValue) {
+        let err = if !self.is_master(index) {
+            ErrResponse::new(
+                ErrorCode::PermissionDenied,
+                "Only the GM can invite players",
+            )
+        } else {
+            match Invite::from_json(json) {
+                Ok(invite) => {
+                    let answer = Invite {
+                        token: Some(self.invites.issue(invite.expires_in)),
+                        ..invite
+                    };
+                    self.players[index].send(json_msg(MsgType::Invite, answer.to_json()));
+                    return;
+                }
+                Err(e) => ErrResponse::new(ErrorCode::BadRequest, &format!("Bad INVITE: {}", e)),
+            }
+        };
+        self.players[index].send(err_msg(err.about(MsgType::Invite, None)));
+    }
+
+    // Rooms without a password are open to everyone. Otherwise JOIN needs
+    // either the password or an invite, which is used up
+    fn check_access(&mut self, hello: &Hello) -> Option<ErrResponse> {
+        let password = self.password.as_ref()?;
+        let (code, message) = match (&hello.invite, &hello.password) {
+            (Some(token), _) => match self.invites.redeem(token) {
+                Ok(()) => return None,
+                Err(InviteError::Expired) => (ErrorCode::InviteExpired, "This invite has expired"),
+                Err(InviteError::Used) => {
+                    (ErrorCode::InviteUsed, "This invite has already been used")
+                }
+                Err(InviteError::Invalid) => (ErrorCode::InviteInvalid, "This invite isn't valid"),
+            },
+            (None, Some(given)) if given == password => return None,
+            (None, Some(_)) => (ErrorCode::WrongPassword, "Wrong password"),
+            (None, None) => (ErrorCode::WrongPassword, "This room needs a password"),
+        };
+        Some(ErrResponse::new(code, message).about(MsgType::Join, Some(&self.id)))
+    }
+
+    // PONG goes right back, and the latency the player has measured is shared
+    // with everybody
+    fn answer_ping(&mut self, index: usize, msg: &Message) {
+        let player = &mut self.players[index];
+        let ping_id = msg.get_prop("pingId").unwrap_or("");
+        player.send(Message::new(MsgType::Pong).set_prop("pingId", ping_id));
+
+        let latency = msg.get_prop("latency").and_then(|s| s.parse().ok());
+        if latency.is_none() || latency == player.latency {
+            return;
+        }
+        player.latency = latency;
+        let mut info = JsonValue::new_object();
+        info[player.id.as_str()] = object! {"latency": latency};
+        self.broadcast(json_msg(MsgType::Player, info));
+    }
+
+    // TODO: Proper permissions (PERM). For now, only the master can clear the
+    // whole map, change its background, walls and the fog of war
+    fn check_map_permissions(&self, index: usize, delta: &JsonValue) -> Option<ErrResponse> {
+        if self.is_master(index) {
+            return None;
+        }
+        let (id, message) = if self.players[index].spectator {
+            (None, "Spectators can't change the map")
+        } else if delta.is_null() {
+            (None, "Only the GM can clear the map")
+        } else if delta.has_key("background") {
+            (Some("background"), "Only the GM can change the background")
+        } else if delta.has_key("fog") {
+            (Some("fog"), "Only the GM can change the fog of war")
+        } else if let Some(id) = self.map.wall_in(delta) {
+            (Some(id), "Only the GM can change walls")
+        } else if let Some(id) = self.map.gm_layer_in(delta) {
+            (Some(id), "Only the GM can use the GM layer")
+        } else if let Some(id) = self.map.owner_in(delta) {
+            (Some(id), "Only the GM can give tokens to players")
+        } else {
+            return None;
+        };
+        Some(ErrResponse::new(ErrorCode::PermissionDenied, message).about(MsgType::Map, id))
+    }
+
+    fn is_master(&self, index: usize) -> bool {
+        Some(&self.players[index].id) == self.master_id.as_ref()
+    }
+
+    fn add_player(&mut self, mut player: Player, hello: Hello) {
+        if self.master_id.is_none() {
+            player.nickname = "Master".to_string();
+            player.color = MASTER_COLOR;
+            self.master_id = Some(player.id.clone());
+            self.password = hello.password.filter(|password| !password.is_empty());
+            let ok = OkResponse {
+                room_id: Some(self.id.clone()),
+                ..player.ok_response()
+            };
+            player.send(json_msg(MsgType::Ok, ok.to_json()));
+            // TODO: Send default permissions
+            player.send(Message::new(MsgType::Synced));
+            self.players.push(player);
+            return;
+        }
+
+        let session = self.take_session(&hello);
+        if session.is_none() {
+            if let Some(err) = self.check_access(&hello) {
+                player.send(err_msg(err));
+                return;
+            }
+        }
+        match session {
+            Some((id, old)) => {
+                player.id = id;
+                player.cookie = old.cookie;
+                player.nickname = old.nickname;
+                player.color = old.color;
+                player.spectator = old.spectator;
+            }
+            None => {
+                player.nickname = format!("Player{}", self.player_counter);
+                player.color = utils::random_color();
+                player.spectator = hello.spectator;
+                self.player_counter += 1;
+            }
+        }
+
+        player.send(json_msg(MsgType::Ok, player.ok_response().to_json()));
+        let mut others = JsonValue::new_object();
+        for other in &self.players {
+            others[other.id.as_str()] = other.info_json();
+        }
+        player.send(json_msg(MsgType::Player, others));
+        let map = if Some(&player.id) == self.master_id.as_ref() {
+            self.map.to_json()
+        } else {
+            self.map.players_view()
+        };
+        player.send(json_msg(MsgType::Map, map));
+        player.send(Message::new(MsgType::Synced));
+
+        let mut info = JsonValue::new_object();
+        info[player.id.as_str()] = player.info_json();
+        self.broadcast(json_msg(MsgType::Player, info));
+        self.players.push(player);
+    }
+
+    // If the client has come back with its old ID and cookie, takes its session
+    // from the departed players. Client may also reconnect before we've noticed
+    // that its old connection is dead, then that connection is dropped
+    fn take_session(&mut self, hello: &Hello) -> Option<(String, Departed)> {
+        let (id, cookie) = match (&hello.user_id, &hello.user_cookie) {
+            (Some(id), Some(cookie)) => (id, cookie),
+            _ => return None,
+        };
+        if let Some(index) = self.players.iter().position(|p| &p.id == id) {
+            if &self.players[index].cookie == cookie {
+                let old = Departed::new(self.players.remove(index));
+                return Some((id.clone(), old));
+            }
+        }
+        match self.departed.get(id) {
+            Some(old) if &old.cookie == cookie => Some((id.clone(), self.departed.remove(id)?)),
+            _ => None,
+        }
+    }
+
+    // If the player just lost connection (Didn't QUIT), they can come back later
+    // with the same ID and cookie. See .take_session()
+    fn remove_player(&mut self, index: usize, resumable: bool) {
+        let player = self.players.remove(index);
+        let id = player.id.clone();
+        if resumable {
+            self.departed.insert(id.clone(), Departed::new(player));
+        }
+        let mut info = JsonValue::new_object();
+        info[id.as_str()] = object! {};
+        self.broadcast(json_msg(MsgType::Player, info));
+    }
+
+    fn is_abandoned(&mut self) -> bool {
+        self.departed
+            .retain(|_, departed| departed.since.elapsed() < RESUME_TIMEOUT);
+        self.players.is_empty() && self.departed.is_empty()
+    }
+
+    fn run_command(&mut self, index: usize, text: &str) {
+        let player = &mut self.players[index];
+        match Command::parse(text) {
+            Some(Command::Roll(query)) => match commands::roll_dice(&query) {
+                Some(result) => {
+                    let text = format!("{} rolls {} = {}", player.nickname, query, result);
+                    self.broadcast(chat_msg("server", text));
+                }
+                None => player.send(chat_msg("server", "Bad roll query".to_string())),
+            },
+            Some(Command::Color(color)) => {
+                player.color = color;
+                let mut info = JsonValue::new_object();
+                info[player.id.as_str()] = object! {"color": color.to_vec()};
+                self.broadcast(json_msg(MsgType::Player, info));
+            }
+            Some(Command::Nickname(nickname)) => {
+                player.nickname = nickname;
+                let mut info = JsonValue::new_object();
+                info[player.id.as_str()] = object! {"nickname": player.nickname.clone()};
+                self.broadcast(json_msg(MsgType::Player, info));
+            }
+            Some(Command::RoomName(name)) => {
+                if Some(&player.id) == self.master_id.as_ref() {
+                    let text = format!("Room is now called {}", name);
+                    self.name = Some(name);
+                    self.broadcast(chat_msg("server", text));
+                } else {
+                    player.send(chat_msg(
+                        "server",
+                        "Only the GM can rename the room".to_string(),
+                    ));
+                }
+            }
+            None => (),
+        }
+    }
+
+    // Requests get IDs of their own, so players can't mess with each other's
+    // transfers
+    fn forward_file_request(&mut self, index: usize, msg: &Message) {
+        let master = match self
+            .players
+            .iter()
+            .find(|p| Some(&p.id) == self.master_id.as_ref())
+        {
+            Some(master) => master,
+            None => return,
+        };
+        let request_id = utils::random_string(16);
+        let mut request = Message::new(MsgType::File)
+            .set_prop("path", msg.get_prop("path").unwrap_or(""))
+            .set_prop("requestId", &request_id);
+        for key in ["offset", "checksum"] {
+            if let Some(val) = msg.get_prop(key) {
+                request = request.set_prop(key, val);
+            }
+        }
+        master.send(request);
+        let player_request_id = msg.get_prop("requestId").unwrap_or("").to_string();
+        self.file_requests.insert(
+            request_id,
+            (self.players[index].id.clone(), player_request_id),
+        );
+    }
+
+    // Master answers every FILE request with a series of chunks, which are sent
+    // only to the player who has requested the file
+    fn relay_chunk(&mut self, msg: &Message, body: Option<MsgBody>) {
+        let request_id = msg.get_prop("requestId").unwrap_or("");
+        let (requester, player_request_id) = match self.file_requests.get(request_id) {
+            Some(request) => request.clone(),
+            None => return,
+        };
+        // Only copying what's needed, master's cookie must not get through
+        let mut chunk = Message::new(MsgType::File).set_prop("requestId", &player_request_id);
+        for key in CHUNK_PROPS {
+            if let Some(val) = msg.get_prop(key) {
+                chunk = chunk.set_prop(key, val);
+            }
+        }
+        let len = match &body {
+            Some(MsgBody::Bin(bytes)) => bytes.len(),
+            _ => 0,
+        };
+        if let Some(body) = body {
+            chunk.attach_body(body);
+        }
+
+        let prop = |key| msg.get_prop(key).and_then(|s| s.parse::<usize>().ok());
+        let mut done = match (prop("offset"), prop("totalSize")) {
+            (Some(offset), Some(total)) => offset + len >= total,
+            _ => true,
+        };
+        match self.players.iter().find(|p| p.id == requester) {
+            Some(player) => player.send(chunk),
+            // Player has left, they will ask again if they come back
+            None => done = true,
+        }
+        if done {
+            self.file_requests.remove(request_id);
+        }
+    }
+
+    // Everybody gets the latest positions of the objects that are being
+    // dragged, except for those who are dragging them. Objects on the GM layer
+    // are only shown to the GM
+    fn send_moves(&mut self) {
+        if self.moves.is_empty() {
+            return;
+        }
+        for player in &self.players {
+            let is_master = Some(&player.id) == self.master_id.as_ref();
+            let mut delta = JsonValue::new_object();
+            for (id, (sender, pos)) in &self.moves {
+                if sender != &player.id && (is_master || !self.map.is_on_gm_layer(id)) {
+                    delta[id.as_str()] = object! {"pos": pos.clone()};
+                }
+            }
+            if !delta.is_empty() {
+                player.send(json_msg(MsgType::Map, delta).set_prop("transient", "true"));
+            }
+        }
+        self.moves.clear();
+    }
+
+    fn broadcast(&self, msg: Message) {
+        for player in &self.players {
+            player.send(msg.clone());
+        }
+    }
+}
+
+fn json_msg(msg_type: MsgType, json: JsonValue) -> Message {
+    let mut msg = Message::new(msg_type);
+    msg.attach_body(MsgBody::Json(json));
+    msg
+}
+
+pub fn err_msg(err: ErrResponse) -> Message {
+    json_msg(MsgType::Err, err.to_json())
+}
+
+fn chat_msg(sender_id: &str, text: String) -> Message {
+    let mut msg = Message::new(MsgType::Msg).set_prop("userId", sender_id);
+    msg.attach_body(MsgBody::Text(text));
+    msg
+}
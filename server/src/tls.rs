@@ -0,0 +1,44 @@
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+// Certificate chain and private key (PKCS#8, RSA or EC) are read from PEM files.
+// Clients pin the certificate the first time they see it, so it should be the
+// same after restarts
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data("no private key found"))?;
+    tls_acceptor(certs, key)
+}
+
+// Takes DER-encoded certificates and key
+pub fn tls_acceptor(certs: Vec<Vec<u8>>, key: Vec<u8>) -> io::Result<TlsAcceptor> {
+    if certs.is_empty() {
+        return Err(invalid_data("no certificates found"));
+    }
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )
+        .map_err(|e| invalid_data(&e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,24 @@
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+const PLAYER_COLORS: &[[u8; 3]] = &[
+    [200, 200, 10],
+    [10, 255, 10],
+    [10, 10, 255],
+    [10, 200, 200],
+    [200, 10, 200],
+    [0, 100, 200],
+];
+
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+pub fn random_color() -> [u8; 3] {
+    *PLAYER_COLORS.choose(&mut rand::thread_rng()).unwrap()
+}
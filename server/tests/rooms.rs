@@ -0,0 +1,656 @@
+// These tests run the server on localhost and talk to it through the client's
+// own connections
+
+use eframe::egui::Context;
+use json::{object, JsonValue};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::runtime::Runtime;
+
+use client::net::model::{ErrResponse, ErrorCode};
+use client::net::{
+    Connection, JoinOptions, KnownHosts, LanBrowser, Message, MsgBody, MsgType, RoomAddr,
+    ServerAddr, ServerConnection, TlsConnection, PING_INTERVAL,
+};
+use client::state::{checksum, RoomState};
+use client::DraduError;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+// Starts a server on a random port in the background
+fn start_server() -> SocketAddr {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || runtime.block_on(server::serve(listener, None, None)));
+    addr
+}
+
+// Also answers LAN discovery probes, on the second address
+fn start_discoverable_server() -> (SocketAddr, SocketAddr) {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let socket = runtime.block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let discovery = socket.local_addr().unwrap();
+    thread::spawn(move || runtime.block_on(server::serve(listener, None, Some(socket))));
+    (addr, discovery)
+}
+
+// Same as start_server, but with a freshly generated self-signed certificate
+fn start_tls_server() -> SocketAddr {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let acceptor = server::tls_acceptor(
+        vec![cert.serialize_der().unwrap()],
+        cert.serialize_private_key_der(),
+    )
+    .unwrap();
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || runtime.block_on(server::serve(listener, Some(acceptor), None)));
+    addr
+}
+
+struct TestClient {
+    conn: Box<dyn Connection>,
+    received: VecDeque<Message>,
+}
+
+impl TestClient {
+    fn create_room(addr: SocketAddr) -> Self {
+        let conn = ServerConnection::create_new_room(&addr.to_string(), None, &Context::default())
+            .unwrap();
+        Self::new(conn)
+    }
+
+    fn join(addr: SocketAddr, room_id: &str) -> Self {
+        let options = JoinOptions::default();
+        let conn =
+            ServerConnection::join_room(&addr.to_string(), room_id, &options, &Context::default())
+                .unwrap();
+        Self::new(conn)
+    }
+
+    fn new(conn: impl Connection + 'static) -> Self {
+        Self {
+            conn: Box::new(conn),
+            received: VecDeque::new(),
+        }
+    }
+
+    fn room_id(&self) -> String {
+        let addr = self.conn.get_room_address().unwrap();
+        addr.parse::<RoomAddr>().unwrap().room_id
+    }
+
+    fn id(&self) -> String {
+        self.conn.get_user_id().to_string()
+    }
+
+    fn send(&mut self, msg_type: MsgType, body: Option<MsgBody>) {
+        let mut msg = Message::new(msg_type);
+        if let Some(body) = body {
+            msg.attach_body(body);
+        }
+        self.conn.send_msg(msg).unwrap();
+    }
+
+    fn say(&mut self, text: &str) {
+        self.send(MsgType::Msg, Some(MsgBody::Text(text.to_string())));
+    }
+
+    // Waits for a message of the given type, skipping everything else
+    fn expect(&mut self, msg_type: MsgType) -> Message {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            while let Some(msg) = self.received.pop_front() {
+                if msg.msg_type() == msg_type {
+                    return msg;
+                }
+            }
+            assert!(Instant::now() < deadline, "No {} from the server", msg_type);
+            self.received.extend(self.conn.new_messages().unwrap());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn expect_json(&mut self, msg_type: MsgType) -> JsonValue {
+        match self.expect(msg_type).take_body() {
+            Some(MsgBody::Json(json)) => json,
+            _ => panic!("Expected a json body"),
+        }
+    }
+
+    fn expect_text(&mut self, msg_type: MsgType) -> (String, String) {
+        let mut msg = self.expect(msg_type);
+        let sender = msg.get_prop("userId").unwrap_or("").to_string();
+        match msg.take_body() {
+            Some(MsgBody::Text(text)) => (sender, text),
+            _ => panic!("Expected a text body"),
+        }
+    }
+}
+
+// Master creates a room and one player joins it
+fn start_room() -> (SocketAddr, TestClient, TestClient) {
+    let addr = start_server();
+    let mut master = TestClient::create_room(addr);
+    let mut player = TestClient::join(addr, &master.room_id());
+    let players = player.expect_json(MsgType::Player);
+    assert_eq!(players[master.id()]["nickname"], "Master");
+    player.expect(MsgType::Synced);
+    let joined = master.expect_json(MsgType::Player);
+    assert_eq!(joined[player.id()]["nickname"], "Player1");
+    (addr, master, player)
+}
+
+#[test]
+fn join_room() {
+    let addr = start_server();
+    let mut master = TestClient::create_room(addr);
+    assert_eq!(master.conn.get_nickname(), "Master");
+    assert!(master.conn.supports("resume"));
+
+    let mut player = TestClient::join(addr, &master.room_id());
+    assert_ne!(player.id(), master.id());
+    assert!(player.expect_json(MsgType::Player).has_key(&master.id()));
+    assert!(player.expect_json(MsgType::Map).is_empty());
+    player.expect(MsgType::Synced);
+    assert!(master.expect_json(MsgType::Player).has_key(&player.id()));
+
+    let options = JoinOptions::default();
+    assert!(ServerConnection::join_room(
+        &addr.to_string(),
+        "nonexistent",
+        &options,
+        &Context::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn chat_and_commands() {
+    let (_, mut master, mut player) = start_room();
+
+    player.say("Hello there");
+    assert_eq!(
+        master.expect_text(MsgType::Msg),
+        (player.id(), "Hello there".to_string())
+    );
+
+    player.say("/nick  Sir Robin");
+    let update = master.expect_json(MsgType::Player);
+    assert_eq!(update[player.id()]["nickname"], "Sir Robin");
+
+    player.say("/color 1 2 3");
+    let update = master.expect_json(MsgType::Player);
+    assert_eq!(update[player.id()]["color"], json::array![1, 2, 3]);
+
+    player.say("/roll 2d1 +  3");
+    let (sender, text) = master.expect_text(MsgType::Msg);
+    assert_eq!(sender, "server");
+    assert_eq!(text, "Sir Robin rolls 2d1 + 3 = 5");
+
+    // Only the one who rolled sees the error
+    player.say("/r 2d");
+    assert_eq!(player.expect_text(MsgType::Msg).1, "Hello there");
+    assert_eq!(
+        player.expect_text(MsgType::Msg).1,
+        "Sir Robin rolls 2d1 + 3 = 5"
+    );
+    assert_eq!(player.expect_text(MsgType::Msg).1, "Bad roll query");
+}
+
+#[test]
+fn map_updates() {
+    let (addr, mut master, mut player) = start_room();
+
+    let token = object! {"abc": {"type": "token", "path": "orc.png", "pos": [1.0, 2.0]}};
+    master.send(MsgType::Map, Some(MsgBody::Json(token)));
+    let delta = player.expect_json(MsgType::Map);
+    assert_eq!(delta["abc"]["path"], "orc.png");
+    // Sender gets the delta too
+    master.expect(MsgType::Map);
+
+    player.send(
+        MsgType::Map,
+        Some(MsgBody::Json(object! {"abc": {"scale": 2.0}})),
+    );
+    assert_eq!(
+        master.expect_json(MsgType::Map),
+        object! {"abc": {"scale": 2.0}}
+    );
+
+    // Players who join later get the whole map
+    let mut late = TestClient::join(addr, &master.room_id());
+    let map = late.expect_json(MsgType::Map);
+    assert_eq!(map["abc"]["scale"], 2.0);
+    assert_eq!(map["abc"]["pos"], json::array![1.0, 2.0]);
+
+    // Players don't get what's on the GM layer until it leaves it
+    let trap = object! {"trap": {"type": "decal", "path": "trap.png", "layer": "gm"}};
+    master.send(MsgType::Map, Some(MsgBody::Json(trap)));
+    master.expect(MsgType::Map);
+    master.send(
+        MsgType::Map,
+        Some(MsgBody::Json(object! {"abc": {"scale": 3.0}})),
+    );
+    assert_eq!(
+        late.expect_json(MsgType::Map),
+        object! {"abc": {"scale": 3.0}}
+    );
+    master.expect(MsgType::Map);
+    let sprung = object! {"trap": {"layer": "map"}};
+    master.send(MsgType::Map, Some(MsgBody::Json(sprung)));
+    assert_eq!(late.expect_json(MsgType::Map)["trap"]["path"], "trap.png");
+    master.expect(MsgType::Map);
+
+    master.send(MsgType::Map, Some(MsgBody::Json(JsonValue::Null)));
+    assert!(late.expect_json(MsgType::Map).is_null());
+}
+
+#[test]
+fn errors() {
+    let (addr, mut master, mut player) = start_room();
+
+    let options = JoinOptions::default();
+    match ServerConnection::join_room(
+        &addr.to_string(),
+        "nonexistent",
+        &options,
+        &Context::default(),
+    ) {
+        Err(DraduError::ServerError(code, message)) => {
+            assert_eq!(code, ErrorCode::RoomNotFound);
+            assert_eq!(message, "Room nonexistent doesn't exist");
+        }
+        _ => panic!("Joined a room which doesn't exist"),
+    }
+
+    player.send(MsgType::Map, Some(MsgBody::Json(JsonValue::Null)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+    assert_eq!(err.reference.unwrap().msg_type, MsgType::Map);
+
+    master.send(MsgType::Map, Some(MsgBody::Json(object! {"abc": 5})));
+    let err = ErrResponse::from_json(&master.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.code, ErrorCode::BadRequest);
+
+    // Walls are up to the GM
+    let wall = object! {"wall": {"type": "wall", "nodes": [[0, 0], [10, 0]]}};
+    player.send(MsgType::Map, Some(MsgBody::Json(wall)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+    assert_eq!(err.reference.unwrap().id.as_deref(), Some("wall"));
+
+    let fog = object! {"fog": {"revealAll": true}};
+    player.send(MsgType::Map, Some(MsgBody::Json(fog)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.reference.unwrap().id.as_deref(), Some("fog"));
+
+    let secret = object! {"trap": {"type": "decal", "path": "trap.png", "layer": "gm"}};
+    player.send(MsgType::Map, Some(MsgBody::Json(secret)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.reference.unwrap().id.as_deref(), Some("trap"));
+
+    // So is who sees through which token
+    let id = player.id();
+    let token = object! {"orc": {"type": "token", "path": "orc.png", "properties": {"owner": id}}};
+    player.send(MsgType::Map, Some(MsgBody::Json(token)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.reference.unwrap().id.as_deref(), Some("orc"));
+}
+
+#[test]
+fn passwords_and_invites() {
+    let addr = start_server();
+    let ctx = Context::default();
+    let conn =
+        ServerConnection::create_new_room(&addr.to_string(), Some("swordfish"), &ctx).unwrap();
+    let mut master = TestClient::new(conn);
+    let room_id = master.room_id();
+    let join = |password: Option<&str>, invite: Option<&str>| {
+        let options = JoinOptions {
+            password: password.map(str::to_string),
+            invite: invite.map(str::to_string),
+            ..JoinOptions::default()
+        };
+        ServerConnection::join_room(&addr.to_string(), &room_id, &options, &ctx)
+    };
+    let error_code = |result: Result<ServerConnection, DraduError>| match result {
+        Err(DraduError::ServerError(code, _)) => code,
+        _ => panic!("Joined without the right password"),
+    };
+
+    assert_eq!(error_code(join(None, None)), ErrorCode::WrongPassword);
+    assert_eq!(
+        error_code(join(Some("hunter2"), None)),
+        ErrorCode::WrongPassword
+    );
+    let mut player = TestClient::new(join(Some("swordfish"), None).unwrap());
+    player.expect(MsgType::Synced);
+
+    // Invites are single use
+    let request = object! {"expiresIn": 60};
+    master.send(MsgType::Invite, Some(MsgBody::Json(request.clone())));
+    let invite = master.expect_json(MsgType::Invite);
+    let token = invite["token"].as_str().unwrap();
+    TestClient::new(join(None, Some(token)).unwrap()).expect(MsgType::Synced);
+    assert_eq!(error_code(join(None, Some(token))), ErrorCode::InviteUsed);
+    assert_eq!(
+        error_code(join(None, Some("abcd.1.ffff"))),
+        ErrorCode::InviteInvalid
+    );
+
+    player.send(MsgType::Invite, Some(MsgBody::Json(request)));
+    let err = ErrResponse::from_json(&player.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+}
+
+#[test]
+fn spectators() {
+    let (addr, mut master, mut player) = start_room();
+    let options = JoinOptions {
+        spectator: true,
+        ..JoinOptions::default()
+    };
+    let conn = ServerConnection::join_room(
+        &addr.to_string(),
+        &master.room_id(),
+        &options,
+        &Context::default(),
+    );
+    let mut spectator = TestClient::new(conn.unwrap());
+    let joined = player.expect_json(MsgType::Player);
+    assert_eq!(joined[spectator.id()]["spectator"], true);
+    master.expect(MsgType::Player);
+    spectator.expect(MsgType::Synced);
+
+    spectator.say("Hello there");
+    let err = ErrResponse::from_json(&spectator.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.code, ErrorCode::PermissionDenied);
+    spectator.send(
+        MsgType::Map,
+        Some(MsgBody::Json(object! {"abc": {"scale": 2.0}})),
+    );
+    let err = ErrResponse::from_json(&spectator.expect_json(MsgType::Err)).unwrap();
+    assert_eq!(err.reference.unwrap().msg_type, MsgType::Map);
+
+    // Their own nickname is the only thing they can change
+    spectator.say("/nick Eve");
+    let update = master.expect_json(MsgType::Player);
+    assert_eq!(update[spectator.id()]["nickname"], "Eve");
+    player.say("Still here");
+    assert_eq!(master.expect_text(MsgType::Msg).1, "Still here");
+
+    // Client doesn't even try
+    let ctx = Context::default();
+    let mut state = RoomState::join_room(
+        ServerAddr::Tcp(addr.to_string()),
+        &master.room_id(),
+        &options,
+        &ctx,
+    );
+    let map = Message::new(MsgType::Map);
+    assert!(matches!(
+        state.as_mut().unwrap().send_msg(map),
+        Err(DraduError::ReadOnly)
+    ));
+}
+
+#[test]
+fn lan_discovery() {
+    let (addr, discovery) = start_discoverable_server();
+    let mut master = TestClient::create_room(addr);
+    master.say("/roomname The Keep");
+    master.expect(MsgType::Msg);
+    let mut player = TestClient::join(addr, &master.room_id());
+    player.expect(MsgType::Synced);
+
+    let mut browser = LanBrowser::with_targets(vec![discovery]).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    let room = loop {
+        browser.poll(Instant::now());
+        if let Some(room) = browser.rooms().next() {
+            break room.clone();
+        }
+        assert!(Instant::now() < deadline, "Room hasn't been discovered");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(room.summary.id, master.room_id());
+    assert_eq!(room.summary.name, "The Keep");
+    assert_eq!(room.summary.gm, "Master");
+    assert_eq!(room.summary.players, 2);
+
+    // Discovered address is good enough to join
+    assert_eq!(room.address, addr.to_string());
+    let ctx = Context::default();
+    let addr = room.address.parse().unwrap();
+    let mut state =
+        RoomState::join_room(addr, &room.summary.id, &JoinOptions::default(), &ctx).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    while state.loading().is_some() {
+        assert!(Instant::now() < deadline, "Never got synced");
+        state.update_self().unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Only the GM names the room
+    player.say("/roomname Mine");
+    let (_, text) = player.expect_text(MsgType::Msg);
+    assert_eq!(text, "Only the GM can rename the room");
+}
+
+#[test]
+fn drag_streaming() {
+    let (_, mut master, mut player) = start_room();
+    let token = object! {"abc": {"type": "token", "path": "orc.png"}};
+    master.send(MsgType::Map, Some(MsgBody::Json(token)));
+    player.expect(MsgType::Map);
+    master.expect(MsgType::Map);
+    assert!(player.conn.supports("transient"));
+
+    // Moves sent in quick succession are coalesced, only the latest ones get
+    // through
+    for x in 1..=5 {
+        let mut msg = Message::new(MsgType::Map).set_prop("transient", "true");
+        msg.attach_body(MsgBody::Json(object! {"abc": {"pos": [x, 0]}}));
+        player.conn.send_msg(msg).unwrap();
+    }
+    loop {
+        let mut moved = master.expect(MsgType::Map);
+        assert!(moved.get_prop("transient").is_some());
+        let x = match moved.take_body() {
+            Some(MsgBody::Json(json)) => json["abc"]["pos"][0].as_f64().unwrap(),
+            _ => panic!("Expected a json body"),
+        };
+        if x == 5.0 {
+            break;
+        }
+    }
+
+    // The final position is what stays on the map, and the one who dragged
+    // the object doesn't get its own moves back
+    let released = object! {"abc": {"pos": [6.0, 0.0]}};
+    player.send(MsgType::Map, Some(MsgBody::Json(released.clone())));
+    let msg = player.expect(MsgType::Map);
+    assert!(msg.get_prop("transient").is_none());
+    assert_eq!(master.expect_json(MsgType::Map), released);
+}
+
+#[test]
+fn file_transfer() {
+    let (_, mut master, mut player) = start_room();
+
+    let request = Message::new(MsgType::File)
+        .set_prop("path", "orc.png")
+        .set_prop("requestId", "mine");
+    player.conn.send_msg(request).unwrap();
+    let request = master.expect(MsgType::File);
+    assert_eq!(request.get_prop("path"), Some("orc.png"));
+    let request_id = request.get_prop("requestId").unwrap().to_string();
+    assert_ne!(request_id, "mine");
+
+    for (offset, chunk) in [(0, b"abc"), (3, b"def")] {
+        let mut msg = Message::new(MsgType::File)
+            .set_prop("path", "orc.png")
+            .set_prop("requestId", &request_id)
+            .set_prop("chunk", &(offset / 3).to_string())
+            .set_prop("offset", &offset.to_string())
+            .set_prop("totalSize", "6")
+            .set_prop("checksum", "whatever");
+        msg.attach_body(MsgBody::Bin(chunk.to_vec()));
+        master.conn.send_msg(msg).unwrap();
+
+        let mut msg = player.expect(MsgType::File);
+        assert_eq!(msg.get_prop("requestId"), Some("mine"));
+        assert_eq!(msg.get_prop("offset"), Some(&*offset.to_string()));
+        assert_eq!(msg.get_prop("userCookie"), None);
+        assert!(matches!(msg.take_body(), Some(MsgBody::Bin(b)) if b == chunk));
+    }
+}
+
+#[test]
+fn preloading() {
+    let addr = start_server();
+    let mut master = TestClient::create_room(addr);
+    let map = object! {
+        "orc": {"type": "token", "path": "orc.png"},
+        "goblin": {"type": "token", "path": "orc.png"},
+        "background": {"path": "bg.png"},
+    };
+    master.send(MsgType::Map, Some(MsgBody::Json(map)));
+    master.expect(MsgType::Map);
+
+    let ctx = Context::default();
+    let mut state = RoomState::join_room(
+        ServerAddr::Tcp(addr.to_string()),
+        &master.room_id(),
+        &JoinOptions::default(),
+        &ctx,
+    )
+    .unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    while !state.loading().unwrap().synced {
+        assert!(Instant::now() < deadline, "No SYNCED from the server");
+        state.update_self().unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(state.loading().unwrap().assets, 2);
+    assert_eq!(state.loaded_assets(), 0);
+
+    // Whether the images are any good doesn't matter, the room is loaded
+    // either way
+    let bytes = b"not really an image";
+    for _ in 0..2 {
+        let request = master.expect(MsgType::File);
+        let mut msg = Message::new(MsgType::File)
+            .set_prop("path", request.get_prop("path").unwrap())
+            .set_prop("requestId", request.get_prop("requestId").unwrap())
+            .set_prop("chunk", "0")
+            .set_prop("offset", "0")
+            .set_prop("totalSize", &bytes.len().to_string())
+            .set_prop("checksum", &checksum(bytes));
+        msg.attach_body(MsgBody::Bin(bytes.to_vec()));
+        master.conn.send_msg(msg).unwrap();
+    }
+    while state.loading().is_some() {
+        assert!(Instant::now() < deadline, "Room hasn't been loaded");
+        state.update_self().unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn resume_and_quit() {
+    let (_, mut master, mut player) = start_room();
+    let id = player.id();
+    player.say("/nick Bob");
+    master.expect_json(MsgType::Player);
+
+    // Connection is lost, but the player comes back
+    player.conn.close();
+    assert!(master.expect_json(MsgType::Player)[&id].is_empty());
+    player.conn.reconnect().unwrap();
+    assert_eq!(player.id(), id);
+    assert_eq!(player.conn.get_nickname(), "Bob");
+    assert_eq!(master.expect_json(MsgType::Player)[&id]["nickname"], "Bob");
+
+    player.send(MsgType::Quit, None);
+    assert!(master.expect_json(MsgType::Player)[&id].is_empty());
+}
+
+#[test]
+fn heartbeat() {
+    let (_, mut master, mut player) = start_room();
+    assert!(master.conn.supports("heartbeat"));
+
+    // Latency measured with the first PING is reported in the next one
+    let deadline = Instant::now() + PING_INTERVAL + TIMEOUT;
+    let master_id = master.id();
+    let latency =
+        loop {
+            // Connection PINGs the server and takes PONGs out by itself
+            let messages = master.conn.new_messages().unwrap();
+            assert!(messages.iter().all(|msg| msg.msg_type() != MsgType::Pong));
+
+            player.received.extend(player.conn.new_messages().unwrap());
+            let reported = player.received.drain(..).find_map(|mut msg| {
+                match (msg.msg_type(), msg.take_body()) {
+                    (MsgType::Player, Some(MsgBody::Json(json))) => {
+                        json[&master_id]["latency"].as_u64()
+                    }
+                    _ => None,
+                }
+            });
+            if let Some(latency) = reported {
+                break latency;
+            }
+            assert!(Instant::now() < deadline, "Latency hasn't been reported");
+            thread::sleep(Duration::from_millis(10));
+        };
+    assert!(master.conn.latency().is_some());
+    assert!(latency < TIMEOUT.as_millis() as u64);
+}
+
+#[test]
+fn tls_and_pinning() {
+    let addr = start_tls_server().to_string();
+    let path = std::env::temp_dir().join(format!("known_hosts_{}", client::utils::random_id()));
+    let known_hosts = KnownHosts::at(path.clone());
+    let ctx = Context::default();
+
+    let conn = TlsConnection::create_new_room(&addr, None, known_hosts.clone(), &ctx).unwrap();
+    assert!(conn.get_room_address().unwrap().starts_with("dradu+tls://"));
+    let mut master = TestClient::new(conn);
+    // The certificate has been pinned on first use
+    assert!(known_hosts.get(&addr).is_some());
+
+    let options = JoinOptions::default();
+    let conn = TlsConnection::join_room(
+        &addr,
+        &master.room_id(),
+        &options,
+        known_hosts.clone(),
+        &ctx,
+    );
+    let mut player = TestClient::new(conn.unwrap());
+    player.say("hello");
+    assert_eq!(
+        master.expect_text(MsgType::Msg),
+        (player.id(), "hello".to_string())
+    );
+
+    // Server's certificate isn't the one we remember anymore
+    known_hosts.pin(&addr, "0000").unwrap();
+    match TlsConnection::join_room(&addr, &master.room_id(), &options, known_hosts, &ctx) {
+        Err(DraduError::CertificateChanged(host, _)) => assert_eq!(host, addr),
+        _ => panic!("Changed certificate was accepted"),
+    }
+    std::fs::remove_file(path).unwrap();
+}